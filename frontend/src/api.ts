@@ -39,10 +39,11 @@ export async function getCanvases() {
   return fetch(`${API_BASE}/canvases/list`);
 }
 
-export async function createCanvas(name: string) {
+/** Retrying with the same `idempotencyKey` returns the canvas of the first attempt instead of creating another. */
+export async function createCanvas(name: string, idempotencyKey: string) {
   return fetch(`${API_BASE}/canvases/create`, {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: { "Content-Type": "application/json", "Idempotency-Key": idempotencyKey },
     body: JSON.stringify({ name }),
   });
 }
@@ -1,9 +1,11 @@
 import type { EventSystem, Canvas } from "./drawer.js";
+import { navigateTo } from "../../router.js";
 
 type Handlers = {
   setEditingPower?: (canEdit: boolean) => void;
   setModerationState?: (isModerated: boolean) => void;
   setModerationPower?: (canToggleModeration: boolean) => void;
+  setAnnouncement?: (text: string | null) => void;
 };
 
 export class BackendSync {
@@ -12,8 +14,16 @@ export class BackendSync {
 
   // track current backend state
   private moderationState: boolean = false;
+  // minimum time between two of our batches in ms, 0 if slow mode is off
+  private slowModeMs = 0;
   private userPermission: string | null = null;
 
+  // shape ids of added shapes by server sequence number, used to apply deletions
+  private shapeIdsBySeq = new Map<number, string>();
+
+  // highest server sequence number applied, events up to it were already seen
+  private lastSeq = 0;
+
   constructor(
     private es: EventSystem,
     private canvas: Canvas,
@@ -34,14 +44,19 @@ export class BackendSync {
     this.socket.addEventListener("message", (evt) =>
       this.handleIncomingMessage(evt.data)
     );
-    this.socket.addEventListener("close", () =>
-      console.warn("[BackendSync] Connection closed")
-    );
+    this.socket.addEventListener("close", (evt) => {
+      console.warn("[BackendSync] Connection closed", evt.code, evt.reason);
+      // 4001: session expired, the user has to log in again
+      if (evt.code === 4001) {
+        window.location.href = "/login";
+      }
+    });
     this.socket.addEventListener("error", (err) =>
       console.error("[BackendSync] Socket error:", err)
     );
 
-    // Forward local events to backend only (do NOT apply here)
+    // Forward local events to backend and apply them locally,
+    // the backend does not echo our own events back to us
     this.es.register((event: any) => this.send(event));
   }
 
@@ -72,11 +87,126 @@ export class BackendSync {
       console.log("[BackendSync] Incoming plain:", data);
       const msg = JSON.parse(data);
 
+      // The session is about to expire, not tied to a canvas
+      if (msg.type === "authExpiring") {
+        const expiresAt = new Date(msg.authExpiring.expiresAt * 1000);
+        console.warn("[BackendSync] Session expires at", expiresAt.toLocaleString());
+        alert(`Your session expires at ${expiresAt.toLocaleTimeString()}. Please log in again to keep working.`);
+        return;
+      }
+
       if (msg.canvasId !== this.canvasId) return;
 
+      // The canvas is gone, e.g. deleted by its owner
+      if (msg.type === "canvasEvicted") {
+        alert(msg.reason === "deleted" ? "This canvas was deleted." : "This canvas is no longer available.");
+        navigateTo("/");
+        return;
+      }
+
+      // The server dropped messages for us: reload the canvas from scratch
+      if (msg.type === "resync") {
+        this.resync();
+        return;
+      }
+
+      // Our events were saved
+      if (msg.type === "ack") {
+        console.debug("[BackendSync]", msg.count, "events saved");
+        return;
+      }
+
+      // Our events wait for a moderator while the canvas is moderated
+      if (msg.type === "eventsHeld") {
+        console.info("[BackendSync]", msg.count, "events held for review in batch", msg.batchId);
+        return;
+      }
+
+      // Moderators only: the number of batches waiting for review
+      if (msg.type === "pendingEvents") {
+        console.info("[BackendSync]", msg.count, "batches of events are waiting for review");
+        return;
+      }
+
+      // Moderators only: someone reported the canvas or some of its events
+      if (msg.type === "reportFiled") {
+        console.info("[BackendSync]", msg.report.reporterName, "reported", msg.report.seqs.length ? `events ${msg.report.seqs.join(", ")}` : "the canvas", "-", msg.report.reason);
+        return;
+      }
+
+      // Our report was stored
+      if (msg.type === "reportReceived") {
+        console.info("[BackendSync] Report", msg.reportId, "filed");
+        return;
+      }
+
+      // Approved events arrive like any others; rejected ones are still drawn locally, so reload
+      if (msg.type === "heldEventsReviewed") {
+        if (!msg.approved) {
+          alert(msg.reason
+            ? `A moderator rejected some of your drawing: ${msg.reason}`
+            : "A moderator rejected some of your drawing.");
+          this.resync();
+        }
+        return;
+      }
+
+      // The announcement pinned by a moderator, null once cleared
+      if (msg.type === "announcement") {
+        if (msg.announcement) {
+          console.info("[BackendSync] Announcement by", msg.announcement.authorName + ":", msg.announcement.text);
+        }
+        this.handlers.setAnnouncement?.(msg.announcement?.text ?? null);
+        return;
+      }
+
+      // A moderator changed how long we have to wait between two batches
+      if (msg.type === "slowMode") {
+        this.slowModeMs = msg.slowModeMs;
+        console.info("[BackendSync] Slow mode", this.slowModeMs ? `set to ${this.slowModeMs} ms` : "turned off");
+        return;
+      }
+
+      // The owner presents: only they and the listed presenters can draw meanwhile
+      if (msg.type === "presentationMode") {
+        console.info("[BackendSync] Presentation mode", msg.enabled ? `started, presenters: ${msg.presenters.join(", ") || "owner only"}` : "ended");
+        return;
+      }
+
+      // A moderator muted us: we can still watch, but our drawing is rejected until the mute ends
+      if (msg.type === "muted") {
+        alert(msg.until
+          ? `A moderator muted you on this canvas until ${new Date(msg.until).toLocaleTimeString()}.`
+          : "A moderator muted you on this canvas.");
+        return;
+      }
+
+      if (msg.type === "unmuted") {
+        alert("A moderator unmuted you on this canvas.");
+        return;
+      }
+
+      // A moderator banned us: the server already removed us from the canvas
+      if (msg.type === "banned") {
+        const until = msg.expiresAt ? ` until ${new Date(msg.expiresAt).toLocaleTimeString()}` : "";
+        alert(`A moderator banned you from this canvas${until}.${msg.reason ? ` Reason: ${msg.reason}` : ""}`);
+        navigateTo("/");
+        return;
+      }
+
+      // Large logs start with a compacted snapshot: deleted shapes are left out, the history follows as usual
+      if (msg.type === "historySnapshot") {
+        console.info("[BackendSync] History starts with a snapshot up to sequence", msg.fromSeq);
+        return;
+      }
+
       // Moderation state messages
       if (typeof msg.moderated === "boolean") {
         this.moderationState = msg.moderated;
+        this.slowModeMs = msg.slowModeMs ?? 0;
+        if (msg.changedBy) {
+          console.info("[BackendSync]", msg.changedBy.displayName, msg.moderated ? "enabled" : "disabled", "moderation");
+        }
         this.handlers.setModerationState?.(msg.moderated);
         this.updateEditingPower(); // recalc based on new moderation state
         return;
@@ -99,9 +229,12 @@ export class BackendSync {
 
       // History / event replay messages
       if (Array.isArray(msg.eventsForCanvas)) {
-        msg.eventsForCanvas.forEach((ev: any) => {
-          this.canvas.apply(ev);
-        });
+        // Large histories arrive in chunks; only the first one starts over
+        if (msg.type === "history" && !msg.chunk) {
+          this.shapeIdsBySeq.clear();
+          this.lastSeq = 0;
+        }
+        msg.eventsForCanvas.forEach((ev: any) => this.applyRemoteEvent(ev));
         return;
       }
     } catch (err) {
@@ -109,6 +242,53 @@ export class BackendSync {
     }
   }
 
+  /**
+   * Drop the local drawing and register again for the full history.
+   */
+  private resync() {
+    this.canvas.reset();
+    const registerMsg = { command: "registerForCanvas", canvasId: this.canvasId, resendHistory: true };
+    this.socket.send(JSON.stringify(registerMsg));
+  }
+
+  /**
+   * Apply an event received from the backend. Tombstones remove the shapes
+   * added by the events they target. Events already applied are skipped by sequence number.
+   */
+  private applyRemoteEvent(ev: any) {
+    if (typeof ev._seq === "number") {
+      if (ev._seq <= this.lastSeq) return;
+      this.lastSeq = ev._seq;
+    }
+
+    if (ev.type === "delete" && Array.isArray(ev.targets)) {
+      ev.targets.forEach((seq: number) => {
+        const shapeId = this.shapeIdsBySeq.get(seq);
+        if (shapeId !== undefined) {
+          this.canvas.apply({ type: "shapeRemovedWithId", shapeId, redraw: true });
+          this.shapeIdsBySeq.delete(seq);
+        }
+      });
+      return;
+    }
+
+    // Records of moderation and admin actions, they don't change the drawing
+    if (ev.type === "system") {
+      console.log("[BackendSync] System event:", ev.action, "by user", ev.by, "at", ev._ts);
+      // The canvas was cleared for everyone, the history starts here
+      if (ev.action === "clear") {
+        this.shapeIdsBySeq.clear();
+        this.canvas.reset();
+      }
+      return;
+    }
+
+    if (typeof ev._seq === "number" && ev.type === "shapeAdded" && ev.shape?.id) {
+      this.shapeIdsBySeq.set(ev._seq, ev.shape.id);
+    }
+    this.canvas.apply(ev);
+  }
+
   /**
    * Decide if user can edit given current permission + moderation state.
    */
@@ -122,8 +302,8 @@ export class BackendSync {
       // Co-owner, Owner, Moderator, VIP can always edit
       canEdit = true;
     } else if (perm === "W") {
-      // Writer can only edit if moderation is OFF
-      canEdit = !this.moderationState;
+      // Writer can always edit too; while moderation is ON their events wait for review
+      canEdit = true;
     } else {
       // R (read-only) or unknown → no editing
       canEdit = false;
@@ -142,5 +322,6 @@ export class BackendSync {
       eventsForCanvas: [event],
     };
     this.socket.send(JSON.stringify(message));
+    this.canvas.apply(event);
   }
 }
\ No newline at end of file
@@ -1,10 +1,17 @@
 import { createCanvas, getCanvases, getUserInfo, logout, updateUserInfo } from "../api.js";
 import { navigateTo } from "../router.js";
 
+/** `CanvasResponse` of the backend, the same for the list and a created canvas. */
 interface CanvasInfo {
   canvas_id: string;
   name: string;
+  owner_user_id: number;
+  owner_display_name: string;
   permission_level: "R" | "W" | "V" | "M" | "O" | "C";
+  /** Approximate number of events on the canvas. */
+  event_count: number;
+  /** Milliseconds since the Unix epoch, null for old canvases. */
+  created_at: number | null;
 }
 
 interface UserInfo {
@@ -90,6 +97,29 @@ export function renderHome() {
   const updateDisplay = document.getElementById("user-display") as HTMLInputElement;
   const updateMsg = document.getElementById("update-user-msg") as HTMLDivElement;
 
+  const renderCanvas = (c: CanvasInfo): HTMLLIElement => {
+    const { label, color } = formatPermission(c.permission_level);
+
+    const li = document.createElement("li");
+    li.style.cursor = "pointer";
+    li.style.padding = "5px 0";
+
+    li.innerHTML = `
+      ${c.name} <span style="
+        background-color: ${color};
+        color: white;
+        padding: 2px 6px;
+        border-radius: 6px;
+        font-size: 0.85em;
+        margin-left: 6px;
+      ">${label}</span>
+      <span style="color: #888; font-size: 0.85em; margin-left: 6px;">${c.event_count} events</span>
+    `;
+
+    li.addEventListener("click", () => navigateTo(`/canvas/${c.canvas_id}`));
+    return li;
+  };
+
   // === Fetch canvases from backend ===
   const loadCanvases = async () => {
     try {
@@ -102,29 +132,9 @@ export function renderHome() {
       const canvases: CanvasInfo[] = await res.json();
       canvasList.innerHTML = canvases.length
         ? ""
-        : `<li>No canvases available.</li>`;
-
-      canvases.forEach((c) => {
-        const { label, color } = formatPermission(c.permission_level);
-
-        const li = document.createElement("li");
-        li.style.cursor = "pointer";
-        li.style.padding = "5px 0";
-
-        li.innerHTML = `
-          ${c.name} <span style="
-            background-color: ${color};
-            color: white;
-            padding: 2px 6px;
-            border-radius: 6px;
-            font-size: 0.85em;
-            margin-left: 6px;
-          ">${label}</span>
-        `;
-
-        li.addEventListener("click", () => navigateTo(`/canvas/${c.canvas_id}`));
-        canvasList.appendChild(li);
-      });
+        : `<li class="canvas-placeholder">No canvases available.</li>`;
+
+      canvases.forEach((c) => canvasList.appendChild(renderCanvas(c)));
     } catch (err) {
       console.error(err);
       canvasList.innerHTML = `<li>Network error while loading canvases.</li>`;
@@ -161,6 +171,8 @@ export function renderHome() {
   });
 
   // === Create new canvas ===
+  // Kept until a canvas was created, so retrying after a network error can't create it twice
+  let createKey = crypto.randomUUID();
   createBtn.addEventListener("click", async () => {
     const name = createInput.value.trim();
     if (!name) {
@@ -170,12 +182,16 @@ export function renderHome() {
     }
 
     try {
-      const res = await createCanvas(name);
+      const res = await createCanvas(name, createKey);
       if (res.ok) {
+        createKey = crypto.randomUUID();
         createMsg.style.color = "green";
         createMsg.textContent = "Canvas created!";
         createInput.value = "";
-        loadCanvases();
+        // The response is the new canvas, no need to fetch the whole list again
+        const canvas: CanvasInfo = await res.json();
+        canvasList.querySelector(".canvas-placeholder")?.remove();
+        canvasList.prepend(renderCanvas(canvas));
       } else {
         const err = await res.text();
         createMsg.style.color = "red";
@@ -199,8 +215,12 @@ export function renderHome() {
     }
 
     try {
-      const res = await updateUserInfo(email, display_name);
+      // Empty fields stay unchanged
+      const res = await updateUserInfo(email || undefined, display_name || undefined);
       if (res.ok) {
+        const user: UserInfo = await res.json();
+        updateEmail.value = user.email;
+        updateDisplay.value = user.display_name;
         updateMsg.style.color = "green";
         updateMsg.textContent = "User info updated!";
       } else {
@@ -0,0 +1,2 @@
+-- Instance admins may use the /api/admin endpoints
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Per-canvas event coalescing. NULL follows the instance default (COALESCE_EVENTS).
+ALTER TABLE Canvas ADD COLUMN coalesce_events BOOLEAN DEFAULT NULL;
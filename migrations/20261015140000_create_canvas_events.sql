@@ -0,0 +1,23 @@
+-- Canvas event logs for CANVAS_STORE=sqlite, one row per event in log order (rowid).
+-- seq is NULL for events written before sequence numbers were introduced.
+CREATE TABLE canvas_events (
+    canvas_id TEXT NOT NULL,
+    seq INTEGER,
+    payload TEXT NOT NULL, -- The event as JSON
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_canvas_events_canvas_seq ON canvas_events(canvas_id, seq);
+
+-- Logs replaced by compaction
+CREATE TABLE canvas_events_backup (
+    canvas_id TEXT NOT NULL,
+    seq INTEGER,
+    payload TEXT NOT NULL,
+    created_at DATETIME,
+    backed_up_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Whether the canvas' event log is stored gzip-compressed (`.jsonl.gz`), see FileEventStore::compress.
+ALTER TABLE Canvas ADD COLUMN compressed BOOLEAN NOT NULL DEFAULT FALSE;
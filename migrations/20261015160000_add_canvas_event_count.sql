@@ -0,0 +1,3 @@
+-- Approximate number of events in the canvas' log, written back by the server periodically.
+-- Existing canvases start at 0; fill them in with `web_server_axum backfill-event-counts`.
+ALTER TABLE Canvas ADD COLUMN event_count INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Groups the rows of a replaced log, so archived logs can be listed and restored.
+-- Milliseconds since the Unix epoch; NULL for quarantined rows and logs replaced before archives.
+ALTER TABLE canvas_events_backup ADD COLUMN archive_id INTEGER;
+
+CREATE INDEX idx_canvas_events_backup_archive ON canvas_events_backup(canvas_id, archive_id);
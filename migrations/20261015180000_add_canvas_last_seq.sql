@@ -0,0 +1,3 @@
+-- Highest sequence number taken for the canvas' log, written back by the server periodically.
+-- Keeps sequence numbers growing across restarts, even after compaction renumbered the log.
+ALTER TABLE Canvas ADD COLUMN last_seq INTEGER NOT NULL DEFAULT 0;
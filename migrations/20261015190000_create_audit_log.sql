@@ -0,0 +1,11 @@
+-- Administrative actions and their outcomes, newest last.
+CREATE TABLE audit_log (
+    audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER, -- Who triggered the action, NULL for the server itself (e.g. scheduled tasks)
+    action TEXT NOT NULL, -- e.g. 'backup'
+    outcome TEXT NOT NULL, -- 'ok' or 'failed'
+    detail TEXT NOT NULL DEFAULT '{}', -- JSON with action-specific details
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_audit_log_action ON audit_log(action, created_at);
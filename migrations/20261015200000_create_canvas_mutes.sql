@@ -0,0 +1,12 @@
+-- Users muted on a canvas: they keep their permission and can view, but their events are rejected.
+CREATE TABLE canvas_mutes (
+    canvas_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    until INTEGER, -- Milliseconds since the Unix epoch, NULL until unmuted
+    muted_by INTEGER NOT NULL, -- The moderator who muted the user
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (canvas_id, user_id),
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+-- Users banned from a canvas: they keep their permission but can't subscribe to it or draw on it.
+CREATE TABLE canvas_bans (
+    canvas_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    expires_at INTEGER, -- Milliseconds since the Unix epoch, NULL for a permanent ban
+    reason TEXT,
+    banned_by INTEGER NOT NULL, -- The moderator who banned the user
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (canvas_id, user_id),
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_canvas_bans_expires_at ON canvas_bans(expires_at);
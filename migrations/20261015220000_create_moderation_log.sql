@@ -0,0 +1,14 @@
+-- Moderation actions on canvases, e.g. toggling moderation, muting or banning a user, newest last.
+CREATE TABLE moderation_log (
+    log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    canvas_id TEXT NOT NULL,
+    actor_id INTEGER, -- The moderator, NULL for the server itself
+    action TEXT NOT NULL, -- e.g. 'moderation_on', 'ban'
+    target_id INTEGER, -- The user acted on, if any
+    details TEXT NOT NULL DEFAULT '{}', -- JSON with action-specific details
+    created_at INTEGER NOT NULL, -- Milliseconds since the Unix epoch
+
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_moderation_log_canvas ON moderation_log(canvas_id, log_id);
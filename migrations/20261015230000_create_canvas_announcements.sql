@@ -0,0 +1,13 @@
+-- Announcements moderators pin to a canvas. At most one per canvas is active; replaced and cleared ones are kept.
+CREATE TABLE canvas_announcements (
+    announcement_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    canvas_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    author_id INTEGER NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at INTEGER NOT NULL, -- Milliseconds since the Unix epoch
+
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_canvas_announcements_active ON canvas_announcements(canvas_id, active);
@@ -0,0 +1,19 @@
+-- Problematic content reported by users, either a whole canvas or specific events of it.
+CREATE TABLE reports (
+    report_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    canvas_id TEXT NOT NULL,
+    reporter_id INTEGER NOT NULL,
+    seqs TEXT NOT NULL DEFAULT '[]', -- Sorted JSON array of the reported sequence numbers, empty for the whole canvas
+    reason TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'open', -- 'open', 'dismissed' or 'actioned'
+    resolved_by INTEGER,
+    resolved_at INTEGER, -- Milliseconds since the Unix epoch
+    created_at INTEGER NOT NULL, -- Milliseconds since the Unix epoch
+
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE,
+    FOREIGN KEY (reporter_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+-- A user's repeated report of the same target updates their open report instead of adding one
+CREATE UNIQUE INDEX idx_reports_open_target ON reports(canvas_id, reporter_id, seqs) WHERE status = 'open';
+CREATE INDEX idx_reports_status ON reports(status, report_id);
@@ -0,0 +1,2 @@
+-- Minimum time between two batches of events of the same user on a canvas. 0 turns slow mode off.
+ALTER TABLE Canvas ADD COLUMN slow_mode_ms INTEGER NOT NULL DEFAULT 0;
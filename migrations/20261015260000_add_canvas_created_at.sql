@@ -0,0 +1,2 @@
+-- Creation time in milliseconds since the Unix epoch. NULL for canvases created before it was recorded.
+ALTER TABLE Canvas ADD COLUMN created_at INTEGER;
@@ -0,0 +1,16 @@
+-- `Idempotency-Key` headers of canvas creations, so a retried request returns the canvas of the first one
+-- instead of creating another. Pruned after 24 hours.
+CREATE TABLE idempotency_keys (
+    user_id INTEGER NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    canvas_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL, -- ms since the Unix epoch
+
+    -- Of concurrent requests with the same key only one can insert its row, and only it creates a canvas
+    UNIQUE (user_id, idempotency_key),
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE,
+    -- Checked on commit: the key is claimed before the canvas row it points to is inserted
+    FOREIGN KEY (canvas_id) REFERENCES Canvas(canvas_id) ON DELETE CASCADE DEFERRABLE INITIALLY DEFERRED
+);
+
+CREATE INDEX idx_idempotency_keys_created_at ON idempotency_keys(created_at);
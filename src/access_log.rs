@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+
+/// Target of the access log lines, so they can be filtered on their own, e.g. `RUST_LOG=access_log=info`.
+pub const TARGET: &str = "access_log";
+
+/// Put into the response extensions by whatever authenticates a request (the auth middleware, the
+/// WebSocket upgrade), so the access log line names the user.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthenticatedUser(pub i64);
+
+/// Logs one line per request with its route pattern, status, latency, response size and user. Runs inside
+/// `request_id_middleware`, so the line carries the request id of the enclosing span. Only the head of the
+/// request is looked at, never its body, so nothing of e.g. `/api/login` or `/api/register` ends up in the log.
+pub async fn access_log_middleware(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    // The pattern, not the path, so canvas ids don't end up in the route field; unmatched paths go to the fallback
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "fallback".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(request).await;
+
+    // Streamed bodies (SSE, files being compressed) have no known size
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    let user_id = response.extensions().get::<AuthenticatedUser>().map(|user| user.0);
+
+    tracing::info!(
+        target: TARGET,
+        %method,
+        route = %route,
+        status = response.status().as_u16(),
+        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+        bytes,
+        user_id,
+        "{} {} {}",
+        method,
+        route,
+        response.status().as_u16(),
+    );
+    response
+}
+
+/// Connect and disconnect lines of a WebSocket connection, with its duration and message counts.
+/// Logged in the connection's `ws` span, which names the user and lies under the upgrade request's span.
+pub struct WsAccessLog {
+    started: Instant,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl WsAccessLog {
+    pub fn connected() -> Self {
+        tracing::info!(target: TARGET, "WebSocket connected");
+        Self {
+            started: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a message from the client.
+    pub fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message written to the client.
+    pub fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        tracing::info!(
+            target: TARGET,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            received = self.received.load(Ordering::Relaxed),
+            sent = self.sent.load(Ordering::Relaxed),
+            "WebSocket disconnected"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::{self, BufRead, IsTerminal};
+
+use clap::{error::ErrorKind, Parser, Subcommand};
+use serde_json::json;
+use sqlx::{query, SqlitePool};
+
+use crate::{
+    audit::{self, AuditOutcome},
+    auth::hash_password,
+    validation::{MAX_PASSWORD_CHARS, MIN_PASSWORD_CHARS},
+};
+
+/// Manages users without starting the server.
+///
+/// Passwords are prompted for, or read as the first line of stdin when it isn't a terminal.
+#[derive(Debug, Parser)]
+#[command(name = "admin")]
+struct AdminCli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Creates a user, e.g. the first admin
+    CreateUser {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        display_name: String,
+        /// Makes the user an admin
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Sets a new password for a user
+    ResetPassword {
+        #[arg(long)]
+        email: String,
+    },
+    /// Makes a user an admin
+    Promote {
+        #[arg(long)]
+        email: String,
+    },
+    /// Lists all users: id, email, display name and whether they are an admin
+    ListUsers,
+}
+
+/// Runs `admin <command> [options]` against the database, without starting the server.
+/// `--help` prints the usage; unknown commands and options are errors, with the usage.
+pub async fn run(pool: &SqlitePool, args: &[String]) -> Result<(), String> {
+    let cli = match AdminCli::try_parse_from(std::iter::once("admin").chain(args.iter().map(String::as_str))) {
+        Ok(cli) => cli,
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => {
+            print!("{}", e.render());
+            return Ok(());
+        }
+        Err(e) => return Err(e.render().to_string()),
+    };
+    match cli.command {
+        Command::CreateUser { email, display_name, admin: is_admin } => {
+            let password_hash = prompt_password_hash()?;
+            let user_id = query!(
+                "INSERT INTO users (email, password_hash, display_name, is_admin) VALUES (?, ?, ?, ?)",
+                email,
+                password_hash,
+                display_name,
+                is_admin
+            )
+            .execute(pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(db) if db.is_unique_violation() => format!("A user with email {} exists already.", email),
+                e => format!("Failed to create the user: {}", e),
+            })?
+            .last_insert_rowid();
+            audit::record(pool, None, "cli_create_user", AuditOutcome::Ok, &json!({ "user_id": user_id, "admin": is_admin })).await;
+            println!("Created user {} ({}){}.", user_id, email, if is_admin { " as admin" } else { "" });
+        }
+        Command::ResetPassword { email } => {
+            let password_hash = prompt_password_hash()?;
+            let user_id = updated_user(
+                &email,
+                query!(r#"UPDATE users SET password_hash = ? WHERE email = ? RETURNING user_id as "user_id!""#, password_hash, email)
+                    .fetch_optional(pool)
+                    .await
+                    .map(|row| row.map(|row| row.user_id)),
+            )?;
+            audit::record(pool, None, "cli_reset_password", AuditOutcome::Ok, &json!({ "user_id": user_id })).await;
+            println!("Reset the password of user {} ({}).", user_id, email);
+        }
+        Command::Promote { email } => {
+            let user_id = updated_user(
+                &email,
+                query!(r#"UPDATE users SET is_admin = TRUE WHERE email = ? RETURNING user_id as "user_id!""#, email)
+                    .fetch_optional(pool)
+                    .await
+                    .map(|row| row.map(|row| row.user_id)),
+            )?;
+            audit::record(pool, None, "cli_promote", AuditOutcome::Ok, &json!({ "user_id": user_id })).await;
+            println!("User {} ({}) is an admin now.", user_id, email);
+        }
+        Command::ListUsers => {
+            let users = query!(r#"SELECT user_id as "user_id!", email, display_name, is_admin FROM users ORDER BY user_id"#)
+                .fetch_all(pool)
+                .await
+                .map_err(|e| format!("Failed to list users: {}", e))?;
+            for user in users {
+                println!(
+                    "{}\t{}\t{}{}",
+                    user.user_id,
+                    user.email,
+                    user.display_name,
+                    if user.is_admin { "\tadmin" } else { "" }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The id of the updated user, or why there is none.
+fn updated_user(email: &str, result: Result<Option<i64>, sqlx::Error>) -> Result<i64, String> {
+    match result {
+        Ok(Some(user_id)) => Ok(user_id),
+        Ok(None) => Err(format!("There is no user with email {}.", email)),
+        Err(e) => Err(format!("Failed to update user {}: {}", email, e)),
+    }
+}
+
+fn prompt_password_hash() -> Result<String, String> {
+    let stdin = io::stdin();
+    let password = if stdin.is_terminal() {
+        let password = rpassword::prompt_password("Password: ").map_err(|e| e.to_string())?;
+        let repeated = rpassword::prompt_password("Repeat password: ").map_err(|e| e.to_string())?;
+        if password != repeated {
+            return Err("The passwords don't match.".to_string());
+        }
+        password
+    } else {
+        let mut line = String::new();
+        stdin.lock().read_line(&mut line).map_err(|e| e.to_string())?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    check_password(&password)?;
+    hash_password(&password).map_err(|e| format!("Failed to hash the password: {}", e))
+}
+
+/// The length rules of `POST /api/register`, so the CLI can't create a password the API would refuse.
+fn check_password(password: &str) -> Result<(), String> {
+    let chars = password.chars().count();
+    if chars < MIN_PASSWORD_CHARS {
+        Err(format!("The password must be at least {} characters long.", MIN_PASSWORD_CHARS))
+    } else if chars > MAX_PASSWORD_CHARS {
+        Err(format!("The password must be at most {} characters long.", MAX_PASSWORD_CHARS))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<AdminCli, clap::Error> {
+        AdminCli::try_parse_from(std::iter::once("admin").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn subcommands_take_their_options() {
+        let cli = parse(&["create-user", "--email", "a@example.com", "--display-name", "A", "--admin"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::CreateUser { email, display_name, admin: true } if email == "a@example.com" && display_name == "A"
+        ));
+        assert!(matches!(parse(&["list-users"]).unwrap().command, Command::ListUsers));
+    }
+
+    #[test]
+    fn unknown_options_and_missing_values_are_errors() {
+        assert_eq!(parse(&["promote", "--email", "a@example.com", "--force"]).unwrap_err().kind(), ErrorKind::UnknownArgument);
+        assert_eq!(parse(&["reset-password"]).unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse(&["delete-user"]).unwrap_err().kind(), ErrorKind::InvalidSubcommand);
+        assert_eq!(parse(&["--help"]).unwrap_err().kind(), ErrorKind::DisplayHelp);
+    }
+
+    #[test]
+    fn passwords_follow_the_registration_rules() {
+        assert!(check_password(&"x".repeat(MIN_PASSWORD_CHARS - 1)).is_err());
+        assert!(check_password(&"x".repeat(MIN_PASSWORD_CHARS)).is_ok());
+        assert!(check_password(&"x".repeat(MAX_PASSWORD_CHARS + 1)).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use serde_json::json;
+use sqlx::{query, SqlitePool};
+use std::time::Duration;
+
+use crate::{auth::Claims, canvas_id::CanvasId, client_ip::ClientIp, error::AppError, handlers::{resolve_report, ReportsQuery, ResolveReportRequest}, identifiable_web_socket::CLOSE_ADMIN_DISCONNECT, maintenance, openapi::{ErrorResponse, MessageResponse}, reports, validation::{AppJson, AppPath}, AppState};
+
+/// Fails with a 403 unless the user is an instance admin.
+pub async fn require_admin(pool: &SqlitePool, user_id: i64) -> Result<(), AppError> {
+    let row = query!("SELECT is_admin FROM users WHERE user_id = ?", user_id)
+        .fetch_optional(pool)
+        .await
+        .with_context(|| format!("Failed to check admin flag for user {}", user_id))?;
+
+    match row {
+        Some(row) if row.is_admin => Ok(()),
+        _ => {
+            tracing::warn!("User {} tried to access an admin endpoint", user_id);
+            Err(AppError::Forbidden {
+                code: "admin_required",
+                message: "Admin privileges required.".to_string(),
+                details: None,
+            })
+        }
+    }
+}
+
+// ====================== metrics ======================
+
+// The handler for the GET /api/admin/metrics route
+#[utoipa::path(
+    get,
+    path = "/api/admin/metrics",
+    tag = "admin",
+    responses(
+        (status = 200, description = "WebSocket metrics", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_ws_metrics(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    Ok(Json(state.metrics.snapshot()))
+}
+
+// The handler for the GET /metrics route, in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", content_type = "text/plain"),
+    ),
+)]
+pub async fn get_prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render_prometheus(),
+    )
+}
+
+// ====================== connections ======================
+
+// The handler for the POST /api/admin/users/{user_id}/disconnect route.
+// Closes all WebSocket connections of a user and unsubscribes them from their canvases.
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{user_id}/disconnect",
+    tag = "admin",
+    params(("user_id" = i64, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "Number of closed connections as `disconnected`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "The user has no open connections", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn force_disconnect_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(user_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let connections = state.socket_claims_manager.get_connections(user_id).await;
+    if connections.is_empty() {
+        return Err(AppError::not_found("User has no open connections."));
+    }
+
+    tracing::warn!(
+        "Admin {} force-disconnects user {} ({} connections)",
+        claims.user_id,
+        user_id,
+        connections.len()
+    );
+
+    for ws in connections.iter() {
+        ws.notify_client("You have been disconnected by an administrator.").await;
+
+        let canvases = state.canvas_manager.take_subscriptions(ws.id).await;
+        for canvas_id in canvases {
+            state.canvas_manager.unregister_connection(&canvas_id, &ws.id).await;
+        }
+
+        ws.close(CLOSE_ADMIN_DISCONNECT, "disconnected by admin").await;
+    }
+
+    Ok(Json(json!({"disconnected": connections.len()})))
+}
+
+// ====================== backups ======================
+
+// The handler for the POST /api/admin/backup route.
+// Writes a backup of the database and the canvas files right away.
+#[utoipa::path(
+    post,
+    path = "/api/admin/backup",
+    tag = "admin",
+    responses(
+        (status = 201, description = "Backup written", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 409, description = "A backup is already running", body = ErrorResponse),
+        (status = 500, description = "Failed to write the backup", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn create_backup(
+    State(state): State<AppState>,
+    claims: Claims,
+    client_ip: ClientIp,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    tracing::info!("Admin {} triggers a backup", claims.user_id);
+    match state.backups.create(&state.pool, &state.canvas_manager, Some(claims.user_id), Some(client_ip.0)).await {
+        Ok(backup) => Ok((StatusCode::CREATED, Json(backup))),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            Err(AppError::Conflict("A backup is already running.".to_string()))
+        }
+        Err(e) => Err(anyhow::Error::new(e).context("Failed to write the backup").into()),
+    }
+}
+
+// The handler for the GET /api/admin/backups route. Newest first.
+#[utoipa::path(
+    get,
+    path = "/api/admin/backups",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Backups, newest first", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 500, description = "Failed to list backups", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn list_backups(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let backups = state.backups.list().await.context("Failed to list backups")?;
+    Ok(Json(backups))
+}
+
+// ====================== maintenance ======================
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyCanvasParams {
+    #[serde(default)]
+    pub quarantine: bool,
+}
+
+// The handler for the POST /api/admin/maintenance/verify_canvas/{canvas_id} route.
+// Reports corrupt lines of a canvas' event log; with `?quarantine=true` they are moved out of the log.
+#[utoipa::path(
+    post,
+    path = "/api/admin/maintenance/verify_canvas/{canvas_id}",
+    tag = "admin",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), VerifyCanvasParams),
+    responses(
+        (status = 200, description = "Verification report", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such canvas", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn verify_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    Query(params): Query<VerifyCanvasParams>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    tracing::info!(
+        "Admin {} verifies canvas {} (quarantine: {})",
+        claims.user_id,
+        canvas_id,
+        params.quarantine
+    );
+    let report = state.canvas_manager.verify(&state.pool, &canvas_id, params.quarantine).await?;
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CompressCanvasParams {
+    #[serde(default)]
+    pub decompress: bool,
+}
+
+// The handler for the POST /api/admin/maintenance/compress_canvas/{canvas_id} route.
+// Compresses a canvas' event log right away; with `?decompress=true` the plain log is restored instead.
+// A loaded canvas is not compressed, the response tells whether the log changed.
+#[utoipa::path(
+    post,
+    path = "/api/admin/maintenance/compress_canvas/{canvas_id}",
+    tag = "admin",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), CompressCanvasParams),
+    responses(
+        (status = 200, description = "Whether the log changed as `changed`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such canvas", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn compress_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    Query(params): Query<CompressCanvasParams>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    tracing::info!(
+        "Admin {} compresses canvas {} (decompress: {})",
+        claims.user_id,
+        canvas_id,
+        params.decompress
+    );
+    let changed = if params.decompress {
+        state.canvas_manager.decompress(&state.pool, &canvas_id).await?
+    } else {
+        state.canvas_manager.compress(&state.pool, &canvas_id, Duration::ZERO).await?
+    };
+    Ok(Json(json!({ "changed": changed })))
+}
+
+// The handler for the POST /api/admin/maintenance/flush_cache route.
+// Drops the cached event logs of all loaded canvases; their histories are read from the store until they reload.
+#[utoipa::path(
+    post,
+    path = "/api/admin/maintenance/flush_cache",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Unloaded canvases", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn flush_cache(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let stats = state.canvas_manager.flush_caches().await;
+    tracing::info!(
+        "Admin {} flushed the event caches of {} canvases ({} bytes)",
+        claims.user_id,
+        stats.canvases,
+        stats.bytes
+    );
+    Ok(Json(stats))
+}
+
+// The handler for the POST /api/admin/content_filter/reload route.
+// Reloads the content filter's denylist without a restart.
+#[utoipa::path(
+    post,
+    path = "/api/admin/content_filter/reload",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Number of loaded terms as `terms`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 500, description = "Failed to read the denylist", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn reload_content_filter(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let terms = state.content_filter.reload().await.context("Failed to reload the content filter")?;
+    tracing::info!("Admin {} reloaded the content filter ({} terms)", claims.user_id, terms);
+    Ok(Json(json!({"terms": terms})))
+}
+
+// The handler for the GET /api/admin/maintenance/store_sync route.
+// Lists the sync state of the event logs with remote storage; empty unless the store has one.
+#[utoipa::path(
+    get,
+    path = "/api/admin/maintenance/store_sync",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Sync state of the event store", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_store_sync_status(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    Ok(Json(state.canvas_manager.store_sync_status()))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OrphansParams {
+    #[serde(default)]
+    pub relocate: bool,
+}
+
+// The handler for the POST /api/admin/maintenance/orphans route.
+// Lists files in the canvases directory that belong to no canvas; with `?relocate=true` they are moved to `canvases/orphans`.
+#[utoipa::path(
+    post,
+    path = "/api/admin/maintenance/orphans",
+    tag = "admin",
+    params(OrphansParams),
+    responses(
+        (status = 200, description = "Orphaned files", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 500, description = "Failed to read the canvases directory", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn find_orphans(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(params): Query<OrphansParams>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    tracing::info!("Admin {} looks for orphaned files (relocate: {})", claims.user_id, params.relocate);
+    let report = maintenance::orphans(&state.pool, &state.data_dir, params.relocate)
+        .await
+        .context("Failed to look for orphaned files")?;
+    Ok(Json(report))
+}
+
+// The handler for the GET /api/admin/maintenance/stats route: row counts and event file sizes.
+#[utoipa::path(
+    get,
+    path = "/api/admin/maintenance/stats",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Row counts and disk usage", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 500, description = "Failed to collect the statistics", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_instance_stats(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let stats = maintenance::stats(&state.pool, &state.data_dir)
+        .await
+        .context("Failed to collect instance stats")?;
+    Ok(Json(stats))
+}
+
+// ====================== reports ======================
+
+// The handler for the GET /api/admin/reports route: the reports of all canvases, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/admin/reports",
+    tag = "admin",
+    params(ReportsQuery),
+    responses(
+        (status = 200, description = "Reports of all canvases, newest first", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn list_reports(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(query): Query<ReportsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    let reports = reports::list(&state.pool, None, !query.all).await.context("Failed to list reports")?;
+    Ok(Json(reports))
+}
+
+// The handler for the POST /api/admin/reports/{report_id}/resolve route. Closes a report of any canvas.
+#[utoipa::path(
+    post,
+    path = "/api/admin/reports/{report_id}/resolve",
+    tag = "admin",
+    params(("report_id" = i64, Path, description = "Id of the report")),
+    request_body = ResolveReportRequest,
+    responses(
+        (status = 200, description = "Report closed", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "The report is not open", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn resolve_any_report(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(report_id): Path<i64>,
+    AppJson(payload): AppJson<ResolveReportRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state.pool, claims.user_id).await?;
+
+    resolve_report(&state, claims.user_id, report_id, None, payload.resolution).await
+}
@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::str::FromStr;
+
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{header, HeaderValue},
+    routing::{get, post},
+    Router,
+};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous};
+use tower_http::{
+    compression::{predicate::{NotForContentType, Predicate}, CompressionLayer, DefaultPredicate},
+    limit::RequestBodyLimitLayer,
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeader,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    access_log::access_log_middleware,
+    admin_handlers::{compress_canvas, create_backup, find_orphans, flush_cache, force_disconnect_user, get_instance_stats, get_prometheus_metrics, get_store_sync_status, get_ws_metrics, list_backups, list_reports, reload_content_filter, resolve_any_report, verify_canvas},
+    auth::auth_middleware,
+    backup::Backups,
+    blocking::BlockingPool,
+    build_info::BuildInfo,
+    canvas_manager::CanvasManager,
+    config::AppConfig,
+    content_filter::{ContentFilter, DenylistFilter},
+    drain::DrainState,
+    error::AppError,
+    event_store::{self, canvases_dir},
+    handlers::{append_canvas_events, approve_held_events, ban_user, clear_canvas, compact_canvas, create_canvas, delete_canvas, get_canvas, get_canvas_archives, get_canvas_events, get_canvas_list, get_canvas_permissions, get_canvas_reports, get_held_events, get_version, healthz, readyz, get_moderation_log, get_online_users, get_user_info, login, logout, mute_user, register, reject_held_events, report_canvas, resolve_canvas_report, restore_canvas_archive, set_slow_mode, toggle_canvas_moderated, unban_user, unmute_user, update_canvas_permissions, update_canvas_settings, update_profile},
+    limits::payload_too_large_middleware,
+    metrics::WsMetrics,
+    openapi::get_openapi,
+    permission_refresh_list::PermissionRefreshList,
+    rate_limiter::{RestRateLimiter, SharedRateLimiter, PUBLIC_REQUESTS_PER_MINUTE, PUBLIC_REQUESTS_PER_MINUTE_PER_CLIENT},
+    request_id::request_id_middleware,
+    review_queue::ReviewQueue,
+    sse_handlers::stream_canvas,
+    socket_claims_manager::SocketClaimsManager,
+    websocket_handlers::ws_handler,
+    AppState,
+};
+
+// Static Migrator instance (ensure your `migrations` directory exists at project root)
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Sets up the managers and the rest of the state on an existing pool. Starts no background tasks,
+/// those are up to the caller (see `main`).
+pub async fn build_state(config: Arc<AppConfig>, pool: SqlitePool) -> AppState {
+    let data_dir = config.data_dir.clone();
+    let metrics = Arc::new(WsMetrics::new(config.metrics_per_canvas));
+    let socket_claims_manager = SocketClaimsManager::new();
+    let content_filter: Arc<dyn ContentFilter> = Arc::new(
+        DenylistFilter::new(config.content_filter_denylist.clone(), config.content_filter_action).await,
+    );
+    // Shared by the event store and the backups, so together they never run more heavy jobs than configured
+    let blocking = BlockingPool::new(config.event_store.blocking_concurrency);
+    let canvas_manager = CanvasManager::new(
+        metrics.clone(),
+        event_store::from_config(pool.clone(), &data_dir, &config.event_store, blocking.clone()),
+        Arc::new(socket_claims_manager.clone()),
+        ReviewQueue::new(canvases_dir(&data_dir)),
+        content_filter.clone(),
+        config.canvas_manager,
+    );
+
+    AppState {
+        pool,
+        permission_refresh_list: Arc::new(PermissionRefreshList::new()),
+        canvas_manager,
+        socket_claims_manager,
+        rate_limit_config: config.rate_limits,
+        rest_rate_limiter: RestRateLimiter::new(config.rate_limits),
+        public_rate_limiter: SharedRateLimiter::new(
+            PUBLIC_REQUESTS_PER_MINUTE,
+            PUBLIC_REQUESTS_PER_MINUTE_PER_CLIENT,
+            Duration::from_secs(60),
+        ),
+        payload_limits: config.payload_limits,
+        max_subscriptions_per_connection: config.max_subscriptions_per_connection,
+        metrics,
+        origin_policy: config.origin_policy.clone(),
+        backups: Backups::new(&data_dir, config.backup_retention, blocking.clone()),
+        blocking,
+        build_info: BuildInfo::new(config.event_store.kind),
+        drain: DrainState::default(),
+        data_dir,
+        content_filter,
+        config,
+    }
+}
+
+/// The app on the given configuration and pool, e.g. for tests driving it with `tower::ServiceExt::oneshot`.
+#[cfg(test)]
+pub async fn build(config: AppConfig, pool: SqlitePool) -> Router {
+    router(build_state(Arc::new(config), pool).await, None)
+}
+
+/// The app on a migrated in-memory database and a temporary data directory (`AppConfig::test_default`).
+/// Returns the state too, for assertions on the managers.
+#[cfg(test)]
+pub async fn test_app() -> (Router, AppState) {
+    test_app_with(AppConfig::test_default()).await
+}
+
+/// Like `test_app`, with settings changed from `AppConfig::test_default`.
+#[cfg(test)]
+pub async fn test_app_with(config: AppConfig) -> (Router, AppState) {
+    crate::auth::init(&config);
+    let pool = setup_database(&config).await;
+    let state = build_state(Arc::new(config), pool).await;
+    (router(state.clone(), None), state)
+}
+
+/// Canonicalizes the public directory (`PUBLIC_DIR`) and checks that it holds the built frontend.
+/// Without this, a binary started from another working directory would answer every page with a 404.
+pub fn resolve_public_dir(public_dir: &Path) -> Result<PathBuf, String> {
+    let resolved = public_dir
+        .canonicalize()
+        .map_err(|e| format!("PUBLIC_DIR {} is not accessible: {}", public_dir.display(), e))?;
+    if !resolved.is_dir() {
+        return Err(format!("PUBLIC_DIR {} is not a directory", resolved.display()));
+    }
+    if !resolved.join("index.html").is_file() {
+        return Err(format!("PUBLIC_DIR {} has no index.html, build the frontend first", resolved.display()));
+    }
+    Ok(resolved)
+}
+
+/// The fallback of API-only deployments.
+async fn not_found() -> AppError {
+    AppError::not_found("Not found.")
+}
+
+/// Connects to `DATABASE_URL` and runs the migrations. Panics if either fails, the app can't run without.
+pub async fn setup_database(config: &AppConfig) -> SqlitePool {
+    let database_url = config.database_url.as_str();
+    tracing::info!("DATABASE_URL: {}", database_url);
+
+    if database_url.starts_with("sqlite://") {
+        let db_path_str = database_url.trim_start_matches("sqlite://");
+        let db_path = std::path::Path::new(db_path_str);
+        if let Some(parent_dir) = db_path.parent() {
+            if !parent_dir.exists() {
+                tracing::info!("Creating database directory: {:?}", parent_dir);
+                std::fs::create_dir_all(parent_dir)
+                    .expect("Failed to create database directory.");
+            }
+        }
+    }
+
+    let database = config.database;
+    let connect_options = SqliteConnectOptions::from_str(database_url)
+        .expect("Invalid DATABASE_URL.")
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(database.busy_timeout);
+
+    tracing::info!(
+        "Connecting to database at: {} (max {} connections, acquire timeout {:?}, busy timeout {:?}, journal_mode=WAL, synchronous=NORMAL)",
+        database_url,
+        database.max_connections,
+        database.acquire_timeout,
+        database.busy_timeout
+    );
+    let pool = SqlitePoolOptions::new()
+        .max_connections(database.max_connections)
+        .acquire_timeout(database.acquire_timeout)
+        .connect_with(connect_options)
+        .await
+        .expect("Failed to create SQLite pool. Check DATABASE_URL and database file permissions.");
+
+    tracing::info!("Running database migrations...");
+    MIGRATOR.run(&pool).await.expect("Failed to run database migrations.");
+    tracing::info!("Database migrations applied successfully.");
+
+    pool
+}
+
+/// The routes of the app with their middleware, serving the public directory for all other paths.
+/// Without a `public_dir` (`--no-frontend`) only the API is served and other paths get a JSON 404.
+pub fn router(state: AppState, public_dir: Option<&Path>) -> Router {
+    // Compresses the API and static files on the fly; SSE streams, images and archives are left alone.
+    // Not applied to /ws, whose upgrade response has no body.
+    let compression = CompressionLayer::new()
+        .compress_when(DefaultPredicate::new().and(NotForContentType::const_new("application/gzip")));
+
+    // Protected API routes that require authentication.
+    // We nest them under a `/api` path and apply the auth middleware.
+    let protected_routes = Router::new()
+        .route("/me", get(get_user_info))
+        .route("/user/update", post(update_profile))
+        .route("/canvases/create", post(create_canvas))
+        .route("/canvases/list", get(get_canvas_list))
+        .route("/canvas/{canvas_id}/permissions", post(update_canvas_permissions).get(get_canvas_permissions))
+        .route("/canvas/{canvas_id}/online", get(get_online_users))
+        .route("/canvas/{canvas_id}/settings", post(update_canvas_settings))
+        .route("/canvas/{canvas_id}/events", get(get_canvas_events))
+        .route("/canvas/{canvas_id}/stream", get(stream_canvas))
+        .route("/canvas/{canvas_id}/compact", post(compact_canvas))
+        .route("/canvas/{canvas_id}/clear", post(clear_canvas))
+        .route("/canvas/{canvas_id}/pending", get(get_held_events))
+        .route("/canvas/{canvas_id}/moderation_log", get(get_moderation_log))
+        .route("/canvas/{canvas_id}/mutes/{user_id}", post(mute_user).delete(unmute_user))
+        .route("/canvas/{canvas_id}/moderated", post(toggle_canvas_moderated))
+        .route("/canvas/{canvas_id}/slow_mode", post(set_slow_mode))
+        .route("/canvas/{canvas_id}/report", post(report_canvas))
+        .route("/canvas/{canvas_id}/reports", get(get_canvas_reports))
+        .route("/canvas/{canvas_id}/reports/{report_id}/resolve", post(resolve_canvas_report))
+        .route("/canvas/{canvas_id}/bans/{user_id}", post(ban_user).delete(unban_user))
+        .route("/canvas/{canvas_id}/pending/{batch_id}/approve", post(approve_held_events))
+        .route("/canvas/{canvas_id}/pending/{batch_id}/reject", post(reject_held_events))
+        .route("/canvas/{canvas_id}/archives", get(get_canvas_archives))
+        .route("/canvas/{canvas_id}/archives/{archive_id}/restore", post(restore_canvas_archive))
+        .route("/canvas/{canvas_id}", get(get_canvas).delete(delete_canvas))
+        .route("/admin/metrics", get(get_ws_metrics))
+        .route("/admin/users/{user_id}/disconnect", post(force_disconnect_user))
+        .route("/admin/backup", post(create_backup))
+        .route("/admin/backups", get(list_backups))
+        .route("/admin/reports", get(list_reports))
+        .route("/admin/reports/{report_id}/resolve", post(resolve_any_report))
+        .route("/admin/maintenance/verify_canvas/{canvas_id}", post(verify_canvas))
+        .route("/admin/maintenance/compress_canvas/{canvas_id}", post(compress_canvas))
+        .route("/admin/maintenance/flush_cache", post(flush_cache))
+        .route("/admin/content_filter/reload", post(reload_content_filter))
+        .route("/admin/maintenance/store_sync", get(get_store_sync_status))
+        .route("/admin/maintenance/orphans", post(find_orphans))
+        .route("/admin/maintenance/stats", get(get_instance_stats))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), auth_middleware));
+
+    // Public API routes for authentication and other unauthenticated endpoints.
+    let public_api_routes = Router::new()
+        .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/register", post(register))
+        .route("/version", get(get_version))
+        .route("/openapi.json", get(get_openapi));
+
+    // Routes taking bulk uploads, with their own, larger body limit
+    let upload_limit = state.config.max_upload_body_bytes;
+    let upload_routes = Router::new()
+        .route("/canvas/{canvas_id}/events", post(append_canvas_events))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .layer(RequestBodyLimitLayer::new(upload_limit))
+        .layer(axum::middleware::from_fn_with_state(upload_limit, payload_too_large_middleware));
+
+    // The JSON 413 has to wrap the body limit
+    let body_limit = state.config.max_request_body_bytes;
+    let mut api_routes = public_api_routes
+        .merge(protected_routes)
+        .layer(RequestBodyLimitLayer::new(body_limit))
+        .layer(axum::middleware::from_fn_with_state(body_limit, payload_too_large_middleware))
+        .merge(upload_routes)
+        // The limit layers above bound every body, instead of axum's default for extractors
+        .layer(DefaultBodyLimit::disable())
+        .layer(compression.clone());
+    // Outside the auth middleware, so preflight requests are answered without a cookie
+    if let Some(cors) = state.origin_policy.cors_layer() {
+        api_routes = api_routes.layer(cors);
+    }
+
+    // Combine all routes and services into the final application router.
+    let mut app = Router::new().nest("/api", api_routes);
+    if state.config.api_docs {
+        // Only the UI, the document itself is always served at /api/openapi.json
+        app = app.merge(SwaggerUi::new("/api/docs").config(utoipa_swagger_ui::Config::from("/api/openapi.json")));
+    }
+    let app = app
+        .route("/ws", get(ws_handler))
+        .route("/metrics", get(get_prometheus_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    let app = match public_dir {
+        Some(public_dir) => {
+            // This service handles requests for files in the public directory, preferring `.br`/`.gz`
+            // siblings if they exist. The file names carry no hash, so browsers have to revalidate every file
+            // (cheap with Last-Modified) to pick up a deployment, index.html included.
+            let serve_dir = ServeDir::new(public_dir)
+                .precompressed_br()
+                .precompressed_gzip()
+                .not_found_service(
+                    ServeFile::new(public_dir.join("index.html"))
+                        .precompressed_br()
+                        .precompressed_gzip()
+                );
+            // The compression layer only adds Vary to what it compresses itself, not to the `.br`/`.gz` siblings
+            let spa_service = SetResponseHeader::overriding(
+                SetResponseHeader::if_not_present(serve_dir, header::VARY, HeaderValue::from_static("accept-encoding")),
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("no-cache"),
+            );
+            app.fallback_service(tower::ServiceBuilder::new().layer(compression).service(spa_service))
+        }
+        None => app.fallback(not_found),
+    };
+    // The access log runs inside the request span, so its lines carry the request id
+    app
+        .layer(axum::middleware::from_fn(access_log_middleware))
+        .layer(axum::middleware::from_fn(request_id_middleware))
+        .with_state(state)
+}
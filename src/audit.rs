@@ -0,0 +1,37 @@
+use serde_json::Value;
+use sqlx::{query, SqlitePool};
+
+/// Outcome of an audited action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Ok,
+    Failed,
+}
+
+impl AuditOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditOutcome::Ok => "ok",
+            AuditOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// Appends an entry to the audit log. `user_id` is `None` for actions of the server itself.
+/// A failed insert is only logged, the audited action has happened either way.
+pub async fn record(pool: &SqlitePool, user_id: Option<i64>, action: &str, outcome: AuditOutcome, detail: &Value) {
+    let outcome = outcome.as_str();
+    let detail = detail.to_string();
+    if let Err(e) = query!(
+        "INSERT INTO audit_log (user_id, action, outcome, detail) VALUES (?, ?, ?, ?)",
+        user_id,
+        action,
+        outcome,
+        detail
+    )
+    .execute(pool)
+    .await
+    {
+        tracing::error!("Failed to record {} ({}) in the audit log: {}", action, outcome, e);
+    }
+}
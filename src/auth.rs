@@ -1,26 +1,24 @@
 //! Parts of this code have been adapted from https://github.com/tokio-rs/axum/blob/main/examples/jwt/src/main.rs
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, sync::OnceLock};
 use axum::{
     body::Body,
     extract::{FromRequestParts, State},
     http::{
         header::{self, COOKIE},
         request::Parts,
-        HeaderMap, HeaderValue, Request, StatusCode,
+        HeaderMap, HeaderValue, Request,
     },
     middleware::Next,
     response::{IntoResponse, Response},
-    Json,
 };
 use jsonwebtoken::{decode, DecodingKey, EncodingKey, Validation};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2, PasswordHash, PasswordVerifier,
 };
 use sqlx::SqlitePool;
-use crate::{AppState, KEYS};
+use crate::{access_log::AuthenticatedUser, canvas_id::CanvasId, config::{AppConfig, CookieConfig}, error::AppError, AppState};
 
 // ───── 1. Types and their impls ────────────
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -32,7 +30,8 @@ pub struct Claims {
     pub exp: usize,
     /// Soft reissue time: absolute epoch seconds
     pub reissue_time: usize,
-    pub canvas_permissions: HashMap<String, String>,
+    /// Keyed by the string form of the ids, as in tokens issued before ids were typed.
+    pub canvas_permissions: HashMap<CanvasId, String>,
 }
 
 impl Display for Claims {
@@ -78,7 +77,7 @@ where
 
         let token_data = decode::<Claims>(
             &token,
-            &KEYS.decoding,
+            &settings().keys.decoding,
             &Validation::default(),
         ).map_err(|_| {
             tracing::debug!("Failed to decode JWT");
@@ -103,6 +102,39 @@ impl Keys {
     }
 }
 
+/// The JWT keys, token lifetimes and cookie attributes of the `AppConfig`.
+/// Global like the keys before them, since the `Claims` extractor doesn't know the state.
+struct AuthSettings {
+    keys: Keys,
+    expiry_seconds: usize,
+    reissue_seconds: usize,
+    cookie: CookieConfig,
+}
+
+static AUTH: OnceLock<AuthSettings> = OnceLock::new();
+
+/// Sets up the JWT keys and cookie attributes, once at startup before the server accepts requests.
+pub fn init(config: &AppConfig) {
+    let settings = AuthSettings {
+        keys: Keys::new(config.jwt.secret.as_bytes()),
+        expiry_seconds: config.jwt.expiry_seconds,
+        reissue_seconds: config.jwt.reissue_seconds,
+        cookie: config.cookie,
+    };
+    if AUTH.set(settings).is_err() {
+        tracing::warn!("auth::init called twice, keeping the first settings");
+    }
+}
+
+fn settings() -> &'static AuthSettings {
+    AUTH.get().expect("auth::init must be called at startup")
+}
+
+/// The `Set-Cookie` value that removes the auth cookie.
+pub fn logout_cookie() -> String {
+    settings().cookie.auth_cookie("", 0)
+}
+
 
 #[derive(Debug)]
 pub enum AuthError {
@@ -115,19 +147,10 @@ pub enum AuthError {
     UserInfoNotFound,
 }
 
+// The responses are built by `AppError`, so they have the same envelope as all other errors
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
-            AuthError::MissingCredentials => (StatusCode::UNAUTHORIZED, "Missing credentials"), // Use 401 for both for security
-            AuthError::UserExists => (StatusCode::CONFLICT, "User already exists"),
-            AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
-            AuthError::PasswordHashingFailed => (StatusCode::INTERNAL_SERVER_ERROR, "Password hashing failed"),
-            AuthError::DbError => (StatusCode::INTERNAL_SERVER_ERROR, "Database error"),
-            AuthError::UserInfoNotFound => (StatusCode::NOT_FOUND, "User information not found"),
-        };
-        let body = Json(json!({ "error": error_message }));
-        (status, body).into_response()
+        AppError::from(self).into_response()
     }
 }
 
@@ -208,8 +231,10 @@ pub async fn auth_middleware(
                 claims.user_id, claims.email, claims.display_name, claims.exp, claims.reissue_time, claims.canvas_permissions, req.uri()
             );
 
+            let user_id = claims.user_id;
             req.extensions_mut().insert(claims);
             let mut response = next.run(req).await;
+            response.extensions_mut().insert(AuthenticatedUser(user_id));
 
             // Add refreshed cookie if needed
             if let Some(cookie_headers) = set_cookie_header {
@@ -286,13 +311,11 @@ pub fn create_cookie_header(cookie: String) -> HeaderMap {
 }
 
 // ───── 4. Create_Jwt ────────────────────────
-pub const EXPIRED_AFTER_SECONDS: usize = 60 * 60 * 24 * 7;
-pub const REISSUE_AFTER_SECONDS: usize = 5 * 60;
 pub struct PartialClaims {
     pub email: String,
     pub user_id: Option<i64>,
     pub display_name: Option<String>,
-    pub canvas_permissions: Option<HashMap<String, String>>,
+    pub canvas_permissions: Option<HashMap<CanvasId, String>>,
     pub exp: usize,
 }
 
@@ -303,7 +326,7 @@ impl Default for PartialClaims {
             user_id: None,
             display_name: None,
             canvas_permissions: None,
-            exp: (jsonwebtoken::get_current_timestamp() as usize) + EXPIRED_AFTER_SECONDS,
+            exp: (jsonwebtoken::get_current_timestamp() as usize) + settings().expiry_seconds,
         }
     }
 }
@@ -344,7 +367,7 @@ pub async fn get_claims(
     if canvas_permissions.is_none() {
         tracing::debug!("Fetching Canvas permissions for user_id: {}", final_user_id);
         let user_permissions = sqlx::query!(
-            "SELECT canvas_id, permission_level FROM Canvas_Permissions WHERE user_id = ?",
+            r#"SELECT canvas_id AS "canvas_id: CanvasId", permission_level FROM Canvas_Permissions WHERE user_id = ?"#,
             final_user_id
         )
         .fetch_all(pool)
@@ -370,13 +393,13 @@ pub async fn get_claims(
         email,
         display_name: final_display_name,
         exp: claims_data.exp,
-        reissue_time: now + REISSUE_AFTER_SECONDS,
+        reissue_time: now + settings().reissue_seconds,
         canvas_permissions: final_canvas_permissions,
     })
 }
 
 pub async fn get_cookie_from_claims(claims: Claims) -> Result<String, AuthError> {
-    let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &KEYS.encoding)
+    let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &settings().keys.encoding)
         .map_err(|e| {
             tracing::error!("Failed to create token in get_cookie_from_claims: {:?}", e);
             AuthError::TokenCreation
@@ -388,10 +411,6 @@ pub async fn get_cookie_from_claims(claims: Claims) -> Result<String, AuthError>
     );
     tracing::debug!("    JWT={}\n", token);
 
-    let cookie = format!(
-        "auth_token={}; HttpOnly; Path=/; Max-Age={}; SameSite=Strict",
-        token, EXPIRED_AFTER_SECONDS
-    );
-
-    Ok(cookie)
+    let settings = settings();
+    Ok(settings.cookie.auth_cookie(&token, settings.expiry_seconds))
 }
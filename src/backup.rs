@@ -0,0 +1,241 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+use tokio::sync::Mutex;
+
+use crate::{
+    audit::{self, AuditOutcome},
+    blocking::BlockingPool,
+    canvas_id::CanvasId,
+    canvas_manager::CanvasManager,
+    event_log::timestamp_ms,
+    event_store::canvases_dir,
+};
+
+/// Default number of backups kept; older ones are removed after each backup.
+pub const DEFAULT_BACKUP_RETENTION: usize = 7;
+
+const BACKUP_PREFIX: &str = "backup-";
+const BACKUP_SUFFIX: &str = ".tar.gz";
+
+/// A backup archive in the backup directory.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    pub name: String,
+    /// Milliseconds since the Unix epoch, from the file name.
+    pub created_ms: u64,
+    pub bytes: u64,
+}
+
+/// Writes backups of the database and the canvas files to `DATA_DIR/backups`,
+/// as `backup-{ms}.tar.gz` holding `db.sqlite` and the `canvases` directory.
+#[derive(Debug, Clone)]
+pub struct Backups {
+    data_dir: PathBuf,
+    dir: PathBuf,
+    retention: usize,
+    blocking: BlockingPool,
+    /// Held while a backup is written, so two never run at once.
+    running: Arc<Mutex<()>>,
+}
+
+impl Backups {
+    /// Backups of `data_dir`, keeping the newest `retention` (at least one).
+    pub fn new(data_dir: &Path, retention: usize, blocking: BlockingPool) -> Self {
+        Self {
+            data_dir: data_dir.to_path_buf(),
+            dir: data_dir.join("backups"),
+            retention: retention.max(1),
+            blocking,
+            running: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Writes a backup and removes the ones beyond the retention. The outcome is recorded
+    /// in the audit log, `user_id` and `client_ip` are `None` for scheduled backups.
+    /// Fails with `io::ErrorKind::WouldBlock` while another backup is running.
+    pub async fn create(
+        &self,
+        pool: &SqlitePool,
+        manager: &CanvasManager,
+        user_id: Option<i64>,
+        client_ip: Option<IpAddr>,
+    ) -> io::Result<BackupInfo> {
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "a backup is already running"));
+        };
+
+        let created_ms = timestamp_ms();
+        let staging = self.dir.join(format!(".staging-{}", created_ms));
+        let result = self.write(pool, manager, &staging, created_ms).await;
+        if let Err(e) = tokio::fs::remove_dir_all(&staging).await {
+            tracing::warn!("Failed to remove backup staging directory {}: {}", staging.display(), e);
+        }
+
+        match &result {
+            Ok(backup) => {
+                tracing::info!("Wrote backup {} ({} bytes).", backup.name, backup.bytes);
+                audit::record(pool, user_id, "backup", AuditOutcome::Ok, &json!({ "name": backup.name, "bytes": backup.bytes, "ip": client_ip })).await;
+                if let Err(e) = self.rotate().await {
+                    tracing::error!("Failed to remove old backups: {}", e);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to write backup: {}", e);
+                audit::record(pool, user_id, "backup", AuditOutcome::Failed, &json!({ "error": e.to_string(), "ip": client_ip })).await;
+            }
+        }
+        result
+    }
+
+    /// Copies the database and the canvas files to `staging` and packs them into the archive.
+    async fn write(
+        &self,
+        pool: &SqlitePool,
+        manager: &CanvasManager,
+        staging: &Path,
+        created_ms: u64,
+    ) -> io::Result<BackupInfo> {
+        let staged_canvases = staging.join("canvases");
+        tokio::fs::create_dir_all(&staged_canvases).await?;
+
+        // A consistent copy of the database while it stays in use
+        let db_path = staging.join("db.sqlite");
+        sqlx::query("VACUUM INTO ?")
+            .bind(db_path.to_string_lossy().into_owned())
+            .execute(pool)
+            .await
+            .map_err(io::Error::other)?;
+
+        copy_canvas_files(manager, &canvases_dir(&self.data_dir), &staged_canvases).await?;
+
+        let name = format!("{}{}{}", BACKUP_PREFIX, created_ms, BACKUP_SUFFIX);
+        let path = self.dir.join(&name);
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let pack = {
+            let (staging, tmp_path, path) = (staging.to_path_buf(), tmp_path.clone(), path.clone());
+            self.blocking
+                .run(move || {
+                    let encoder = GzEncoder::new(std::fs::File::create(&tmp_path)?, Compression::default());
+                    let mut archive = tar::Builder::new(encoder);
+                    archive.append_dir_all(".", &staging)?;
+                    archive.into_inner()?.finish()?.sync_all()?;
+                    std::fs::rename(&tmp_path, &path)
+                })
+                .await
+        };
+        if let Err(e) = pack {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        let bytes = tokio::fs::metadata(&path).await?.len();
+        Ok(BackupInfo { name, created_ms, bytes })
+    }
+
+    /// Lists the backups, newest first.
+    pub async fn list(&self) -> io::Result<Vec<BackupInfo>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut backups = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(created_ms) = name
+                .strip_prefix(BACKUP_PREFIX)
+                .and_then(|rest| rest.strip_suffix(BACKUP_SUFFIX))
+                .and_then(|ms| ms.parse().ok())
+            else {
+                continue;
+            };
+            let bytes = entry.metadata().await?.len();
+            backups.push(BackupInfo { name, created_ms, bytes });
+        }
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_ms));
+        Ok(backups)
+    }
+
+    /// Removes all but the newest `retention` backups.
+    async fn rotate(&self) -> io::Result<()> {
+        for backup in self.list().await?.into_iter().skip(self.retention) {
+            tokio::fs::remove_file(self.dir.join(&backup.name)).await?;
+            tracing::info!("Removed old backup {}.", backup.name);
+        }
+        Ok(())
+    }
+}
+
+/// Copies the files in the canvases directory to `to`. A canvas' log is copied while
+/// nothing is appended to it (see `CanvasManager::with_log_idle`), so no copy ends in a torn line.
+/// Subdirectories (archived logs) are only ever written whole and are copied as they are.
+async fn copy_canvas_files(manager: &CanvasManager, from: &Path, to: &Path) -> io::Result<()> {
+    let mut entries = match tokio::fs::read_dir(from).await {
+        Ok(entries) => entries,
+        // No file logs, e.g. with CANVAS_STORE=sqlite
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let target = to.join(&file_name);
+        if entry.file_type().await?.is_dir() {
+            copy_dir(entry.path(), target).await?;
+            continue;
+        }
+        let name = file_name.to_string_lossy();
+        // `{canvas_id}.jsonl`, `{canvas_id}.jsonl.gz`, ...; other files can't be written to by a canvas
+        let copied = match name.split('.').next().unwrap_or_default().parse::<CanvasId>() {
+            Ok(canvas_id) => manager.with_log_idle(&canvas_id, tokio::fs::copy(entry.path(), &target)).await,
+            Err(_) => tokio::fs::copy(entry.path(), &target).await,
+        };
+        match copied {
+            Ok(_) => {}
+            // Removed since listing, e.g. a temporary file
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copies a directory and everything below it.
+async fn copy_dir(from: PathBuf, to: PathBuf) -> io::Result<()> {
+    tokio::fs::create_dir_all(&to).await?;
+    let mut entries = tokio::fs::read_dir(&from).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let target = to.join(entry.file_name());
+        if entry.file_type().await?.is_dir() {
+            Box::pin(copy_dir(entry.path(), target)).await?;
+        } else {
+            tokio::fs::copy(entry.path(), &target).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a backup every `interval`, starting one interval after startup.
+pub async fn start_backup_task(backups: Backups, manager: CanvasManager, pool: SqlitePool, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        // Other failures are logged and audited by `create`
+        if let Err(e) = backups.create(&pool, &manager, None, None).await
+            && e.kind() == io::ErrorKind::WouldBlock
+        {
+            tracing::info!("Skipping scheduled backup, another one is running.");
+        }
+    }
+}
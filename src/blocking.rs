@@ -0,0 +1,58 @@
+use std::{io, sync::Arc};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default number of heavy file jobs (e.g. gzip compression) running at once.
+pub const DEFAULT_BLOCKING_CONCURRENCY: usize = 2;
+
+/// Runs heavy synchronous file work on tokio's blocking pool, so it never occupies the threads
+/// serving WebSockets. A semaphore bounds how many jobs run at once, so a burst of them
+/// can't monopolize the machine.
+#[derive(Debug, Clone)]
+pub struct BlockingPool {
+    slots: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    /// Runs `work` once a slot is free.
+    pub async fn run<T, F>(&self, work: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let permit = self.slots.clone().acquire_owned().await.map_err(io::Error::other)?;
+        Self::spawn(permit, work).await
+    }
+
+    /// Runs `work` if a slot is free. Fails with `io::ErrorKind::WouldBlock` otherwise,
+    /// so requests can be answered with 503 instead of queueing up.
+    pub async fn try_run<T, F>(&self, work: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let permit = self.slots.clone().try_acquire_owned().map_err(|_| {
+            io::Error::new(io::ErrorKind::WouldBlock, "all blocking slots are busy")
+        })?;
+        Self::spawn(permit, work).await
+    }
+
+    async fn spawn<T, F>(permit: OwnedSemaphorePermit, work: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
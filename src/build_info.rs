@@ -0,0 +1,48 @@
+use std::fmt;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::event_store::StoreKind;
+
+/// What is running: the version and commit of the binary and the backends it was started with.
+/// Served by `/api/version` and `/healthz` and logged at startup.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short hash of the commit the binary was built from, `unknown` if neither git nor `GIT_COMMIT` had it.
+    pub git_commit: &'static str,
+    /// Seconds since 1970.
+    pub build_timestamp: u64,
+    /// `CANVAS_STORE`
+    pub event_store: &'static str,
+    pub database: &'static str,
+}
+
+impl BuildInfo {
+    pub fn new(event_store: StoreKind) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GIT_COMMIT"),
+            build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+            event_store: event_store.name(),
+            database: "sqlite",
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (commit {}, built {}), event store: {}, database: {}",
+            env!("CARGO_PKG_NAME"),
+            self.version,
+            self.git_commit,
+            self.build_timestamp,
+            self.event_store,
+            self.database
+        )
+    }
+}
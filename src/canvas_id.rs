@@ -0,0 +1,95 @@
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Decode, Encode, Sqlite, Type,
+};
+use uuid::Uuid;
+
+/// The id of a canvas. Always a UUID, so an id taken from a path, a WebSocket message or the claims
+/// can't carry anything into SQL or file names. Serialized, stored and displayed in the lowercase
+/// hyphenated form ids had as plain strings, so tokens, the DB and the log files stay compatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CanvasId(Uuid);
+
+impl CanvasId {
+    /// A new random id for a canvas being created.
+    pub fn random() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for CanvasId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+impl FromStr for CanvasId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
+
+impl Serialize for CanvasId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Also used for map keys, e.g. the canvas permissions in the claims
+impl<'de> Deserialize<'de> for CanvasId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(|_| de::Error::custom(format!("invalid canvas id '{}', expected a UUID", id)))
+    }
+}
+
+// Stored as TEXT, like the ids before
+impl Type<Sqlite> for CanvasId {
+    fn type_info() -> SqliteTypeInfo {
+        <str as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for CanvasId {
+    fn encode_by_ref(&self, args: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+        <String as Encode<'q, Sqlite>>::encode(self.to_string(), args)
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for CanvasId {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(<&str as Decode<'r, Sqlite>>::decode(value)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_in_the_hyphenated_form() {
+        let id: CanvasId = "6F9619FF-8B86-D011-B42D-00C04FC964FF".parse().unwrap();
+        assert_eq!(id.to_string(), "6f9619ff-8b86-d011-b42d-00c04fc964ff");
+        assert_eq!(serde_json::to_value(id).unwrap(), "6f9619ff-8b86-d011-b42d-00c04fc964ff");
+        assert_eq!(serde_json::from_value::<CanvasId>(serde_json::to_value(id).unwrap()).unwrap(), id);
+    }
+
+    #[test]
+    fn rejects_anything_but_a_uuid() {
+        for id in ["", "canvas", "' OR 1=1 --", "../../etc/passwd", "6f9619ff-8b86-d011-b42d-00c04fc964f"] {
+            assert!(id.parse::<CanvasId>().is_err(), "{:?}", id);
+            assert!(serde_json::from_value::<CanvasId>(serde_json::json!(id)).is_err(), "{:?}", id);
+        }
+    }
+}
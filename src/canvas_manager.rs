@@ -1,13 +1,30 @@
-use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex as StdMutex}, time::{Duration, Instant}};
 
-use axum::extract::ws::Message;
-use serde_json::json;
+use serde::Serialize;
 use sqlx::{query, SqlitePool};
-use tokio::{fs::OpenOptions, sync::{Mutex, RwLock}};
+use tokio::{sync::{broadcast, mpsc, oneshot, Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock}, task::JoinHandle};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use uuid::Uuid;
-use tokio::io::AsyncWriteExt;
+use axum::extract::ws::Message;
 
-use crate::{identifiable_web_socket::IdentifiableWebSocket, websocket_handlers::WebSocketEvents, AppState};
+use crate::{
+    canvas_id::CanvasId,
+    content_filter::ContentFilter,
+    error_reporting,
+    chat_store::{append_chat_message, read_recent_chat, ChatMessage, CHAT_HISTORY_LIMIT, MAX_CHAT_MESSAGE_CHARS},
+    events::{validate_events, EventValidationError},
+    event_log::{self, apply_tombstones, compact_events, encoded_len, event_author, event_seq, garbage_ratio, is_system_event, is_tombstone, VerifyReport},
+    event_store::{EventStore, EventWriter, LogArchive, RemoteSyncStatus},
+    permission_source::PermissionSource,
+    review_queue::{HeldBatch, ReviewQueue},
+    identifiable_web_socket::{Delivery, IdentifiableWebSocket},
+    moderation_log::{self, ModerationAction},
+    reports::{self, Report, MAX_REPORTED_SEQS, MAX_REPORT_REASON_CHARS},
+    metrics::WsMetrics,
+    server_message::{PresenceUpdate, ServerMessage},
+    websocket_handlers::WebSocketEvents,
+    AppState,
+};
 
 
 
@@ -18,512 +35,6031 @@ use crate::{identifiable_web_socket::IdentifiableWebSocket, websocket_handlers::
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ConnectionInfo {
     pub user_id: i64,
+    /// Display name at the time of registration, used for presence messages.
+    pub display_name: String,
     pub connection: IdentifiableWebSocket,
+    /// Viewers (e.g. SSE streams) only receive messages. They are left out of presence.
+    pub read_only: bool,
+}
+
+/// A single user entry in presence messages.
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceEntry {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+}
+
+/// A user currently subscribed to a canvas, as returned by the online users list.
+#[derive(Debug, Clone, Serialize)]
+pub struct OnlineUser {
+    pub user_id: i64,
+    pub display_name: String,
+    pub permission_level: String,
+    /// Number of open connections of this user. Only included for O/C/M callers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_count: Option<usize>,
 }
 
 /// Helper struct for data retrieved from the Canvas DB table.
 #[derive(Debug)]
 pub struct CanvasDBInfo {
-    pub file_path: PathBuf,
     pub is_moderated: bool,
+    /// Per-canvas coalescing setting. `None` follows the instance default.
+    pub coalesce_events: Option<bool>,
+    /// Number of events in the log as last flushed to the DB.
+    pub event_count: u64,
+    /// Highest sequence number taken as last flushed to the DB.
+    pub last_seq: u64,
+    /// Minimum time between two batches of a user in milliseconds, 0 if slow mode is off.
+    pub slow_mode_ms: u64,
 }
 
-#[derive(Debug)]
-pub struct CanvasState {
-    pub subscribers: HashSet<ConnectionInfo>,
-    pub file_mutex: Arc<Mutex<()>>,
-    pub is_moderated: bool,
-    pub file_path: PathBuf,
+/// What the DB keeps about a canvas' log, written back periodically (see `flush_log_counters`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LogCounters {
+    event_count: u64,
+    last_seq: u64,
 }
 
-impl CanvasState {
-    /// Creates a new CanvasState from database info. (Kept simple/synchronous)
-    pub fn new(info: CanvasDBInfo) -> Self {
+/// Settings for coalescing live events into batches.
+/// In coalescing mode events are buffered and written and broadcast once per interval,
+/// instead of once per message.
+#[derive(Debug, Clone, Copy)]
+pub struct CoalesceConfig {
+    /// Whether canvases without their own setting coalesce events.
+    pub enabled_by_default: bool,
+    /// How long events are buffered before they are flushed.
+    pub interval: Duration,
+}
+
+impl Default for CoalesceConfig {
+    /// Off, with an interval of 40ms.
+    fn default() -> Self {
         Self {
-            subscribers: HashSet::new(),
-            file_mutex: Arc::new(Mutex::new(())),
-            file_path: info.file_path,
-            is_moderated: info.is_moderated,
+            enabled_by_default: false,
+            interval: Duration::from_millis(40),
         }
     }
 }
 
-// ============================= Manager =============================
-
-#[derive(Clone)]
-pub struct CanvasManager {
-    inner: Arc<RwLock<HashMap<String, CanvasState>>>,
+/// Events waiting to be written and broadcast in coalescing mode.
+#[derive(Debug, Default)]
+struct PendingEvents {
+    /// Stamped events, each with the connection that sent it.
+    events: Vec<(serde_json::Value, Uuid)>,
+    /// A flush task is already waiting to write these events.
+    flush_scheduled: bool,
 }
 
+/// Default number of events above which joining clients get a compacted snapshot instead of the full history.
+pub const DEFAULT_HISTORY_MAX_EVENTS: u64 = 100_000;
+
+/// How the history is sent to a registering connection.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HistoryOptions {
+    /// Leave deleted events out of the history.
+    pub compact: bool,
+    /// Always send the full history, even if the log exceeds the history cap.
+    pub full: bool,
+}
 
+/// The events of a canvas' log up to `seq`, with deleted events and tombstones removed.
+/// Sent in place of that part of the log to clients joining a canvas with a large log.
 #[derive(Debug)]
-#[allow(dead_code)]
-pub enum CanvasRegistrationError {
-    NotFound,
-    DatabaseError(String),
+struct CompactedPrefix {
+    seq: u64,
+    /// Number of events in the log when the prefix was built.
+    log_events: u64,
+    events: Vec<serde_json::Value>,
 }
 
-impl CanvasManager {
-    pub fn new() -> Self {
+/// Default memory budget for the cached event log of a single canvas.
+pub const DEFAULT_EVENT_CACHE_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Default memory budget for the cached event logs of all loaded canvases together.
+pub const DEFAULT_EVENT_CACHE_TOTAL_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Memory budgets for the cached event logs.
+#[derive(Debug, Clone, Copy)]
+pub struct EventCacheConfig {
+    /// Logs larger than this are not cached at all.
+    pub max_bytes: u64,
+    /// Once the cached logs of all canvases exceed this, the least recently used ones are dropped.
+    /// Zero disables the limit.
+    pub max_total_bytes: u64,
+}
+
+impl Default for EventCacheConfig {
+    fn default() -> Self {
         Self {
-            inner: Arc::new(RwLock::new(HashMap::new())),
+            max_bytes: DEFAULT_EVENT_CACHE_MAX_BYTES,
+            max_total_bytes: DEFAULT_EVENT_CACHE_TOTAL_MAX_BYTES,
         }
     }
+}
 
-    /// Helper function to find the file path and moderation state from the DB.
-    /// This remains the source of truth for loading the initial state.
-    async fn get_canvas_info(
-        pool: &SqlitePool,
-        canvas_uuid: &str,
-    ) -> Result<CanvasDBInfo, CanvasRegistrationError> {
-        let row = query!(
-            "SELECT event_file_path, moderated FROM Canvas WHERE canvas_id = ?",
-            canvas_uuid
-        )
-        .fetch_one(pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => CanvasRegistrationError::NotFound,
-            _ => CanvasRegistrationError::DatabaseError(format!(
-                "DB query failed for canvas {}: {}",
-                canvas_uuid, e
-            )),
-        })?;
+/// The parsed event log of a canvas, kept in memory so registrations don't reread the file.
+/// Logs larger than the budget are not cached; their history is read from disk.
+#[derive(Debug)]
+struct EventCache {
+    /// `None` once the log outgrew the budget, a write failed or the cache was evicted.
+    /// Shared with history snapshots, so appending copies the events only while a snapshot is alive.
+    events: Option<Arc<Vec<serde_json::Value>>>,
+    /// Size of the cached log in bytes, as written to the file.
+    bytes: u64,
+    max_bytes: u64,
+    /// Sequence number of the last event written and published by the writer task.
+    /// Kept even when the events are not cached, so history reads stop where live messages begin.
+    written_seq: u64,
+    /// Sequence number of the last clear in the log, 0 if the canvas was never cleared.
+    /// Also kept without cached events, so histories start at the clear either way.
+    clear_seq: u64,
+    /// When the cache was last read or appended to. Caches used least recently are evicted first.
+    last_used: Instant,
+    /// The cached bytes of all canvases are tracked in `event_cache_bytes`.
+    metrics: Arc<WsMetrics>,
+}
 
-        Ok(CanvasDBInfo {
-            file_path: PathBuf::from(row.event_file_path),
-            is_moderated: row.moderated,
-        })
-    }
+/// What a cold load learns from one pass over a canvas' log (see `CanvasManager::scan_log`).
+struct ScannedLog {
+    /// The whole log, `None` if it is larger than the cache budget.
+    events: Option<Vec<serde_json::Value>>,
+    /// Size of the log in bytes, as written to the file.
+    bytes: u64,
+    count: u64,
+    last_seq: u64,
+    /// Sequence number of the last clear, 0 if the canvas was never cleared.
+    clear_seq: u64,
+}
 
+impl EventCache {
+    fn new(
+        events: Option<Vec<serde_json::Value>>,
+        bytes: u64,
+        max_bytes: u64,
+        written_seq: u64,
+        metrics: Arc<WsMetrics>,
+    ) -> Self {
+        let clear_seq = events.as_deref().map_or(0, event_log::last_clear_seq);
+        let cache = Self {
+            events: events.filter(|_| bytes <= max_bytes).map(Arc::new),
+            bytes,
+            max_bytes,
+            written_seq,
+            clear_seq,
+            last_used: Instant::now(),
+            metrics,
+        };
+        WsMetrics::add(&cache.metrics.event_cache_bytes, cache.cached_bytes());
+        cache
+    }
 
-    // Helper function to read history and send moderation state first
-    async fn send_canvas_history(
-        connection: &IdentifiableWebSocket,
-        file_path: &PathBuf,
-        canvas_uuid: &str,
-        is_moderated: bool,
-        your_permission: &str,   
-    ) {
-        // 1. Send moderation state
-        let moderated_msg = json!({
-            "canvasId": canvas_uuid,
-            "moderated": is_moderated
-        });
+    /// Bytes held in memory, zero if the events are not cached.
+    fn cached_bytes(&self) -> u64 {
+        if self.events.is_some() { self.bytes } else { 0 }
+    }
 
-        if let Err(e) = connection.send(Message::Text(moderated_msg.to_string().into())).await {
-            tracing::error!("Failed to send moderation state to client {}: {}", connection.id, e);
+    /// Appends events that were just written to the log.
+    fn append(&mut self, events: &[serde_json::Value], bytes: u64) {
+        self.written_seq = self.written_seq.max(event_log::last_seq(events));
+        self.clear_seq = self.clear_seq.max(event_log::last_clear_seq(events));
+        self.last_used = Instant::now();
+        if self.events.is_some() {
+            WsMetrics::add(&self.metrics.event_cache_bytes, bytes);
         }
+        self.bytes += bytes;
+        if self.bytes > self.max_bytes {
+            self.invalidate();
+        }
+        if let Some(cached) = self.events.as_mut() {
+            Arc::make_mut(cached).extend_from_slice(events);
+        }
+    }
 
-        // 2. Send history
-        match tokio::fs::read_to_string(file_path).await {
-            Ok(content) => {
-                let mut events = Vec::new();
+    /// Drops the cached events, e.g. when the log may no longer match them.
+    /// Returns the number of bytes freed.
+    fn invalidate(&mut self) -> u64 {
+        let freed = self.cached_bytes();
+        self.events = None;
+        WsMetrics::sub(&self.metrics.event_cache_bytes, freed);
+        freed
+    }
+}
 
-                for line in content.lines() {
-                    if line.trim().is_empty() {
-                        continue;
-                    }
+impl Drop for EventCache {
+    fn drop(&mut self) {
+        self.invalidate();
+    }
+}
 
-                    match serde_json::from_str::<serde_json::Value>(line) {
-                        Ok(value) => events.push(value),
-                        Err(e) => {
-                            tracing::warn!(
-                                "Skipping invalid line in canvas {} history: {}",
-                                canvas_uuid, e
-                            );
-                        }
-                    }
-                }
+/// Default time a canvas without subscribers stays loaded, so a quick rejoin skips the reload.
+pub const DEFAULT_CANVAS_IDLE_TTL_SECONDS: u64 = 5 * 60;
 
-                let history_message = json!({
-                    "canvasId": canvas_uuid,
-                    "eventsForCanvas": events
-                });
+/// Default time events stay held for review before they expire.
+pub const DEFAULT_HELD_EVENTS_TTL_SECONDS: u64 = 24 * 60 * 60;
 
-                if let Err(e) = connection.send(Message::Text(history_message.to_string().into())).await {
-                    tracing::error!("Failed to send history to client {}: {}", connection.id, e);
-                }
-            }
-            Err(_) => {
-                connection
-                    .notify_client("Failed to load canvas history. Try refreshing.")
-                    .await;
-            }
-        }
+/// Longest slow mode interval moderators can set, in milliseconds.
+pub const MAX_SLOW_MODE_MS: u64 = 60 * 60 * 1000;
 
-        // 3. Send permission
-        let permission_msg = json!({
-            "canvasId": canvas_uuid,
-            "yourPermission": your_permission
-        });
+/// Maximum length of an announcement pinned to a canvas.
+const MAX_ANNOUNCEMENT_CHARS: usize = 500;
 
-        if let Err(e) = connection.send(Message::Text(permission_msg.to_string().into())).await {
-            tracing::error!(
-                "Failed to send permission to client {}: {}",
-                connection.id,
-                e
-            );
-        }
-    }
+/// Maximum length of the reason a moderator gives, e.g. for rejecting held events or banning a user.
+const MAX_MODERATION_REASON_CHARS: usize = 500;
 
+/// Sent to authors as the rejection reason of held events that expired.
+const HELD_EVENTS_EXPIRED_REASON: &str = "Expired before a moderator reviewed it.";
 
+/// Settings of the canvas manager.
+#[derive(Debug, Clone, Copy)]
+pub struct CanvasManagerConfig {
+    pub coalesce: CoalesceConfig,
+    pub event_cache: EventCacheConfig,
+    /// How long a canvas without subscribers stays loaded. Zero unloads it right away.
+    pub idle_ttl: Duration,
+    /// Logs with more events are sent to joining clients as a compacted snapshot. Zero sends every log in full.
+    pub history_max_events: u64,
+    /// How long events stay held for review before they expire. Zero keeps them until reviewed.
+    pub held_events_ttl: Duration,
+}
 
+impl Default for CanvasManagerConfig {
+    fn default() -> Self {
+        Self {
+            coalesce: CoalesceConfig::default(),
+            event_cache: EventCacheConfig::default(),
+            idle_ttl: Duration::from_secs(DEFAULT_CANVAS_IDLE_TTL_SECONDS),
+            history_max_events: DEFAULT_HISTORY_MAX_EVENTS,
+            held_events_ttl: Duration::from_secs(DEFAULT_HELD_EVENTS_TTL_SECONDS),
+        }
+    }
+}
 
+/// How often the background task looks for idle canvases to unload.
+const IDLE_SWEEP_INTERVAL_SECONDS: u64 = 30;
 
+/// How often ended bans and mutes are removed from the DB.
+const MODERATION_CLEANUP_INTERVAL_SECONDS: u64 = 5 * 60;
 
-    /// Registers a connection to a canvas.
-    /// Returns an error only if there's a problem internal to the manager (e.g., lock poisoning).
-    /// Sends a notification to the client if the canvas is not found in the DB.
-    pub async fn register(
-        &self,
-        app_state: &AppState,
-        canvas_uuid: String,
-        user_id: i64,
-        connection: IdentifiableWebSocket,
-    ) {
-        let connection_clone = connection.clone(); // Clone for error path and final insertion
+/// How often held events of loaded canvases are checked for expiry.
+const HELD_EVENTS_EXPIRY_INTERVAL_SECONDS: u64 = 60;
 
-        // === Check permissions before anything else ===
-        let perm = app_state
-            .socket_claims_manager
-            .get_permission_level(user_id, &canvas_uuid.clone())
-            .await;
+/// How often the cached event logs are checked against their total budget.
+const CACHE_BUDGET_INTERVAL_SECONDS: u64 = 30;
 
-        if perm.is_empty() {
-            connection_clone
-                .notify_client("You do not have permission to access this canvas.")
-                .await;
-            tracing::warn!(
-                "User {} tried to register to canvas {} without permission",
-                user_id,
-                canvas_uuid
-            );
-            return;
-        }
+/// How often the background task looks for canvases worth compacting.
+const COMPACTION_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
 
-        // Acquire write lock on the manager's HashMap
-        let mut manager_lock = self.inner.write().await;
+/// How often the event counts and sequence numbers of loaded canvases are written to the DB.
+const LOG_COUNTER_FLUSH_INTERVAL_SECONDS: u64 = 30;
 
-        // Ensure canvas state exists in memory
-        if !manager_lock.contains_key(&canvas_uuid) {
-            tracing::info!("Canvas {} not in memory. Fetching info from DB.", canvas_uuid);
+/// How often the logs of idle canvases are checked for compression, if enabled.
+const COMPRESSION_INTERVAL_SECONDS: u64 = 60 * 60;
 
-            // Attempt to load info from DB
-            match Self::get_canvas_info(&app_state.pool, &canvas_uuid).await {
-                Ok(db_info) => {
-                    let new_state = CanvasState::new(db_info);
-                    manager_lock.insert(canvas_uuid.clone(), new_state);
-                }
-                Err(CanvasRegistrationError::NotFound) => {
-                    connection_clone
-                        .notify_client(&format!(
-                            "Canvas ID '{}' is invalid or does not exist.",
-                            canvas_uuid
-                        ))
-                        .await;
-                    tracing::error!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid);
-                    return;
-                }
-                Err(_) => {
-                    connection_clone
-                        .notify_client("A database error occurred. Cannot subscribe to canvas.")
-                        .await;
-                    tracing::error!("A database error occurred. Cannot subscribe to canvas.");
-                    return;
-                }
-            }
-        }
+/// Default time archived event logs are kept before they are removed.
+pub const DEFAULT_ARCHIVE_RETENTION_DAYS: u64 = 30;
 
-        // Now the state is guaranteed to exist
-        let canvas_state = manager_lock
-            .get_mut(&canvas_uuid)
-            .expect("CanvasState must exist after check/insert.");
+/// How often archived event logs are checked against the retention.
+const ARCHIVE_GC_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
 
-        let file_path = canvas_state.file_path.clone();
+/// Number of writes a canvas' writer task queues before further appends are rejected.
+const WRITE_QUEUE_CAPACITY: usize = 256;
 
-        // Add the connection info to the set.
-        let connection_info = ConnectionInfo { user_id, connection };
-        canvas_state.subscribers.insert(connection_info.clone());
+/// Maximum number of queued writes the writer task combines into one append.
+const MAX_WRITE_BATCH: usize = 64;
 
-        tracing::info!(
-            "User {} subscribed to canvas {} (conn_id: {}). Total subscribers: {}. Moderated: {}",
-            user_id,
-            canvas_uuid,
-            connection_info.connection.id,
-            canvas_state.subscribers.len(),
-            canvas_state.is_moderated,
-        );
+/// Number of messages a canvas' broadcast channel keeps for subscribers that fall behind.
+/// A subscriber that misses more is asked to resync.
+const BROADCAST_CAPACITY: usize = 1024;
 
-        // Send moderation, history, and permissions to the client
-        Self::send_canvas_history(
-            &connection_info.connection,
-            &file_path,
-            &canvas_uuid,
-            canvas_state.is_moderated,
-            &perm, 
-        )
-        .await;
-    }
+/// Number of NDJSON lines a replay reads ahead of a slow client.
+const REPLAY_QUEUE_LINES: usize = 256;
 
+/// Maximum number of events in one `history` message.
+/// Larger histories are sent as several numbered chunks.
+const HISTORY_CHUNK_EVENTS: usize = 500;
 
+/// Which subscribers of a canvas a broadcast is meant for.
+#[derive(Debug, Clone, Copy)]
+enum Recipients {
+    All,
+    AllExceptConnection(Uuid),
+    AllExceptUser(i64),
+    /// Only the connections with an override.
+    Listed,
+}
 
-    /// Unregisters a specific connection from a canvas.
-    pub async fn unregister_connection(
-        &self,
-        canvas_uuid: &str,
-        conn_id: &Uuid,
-    ) -> bool {
-        let mut manager_lock = self.inner.write().await;
+/// A message fanned out through a canvas' broadcast channel.
+/// It is serialized once and shared by the relay tasks of all subscribers.
+#[derive(Debug)]
+struct CanvasBroadcast {
+    message: Message,
+    recipients: Recipients,
+    /// Replacement messages for single connections; `None` skips the connection.
+    /// Coalesced batches use this to leave out a connection's own events.
+    overrides: HashMap<Uuid, Option<Message>>,
+    /// When the message was published, for the fan-out latency.
+    published_at: Instant,
+    /// Highest sequence number of the events in the message, 0 for messages without events.
+    last_seq: u64,
+}
 
-        if let Some(canvas_state) = manager_lock.get_mut(canvas_uuid) {
-            let initial_len = canvas_state.subscribers.len();
-            canvas_state.subscribers.retain(|info| &info.connection.id != conn_id);
-            
-            let was_removed = initial_len > canvas_state.subscribers.len();
-            if was_removed {
-                tracing::info!(
-                    "Connection {} unsubscribed from canvas {}. Remaining subscribers: {}",
-                    conn_id,
-                    canvas_uuid,
-                    canvas_state.subscribers.len()
-                );
-            }
-            
-            // Cleanup: If no more subscribers, remove the canvas from the map.
-            if canvas_state.subscribers.is_empty() {
-                manager_lock.remove(canvas_uuid);
-                tracing::info!("Canvas {} removed from manager as it is now empty.", canvas_uuid);
-            }
-            was_removed
-        } else {
-            tracing::warn!("Attempted to unregister from a non-existent canvas: {}", canvas_uuid);
-            false
+impl CanvasBroadcast {
+    fn new(message: &ServerMessage, recipients: Recipients) -> Self {
+        Self {
+            message: message.to_ws_message(),
+            recipients,
+            overrides: HashMap::new(),
+            published_at: Instant::now(),
+            last_seq: 0,
         }
     }
 
-    /// Unregisters all connections for a given user from a canvas.
-    pub async fn unregister_user(
-        &self,
-        canvas_uuid: &str,
-        user_id: i64,
-    ) -> bool {
-        let mut manager_lock = self.inner.write().await;
-
-        if let Some(canvas_state) = manager_lock.get_mut(canvas_uuid) {
-            let initial_len = canvas_state.subscribers.len();
-            canvas_state.subscribers.retain(|info| info.user_id != user_id);
-            
-            let was_removed = initial_len > canvas_state.subscribers.len();
-            if was_removed {
-                tracing::info!(
-                    "User {} unsubscribed all connections from canvas {}. Remaining subscribers: {}",
-                    user_id,
-                    canvas_uuid,
-                    canvas_state.subscribers.len()
-                );
-            }
-            
-            if canvas_state.subscribers.is_empty() {
-                manager_lock.remove(canvas_uuid);
-                tracing::info!("Canvas {} removed from manager as it is now empty.", canvas_uuid);
-            }
-            was_removed
-        } else {
-            tracing::warn!("Attempted to unregister a user from a non-existent canvas: {}", canvas_uuid);
-            false
+    /// A message for the given connections only.
+    fn to_connections(message: &ServerMessage, conn_ids: impl IntoIterator<Item = Uuid>) -> Self {
+        let mut broadcast = Self::new(message, Recipients::Listed);
+        for conn_id in conn_ids {
+            broadcast.overrides.insert(conn_id, Some(broadcast.message.clone()));
         }
+        broadcast
     }
 
+    /// The message a subscriber receives, if any.
+    fn message_for(&self, info: &ConnectionInfo) -> Option<&Message> {
+        if let Some(message) = self.overrides.get(&info.connection.id) {
+            return message.as_ref();
+        }
 
+        let included = match self.recipients {
+            Recipients::All => true,
+            Recipients::AllExceptConnection(conn_id) => info.connection.id != conn_id,
+            Recipients::AllExceptUser(user_id) => info.user_id != user_id,
+            Recipients::Listed => false,
+        };
+        included.then_some(&self.message)
+    }
+}
 
-    /// Handles an incoming event from a client, performing validation,
-    /// permission checks, file writing, and broadcasting.
-    ///
-    /// The `sender_connection` is the specific WebSocket connection that sent the event.
-    pub async fn handle_event(
-        &self,
-        state: &AppState,
-        sender_id: i64,
-        events: WebSocketEvents,
-        original_message_text: String,
-    ) {
-        let canvas_uuid = &events.canvas_id;
+/// Everything needed to send the history of a canvas, captured under the canvas lock
+/// so the history can be read without holding it.
+#[derive(Debug)]
+struct HistorySnapshot {
+    is_moderated: bool,
+    slow_mode_ms: u64,
+    /// Sequence number of the last event published when the snapshot was taken.
+    /// Later events reach the connection live, so the history stops here.
+    last_seq: u64,
+    /// Sequence number of the last clear. Events before it are left out of the history.
+    clear_seq: u64,
+    online_users: Vec<PresenceEntry>,
+    /// The cached event log, if the canvas has one.
+    cached_events: Option<Arc<Vec<serde_json::Value>>>,
+    /// Number of events in the log, to decide whether the history cap applies.
+    event_count: u64,
+    compacted_prefix: Option<Arc<CompactedPrefix>>,
+    /// The canvas' `log_generation`, so a prefix built from a log rewritten meanwhile is not kept.
+    log_generation: u64,
+    announcement: Option<Announcement>,
+    presenters: Option<Vec<i64>>,
+}
 
-        let manager_lock = self.inner.read().await;
-        let canvas_state = if let Some(cs) = manager_lock.get(canvas_uuid) {
-            cs
-        } else {
-            tracing::warn!(
-                "Events received for canvas {} with no active manager entry. Dropping event.",
-                canvas_uuid
-            );
-            return;
-        };
+#[derive(Debug)]
+pub struct CanvasState {
+    /// Subscribed connections by connection id. Only changed through `add_subscriber`
+    /// and `remove_subscribers`, which keep `subscribers_by_user` in sync.
+    subscribers: HashMap<Uuid, ConnectionInfo>,
+    /// Connection ids of the subscribers, by user.
+    subscribers_by_user: HashMap<i64, HashSet<Uuid>>,
+    /// Serializes taking sequence numbers and queueing writes, so the log stays ordered by sequence.
+    pub file_mutex: Arc<Mutex<()>>,
+    pub chat_mutex: Arc<Mutex<()>>,
+    pub is_moderated: bool,
+    /// Sequence number for the next event written to the log.
+    /// Only taken while holding `file_mutex`, so the log stays ordered by sequence.
+    pub next_seq: AtomicU64,
+    /// Whether live events are coalesced into batches (see `CoalesceConfig`).
+    pub coalesce: bool,
+    pending: StdMutex<PendingEvents>,
+    /// Updated by the writer task as events are written, in the order of the log.
+    cache: Arc<StdMutex<EventCache>>,
+    /// Fans messages out to the subscribers. Written events are sent by the writer task
+    /// while holding the cache lock, everything else while holding the canvas lock,
+    /// so a history snapshot and the messages after it never overlap.
+    channel: broadcast::Sender<Arc<CanvasBroadcast>>,
+    /// `None` only while the canvas unloads or its log is rewritten.
+    writer: Option<CanvasWriter>,
+    /// Relay tasks forwarding the channel to each subscribed connection, by connection id.
+    relays: HashMap<Uuid, JoinHandle<()>>,
+    /// Set when the canvas is removed from the manager. Holders of a stale handle
+    /// see it after acquiring the canvas lock and treat the canvas as not loaded.
+    unloaded: bool,
+    /// Since when the canvas has had no subscribers. The idle sweep unloads it once
+    /// this is older than the idle TTL.
+    last_empty_at: Option<Instant>,
+    /// Number of events in the log, counted by the writer task as events are written.
+    event_count: Arc<AtomicU64>,
+    /// The counters the DB has. They are written back periodically and after unloading.
+    stored_counters: StdMutex<LogCounters>,
+    /// Sent instead of the start of the log to clients joining while the log exceeds the history cap.
+    /// Rebuilt from the current log once the events after it grow too many.
+    compacted_prefix: StdMutex<Option<Arc<CompactedPrefix>>>,
+    /// Bumped whenever the log is rewritten (see `reset_log`).
+    log_generation: AtomicU64,
+    /// Batches of writers held for review while the canvas is moderated, oldest first.
+    /// Mirrors the canvas' review file; held while it is written.
+    review: Mutex<Vec<HeldBatch>>,
+    /// Muted users by user id. Mirrors `canvas_mutes`, changed under the canvas' write lock
+    /// after the DB; ended mutes may linger until the next load.
+    mutes: HashMap<i64, CanvasMute>,
+    /// Banned users by user id. Mirrors `canvas_bans` like `mutes` does `canvas_mutes`.
+    bans: HashMap<i64, CanvasBan>,
+    /// The active announcement pinned by a moderator, mirroring `canvas_announcements`.
+    announcement: Option<Announcement>,
+    /// While the owner presents: the users besides the owner who may still draw, `None` otherwise.
+    /// Only kept in memory, a presentation is a live session and ends when the canvas unloads.
+    presenters: Option<Vec<i64>>,
+    /// Minimum time between two batches of a user in milliseconds, mirroring `Canvas.slow_mode_ms`. 0 is off.
+    slow_mode_ms: u64,
+    /// When each user's last batch was accepted, while slow mode is on. Moderators are not tracked.
+    last_batches: StdMutex<HashMap<i64, Instant>>,
+}
 
-        // 1. Permission Check
-        let permission = state
-            .socket_claims_manager
-            .get_permission_level(sender_id, canvas_uuid)
-            .await;
+/// Resolves once queued events are written, or with the reason they could not be.
+pub type WriteAck = oneshot::Receiver<Result<(), String>>;
 
-        let can_draw = matches!(permission.as_str(), "W" | "V" | "M" | "O" | "C");
+/// Events queued for a canvas' writer task.
+#[derive(Debug)]
+struct WriteRequest {
+    events: Vec<serde_json::Value>,
+    /// Published once the events are written.
+    broadcast: CanvasBroadcast,
+    ack: oneshot::Sender<Result<(), String>>,
+}
 
-        // If the canvas is moderated, "W" (Writer) permission is not enough to draw.
-        let can_draw_in_moderated = can_draw && !canvas_state.is_moderated;
-        let can_moderate = matches!(permission.as_str(), "M" | "O" | "C");
-        let has_permission = can_draw_in_moderated || can_moderate;
+/// The writer task of a loaded canvas and its queue.
+#[derive(Debug)]
+struct CanvasWriter {
+    queue: mpsc::Sender<WriteRequest>,
+    task: JoinHandle<()>,
+}
 
-        if !has_permission {
-            tracing::warn!(
-                "User {} denied drawing permission on canvas {}, their permission level is {}",
-                sender_id,
-                canvas_uuid,
-                permission.as_str()
-            );
-            return;
+/// Writes the queued events of a canvas, combining writes that queued up meanwhile into one append.
+/// Written events are added to the cache and published in the order of the log.
+/// Exits once the queue is closed and drained, i.e. when the canvas unloads.
+async fn run_writer(
+    canvas_uuid: CanvasId,
+    store: Arc<dyn EventStore>,
+    mut queue: mpsc::Receiver<WriteRequest>,
+    cache: Arc<StdMutex<EventCache>>,
+    channel: broadcast::Sender<Arc<CanvasBroadcast>>,
+    event_count: Arc<AtomicU64>,
+    metrics: Arc<WsMetrics>,
+) {
+    // Opened on the first write, and again after a failed one
+    let mut writer: Option<Box<dyn EventWriter>> = None;
+
+    while let Some(first) = queue.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_WRITE_BATCH {
+            match queue.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
         }
+        let events: Vec<serde_json::Value> = batch.iter().flat_map(|request| request.events.iter().cloned()).collect();
 
-        // 2. Extract events_for_canvas
-        let events_to_write = match events.events_for_canvas {
-            serde_json::Value::Array(arr) => arr,
-            _ => {
-                tracing::error!("eventsForCanvas field is not an array.");
-                return;
+        let opened = match writer.take() {
+            Some(open) => Ok(open),
+            None => store.open_writer(&canvas_uuid.to_string()).await,
+        };
+        let result = match opened {
+            Ok(mut open) => {
+                let started = Instant::now();
+                let result = open.append(&events).await;
+                metrics.latency.append_duration.observe_duration(&canvas_uuid, started.elapsed());
+                if result.is_ok() {
+                    writer = Some(open);
+                }
+                result
             }
+            Err(e) => Err(e),
         };
 
-        // 3. Acquire File Mutex
-        let file_path = &canvas_state.file_path;
-        let lock_guard = canvas_state.file_mutex.lock().await;
-
+        match result {
+            Ok(_) => {
+                let bytes = encoded_len(&events);
+                WsMetrics::add(&metrics.events_persisted, events.len() as u64);
+                WsMetrics::add(&metrics.bytes_written, bytes);
+                metrics.latency.append_bytes.observe(&canvas_uuid, bytes);
+                event_count.fetch_add(events.len() as u64, Ordering::SeqCst);
 
-        // 4. Write Events to File
-        match OpenOptions::new().append(true).create(true).open(file_path).await {
-            Ok(mut file) => {
-                for event in events_to_write {
-                    let event_line = event.to_string() + "\n";
-                    if let Err(e) = file.write_all(event_line.as_bytes()).await {
-                        tracing::error!(
-                            "Failed to write event to file {}: {}",
-                            file_path.display(),
-                            e
-                        );
-                    }
+                let mut cache = cache.lock().unwrap();
+                cache.append(&events, bytes);
+                for request in batch {
+                    let mut broadcast = request.broadcast;
+                    broadcast.published_at = Instant::now();
+                    // Fails only if nobody is subscribed
+                    let _ = channel.send(Arc::new(broadcast));
+                    let _ = request.ack.send(Ok(()));
                 }
             }
             Err(e) => {
-                tracing::error!(
-                    "Failed to open/create file {}: {}",
-                    file_path.display(),
-                    e
-                );
-                return;
+                tracing::error!("Failed to write {} events of canvas {}: {}", events.len(), canvas_uuid, e);
+                // The cache must match the log, after a failed write only the store is trusted
+                cache.lock().unwrap().invalidate();
+                for request in batch {
+                    let _ = request.ack.send(Err(e.to_string()));
+                }
             }
         }
-        drop(lock_guard);
+    }
+}
 
-        // 5. Broadcast the Original Message
-        self.broadcast(canvas_uuid, Message::Text(original_message_text.into()))
-            .await;
+/// Sends a canvas history as `history` messages of at most `HISTORY_CHUNK_EVENTS` events,
+/// so large logs are never held in memory as a whole. An empty history is still sent once.
+/// Read errors are returned; a failed send only means the client is gone and ends the history.
+async fn send_history_chunks(
+    connection: &IdentifiableWebSocket,
+    canvas_uuid: &CanvasId,
+    events: impl Stream<Item = std::io::Result<serde_json::Value>>,
+) -> std::io::Result<()> {
+    let mut events = std::pin::pin!(events);
+    let mut chunk = 0;
+    let mut buffer = Vec::with_capacity(HISTORY_CHUNK_EVENTS);
+
+    loop {
+        let next = events.try_next().await?;
+        let done = next.is_none();
+        if let Some(event) = next {
+            buffer.push(event);
+            if buffer.len() < HISTORY_CHUNK_EVENTS {
+                continue;
+            }
+        } else if buffer.is_empty() && chunk > 0 {
+            return Ok(());
+        }
+
+        let history_message = ServerMessage::History {
+            canvas_id: canvas_uuid.to_string(),
+            chunk,
+            events_for_canvas: std::mem::take(&mut buffer),
+        };
+        if let Err(e) = connection.send_msg(&history_message).await {
+            tracing::error!("Failed to send history to client {}: {}", connection.id, e);
+            return Ok(());
+        }
+        if done {
+            return Ok(());
+        }
+        chunk += 1;
     }
+}
 
-    
-    /// Sends a message to all active subscribers of a canvas.
-    pub async fn broadcast(&self, canvas_uuid: &str, message: Message) {
+/// Error message for events that could not be written.
+const PERSIST_FAILED_MESSAGE: &str = "Your events could not be saved and were not shown to anyone. Try again.";
 
+/// Tells the connections that sent queued events, with the number of events each sent, once they are
+/// written: an `ack` if they were, `persist_failed` if writing them failed. Failed events are never
+/// broadcast, so no other client has them either.
+fn report_persisted(persisted: WriteAck, canvas_uuid: &CanvasId, origins: Vec<(IdentifiableWebSocket, usize)>) {
+    if origins.is_empty() {
+        return;
+    }
+    let canvas_uuid = *canvas_uuid;
 
-        let map = self.inner.read().await;
-        
-        if let Some(canvas_state) = map.get(canvas_uuid) {
-            let cloned_message = message.clone();
-            
-            for conn_info in canvas_state.subscribers.iter() {
-                if let Err(e) = conn_info.connection.sender.send(cloned_message.clone()).await {
-                    tracing::error!("Failed to send broadcast to conn {}: {}", conn_info.connection.id, e);
+    tokio::spawn(async move {
+        match persisted.await {
+            Ok(Ok(())) => {
+                for (connection, count) in origins {
+                    let ack = ServerMessage::Ack { canvas_id: canvas_uuid.to_string(), count };
+                    if let Err(e) = connection.send_msg(&ack).await {
+                        tracing::debug!("Failed to acknowledge {} events to client {}: {}", count, connection.id, e);
+                    }
                 }
+                return;
             }
-        } else {
-            tracing::warn!("Attempted to broadcast to non-existent canvas: {}", canvas_uuid);
+            // Logged by the writer task
+            Ok(Err(_)) => {}
+            Err(_) => tracing::error!("Writer of canvas {} stopped before acknowledging a write.", canvas_uuid),
         }
-    }
+        for (connection, count) in origins {
+            connection
+                .send_error(
+                    "persist_failed",
+                    PERSIST_FAILED_MESSAGE,
+                    Some(serde_json::json!({ "canvasId": canvas_uuid, "count": count })),
+                )
+                .await;
+        }
+    });
+}
 
-    pub async fn toggle_moderated_state(
-        &self,
-        state: &AppState,
-        user_id: i64,
-        canvas_uuid: String,
-    ) {
-        // 1. Check permissions
-        let permission = state
-            .socket_claims_manager
-            .get_permission_level(user_id, &canvas_uuid)
-            .await;
+/// Selects the events of a history: from the clear at `clear_seq` (if any) up to `last_seq`.
+/// Events without a sequence number predate sequence numbers, and thus any clear.
+fn history_bounds(clear_seq: u64, last_seq: u64) -> impl Fn(&serde_json::Value) -> bool + Copy + Send + 'static {
+    move |event| match event_seq(event) {
+        Some(seq) => seq >= clear_seq && seq <= last_seq,
+        None => clear_seq == 0,
+    }
+}
 
-        let can_toggle = matches!(permission.as_str(), "M" | "O" | "C");
-        if !can_toggle {
-            tracing::warn!(
-                "User {} denied moderation toggle on canvas {} (permission: {})",
-                user_id,
-                canvas_uuid,
-                permission
-            );
-            return;
+/// A loaded canvas. Each canvas has its own lock; the manager map is only locked
+/// briefly to look canvases up, so work on one canvas never blocks another.
+type SharedCanvas = Arc<RwLock<CanvasState>>;
+
+impl CanvasState {
+    /// Creates a new CanvasState from database info, the last sequence number in its log
+    /// and the number of events in it, if the log could be read.
+    fn new(
+        info: CanvasDBInfo,
+        last_seq: u64,
+        event_count: Option<u64>,
+        coalesce_by_default: bool,
+        cache: EventCache,
+        held: Vec<HeldBatch>,
+        moderation: CanvasModeration,
+    ) -> Self {
+        Self {
+            event_count: Arc::new(AtomicU64::new(event_count.unwrap_or(info.event_count))),
+            stored_counters: StdMutex::new(LogCounters { event_count: info.event_count, last_seq: info.last_seq }),
+            cache: Arc::new(StdMutex::new(cache)),
+            writer: None,
+            coalesce: info.coalesce_events.unwrap_or(coalesce_by_default),
+            pending: StdMutex::new(PendingEvents::default()),
+            channel: broadcast::channel(BROADCAST_CAPACITY).0,
+            relays: HashMap::new(),
+            unloaded: false,
+            // Loaded without subscribers, e.g. for a REST append
+            last_empty_at: Some(Instant::now()),
+            subscribers: HashMap::new(),
+            subscribers_by_user: HashMap::new(),
+            file_mutex: Arc::new(Mutex::new(())),
+            chat_mutex: Arc::new(Mutex::new(())),
+            is_moderated: info.is_moderated,
+            // The DB remembers sequence numbers the log may have lost, e.g. to compaction
+            next_seq: AtomicU64::new(last_seq.max(info.last_seq) + 1),
+            compacted_prefix: StdMutex::new(None),
+            log_generation: AtomicU64::new(0),
+            review: Mutex::new(held),
+            mutes: moderation.mutes,
+            bans: moderation.bans,
+            announcement: moderation.announcement,
+            presenters: None,
+            slow_mode_ms: info.slow_mode_ms,
+            last_batches: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a user's slot for a batch of events while slow mode is on.
+    /// Returns the remaining wait in milliseconds if their last batch was too recent.
+    fn take_batch_slot(&self, user_id: i64, permission: &str) -> Result<(), u64> {
+        if self.slow_mode_ms == 0 || can_moderate(permission) {
+            return Ok(());
+        }
+        let interval = Duration::from_millis(self.slow_mode_ms);
+        let now = Instant::now();
+        let mut last_batches = self.last_batches.lock().unwrap();
+        if let Some(last) = last_batches.get(&user_id)
+            && let Some(remaining) = interval.checked_sub(now.duration_since(*last))
+            && !remaining.is_zero()
+        {
+            return Err(remaining.as_millis().max(1) as u64);
         }
+        last_batches.insert(user_id, now);
+        Ok(())
+    }
+
+    /// The mute of a user, if they are muted right now.
+    fn active_mute(&self, user_id: i64) -> Option<CanvasMute> {
+        let now_ms = event_log::timestamp_ms();
+        self.mutes.get(&user_id).copied().filter(|mute| mute.is_active(now_ms))
+    }
+
+    /// The ban of a user, if they are banned right now.
+    fn active_ban(&self, user_id: i64) -> Option<&CanvasBan> {
+        let now_ms = event_log::timestamp_ms();
+        self.bans.get(&user_id).filter(|ban| ban.is_active(now_ms))
+    }
+
+    /// The current counters of the log, to be written back to the DB.
+    fn log_counters(&self) -> LogCounters {
+        LogCounters {
+            event_count: self.event_count.load(Ordering::SeqCst),
+            last_seq: self.next_seq.load(Ordering::SeqCst).saturating_sub(1),
+        }
+    }
 
-        // 2. Acquire write lock on manager
-        let mut map = self.inner.write().await;
+    /// Takes the next sequence number. Call only while holding `file_mutex`.
+    fn take_seq(&self) -> u64 {
+        self.next_seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Buffers stamped events for the next flush.
+    /// Returns true if the caller has to schedule a flush.
+    fn queue_events(&self, events: Vec<serde_json::Value>, sender: Uuid) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        pending.events.extend(events.into_iter().map(|event| (event, sender)));
+        !std::mem::replace(&mut pending.flush_scheduled, true)
+    }
+
+    /// Captures what is needed to send the history to a newly registered connection,
+    /// and subscribes to the messages published after it.
+    fn history_snapshot(&self) -> (HistorySnapshot, broadcast::Receiver<Arc<CanvasBroadcast>>) {
+        // Under the cache lock, so the writer task cannot publish events in between
+        let mut cache = self.cache.lock().unwrap();
+        cache.last_used = Instant::now();
+        let receiver = self.channel.subscribe();
+        let snapshot = HistorySnapshot {
+            is_moderated: self.is_moderated,
+            slow_mode_ms: self.slow_mode_ms,
+            last_seq: cache.written_seq,
+            clear_seq: cache.clear_seq,
+            online_users: self.presence_list(),
+            cached_events: cache.events.clone(),
+            event_count: self.event_count.load(Ordering::SeqCst),
+            compacted_prefix: self.compacted_prefix.lock().unwrap().clone(),
+            log_generation: self.log_generation.load(Ordering::SeqCst),
+            announcement: self.announcement.clone(),
+            presenters: self.presenters.clone(),
+        };
+        (snapshot, receiver)
+    }
+
+    /// The cached event log, if the canvas has one.
+    fn cached_events(&self) -> Option<Arc<Vec<serde_json::Value>>> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.last_used = Instant::now();
+        cache.events.clone()
+    }
 
-        let canvas_state = if let Some(cs) = map.get_mut(&canvas_uuid) {
-            cs
+    /// Stamps events with their sequence numbers and checksums and queues them for the writer task,
+    /// together with the broadcast built from the stamped events.
+    /// Call only while holding `file_mutex`, so the queue stays in sequence order.
+    ///
+    /// With `wait`, waits for room in the queue. Otherwise a full queue fails with `QueueFull`
+    /// before any sequence number is taken.
+    async fn queue_write(
+        &self,
+        mut events: Vec<serde_json::Value>,
+        build_broadcast: impl FnOnce(&[serde_json::Value]) -> CanvasBroadcast,
+        wait: bool,
+    ) -> Result<WriteAck, AppendEventsError> {
+        let stopped = || AppendEventsError::Storage("The canvas writer is not running.".to_string());
+        let Some(writer) = &self.writer else {
+            return Err(stopped());
+        };
+        let permit = if wait {
+            writer.queue.reserve().await.map_err(|_| stopped())?
         } else {
-            tracing::warn!(
-                "toggle_moderated_state: Canvas {} not found in memory",
-                canvas_uuid
-            );
-            return;
+            writer.queue.try_reserve().map_err(|e| match e {
+                mpsc::error::TrySendError::Full(()) => AppendEventsError::QueueFull,
+                mpsc::error::TrySendError::Closed(()) => stopped(),
+            })?
         };
 
-        // Flip the moderation flag
-        canvas_state.is_moderated = !canvas_state.is_moderated;
-        let new_state = canvas_state.is_moderated;
+        for event in events.iter_mut() {
+            if let Some(object) = event.as_object_mut() {
+                object.insert("_seq".to_string(), self.take_seq().into());
+            }
+            event_log::stamp_crc(event);
+        }
+        let mut broadcast = build_broadcast(&events);
+        broadcast.last_seq = event_log::last_seq(&events);
+        let (ack, persisted) = oneshot::channel();
+        permit.send(WriteRequest { events, broadcast, ack });
+        Ok(persisted)
+    }
+
+    /// Replaces the log after it was rewritten: resets the cache and drops the compacted prefix.
+    /// Sequence numbers keep growing, so relays never mistake new events for ones from a history they sent.
+    /// Call only while the writer task is stopped.
+    fn reset_log(&self, events: Vec<serde_json::Value>, bytes: u64) {
+        let last_seq = event_log::last_seq(&events);
+        self.next_seq.fetch_max(last_seq + 1, Ordering::SeqCst);
+        self.event_count.store(events.len() as u64, Ordering::SeqCst);
+        self.log_generation.fetch_add(1, Ordering::SeqCst);
+        *self.compacted_prefix.lock().unwrap() = None;
+        let mut cache = self.cache.lock().unwrap();
+        *cache = EventCache::new(Some(events), bytes, cache.max_bytes, last_seq, cache.metrics.clone());
+    }
 
-        tracing::info!(
-            "User {} toggled moderation for canvas {} -> {}",
-            user_id,
+    /// Returns true if the user has at least one connection subscribed to this canvas.
+    /// Read-only viewers don't count.
+    pub fn has_user(&self, user_id: i64) -> bool {
+        self.subscribers_by_user.get(&user_id).is_some_and(|conn_ids| {
+            conn_ids
+                .iter()
+                .any(|conn_id| self.subscribers.get(conn_id).is_some_and(|info| !info.read_only))
+        })
+    }
+
+    /// Builds the list of distinct users currently subscribed to this canvas.
+    /// Presence is per user, so multiple connections of one user appear only once.
+    pub fn presence_list(&self) -> Vec<PresenceEntry> {
+        let mut users: HashMap<i64, String> = HashMap::new();
+        for info in self.subscribers.values().filter(|info| !info.read_only) {
+            users.entry(info.user_id).or_insert_with(|| info.display_name.clone());
+        }
+
+        let mut list: Vec<PresenceEntry> = users
+            .into_iter()
+            .map(|(user_id, display_name)| PresenceEntry { user_id, display_name })
+            .collect();
+        list.sort_by_key(|entry| entry.user_id);
+        list
+    }
+
+    /// Hands a message to the relay tasks of all subscribers. Never waits.
+    fn publish(&self, broadcast: CanvasBroadcast) {
+        // Fails only if nobody is subscribed, in which case there is nobody to tell
+        let _ = self.channel.send(Arc::new(broadcast));
+    }
+
+    /// Sends a message to every subscriber that does not belong to `excluded_user`.
+    fn send_to_other_users(&self, excluded_user: i64, message: &ServerMessage) {
+        self.publish(CanvasBroadcast::new(message, Recipients::AllExceptUser(excluded_user)));
+    }
+
+    /// Adds a connection to the subscribers, replacing an earlier entry for the same connection.
+    fn add_subscriber(&mut self, info: ConnectionInfo) {
+        let conn_id = info.connection.id;
+        if let Some(old) = self.subscribers.remove(&conn_id) {
+            self.remove_from_user_index(old.user_id, &conn_id);
+        }
+        self.subscribers_by_user.entry(info.user_id).or_default().insert(conn_id);
+        self.subscribers.insert(conn_id, info);
+    }
+
+    fn remove_from_user_index(&mut self, user_id: i64, conn_id: &Uuid) {
+        if let Some(user_conn_ids) = self.subscribers_by_user.get_mut(&user_id) {
+            user_conn_ids.remove(conn_id);
+            if user_conn_ids.is_empty() {
+                self.subscribers_by_user.remove(&user_id);
+            }
+        }
+    }
+
+    /// Removes connections from the subscribers and stops their relay tasks.
+    /// Returns the removed connections.
+    fn remove_subscribers(&mut self, conn_ids: &HashSet<Uuid>) -> Vec<ConnectionInfo> {
+        let removed: Vec<ConnectionInfo> = conn_ids
+            .iter()
+            .filter_map(|conn_id| self.subscribers.remove(conn_id))
+            .collect();
+
+        for info in removed.iter() {
+            self.remove_from_user_index(info.user_id, &info.connection.id);
+            if let Some(relay) = self.relays.remove(&info.connection.id) {
+                relay.abort();
+            }
+        }
+        removed
+    }
+}
+
+// ============================= Manager =============================
+
+#[derive(Clone)]
+pub struct CanvasManager {
+    /// Never held while acquiring a canvas lock, so canvas locks may take it.
+    inner: Arc<RwLock<HashMap<CanvasId, SharedCanvas>>>,
+    /// Canvases currently being loaded from the DB. Later loaders wait on the canvas' slot
+    /// and find the state ready once the first loader is done, or the error it failed with.
+    loading: Arc<StdMutex<HashMap<CanvasId, LoadSlot>>>,
+    metrics: Arc<WsMetrics>,
+    /// The canvases each connection is subscribed to, so a closed connection can leave all of them.
+    subscriptions: Arc<RwLock<HashMap<Uuid, HashSet<CanvasId>>>>,
+    coalesce: CoalesceConfig,
+    event_cache: EventCacheConfig,
+    /// Where the event logs are read from and appended to.
+    store: Arc<dyn EventStore>,
+    /// Where events held for review are kept.
+    review: ReviewQueue,
+    held_events_ttl: Duration,
+    /// Where the permission levels, names and connections of users are looked up.
+    permissions: Arc<dyn PermissionSource>,
+    /// Bumped by every eviction. A load that overlaps one checks that its canvas still exists,
+    /// so a write racing with the eviction cannot bring the canvas back.
+    evictions: Arc<AtomicU64>,
+    /// How long a canvas without subscribers stays loaded. Zero unloads it right away.
+    idle_ttl: Duration,
+    /// Counters of unloaded canvases not yet written to the DB (see `flush_log_counters`).
+    unflushed_counters: Arc<StdMutex<HashMap<CanvasId, LogCounters>>>,
+    /// Logs with more events are sent to joining clients as a compacted snapshot. Zero sends every log in full.
+    history_max_events: u64,
+    /// Applied to chat messages and announcements before they are stored.
+    content_filter: Arc<dyn ContentFilter>,
+}
+
+
+/// Result of compacting the event log of a canvas.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactionStats {
+    pub events_before: usize,
+    pub events_after: usize,
+}
+
+/// Result of dropping the cached event logs (see `CanvasManager::flush_caches`).
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheFlushStats {
+    pub canvases: usize,
+    pub bytes: u64,
+}
+
+/// A batch of held events as listed for moderators (see `CanvasManager::held_batches`).
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeldBatchSummary {
+    pub batch_id: String,
+    pub user_id: i64,
+    pub display_name: String,
+    /// Milliseconds since the Unix epoch.
+    pub held_at: u64,
+    /// Number of events in the batch.
+    pub count: usize,
+}
+
+/// Held by the loader of a canvas. Once the load is done it holds the outcome,
+/// so the loaders that waited for it don't repeat a failed load.
+type LoadSlot = Arc<Mutex<Option<Result<(), CanvasRegistrationError>>>>;
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub enum CanvasRegistrationError {
+    NotFound,
+    DatabaseError(String),
+}
+
+/// Why events could not be appended to (or read from, or compacted on) a canvas.
+/// Shared by the WebSocket and REST transports, which report it in their own way.
+#[derive(Debug)]
+pub enum AppendEventsError {
+    NotFound,
+    Forbidden,
+    InvalidPayload(String),
+    /// Some events don't match the accepted event shapes (see `events::validate_events`).
+    InvalidEvents(Vec<EventValidationError>),
+    /// The canvas' write queue is full. Nothing was written, the sender should retry later.
+    QueueFull,
+    /// Heavy file work (e.g. compression) is already running at the configured concurrency.
+    /// Nothing was done, the caller should retry later.
+    Busy,
+    /// The held batch to review does not exist, e.g. it was already reviewed.
+    BatchNotFound,
+    /// The user is muted on the canvas until the given time (milliseconds since the Unix epoch),
+    /// or until unmuted if there is none.
+    Muted(Option<u64>),
+    /// The user is banned from the canvas.
+    Banned(CanvasBan),
+    /// The canvas is in presentation mode and the user is neither its owner nor a presenter.
+    Presenting,
+    /// The content filter rejected a text.
+    ContentFiltered,
+    /// Slow mode is on and the user's last batch was too recent. Carries the remaining wait in milliseconds.
+    SlowMode(u64),
+    Storage(String),
+}
+
+/// Selects the events of a replay (see `CanvasManager::replay_events`).
+/// Bounds are inclusive; timestamps are the `_ts` stamps in milliseconds since the Unix epoch.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EventRange {
+    pub from_seq: Option<u64>,
+    pub to_seq: Option<u64>,
+    pub from_ts: Option<u64>,
+    pub to_ts: Option<u64>,
+    /// Maximum number of events; the rest can be fetched from the returned `next_from_seq`.
+    pub limit: Option<usize>,
+}
+
+impl EventRange {
+    /// Events without a sequence number (or timestamp) only match if no bound on it is set.
+    fn matches(&self, event: &serde_json::Value) -> bool {
+        let in_bounds = |value: Option<u64>, from: Option<u64>, to: Option<u64>| {
+            if from.is_none() && to.is_none() {
+                return true;
+            }
+            value.is_some_and(|value| from.is_none_or(|from| value >= from) && to.is_none_or(|to| value <= to))
+        };
+        let ts = event.get("_ts").and_then(serde_json::Value::as_u64);
+        in_bounds(event_seq(event), self.from_seq, self.to_seq) && in_bounds(ts, self.from_ts, self.to_ts)
+    }
+
+    /// The log is ordered by sequence number, so nothing after an event past `to_seq` can match.
+    fn is_past_end(&self, event: &serde_json::Value) -> bool {
+        self.to_seq.is_some_and(|to_seq| event_seq(event).is_some_and(|seq| seq > to_seq))
+    }
+}
+
+/// Events accepted by `append_events`.
+pub struct AppendedEvents {
+    pub count: usize,
+    /// Resolves once the events are written. `None` for coalesced events,
+    /// which are written with the next batch, and held events.
+    pub persisted: Option<WriteAck>,
+    /// The batch the events are held in for review: on a moderated canvas, writers' events
+    /// are only written once a moderator approves them.
+    pub held: Option<String>,
+}
+
+/// How a moderator deletes events (see `CanvasManager::moderator_delete_events`).
+struct ModeratorDeletion<'a> {
+    pool: &'a SqlitePool,
+    /// Keep the deleted events in a sidecar file.
+    quarantine: bool,
+}
+
+/// A moderator's decision on a batch of held events.
+#[derive(Debug)]
+pub enum HeldReview {
+    Approve,
+    /// Drops the events, optionally telling the author why.
+    Reject(Option<String>),
+}
+
+/// A user muted on a canvas: they can still view it, but their events are rejected.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CanvasMute {
+    /// Milliseconds since the Unix epoch; `None` keeps the user muted until unmuted.
+    pub until: Option<u64>,
+}
+
+impl CanvasMute {
+    fn is_active(&self, now_ms: u64) -> bool {
+        self.until.is_none_or(|until| until > now_ms)
+    }
+}
+
+/// A user banned from a canvas: they can neither subscribe to it nor draw on it until the ban ends.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasBan {
+    /// Milliseconds since the Unix epoch; `None` for a permanent ban.
+    pub expires_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl CanvasBan {
+    fn is_active(&self, now_ms: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now_ms)
+    }
+
+    /// Details of the `banned` error, including the seconds left until the ban ends.
+    pub fn error_details(&self, canvas_uuid: &CanvasId) -> serde_json::Value {
+        let remaining_secs = self
+            .expires_at
+            .map(|expires_at| expires_at.saturating_sub(event_log::timestamp_ms()).div_ceil(1000));
+        serde_json::json!({
+            "canvasId": canvas_uuid,
+            "expiresAt": self.expires_at,
+            "remainingSecs": remaining_secs,
+            "reason": self.reason,
+        })
+    }
+}
+
+/// The moderation state of a canvas kept in the DB, as read when it loads.
+#[derive(Debug)]
+struct CanvasModeration {
+    mutes: HashMap<i64, CanvasMute>,
+    bans: HashMap<i64, CanvasBan>,
+    announcement: Option<Announcement>,
+}
+
+/// An announcement a moderator pinned to a canvas. Sent to every subscriber, including late joiners.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Announcement {
+    pub announcement_id: i64,
+    pub text: String,
+    pub author_id: i64,
+    pub author_name: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// A moderator's change to a user's ban on a canvas.
+#[derive(Debug)]
+pub enum BanChange {
+    /// Bans the user for the given time, or permanently, optionally telling them why.
+    Ban(Option<Duration>, Option<String>),
+    Unban,
+}
+
+/// A moderator's change to a user's mute on a canvas.
+#[derive(Debug)]
+pub enum MuteChange {
+    /// Mutes the user for the given time, or until unmuted.
+    Mute(Option<Duration>),
+    Unmute,
+}
+
+impl From<CanvasRegistrationError> for AppendEventsError {
+    fn from(e: CanvasRegistrationError) -> Self {
+        match e {
+            CanvasRegistrationError::NotFound => AppendEventsError::NotFound,
+            CanvasRegistrationError::DatabaseError(e) => AppendEventsError::Storage(e),
+        }
+    }
+}
+
+/// Returns true if a user with `permission` may act on a user with `target_permission`, e.g. ban them.
+/// Mirrors the hierarchy of permission changes: owners and co-owners reach everyone but the owner,
+/// moderators only users who can't moderate.
+fn outranks(permission: &str, target_permission: &str) -> bool {
+    match permission {
+        "O" | "C" => target_permission != "O",
+        "M" => !can_moderate(target_permission),
+        _ => false,
+    }
+}
+
+/// Returns true if the given permission level allows moderating a canvas.
+fn can_moderate(permission: &str) -> bool {
+    matches!(permission, "M" | "O" | "C")
+}
+
+/// Returns true if the given permission level allows drawing on a canvas.
+/// If the canvas is moderated, "W" (Writer) permission is not enough to draw.
+fn can_draw(permission: &str, is_moderated: bool) -> bool {
+    let can_write = matches!(permission, "W" | "V" | "M" | "O" | "C");
+    (can_write && !is_moderated) || can_moderate(permission)
+}
+
+impl CanvasManager {
+    pub fn new(
+        metrics: Arc<WsMetrics>,
+        store: Arc<dyn EventStore>,
+        permissions: Arc<dyn PermissionSource>,
+        review: ReviewQueue,
+        content_filter: Arc<dyn ContentFilter>,
+        config: CanvasManagerConfig,
+    ) -> Self {
+        Self {
+            review,
+            content_filter,
+            history_max_events: config.history_max_events,
+            held_events_ttl: config.held_events_ttl,
+            event_cache: config.event_cache,
+            store,
+            permissions,
+            idle_ttl: config.idle_ttl,
+            evictions: Arc::new(AtomicU64::new(0)),
+            unflushed_counters: Arc::new(StdMutex::new(HashMap::new())),
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            loading: Arc::new(StdMutex::new(HashMap::new())),
+            metrics,
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            coalesce: config.coalesce,
+        }
+    }
+
+    /// Helper function to find the moderation and coalescing state from the DB.
+    /// This remains the source of truth for loading the initial state.
+    async fn get_canvas_info(
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<CanvasDBInfo, CanvasRegistrationError> {
+        let row = query!(
+            "SELECT moderated, coalesce_events, event_count, last_seq, slow_mode_ms FROM Canvas WHERE canvas_id = ?",
+            canvas_uuid
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => CanvasRegistrationError::NotFound,
+            _ => CanvasRegistrationError::DatabaseError(format!(
+                "DB query failed for canvas {}: {}",
+                canvas_uuid, e
+            )),
+        })?;
+
+        Ok(CanvasDBInfo {
+            is_moderated: row.moderated,
+            coalesce_events: row.coalesce_events,
+            event_count: row.event_count.max(0) as u64,
+            last_seq: row.last_seq.max(0) as u64,
+            slow_mode_ms: row.slow_mode_ms.max(0) as u64,
+        })
+    }
+
+    /// Reads the users currently muted on a canvas from the DB.
+    async fn get_canvas_mutes(
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<HashMap<i64, CanvasMute>, CanvasRegistrationError> {
+        let now_ms = event_log::timestamp_ms() as i64;
+        let rows = query!(
+            "SELECT user_id, until FROM canvas_mutes WHERE canvas_id = ? AND (until IS NULL OR until > ?)",
             canvas_uuid,
-            new_state
-        );
+            now_ms
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            CanvasRegistrationError::DatabaseError(format!("Failed to read mutes of canvas {}: {}", canvas_uuid, e))
+        })?;
 
-        // 3. Update DB
-        let moderated_value = if new_state { 1 } else { 0 };
-        let update_res = query!(
-            "UPDATE Canvas SET moderated = ? WHERE canvas_id = ?",
-            moderated_value,
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.user_id, CanvasMute { until: row.until.map(|until| until.max(0) as u64) }))
+            .collect())
+    }
+
+    /// Reads the active announcement of a canvas from the DB.
+    async fn get_canvas_announcement(
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<Option<Announcement>, CanvasRegistrationError> {
+        let row = query!(
+            r#"SELECT A.announcement_id AS "announcement_id!", A.text, A.author_id, U.display_name AS "author_name?", A.created_at
+               FROM canvas_announcements AS A LEFT JOIN users AS U ON U.user_id = A.author_id
+               WHERE A.canvas_id = ? AND A.active
+               ORDER BY A.announcement_id DESC LIMIT 1"#,
             canvas_uuid
         )
-        .execute(&state.pool)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            CanvasRegistrationError::DatabaseError(format!("Failed to read announcement of canvas {}: {}", canvas_uuid, e))
+        })?;
+
+        Ok(row.map(|row| Announcement {
+            announcement_id: row.announcement_id,
+            text: row.text,
+            author_id: row.author_id,
+            author_name: row.author_name.unwrap_or_default(),
+            created_at: row.created_at.max(0) as u64,
+        }))
+    }
+
+    /// Reads the users currently banned from a canvas from the DB.
+    async fn get_canvas_bans(
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<HashMap<i64, CanvasBan>, CanvasRegistrationError> {
+        let now_ms = event_log::timestamp_ms() as i64;
+        let rows = query!(
+            "SELECT user_id, expires_at, reason FROM canvas_bans
+             WHERE canvas_id = ? AND (expires_at IS NULL OR expires_at > ?)",
+            canvas_uuid,
+            now_ms
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            CanvasRegistrationError::DatabaseError(format!("Failed to read bans of canvas {}: {}", canvas_uuid, e))
+        })?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let ban = CanvasBan { expires_at: row.expires_at.map(|expires_at| expires_at.max(0) as u64), reason: row.reason };
+                (row.user_id, ban)
+            })
+            .collect())
+    }
+
+    /// Makes sure the state of a canvas is in the map, loading it from the DB if needed.
+    /// The sequence numbers continue after the highest one in the event log or the DB.
+    ///
+    /// The DB and the log are read without holding the manager lock, so other canvases
+    /// stay responsive meanwhile. Concurrent loads of the same canvas wait for the first one
+    /// (see `loading`) instead of hitting the DB again, and share its error if it fails.
+    async fn ensure_loaded(&self, pool: &SqlitePool, canvas_uuid: &CanvasId) -> Result<(), CanvasRegistrationError> {
+        if self.inner.read().await.contains_key(canvas_uuid) {
+            return Ok(());
+        }
+
+        let load_lock = self
+            .loading
+            .lock()
+            .unwrap()
+            .entry(*canvas_uuid)
+            .or_default()
+            .clone();
+        let mut load_outcome = load_lock.lock().await;
+
+        // The load we waited for failed
+        if let Some(Err(e)) = &*load_outcome {
+            return Err(e.clone());
+        }
+        // Someone else loaded it while we waited
+        if self.inner.read().await.contains_key(canvas_uuid) {
+            return Ok(());
+        }
+        tracing::info!("Canvas {} not in memory. Fetching info from DB.", canvas_uuid);
+        let evictions = self.evictions.load(Ordering::SeqCst);
+
+        let result = async {
+            let mut db_info = Self::get_canvas_info(pool, canvas_uuid).await?;
+            let moderation = CanvasModeration {
+                mutes: Self::get_canvas_mutes(pool, canvas_uuid).await?,
+                bans: Self::get_canvas_bans(pool, canvas_uuid).await?,
+                announcement: Self::get_canvas_announcement(pool, canvas_uuid).await?,
+            };
+            // Unloaded shortly before, the DB may not have the latest sequence number yet
+            if let Some(unflushed) = self.unflushed_counters.lock().unwrap().get(canvas_uuid) {
+                db_info.last_seq = db_info.last_seq.max(unflushed.last_seq);
+            }
+            let (last_seq, event_count, cache) = match self.scan_log(canvas_uuid).await {
+                Ok(scanned) => {
+                    let max_bytes = self.event_cache.max_bytes;
+                    let mut cache =
+                        EventCache::new(scanned.events, scanned.bytes, max_bytes, scanned.last_seq, self.metrics.clone());
+                    cache.clear_seq = scanned.clear_seq;
+                    (scanned.last_seq, Some(scanned.count), cache)
+                }
+                Err(e) => {
+                    tracing::warn!("Could not read event log of canvas {}: {}", canvas_uuid, e);
+                    (0, None, EventCache::new(None, 0, self.event_cache.max_bytes, 0, self.metrics.clone()))
+                }
+            };
+            let held = self.review.read(canvas_uuid).await.unwrap_or_else(|e| {
+                tracing::warn!("Could not read the held events of canvas {}: {}", canvas_uuid, e);
+                Vec::new()
+            });
+            let mut canvas_state =
+                CanvasState::new(db_info, last_seq, event_count, self.coalesce.enabled_by_default, cache, held, moderation);
+            WsMetrics::inc(&self.metrics.canvas_loads_store);
+            self.start_writer(canvas_uuid, &mut canvas_state);
+            Ok::<_, CanvasRegistrationError>(canvas_state)
+        }
         .await;
 
-        if let Err(e) = update_res {
-            tracing::error!(
-                "Failed to update moderated state for canvas {} in DB: {}",
-                canvas_uuid,
-                e
-            );
-            return;
+        let outcome = match result {
+            Ok(new_state) => self.insert_loaded(pool, canvas_uuid, new_state, evictions).await,
+            Err(e) => Err(e),
+        };
+
+        *load_outcome = Some(outcome.clone());
+        self.loading.lock().unwrap().remove(canvas_uuid);
+        if outcome.is_ok() && self.cache_over_budget() {
+            // In the background, the caller may hold other canvas locks
+            let manager = self.clone();
+            tokio::spawn(async move { manager.enforce_cache_budget().await });
+        }
+        outcome
+    }
+
+    /// Reads the log of a canvas once, for its counters and the sequence number of its last clear.
+    /// The events are kept only while they fit the cache budget, so a log too large to cache
+    /// is never held in memory as a whole.
+    async fn scan_log(&self, canvas_uuid: &CanvasId) -> std::io::Result<ScannedLog> {
+        let log_id = canvas_uuid.to_string();
+        let mut events = self.store.read_since(&log_id, None);
+        let mut scanned = ScannedLog { events: Some(Vec::new()), bytes: 0, count: 0, last_seq: 0, clear_seq: 0 };
+        while let Some(event) = events.try_next().await? {
+            scanned.bytes += encoded_len(std::slice::from_ref(&event));
+            scanned.count += 1;
+            if let Some(seq) = event_seq(&event) {
+                scanned.last_seq = scanned.last_seq.max(seq);
+            }
+            if event_log::is_clear(&event) {
+                scanned.clear_seq = event_seq(&event).unwrap_or(0);
+            }
+            if scanned.bytes > self.event_cache.max_bytes {
+                scanned.events = None;
+            } else if let Some(cached) = scanned.events.as_mut() {
+                cached.push(event);
+            }
         }
+        Ok(scanned)
+    }
 
-        // 4. Broadcast to all subscribers
-        let msg = json!({
-            "canvasId": canvas_uuid,
-            "moderated": new_state
-        });
+    /// Inserts a freshly loaded canvas state. `evictions` is the eviction count from before the load.
+    /// If canvases were evicted since, the canvas is looked up again, so a canvas deleted
+    /// while it was loading is not brought back.
+    async fn insert_loaded(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+        new_state: CanvasState,
+        mut evictions: u64,
+    ) -> Result<(), CanvasRegistrationError> {
+        loop {
+            {
+                let mut inner = self.inner.write().await;
+                if self.evictions.load(Ordering::SeqCst) == evictions {
+                    // First writer wins, a state loaded in the meantime is kept.
+                    // A discarded state's writer task exits with it.
+                    inner
+                        .entry(*canvas_uuid)
+                        .or_insert_with(|| Arc::new(RwLock::new(new_state)));
+                    return Ok(());
+                }
+            }
+            evictions = self.evictions.load(Ordering::SeqCst);
+            Self::get_canvas_info(pool, canvas_uuid).await?;
+        }
+    }
 
-        // Drop lock before broadcasting (avoid holding write lock while sending)
-        drop(map);
+    /// Looks up a loaded canvas. The map lock is released before returning.
+    async fn canvas(&self, canvas_uuid: &CanvasId) -> Option<SharedCanvas> {
+        self.inner.read().await.get(canvas_uuid).cloned()
+    }
 
-        self.broadcast(&canvas_uuid, Message::Text(msg.to_string().into()))
-            .await;
+    /// Read-locks a loaded canvas. Returns `None` if it is not loaded.
+    async fn read_canvas(&self, canvas_uuid: &CanvasId) -> Option<OwnedRwLockReadGuard<CanvasState>> {
+        let canvas_state = self.canvas(canvas_uuid).await?.read_owned().await;
+        (!canvas_state.unloaded).then_some(canvas_state)
+    }
+
+    /// Write-locks a loaded canvas. Returns `None` if it is not loaded.
+    async fn write_canvas(&self, canvas_uuid: &CanvasId) -> Option<OwnedRwLockWriteGuard<CanvasState>> {
+        let canvas_state = self.canvas(canvas_uuid).await?.write_owned().await;
+        (!canvas_state.unloaded).then_some(canvas_state)
+    }
+
+    /// Write-locks a canvas, loading it first if needed.
+    async fn write_loaded(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<OwnedRwLockWriteGuard<CanvasState>, CanvasRegistrationError> {
+        loop {
+            self.ensure_loaded(pool, canvas_uuid).await?;
+            if let Some(canvas_state) = self.write_canvas(canvas_uuid).await {
+                return Ok(canvas_state);
+            }
+            // The last subscriber left between loading and locking, load it again
+        }
+    }
+
+    /// Read-locks a canvas, loading it first if needed.
+    async fn read_loaded(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+    ) -> Result<OwnedRwLockReadGuard<CanvasState>, CanvasRegistrationError> {
+        loop {
+            self.ensure_loaded(pool, canvas_uuid).await?;
+            if let Some(canvas_state) = self.read_canvas(canvas_uuid).await {
+                return Ok(canvas_state);
+            }
+        }
+    }
+
+    /// Spawns the writer task of a canvas.
+    fn start_writer(&self, canvas_uuid: &CanvasId, canvas_state: &mut CanvasState) {
+        let (queue, receiver) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        let task = error_reporting::spawn("canvas_writer", None, Some(canvas_uuid), run_writer(
+            *canvas_uuid,
+            self.store.clone(),
+            receiver,
+            canvas_state.cache.clone(),
+            canvas_state.channel.clone(),
+            canvas_state.event_count.clone(),
+            self.metrics.clone(),
+        ));
+        canvas_state.writer = Some(CanvasWriter { queue, task });
+    }
+
+    /// Stops the writer task of a canvas once it has written everything queued.
+    async fn stop_writer(canvas_state: &mut CanvasState) {
+        if let Some(CanvasWriter { queue, task }) = canvas_state.writer.take() {
+            drop(queue);
+            let _ = task.await;
+        }
+    }
+
+    /// Removes a canvas without subscribers from the manager.
+    /// Coalesced events still waiting for their flush and queued writes are written first.
+    async fn unload(&self, canvas_uuid: &CanvasId, canvas_state: &mut CanvasState) {
+        self.write_pending(canvas_uuid, canvas_state).await;
+        Self::stop_writer(canvas_state).await;
+        if let Err(e) = self.store.sync(&canvas_uuid.to_string()).await {
+            tracing::error!("Failed to upload the event log of canvas {}: {}", canvas_uuid, e);
+        }
+        canvas_state.unloaded = true;
+        let counters = canvas_state.log_counters();
+        if counters != *canvas_state.stored_counters.lock().unwrap() {
+            self.unflushed_counters.lock().unwrap().insert(*canvas_uuid, counters);
+        }
+        self.inner.write().await.remove(canvas_uuid);
+        tracing::info!("Canvas {} removed from manager as it is now empty.", canvas_uuid);
+    }
+
+    /// Removes a canvas from the manager while it may still have subscribers,
+    /// e.g. because it was deleted. Subscribers receive a `canvasEvicted` message;
+    /// coalesced events still waiting are dropped.
+    pub async fn evict(&self, canvas_uuid: &CanvasId, reason: &str) {
+        self.evictions.fetch_add(1, Ordering::SeqCst);
+        let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await else {
+            return;
+        };
+
+        let evicted = ServerMessage::CanvasEvicted {
+            canvas_id: canvas_uuid.to_string(),
+            reason: reason.to_string(),
+        };
+        canvas_state.publish(CanvasBroadcast::new(&evicted, Recipients::All));
+
+        for info in canvas_state.subscribers.values() {
+            self.remove_subscription(info.connection.id, canvas_uuid).await;
+        }
+
+        // The relays end once the channel closes, after delivering the message
+        Self::stop_writer(&mut canvas_state).await;
+        canvas_state.unloaded = true;
+        self.inner.write().await.remove(canvas_uuid);
+        tracing::info!(
+            "Canvas {} evicted ({}) with {} subscribers.",
+            canvas_uuid,
+            reason,
+            canvas_state.subscribers.len()
+        );
+    }
+
+    /// Builds the presence message announcing that a user joined or left a canvas.
+    fn presence_message(canvas_uuid: &CanvasId, update: PresenceUpdate) -> ServerMessage {
+        ServerMessage::Presence {
+            canvas_id: canvas_uuid.to_string(),
+            presence: update,
+        }
+    }
+
+    // Helper function to read history and send moderation state first.
+    // Live messages for the canvas wait in the connection's broadcast receiver
+    // until the history is complete (see `start_relay`).
+    async fn send_canvas_history(
+        &self,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        mut snapshot: HistorySnapshot,
+        your_permission: &str,   
+        history: HistoryOptions,
+    ) {
+        let started = Instant::now();
+
+        // 1. Send moderation state
+        let moderated_msg = ServerMessage::Moderated {
+            canvas_id: canvas_uuid.to_string(),
+            moderated: snapshot.is_moderated,
+            slow_mode_ms: snapshot.slow_mode_ms,
+            changed_by: None,
+        };
+
+        if let Err(e) = connection.send_msg(&moderated_msg).await {
+            tracing::error!("Failed to send moderation state to client {}: {}", connection.id, e);
+        }
+
+        // The active announcement, so late joiners see it too
+        if let Some(announcement) = snapshot.announcement.take() {
+            let announcement_msg = ServerMessage::Announcement {
+                canvas_id: canvas_uuid.to_string(),
+                announcement: Some(announcement),
+            };
+            if let Err(e) = connection.send_msg(&announcement_msg).await {
+                tracing::error!("Failed to send announcement to client {}: {}", connection.id, e);
+            }
+        }
+
+        // A running presentation, so late joiners know who draws
+        if let Some(presenters) = snapshot.presenters.take() {
+            let presentation_msg = ServerMessage::PresentationMode {
+                canvas_id: canvas_uuid.to_string(),
+                enabled: true,
+                presenters,
+            };
+            if let Err(e) = connection.send_msg(&presentation_msg).await {
+                tracing::error!("Failed to send presentation mode to client {}: {}", connection.id, e);
+            }
+        }
+
+        // 2. Send history, with deleted events filtered out if the client asked for it.
+        // Served from the cache when the canvas has one, otherwise streamed from the store.
+        // It starts at the last clear; events written after the snapshot are delivered live.
+        let in_history = history_bounds(snapshot.clear_seq, snapshot.last_seq);
+        let capped = !history.full && self.history_max_events > 0 && snapshot.event_count > self.history_max_events;
+        let compact_history = history.compact;
+        let sent = if capped {
+            self.send_capped_history(connection, canvas_uuid, &snapshot, compact_history).await
+        } else {
+            match snapshot.cached_events.take() {
+                Some(cached) => {
+                    let mut events = Arc::unwrap_or_clone(cached);
+                    events.retain(in_history);
+                    let events = if compact_history { apply_tombstones(events) } else { events };
+                    send_history_chunks(connection, canvas_uuid, stream::iter(events.into_iter().map(Ok))).await
+                }
+                // Tombstones follow the events they delete, so compacting needs the whole log
+                None if compact_history => match self.store.read_all(&canvas_uuid.to_string()).await {
+                    Ok(mut events) => {
+                        events.retain(in_history);
+                        let events = apply_tombstones(events);
+                        send_history_chunks(connection, canvas_uuid, stream::iter(events.into_iter().map(Ok))).await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => {
+                    let log_id = canvas_uuid.to_string();
+                    let events = self
+                        .store
+                        .read_since(&log_id, snapshot.clear_seq.checked_sub(1))
+                        .try_filter(move |event| future::ready(in_history(event)));
+                    send_history_chunks(connection, canvas_uuid, events).await
+                }
+            }
+        };
+        let sent = match sent {
+            // The log is gone, the canvas continues with an empty one
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to read history of canvas {}: {}", canvas_uuid, e);
+                self.recover_missing_log(canvas_uuid).await;
+                send_history_chunks(connection, canvas_uuid, stream::empty()).await
+            }
+            sent => sent,
+        };
+        if let Err(e) = sent {
+            tracing::error!("Failed to read history of canvas {}: {}", canvas_uuid, e);
+            connection
+                .notify_client("Failed to load canvas history. Try refreshing.")
+                .await;
+        }
+
+        // Owners and co-owners learn about lines of the log that could not be read
+        let corrupt = self.store.corrupt_entries(&canvas_uuid.to_string());
+        if corrupt > 0 && matches!(your_permission, "O" | "C") {
+            connection
+                .notify_client(&format!(
+                    "{} corrupt entries of this canvas' history were skipped. An administrator can verify the log.",
+                    corrupt
+                ))
+                .await;
+        }
+
+        // 3. Send permission
+        let permission_msg = ServerMessage::Permission {
+            canvas_id: canvas_uuid.to_string(),
+            your_permission: your_permission.to_string(),
+        };
+
+        if let Err(e) = connection.send_msg(&permission_msg).await {
+            tracing::error!(
+                "Failed to send permission to client {}: {}",
+                connection.id,
+                e
+            );
+        }
+
+        // 4. Send the users currently present on the canvas
+        let presence_msg = Self::presence_message(canvas_uuid, PresenceUpdate::Online(snapshot.online_users));
+
+        if let Err(e) = connection.send_msg(&presence_msg).await {
+            tracing::error!(
+                "Failed to send presence list to client {}: {}",
+                connection.id,
+                e
+            );
+        }
+
+        // 5. Send the most recent chat messages
+        match read_recent_chat(canvas_uuid, CHAT_HISTORY_LIMIT).await {
+            Ok(chat_history) => {
+                let chat_msg = ServerMessage::ChatHistory {
+                    canvas_id: canvas_uuid.to_string(),
+                    chat_history,
+                };
+
+                if let Err(e) = connection.send_msg(&chat_msg).await {
+                    tracing::error!("Failed to send chat history to client {}: {}", connection.id, e);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to read chat history for canvas {}: {}", canvas_uuid, e);
+            }
+        }
+
+        // 6. Mark the end of the registration state, live messages follow
+        let complete_msg = ServerMessage::HistoryComplete {
+            canvas_id: canvas_uuid.to_string(),
+        };
+
+        if let Err(e) = connection.send_msg(&complete_msg).await {
+            tracing::error!("Failed to send history complete to client {}: {}", connection.id, e);
+        }
+        self.metrics.latency.history_duration.observe_duration(canvas_uuid, started.elapsed());
+    }
+
+
+
+
+
+
+    /// Sends the history of a canvas whose log exceeds the history cap: a compacted prefix of the log,
+    /// announced with a `historySnapshot` message, followed by the events after it.
+    /// The prefix is kept on the canvas and reused until the events after it outgrow half the cap.
+    async fn send_capped_history(
+        &self,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        snapshot: &HistorySnapshot,
+        compact_history: bool,
+    ) -> std::io::Result<()> {
+        let last_seq = snapshot.last_seq;
+        let prefix = match &snapshot.compacted_prefix {
+            // A prefix built before the last clear would bring back cleared events
+            Some(prefix)
+                if snapshot.event_count.saturating_sub(prefix.log_events) <= self.history_max_events / 2
+                    && snapshot.clear_seq <= prefix.seq =>
+            {
+                prefix.clone()
+            }
+            _ => {
+                let mut events = match &snapshot.cached_events {
+                    Some(cached) => cached.as_ref().clone(),
+                    None => self.store.read_all(&canvas_uuid.to_string()).await?,
+                };
+                events.retain(history_bounds(snapshot.clear_seq, last_seq));
+                let prefix = Arc::new(CompactedPrefix {
+                    seq: last_seq,
+                    log_events: snapshot.event_count,
+                    events: apply_tombstones(events),
+                });
+                tracing::info!(
+                    "Built a history snapshot of canvas {} up to sequence {} with {} events.",
+                    canvas_uuid,
+                    prefix.seq,
+                    prefix.events.len()
+                );
+                if let Some(canvas_state) = self.read_canvas(canvas_uuid).await
+                    && canvas_state.log_generation.load(Ordering::SeqCst) == snapshot.log_generation
+                {
+                    *canvas_state.compacted_prefix.lock().unwrap() = Some(prefix.clone());
+                }
+                prefix
+            }
+        };
+
+        let snapshot_msg = ServerMessage::HistorySnapshot {
+            canvas_id: canvas_uuid.to_string(),
+            from_seq: prefix.seq,
+        };
+        if let Err(e) = connection.send_msg(&snapshot_msg).await {
+            tracing::error!("Failed to send history snapshot to client {}: {}", connection.id, e);
+        }
+
+        // Tombstones after the prefix may still delete events in it
+        let prefix_seq = prefix.seq;
+        let after_prefix =
+            move |event: &serde_json::Value| event_seq(event).is_some_and(|seq| seq > prefix_seq && seq <= last_seq);
+        let log_id = canvas_uuid.to_string();
+        let tail = match &snapshot.cached_events {
+            Some(cached) => {
+                let tail: Vec<serde_json::Value> = cached.iter().filter(|event| after_prefix(event)).cloned().collect();
+                stream::iter(tail.into_iter().map(Ok)).boxed()
+            }
+            None => self
+                .store
+                .read_since(&log_id, Some(prefix_seq))
+                .try_filter(move |event| future::ready(after_prefix(event)))
+                .boxed(),
+        };
+        let events = stream::iter(prefix.events.clone().into_iter().map(Ok)).chain(tail);
+
+        if compact_history {
+            let events = apply_tombstones(events.try_collect().await?);
+            send_history_chunks(connection, canvas_uuid, stream::iter(events.into_iter().map(Ok))).await
+        } else {
+            send_history_chunks(connection, canvas_uuid, events).await
+        }
+    }
+
+    /// Registers a connection to a canvas.
+    /// Returns an error only if there's a problem internal to the manager (e.g., lock poisoning).
+    /// Sends a notification to the client if the canvas is not found in the DB.
+    ///
+    /// Registering a connection that is already subscribed is a no-op,
+    /// unless `resend_history` is set, in which case the history is sent again.
+    /// `history` selects how the history is sent (see `HistoryOptions`).
+    pub async fn register(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: CanvasId,
+        user_id: i64,
+        connection: IdentifiableWebSocket,
+        resend_history: bool,
+        history: HistoryOptions,
+    ) {
+        let connection_clone = connection.clone(); // Clone for error path and final insertion
+
+        // === Check permissions before anything else ===
+        let perm = self.permissions.permission_level(user_id, &canvas_uuid).await;
+
+        if perm.is_empty() {
+            connection_clone
+                .notify_client("You do not have permission to access this canvas.")
+                .await;
+            tracing::warn!(
+                "User {} tried to register to canvas {} without permission",
+                user_id,
+                canvas_uuid
+            );
+            return;
+        }
+
+        let display_name = self.permissions.display_name(user_id).await.unwrap_or_default();
+
+        let connection_info = ConnectionInfo {
+            user_id,
+            display_name,
+            connection,
+            read_only: false,
+        };
+        self.subscribe(pool, canvas_uuid, connection_info, &perm, resend_history, history)
+            .await;
+    }
+
+    /// Registers a read-only viewer to a canvas, e.g. an SSE stream.
+    /// The viewer receives the history and live messages like any connection,
+    /// but is not announced to the other users. `permission` comes from the viewer's claims.
+    pub async fn register_viewer(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: CanvasId,
+        user_id: i64,
+        display_name: String,
+        permission: &str,
+        connection: IdentifiableWebSocket,
+    ) {
+        let connection_info = ConnectionInfo {
+            user_id,
+            display_name,
+            connection,
+            read_only: true,
+        };
+        self.subscribe(pool, canvas_uuid, connection_info, permission, false, HistoryOptions::default()).await;
+    }
+
+    /// Subscribes a connection to a canvas once its permission has been checked,
+    /// then sends it the history and starts relaying live messages.
+    async fn subscribe(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: CanvasId,
+        connection_info: ConnectionInfo,
+        perm: &str,
+        resend_history: bool,
+        history: HistoryOptions,
+    ) {
+        let connection = connection_info.connection.clone();
+        let user_id = connection_info.user_id;
+        // Counts as a cache hit below, e.g. a rejoin while the canvas was idle
+        let was_loaded = self.canvas(&canvas_uuid).await.is_some();
+
+        // Acquire the canvas write lock, with the canvas state loaded
+        let mut canvas_state = match self.write_loaded(pool, &canvas_uuid).await {
+            Ok(canvas_state) => canvas_state,
+            Err(CanvasRegistrationError::NotFound) => {
+                connection
+                    .notify_client(&format!(
+                        "Canvas ID '{}' is invalid or does not exist.",
+                        canvas_uuid
+                    ))
+                    .await;
+                tracing::error!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid);
+                return;
+            }
+            Err(_) => {
+                connection
+                    .notify_client("A database error occurred. Cannot subscribe to canvas.")
+                    .await;
+                tracing::error!("A database error occurred. Cannot subscribe to canvas.");
+                return;
+            }
+        };
+
+        if let Some(ban) = canvas_state.active_ban(user_id) {
+            let details = ban.error_details(&canvas_uuid);
+            drop(canvas_state);
+            tracing::warn!("Banned user {} tried to subscribe to canvas {}", user_id, canvas_uuid);
+            connection.send_error("banned", "You are banned from this canvas.", Some(details)).await;
+            return;
+        }
+
+        if canvas_state.subscribers.contains_key(&connection.id) {
+            tracing::debug!(
+                "Connection {} is already subscribed to canvas {}. Resend history: {}",
+                connection.id,
+                canvas_uuid,
+                resend_history
+            );
+            if resend_history {
+                // Live messages now follow the new history, so stop relaying the old receiver
+                if let Some(relay) = canvas_state.relays.remove(&connection.id) {
+                    relay.abort();
+                }
+                let (snapshot, receiver) = canvas_state.history_snapshot();
+                let after_seq = snapshot.last_seq;
+                let connection_info = canvas_state
+                    .subscribers
+                    .get(&connection.id)
+                    .cloned()
+                    .expect("Subscriber must exist after check.");
+                drop(canvas_state);
+
+                self.send_canvas_history(&connection, &canvas_uuid, snapshot, perm, history).await;
+                self.start_relay(&canvas_uuid, connection_info, receiver, after_seq).await;
+            }
+            return;
+        }
+
+        // Presence is per user: only the user's first connection counts as a join.
+        let is_new_user = !connection_info.read_only && !canvas_state.has_user(user_id);
+
+        canvas_state.add_subscriber(connection_info.clone());
+        canvas_state.last_empty_at = None;
+        self.add_subscription(connection.id, &canvas_uuid).await;
+        if was_loaded {
+            WsMetrics::inc(&self.metrics.canvas_loads_memory);
+        }
+
+        tracing::info!(
+            "User {} subscribed to canvas {} (conn_id: {}). Total subscribers: {}. Moderated: {}",
+            user_id,
+            canvas_uuid,
+            connection_info.connection.id,
+            canvas_state.subscribers.len(),
+            canvas_state.is_moderated,
+        );
+
+        // Messages published from here on are after the history snapshot.
+        // They wait in the receiver until the history has been sent.
+        let (snapshot, receiver) = canvas_state.history_snapshot();
+        let after_seq = snapshot.last_seq;
+        let held_batches = if can_moderate(perm) { canvas_state.review.lock().await.len() } else { 0 };
+
+        // Announce the new user to everyone else on the canvas
+        if is_new_user {
+            let joined_msg = Self::presence_message(
+                &canvas_uuid,
+                PresenceUpdate::Joined(PresenceEntry { user_id, display_name: connection_info.display_name.clone() }),
+            );
+            canvas_state.send_to_other_users(user_id, &joined_msg);
+        }
+
+        // The history is read without holding the canvas lock
+        drop(canvas_state);
+
+        // Send moderation, history, permissions, and presence to the client
+        self.send_canvas_history(
+            &connection_info.connection,
+            &canvas_uuid,
+            snapshot,
+            perm,
+            history,
+        )
+        .await;
+
+        // Moderators learn about events still waiting for review
+        if held_batches > 0 {
+            let message = ServerMessage::PendingEvents { canvas_id: canvas_uuid.to_string(), count: held_batches };
+            if let Err(e) = connection.send_msg(&message).await {
+                tracing::error!("Failed to send held event count to client {}: {}", connection.id, e);
+            }
+        }
+
+        self.start_relay(&canvas_uuid, connection_info, receiver, after_seq).await;
+    }
+
+    /// Starts forwarding a canvas' broadcast channel to a subscribed connection,
+    /// whose history ended at sequence number `after_seq`.
+    /// Does nothing if the connection unsubscribed while its history was being sent.
+    async fn start_relay(
+        &self,
+        canvas_uuid: &CanvasId,
+        connection_info: ConnectionInfo,
+        receiver: broadcast::Receiver<Arc<CanvasBroadcast>>,
+        after_seq: u64,
+    ) {
+        let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await else {
+            return;
+        };
+        if !canvas_state.subscribers.contains_key(&connection_info.connection.id) {
+            return;
+        }
+
+        let conn_id = connection_info.connection.id;
+        let user_id = connection_info.user_id;
+        let relay = error_reporting::spawn(
+            "canvas_relay",
+            Some(user_id),
+            Some(canvas_uuid),
+            self.clone().relay(*canvas_uuid, connection_info, receiver, after_seq),
+        );
+        if let Some(old_relay) = canvas_state.relays.insert(conn_id, relay) {
+            old_relay.abort();
+        }
+    }
+
+    /// Forwards the messages of a canvas' broadcast channel to one connection.
+    /// Delivery never waits on the connection (see `IdentifiableWebSocket::try_deliver`).
+    ///
+    /// Only events with a sequence number above `after_seq` are forwarded, the history covered the others.
+    /// A connection that fell too far behind the channel is asked to resync.
+    /// A connection that turned out to be gone is evicted from the canvas and the claims manager.
+    async fn relay(
+        self,
+        canvas_uuid: CanvasId,
+        info: ConnectionInfo,
+        mut receiver: broadcast::Receiver<Arc<CanvasBroadcast>>,
+        after_seq: u64,
+    ) {
+        loop {
+            let published = match receiver.recv().await {
+                Ok(published) => published,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(
+                        "Connection {} missed {} messages on canvas {}. Asking it to resync.",
+                        info.connection.id,
+                        missed,
+                        canvas_uuid
+                    );
+                    WsMetrics::add(&self.metrics.broadcast_failures, missed);
+                    let resync = ServerMessage::Resync { canvas_id: canvas_uuid.to_string() };
+                    info.connection.try_deliver(&canvas_uuid, resync.to_ws_message());
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            // The snapshot is taken under the cache lock, so this only holds off events
+            // if the writer ever published out of order
+            if published.last_seq != 0 && published.last_seq <= after_seq {
+                continue;
+            }
+            let Some(message) = published.message_for(&info) else {
+                continue;
+            };
+
+            match info.connection.try_deliver(&canvas_uuid, message.clone()) {
+                Delivery::Delivered => {
+                    WsMetrics::inc(&self.metrics.broadcast_messages);
+                    self.metrics
+                        .latency
+                        .broadcast_duration
+                        .observe_duration(&canvas_uuid, published.published_at.elapsed());
+                }
+                Delivery::Dropped => {
+                    WsMetrics::inc(&self.metrics.broadcast_failures);
+                    tracing::debug!("Dropped broadcast for slow conn {}", info.connection.id);
+                }
+                Delivery::Closed => {
+                    WsMetrics::inc(&self.metrics.broadcast_failures);
+                    // Evicting aborts this relay, so it runs as a task of its own
+                    tokio::spawn(self.clone().evict_connection(canvas_uuid, info));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Records that a connection subscribed to a canvas.
+    async fn add_subscription(&self, conn_id: Uuid, canvas_uuid: &CanvasId) {
+        self.subscriptions.write().await.entry(conn_id).or_default().insert(*canvas_uuid);
+    }
+
+    /// Records that a connection unsubscribed from a canvas.
+    async fn remove_subscription(&self, conn_id: Uuid, canvas_uuid: &CanvasId) {
+        let mut subscriptions = self.subscriptions.write().await;
+        if let Some(canvases) = subscriptions.get_mut(&conn_id) {
+            canvases.remove(canvas_uuid);
+            if canvases.is_empty() {
+                subscriptions.remove(&conn_id);
+            }
+        }
+    }
+
+    /// Returns the canvases a connection is subscribed to.
+    pub async fn subscriptions(&self, conn_id: Uuid) -> HashSet<CanvasId> {
+        self.subscriptions.read().await.get(&conn_id).cloned().unwrap_or_default()
+    }
+
+    /// Forgets all subscriptions of a connection and returns them.
+    pub async fn take_subscriptions(&self, conn_id: Uuid) -> HashSet<CanvasId> {
+        self.subscriptions.write().await.remove(&conn_id).unwrap_or_default()
+    }
+
+    /// Evicts a connection whose channel was found closed, from all its canvases and the permission source,
+    /// so later broadcasts don't keep trying to reach it.
+    async fn evict_connection(self, canvas_uuid: CanvasId, info: ConnectionInfo) {
+        tracing::info!("Removing dead connection {} from canvas {}", info.connection.id, canvas_uuid);
+        // A dead connection is gone from every canvas, not just this one
+        let mut canvases = self.take_subscriptions(info.connection.id).await;
+        canvases.insert(canvas_uuid);
+        self.permissions.remove_connection(info.user_id, &info.connection).await;
+        for canvas_uuid in canvases {
+            self.unregister_connection(&canvas_uuid, &info.connection.id).await;
+        }
+    }
+
+
+
+    /// Lists the distinct users currently subscribed to a canvas, joined with their
+    /// permission level from the permission source.
+    /// Returns an empty list if the canvas is not loaded in memory.
+    pub async fn online_users(
+        &self,
+        canvas_uuid: &CanvasId,
+        include_connection_counts: bool,
+    ) -> Vec<OnlineUser> {
+        // Collect display names and connection counts per user
+        let users: HashMap<i64, (String, usize)> = {
+            let Some(canvas_state) = self.read_canvas(canvas_uuid).await else {
+                return Vec::new();
+            };
+
+            let mut users = HashMap::new();
+            for info in canvas_state.subscribers.values().filter(|info| !info.read_only) {
+                let entry = users
+                    .entry(info.user_id)
+                    .or_insert_with(|| (info.display_name.clone(), 0));
+                entry.1 += 1;
+            }
+            users
+        };
+
+        let mut online_users = Vec::with_capacity(users.len());
+        for (user_id, (display_name, count)) in users {
+            let permission_level = self.permissions.permission_level(user_id, canvas_uuid).await;
+
+            online_users.push(OnlineUser {
+                user_id,
+                display_name,
+                permission_level,
+                connection_count: include_connection_counts.then_some(count),
+            });
+        }
+        online_users.sort_by_key(|user| user.user_id);
+        online_users
+    }
+
+    /// Sends the list of online users of a canvas to the requesting connection only.
+    pub async fn send_online_users(
+        &self,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        connection: &IdentifiableWebSocket,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+
+        if permission.is_empty() {
+            tracing::warn!(
+                "User {} requested online users of canvas {} without permission",
+                user_id,
+                canvas_uuid
+            );
+            connection
+                .notify_client("You do not have permission to access this canvas.")
+                .await;
+            return;
+        }
+
+        let include_connection_counts = matches!(permission.as_str(), "M" | "O" | "C");
+        let online_users = self
+            .online_users(canvas_uuid, include_connection_counts)
+            .await;
+
+        let msg = ServerMessage::OnlineUsers {
+            canvas_id: canvas_uuid.to_string(),
+            online_users,
+        };
+
+        if let Err(e) = connection.send_msg(&msg).await {
+            tracing::error!("Failed to send online users to client {}: {}", connection.id, e);
+        }
+    }
+
+    /// Unregisters a specific connection from a canvas.
+    pub async fn unregister_connection(
+        &self,
+        canvas_uuid: &CanvasId,
+        conn_id: &Uuid,
+    ) -> bool {
+        self.unregister_connections(canvas_uuid, &HashSet::from([*conn_id])).await > 0
+    }
+
+    /// Unregisters a set of connections from a canvas under a single lock.
+    /// Returns the number of connections that were removed.
+    pub async fn unregister_connections(
+        &self,
+        canvas_uuid: &CanvasId,
+        conn_ids: &HashSet<Uuid>,
+    ) -> usize {
+        if let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await {
+            let removed = canvas_state.remove_subscribers(conn_ids);
+            
+            let mut announced = HashSet::new();
+            for info in removed.iter() {
+                self.remove_subscription(info.connection.id, canvas_uuid).await;
+                tracing::info!(
+                    "Connection {} unsubscribed from canvas {}. Remaining subscribers: {}",
+                    info.connection.id,
+                    canvas_uuid,
+                    canvas_state.subscribers.len()
+                );
+
+                // Only announce the departure once the user's last connection is gone
+                if !info.read_only && !canvas_state.has_user(info.user_id) && announced.insert(info.user_id) {
+                    let left_msg = Self::presence_message(
+                        canvas_uuid,
+                        PresenceUpdate::Left(PresenceEntry {
+                            user_id: info.user_id,
+                            display_name: info.display_name.clone(),
+                        }),
+                    );
+                    canvas_state.send_to_other_users(info.user_id, &left_msg);
+                }
+            }
+            
+            // Cleanup: If no more subscribers, the canvas goes idle
+            self.release_if_empty(canvas_uuid, &mut canvas_state).await;
+            removed.len()
+        } else {
+            tracing::warn!("Attempted to unregister from a non-existent canvas: {}", canvas_uuid);
+            0
+        }
+    }
+
+    /// Unregisters all connections for a given user from a canvas.
+    /// Returns the connections that were removed.
+    pub async fn unregister_user(
+        &self,
+        canvas_uuid: &CanvasId,
+        user_id: i64,
+    ) -> Vec<IdentifiableWebSocket> {
+        if let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await {
+            let conn_ids = canvas_state.subscribers_by_user.get(&user_id).cloned().unwrap_or_default();
+            let removed = canvas_state.remove_subscribers(&conn_ids);
+            for info in removed.iter() {
+                self.remove_subscription(info.connection.id, canvas_uuid).await;
+            }
+            
+            if !removed.is_empty() {
+                tracing::info!(
+                    "User {} unsubscribed all connections from canvas {}. Remaining subscribers: {}",
+                    user_id,
+                    canvas_uuid,
+                    canvas_state.subscribers.len()
+                );
+            }
+
+            if let Some(info) = removed.iter().find(|info| !info.read_only) {
+                let left_msg = Self::presence_message(
+                    canvas_uuid,
+                    PresenceUpdate::Left(PresenceEntry { user_id, display_name: info.display_name.clone() }),
+                );
+                canvas_state.send_to_other_users(user_id, &left_msg);
+            }
+            
+            self.release_if_empty(canvas_uuid, &mut canvas_state).await;
+            removed.into_iter().map(|info| info.connection).collect()
+        } else {
+            tracing::warn!("Attempted to unregister a user from a non-existent canvas: {}", canvas_uuid);
+            Vec::new()
+        }
+    }
+
+
+
+    /// Handles an incoming event message from a WebSocket client.
+    /// The events are appended through `append_events` and broadcast to everyone except the sender connection.
+    /// Only queues the events; the canvas' writer task writes and broadcasts them.
+    pub async fn handle_event(
+        &self,
+        state: &AppState,
+        sender_id: i64,
+        sender_connection: &IdentifiableWebSocket,
+        events: WebSocketEvents,
+    ) {
+        let canvas_uuid = &events.canvas_id;
+        let permission = self.permissions.permission_level(sender_id, canvas_uuid).await;
+
+        let result = self
+            .append_events(
+                &state.pool,
+                &permission,
+                sender_id,
+                canvas_uuid,
+                events.events_for_canvas,
+                Some(sender_connection.id),
+            )
+            .await;
+
+        match result {
+            Ok(AppendedEvents { count, held: Some(batch_id), .. }) => {
+                let held = ServerMessage::EventsHeld { canvas_id: canvas_uuid.to_string(), batch_id, count };
+                if let Err(e) = sender_connection.send_msg(&held).await {
+                    tracing::error!("Failed to tell client {} about held events: {}", sender_connection.id, e);
+                }
+            }
+            // Written by the writer task, the connection goes on with its next message meanwhile
+            Ok(AppendedEvents { count, persisted: Some(persisted), .. }) => {
+                report_persisted(persisted, canvas_uuid, vec![(sender_connection.clone(), count)]);
+            }
+            // Coalesced, see `write_pending`
+            Ok(AppendedEvents { persisted: None, .. }) => {}
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                sender_connection.send_error("invalid_payload", &reason, None).await;
+            }
+            Err(AppendEventsError::InvalidEvents(invalid)) => {
+                sender_connection
+                    .send_error(
+                        "invalid_events",
+                        "Some events are invalid. None of the events were saved.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "invalidEvents": invalid })),
+                    )
+                    .await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                sender_connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(AppendEventsError::QueueFull) => {
+                sender_connection
+                    .send_error(
+                        "write_queue_full",
+                        "The canvas is busy. None of the events were saved, try again shortly.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid })),
+                    )
+                    .await;
+            }
+            Err(AppendEventsError::Storage(_)) => {
+                sender_connection
+                    .send_error(
+                        "persist_failed",
+                        PERSIST_FAILED_MESSAGE,
+                        Some(serde_json::json!({ "canvasId": canvas_uuid })),
+                    )
+                    .await;
+            }
+            Err(AppendEventsError::Banned(ban)) => {
+                sender_connection
+                    .send_error("banned", "You are banned from this canvas.", Some(ban.error_details(canvas_uuid)))
+                    .await;
+            }
+            Err(AppendEventsError::Muted(until)) => {
+                sender_connection
+                    .send_error(
+                        "muted",
+                        "You are muted on this canvas. None of the events were saved.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "until": until })),
+                    )
+                    .await;
+            }
+            Err(AppendEventsError::SlowMode(retry_after_ms)) => {
+                sender_connection
+                    .send_error(
+                        "slow_mode",
+                        "Slow mode is on. None of the events were saved, wait before sending more.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "retryAfterMs": retry_after_ms })),
+                    )
+                    .await;
+            }
+            Err(AppendEventsError::Presenting) => {
+                sender_connection
+                    .send_error(
+                        "presentation_mode",
+                        "Only the presenters can draw while the canvas is in presentation mode.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid })),
+                    )
+                    .await;
+            }
+            // Already logged by `append_events`; appends are never `Busy`, `BatchNotFound` or `ContentFiltered`
+            Err(
+                AppendEventsError::Forbidden
+                | AppendEventsError::Busy
+                | AppendEventsError::BatchNotFound
+                | AppendEventsError::ContentFiltered,
+            ) => {}
+        }
+    }
+
+    /// Appends events to a canvas and broadcasts them to its live subscribers.
+    /// Used by every transport, so all of them go through the same permission checks.
+    ///
+    /// `permission` is the user's permission level on the canvas. Events sent over a
+    /// WebSocket pass their connection as `origin`, which is excluded from the broadcast.
+    /// Canvases that are not loaded are loaded for the write and unloaded again if nobody is subscribed.
+    /// The events are queued for the canvas' writer task; callers that need to know when they are
+    /// written wait on `AppendedEvents::persisted`.
+    pub async fn append_events(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        events: serde_json::Value,
+        origin: Option<Uuid>,
+    ) -> Result<AppendedEvents, AppendEventsError> {
+        // 1. Permission Check (the moderation state is checked once the canvas is loaded)
+        if permission.is_empty() {
+            tracing::warn!("User {} tried to draw on canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        // 2. Extract events_for_canvas
+        let mut events_to_write = match events {
+            serde_json::Value::Array(arr) => arr,
+            _ => {
+                tracing::error!("eventsForCanvas field is not an array.");
+                return Err(AppendEventsError::InvalidPayload("eventsForCanvas must be an array.".to_string()));
+            }
+        };
+
+        // Tombstones are only written by the server (see `delete_events`)
+        if events_to_write.iter().any(is_tombstone) {
+            tracing::warn!("User {} sent a tombstone event on canvas {}. Dropping events.", user_id, canvas_uuid);
+            return Err(AppendEventsError::InvalidPayload("Use deleteEvents to delete events.".to_string()));
+        }
+
+        // A single invalid event rejects the whole message
+        if let Err(invalid) = validate_events(&events_to_write) {
+            tracing::warn!("User {} sent {} invalid events on canvas {}. Dropping events.", user_id, invalid.len(), canvas_uuid);
+            return Err(AppendEventsError::InvalidEvents(invalid));
+        }
+
+        // Stamp each event with its author and the server time (epoch millis).
+        // The sequence number is added when the events are written.
+        let timestamp_ms = event_log::timestamp_ms();
+        for event in events_to_write.iter_mut() {
+            if let Some(object) = event.as_object_mut() {
+                object.insert("_uid".to_string(), user_id.into());
+                object.insert("_ts".to_string(), timestamp_ms.into());
+            }
+        }
+        let count = events_to_write.len();
+        self.metrics.latency.events_per_message.observe(canvas_uuid, count as u64);
+
+        let canvas_state = self.read_loaded(pool, canvas_uuid).await?;
+
+        if let Some(ban) = canvas_state.active_ban(user_id) {
+            tracing::warn!("Rejected {} events of user {} banned from canvas {}", count, user_id, canvas_uuid);
+            return Err(AppendEventsError::Banned(ban.clone()));
+        }
+        if let Some(mute) = canvas_state.active_mute(user_id) {
+            tracing::info!("Rejected {} events of muted user {} on canvas {}", count, user_id, canvas_uuid);
+            return Err(AppendEventsError::Muted(mute.until));
+        }
+        // Presentation mode comes before moderation: other users' events are rejected, not held
+        if let Some(presenters) = &canvas_state.presenters
+            && permission != "O"
+            && !presenters.contains(&user_id)
+        {
+            tracing::info!("Rejected {} events of user {} on canvas {} in presentation mode", count, user_id, canvas_uuid);
+            return Err(AppendEventsError::Presenting);
+        }
+
+        // Writers' events wait for a moderator while the canvas is moderated
+        let hold = canvas_state.is_moderated && permission == "W";
+        if !hold && !can_draw(permission, canvas_state.is_moderated) {
+            tracing::warn!(
+                "User {} denied drawing permission on canvas {}, their permission level is {}",
+                user_id,
+                canvas_uuid,
+                permission
+            );
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        if let Err(remaining_ms) = canvas_state.take_batch_slot(user_id, permission) {
+            tracing::debug!("Rejected {} events of user {} on canvas {} in slow mode", count, user_id, canvas_uuid);
+            return Err(AppendEventsError::SlowMode(remaining_ms));
+        }
+
+        if hold {
+            let held = self.hold_events(canvas_uuid, &canvas_state, user_id, events_to_write, origin).await;
+            let unsubscribed = canvas_state.subscribers.is_empty();
+            drop(canvas_state);
+            if unsubscribed {
+                self.release_if_unsubscribed(canvas_uuid).await;
+            }
+            return held.map(|batch_id| AppendedEvents { count, persisted: None, held: Some(batch_id) });
+        }
+
+        // In coalescing mode the events are written and broadcast with the next batch.
+        // Events without a WebSocket origin are attributed to a nil connection, which no subscriber has.
+        // Without subscribers there is nothing to batch broadcasts for, so the events are written right away.
+        if canvas_state.coalesce && !canvas_state.subscribers.is_empty() {
+            if canvas_state.queue_events(events_to_write, origin.unwrap_or_default()) {
+                self.schedule_flush(*canvas_uuid);
+            }
+            return Ok(AppendedEvents { count, persisted: None, held: None });
+        }
+
+        // 3. Queue the events for the writer task, which writes them and broadcasts them
+        // to everyone but the origin connection. The same stamped events are broadcast,
+        // so every client sees what was persisted.
+        let lock_guard = canvas_state.file_mutex.lock().await;
+        let recipients = origin.map_or(Recipients::All, Recipients::AllExceptConnection);
+        let persisted = canvas_state
+            .queue_write(
+                events_to_write,
+                |events| {
+                    let message = ServerMessage::Events {
+                        canvas_id: canvas_uuid.to_string(),
+                        events_for_canvas: events.to_vec(),
+                    };
+                    CanvasBroadcast::new(&message, recipients)
+                },
+                false,
+            )
+            .await;
+        drop(lock_guard);
+        let persisted = persisted.inspect_err(|e| {
+            tracing::warn!("Could not queue events of user {} on canvas {}: {:?}", user_id, canvas_uuid, e);
+        })?;
+
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        Ok(AppendedEvents { count, persisted: Some(persisted), held: None })
+    }
+
+    /// Holds events of a writer on a moderated canvas for review and tells the moderators.
+    /// Returns the id of the held batch.
+    async fn hold_events(
+        &self,
+        canvas_uuid: &CanvasId,
+        canvas_state: &CanvasState,
+        user_id: i64,
+        events: Vec<serde_json::Value>,
+        origin: Option<Uuid>,
+    ) -> Result<String, AppendEventsError> {
+        let count = events.len();
+        let batch = HeldBatch {
+            batch_id: Uuid::new_v4().to_string(),
+            user_id,
+            held_at: event_log::timestamp_ms(),
+            events,
+            origin,
+        };
+
+        let mut review = canvas_state.review.lock().await;
+        self.review.append(canvas_uuid, &batch).await.map_err(|e| {
+            tracing::error!("Failed to hold events of user {} on canvas {}: {}", user_id, canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+        let batch_id = batch.batch_id.clone();
+        review.push(batch);
+        let held_batches = review.len();
+        drop(review);
+
+        tracing::info!("Held {} events of user {} on canvas {} for review (batch {})", count, user_id, canvas_uuid, batch_id);
+        self.publish_held_count(canvas_uuid, canvas_state, held_batches).await;
+        Ok(batch_id)
+    }
+
+    /// Tells the moderators subscribed to a canvas how many batches are held for review.
+    async fn publish_held_count(&self, canvas_uuid: &CanvasId, canvas_state: &CanvasState, held_batches: usize) {
+        let message = ServerMessage::PendingEvents { canvas_id: canvas_uuid.to_string(), count: held_batches };
+        let connections = self.moderator_connections(canvas_uuid, canvas_state).await;
+        canvas_state.publish(CanvasBroadcast::to_connections(&message, connections));
+    }
+
+    /// The connections of the subscribed moderators, owners and co-owners of a canvas.
+    async fn moderator_connections(&self, canvas_uuid: &CanvasId, canvas_state: &CanvasState) -> Vec<Uuid> {
+        let users: HashSet<i64> = canvas_state
+            .subscribers
+            .values()
+            .filter(|info| !info.read_only)
+            .map(|info| info.user_id)
+            .collect();
+        let mut moderators = HashSet::new();
+        for user_id in users {
+            if can_moderate(&self.permissions.permission_level(user_id, canvas_uuid).await) {
+                moderators.insert(user_id);
+            }
+        }
+
+        canvas_state
+            .subscribers
+            .values()
+            .filter(|info| moderators.contains(&info.user_id))
+            .map(|info| info.connection.id)
+            .collect()
+    }
+
+    /// Files a report of a whole canvas or, with `seqs`, of some of its events. Anyone with access
+    /// to the canvas can report; repeating a report of the same target only updates its reason.
+    /// The subscribed moderators, owners and co-owners are told right away.
+    pub async fn report(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        mut seqs: Vec<u64>,
+        reason: &str,
+    ) -> Result<Report, AppendEventsError> {
+        if permission.is_empty() {
+            tracing::warn!("User {} tried to report canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        let reason = reason.trim();
+        if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_CHARS {
+            return Err(AppendEventsError::InvalidPayload(format!(
+                "The reason must be between 1 and {} characters.",
+                MAX_REPORT_REASON_CHARS
+            )));
+        }
+        seqs.sort_unstable();
+        seqs.dedup();
+        if seqs.len() > MAX_REPORTED_SEQS {
+            return Err(AppendEventsError::InvalidPayload(format!(
+                "A report can name at most {} events.",
+                MAX_REPORTED_SEQS
+            )));
+        }
+
+        let report = reports::file(pool, canvas_uuid, user_id, &seqs, reason).await.map_err(|e| {
+            tracing::error!("Failed to store report of user {} on canvas {}: {}", user_id, canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+        tracing::info!("User {} reported canvas {} (report {}, events {:?})", user_id, canvas_uuid, report.report_id, seqs);
+
+        if let Some(canvas_state) = self.read_canvas(canvas_uuid).await {
+            let message = ServerMessage::ReportFiled { canvas_id: canvas_uuid.to_string(), report: report.clone() };
+            let connections = self.moderator_connections(canvas_uuid, &canvas_state).await;
+            canvas_state.publish(CanvasBroadcast::to_connections(&message, connections));
+        }
+        Ok(report)
+    }
+
+    /// Handles a `report` command from a WebSocket client. The sender gets the id of the report.
+    pub async fn handle_report(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        seqs: Vec<u64>,
+        reason: &str,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid });
+
+        match self.report(&state.pool, &permission, user_id, canvas_uuid, seqs, reason).await {
+            Ok(report) => {
+                let received = ServerMessage::ReportReceived {
+                    canvas_id: canvas_uuid.to_string(),
+                    report_id: report.report_id,
+                };
+                if let Err(e) = connection.send_msg(&received).await {
+                    tracing::error!("Failed to confirm report to client {}: {}", connection.id, e);
+                }
+            }
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("report_not_allowed", "You do not have access to this canvas.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(_) => {
+                connection.send_error("report_failed", "The report could not be saved.", Some(details)).await;
+            }
+        }
+    }
+
+    /// The batches held for review on a canvas, oldest first. Moderators, owners and co-owners only.
+    pub async fn held_batches(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+    ) -> Result<Vec<HeldBatchSummary>, AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!("User {} tried to list held events on canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        let canvas_state = self.read_loaded(pool, canvas_uuid).await?;
+        let expired = self.expire_held_batches(canvas_uuid, &canvas_state).await;
+        let batches: Vec<HeldBatch> = canvas_state.review.lock().await.clone();
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        self.notify_expired_authors(canvas_uuid, &expired).await;
+
+        let authors: HashSet<i64> = batches.iter().map(|batch| batch.user_id).collect();
+        let mut display_names = HashMap::with_capacity(authors.len());
+        for author in authors {
+            let display_name = query!("SELECT display_name FROM users WHERE user_id = ?", author)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| AppendEventsError::Storage(e.to_string()))?
+                .map(|row| row.display_name)
+                .unwrap_or_default();
+            display_names.insert(author, display_name);
+        }
+
+        Ok(batches
+            .into_iter()
+            .map(|batch| HeldBatchSummary {
+                display_name: display_names.get(&batch.user_id).cloned().unwrap_or_default(),
+                count: batch.events.len(),
+                batch_id: batch.batch_id,
+                user_id: batch.user_id,
+                held_at: batch.held_at,
+            })
+            .collect())
+    }
+
+    /// Approves or rejects a batch of events held for review. Approved events are written and
+    /// broadcast like drawn events, rejected ones are dropped; the author's connections are told either way
+    /// and the review is recorded in the moderation log.
+    /// Moderators, owners and co-owners only. `persisted` resolves once approved events are written.
+    pub async fn review_held_events(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        batch_id: &str,
+        review: HeldReview,
+    ) -> Result<AppendedEvents, AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!("User {} tried to review held events on canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        if let HeldReview::Reject(Some(reason)) = &review
+            && reason.chars().count() > MAX_MODERATION_REASON_CHARS
+        {
+            return Err(AppendEventsError::InvalidPayload(format!(
+                "The reason must be at most {} characters.",
+                MAX_MODERATION_REASON_CHARS
+            )));
+        }
+        let approve = matches!(review, HeldReview::Approve);
+        let action = if approve { ModerationAction::ApproveHeldEvents } else { ModerationAction::RejectHeldEvents };
+
+        let canvas_state = self.read_loaded(pool, canvas_uuid).await?;
+        let expired = self.expire_held_batches(canvas_uuid, &canvas_state).await;
+        let reviewed = self.take_held_batch(canvas_uuid, &canvas_state, batch_id, approve).await;
+        if let Ok((batch, _)) = &reviewed {
+            Self::record_action(&canvas_state, canvas_uuid, action, user_id, Some(batch.user_id)).await;
+        }
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        self.notify_expired_authors(canvas_uuid, &expired).await;
+        let (batch, persisted) = reviewed?;
+
+        let reason = match review {
+            HeldReview::Approve => None,
+            HeldReview::Reject(reason) => reason,
+        };
+        tracing::info!(
+            "User {} {} {} held events of user {} on canvas {} (batch {})",
+            user_id,
+            if approve { "approved" } else { "rejected" },
+            batch.events.len(),
+            batch.user_id,
+            canvas_uuid,
+            batch_id
+        );
+        let details = serde_json::json!({ "batchId": batch_id, "count": batch.events.len(), "reason": reason });
+        moderation_log::record(pool, canvas_uuid, Some(user_id), action, Some(batch.user_id), &details).await;
+
+        self.notify_held_author(canvas_uuid, &batch, approve, reason).await;
+        Ok(AppendedEvents { count: batch.events.len(), persisted, held: None })
+    }
+
+    /// Tells the author's connections that a moderator approved or rejected their held events.
+    async fn notify_held_author(&self, canvas_uuid: &CanvasId, batch: &HeldBatch, approved: bool, reason: Option<String>) {
+        let message = ServerMessage::HeldEventsReviewed {
+            canvas_id: canvas_uuid.to_string(),
+            batch_id: batch.batch_id.clone(),
+            approved,
+            reason,
+        };
+        self.send_to_user(batch.user_id, &message).await;
+    }
+
+    /// Sends a message to every connection of a user, whether subscribed to the canvas or not.
+    async fn send_to_user(&self, user_id: i64, message: &ServerMessage) {
+        for connection in self.permissions.connections(user_id).await.iter() {
+            if let Err(e) = connection.send_msg(message).await {
+                tracing::error!("Failed to send message to client {}: {}", connection.id, e);
+            }
+        }
+    }
+
+    /// Mutes or unmutes a user on a canvas. Muted users keep their permission and can still view the canvas,
+    /// but their events are rejected until the mute ends; their connections are told either way.
+    /// Moderators, owners and co-owners only, and only users who can't moderate can be muted.
+    /// Returns the mute that was set or lifted, `None` if the user wasn't muted.
+    pub async fn set_mute(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        target: i64,
+        change: MuteChange,
+    ) -> Result<Option<CanvasMute>, AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!("User {} tried to change a mute on canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        if can_moderate(&self.permissions.permission_level(target, canvas_uuid).await) {
+            return Err(AppendEventsError::InvalidPayload(
+                "Moderators, owners and co-owners can't be muted.".to_string(),
+            ));
+        }
+        let mute = match change {
+            MuteChange::Mute(Some(duration)) if duration.is_zero() => {
+                return Err(AppendEventsError::InvalidPayload("The duration must be positive.".to_string()));
+            }
+            MuteChange::Mute(duration) => Some(CanvasMute {
+                until: duration.map(|duration| event_log::timestamp_ms() + duration.as_millis() as u64),
+            }),
+            MuteChange::Unmute => None,
+        };
+
+        // Under the write lock, so the in-memory mutes follow the DB
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+        let stored = match mute {
+            Some(mute) => {
+                let until = mute.until.map(|until| until as i64);
+                query!(
+                    "INSERT INTO canvas_mutes (canvas_id, user_id, until, muted_by) VALUES (?, ?, ?, ?)
+                     ON CONFLICT (canvas_id, user_id)
+                     DO UPDATE SET until = excluded.until, muted_by = excluded.muted_by, created_at = CURRENT_TIMESTAMP",
+                    canvas_uuid,
+                    target,
+                    until,
+                    user_id
+                )
+                .execute(pool)
+                .await
+                .map(|_| {
+                    canvas_state.mutes.insert(target, mute);
+                    Some(mute)
+                })
+            }
+            None => query!("DELETE FROM canvas_mutes WHERE canvas_id = ? AND user_id = ?", canvas_uuid, target)
+                .execute(pool)
+                .await
+                .map(|_| {
+                    let now_ms = event_log::timestamp_ms();
+                    canvas_state.mutes.remove(&target).filter(|mute| mute.is_active(now_ms))
+                }),
+        };
+        match (mute, &stored) {
+            (Some(_), Ok(_)) => {
+                Self::record_action(&canvas_state, canvas_uuid, ModerationAction::Mute, user_id, Some(target)).await
+            }
+            (None, Ok(Some(_))) => {
+                Self::record_action(&canvas_state, canvas_uuid, ModerationAction::Unmute, user_id, Some(target))
+                    .await
+            }
+            _ => {}
+        }
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        let changed = stored.map_err(|e| {
+            tracing::error!("Failed to change the mute of user {} on canvas {}: {}", target, canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+
+        let (action, message) = match (mute, changed) {
+            (Some(mute), _) => {
+                tracing::info!("User {} muted user {} on canvas {} until {:?}", user_id, target, canvas_uuid, mute.until);
+                (ModerationAction::Mute, ServerMessage::Muted { canvas_id: canvas_uuid.to_string(), until: mute.until })
+            }
+            (None, Some(_)) => {
+                tracing::info!("User {} unmuted user {} on canvas {}", user_id, target, canvas_uuid);
+                (ModerationAction::Unmute, ServerMessage::Unmuted { canvas_id: canvas_uuid.to_string() })
+            }
+            (None, None) => return Ok(None),
+        };
+        let details = serde_json::json!({ "until": mute.and_then(|mute| mute.until) });
+        moderation_log::record(pool, canvas_uuid, Some(user_id), action, Some(target), &details).await;
+        self.send_to_user(target, &message).await;
+        Ok(changed)
+    }
+
+    /// Bans or unbans a user from a canvas. Banned users keep their permission, but can't subscribe
+    /// or draw until the ban ends. A newly banned user's connections are removed from the canvas and
+    /// told the reason and end of the ban; an unbanned user's connections are told too.
+    /// Moderators, owners and co-owners only, following the hierarchy of permission changes:
+    /// owners and co-owners can ban anyone but the owner, moderators only users below them.
+    /// Returns the ban that was set or lifted, `None` if the user wasn't banned.
+    pub async fn set_ban(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        target: i64,
+        change: BanChange,
+    ) -> Result<Option<CanvasBan>, AppendEventsError> {
+        let target_permission = self.permissions.permission_level(target, canvas_uuid).await;
+        if target == user_id || !outranks(permission, &target_permission) {
+            tracing::warn!(
+                "User {} ({}) tried to change the ban of user {} ({}) on canvas {}",
+                user_id,
+                permission,
+                target,
+                target_permission,
+                canvas_uuid
+            );
+            return Err(AppendEventsError::Forbidden);
+        }
+        let ban = match change {
+            BanChange::Ban(Some(duration), _) if duration.is_zero() => {
+                return Err(AppendEventsError::InvalidPayload("The duration must be positive.".to_string()));
+            }
+            BanChange::Ban(_, Some(reason)) if reason.chars().count() > MAX_MODERATION_REASON_CHARS => {
+                return Err(AppendEventsError::InvalidPayload(format!(
+                    "The reason must be at most {} characters.",
+                    MAX_MODERATION_REASON_CHARS
+                )));
+            }
+            BanChange::Ban(duration, reason) => Some(CanvasBan {
+                expires_at: duration.map(|duration| event_log::timestamp_ms() + duration.as_millis() as u64),
+                reason,
+            }),
+            BanChange::Unban => None,
+        };
+
+        // Under the write lock, so the in-memory bans follow the DB
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+        let stored = match &ban {
+            Some(ban) => {
+                let expires_at = ban.expires_at.map(|expires_at| expires_at as i64);
+                query!(
+                    "INSERT INTO canvas_bans (canvas_id, user_id, expires_at, reason, banned_by) VALUES (?, ?, ?, ?, ?)
+                     ON CONFLICT (canvas_id, user_id) DO UPDATE SET
+                         expires_at = excluded.expires_at, reason = excluded.reason,
+                         banned_by = excluded.banned_by, created_at = CURRENT_TIMESTAMP",
+                    canvas_uuid,
+                    target,
+                    expires_at,
+                    ban.reason,
+                    user_id
+                )
+                .execute(pool)
+                .await
+                .map(|_| {
+                    canvas_state.bans.insert(target, ban.clone());
+                    Some(ban.clone())
+                })
+            }
+            None => query!("DELETE FROM canvas_bans WHERE canvas_id = ? AND user_id = ?", canvas_uuid, target)
+                .execute(pool)
+                .await
+                .map(|_| {
+                    let now_ms = event_log::timestamp_ms();
+                    canvas_state.bans.remove(&target).filter(|ban| ban.is_active(now_ms))
+                }),
+        };
+        match (&ban, &stored) {
+            (Some(_), Ok(_)) => {
+                Self::record_action(&canvas_state, canvas_uuid, ModerationAction::Ban, user_id, Some(target)).await;
+                // The banned user's connections are removed from the canvas right after
+                if canvas_state.subscribers_by_user.contains_key(&target) {
+                    Self::record_action(&canvas_state, canvas_uuid, ModerationAction::Kick, user_id, Some(target)).await;
+                }
+            }
+            (None, Ok(Some(_))) => {
+                Self::record_action(&canvas_state, canvas_uuid, ModerationAction::Unban, user_id, Some(target))
+                    .await
+            }
+            _ => {}
+        }
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        let changed = stored.map_err(|e| {
+            tracing::error!("Failed to change the ban of user {} on canvas {}: {}", target, canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+
+        match (ban, &changed) {
+            (Some(ban), _) => {
+                tracing::info!(
+                    "User {} banned user {} from canvas {} until {:?}",
+                    user_id,
+                    target,
+                    canvas_uuid,
+                    ban.expires_at
+                );
+                let kicked = self.unregister_user(canvas_uuid, target).await;
+                let details = serde_json::json!({
+                    "expiresAt": ban.expires_at,
+                    "reason": ban.reason,
+                    "kickedConnections": kicked.len(),
+                });
+                moderation_log::record(pool, canvas_uuid, Some(user_id), ModerationAction::Ban, Some(target), &details).await;
+
+                let message = ServerMessage::Banned {
+                    canvas_id: canvas_uuid.to_string(),
+                    expires_at: ban.expires_at,
+                    reason: ban.reason,
+                };
+                for connection in kicked {
+                    if let Err(e) = connection.send_msg(&message).await {
+                        tracing::error!("Failed to tell client {} about its ban: {}", connection.id, e);
+                    }
+                }
+            }
+            (None, Some(_)) => {
+                tracing::info!("User {} unbanned user {} from canvas {}", user_id, target, canvas_uuid);
+                let details = serde_json::json!({});
+                moderation_log::record(pool, canvas_uuid, Some(user_id), ModerationAction::Unban, Some(target), &details)
+                    .await;
+                self.send_to_user(target, &ServerMessage::Unbanned { canvas_id: canvas_uuid.to_string() }).await;
+            }
+            (None, None) => {}
+        }
+        Ok(changed)
+    }
+
+    /// Pins an announcement to a canvas, replacing the active one, or clears it if `text` is `None`.
+    /// Every subscriber gets the change; late joiners get the active announcement with the history.
+    /// Moderators, owners and co-owners only.
+    pub async fn set_announcement(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        text: Option<String>,
+    ) -> Result<Option<Announcement>, AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!("User {} tried to change the announcement of canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        let text = text.map(|text| text.trim().to_string());
+        if let Some(text) = &text {
+            if text.is_empty() {
+                return Err(AppendEventsError::InvalidPayload("The announcement must not be empty.".to_string()));
+            }
+            if text.chars().count() > MAX_ANNOUNCEMENT_CHARS {
+                return Err(AppendEventsError::InvalidPayload(format!(
+                    "The announcement must be at most {} characters.",
+                    MAX_ANNOUNCEMENT_CHARS
+                )));
+            }
+        }
+        let text = text
+            .map(|text| self.content_filter.apply(&text))
+            .transpose()
+            .map_err(|_| {
+                tracing::info!("Content filter rejected an announcement of user {} on canvas {}", user_id, canvas_uuid);
+                AppendEventsError::ContentFiltered
+            })?;
+        let author_name = self.permissions.display_name(user_id).await.unwrap_or_default();
+
+        // Under the write lock, so the in-memory announcement follows the DB
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+        let stored = Self::store_announcement(pool, canvas_uuid, user_id, text.as_deref()).await;
+        let announcement = stored.map(|stored| {
+            let announcement = stored.zip(text).map(|((announcement_id, created_at), text)| Announcement {
+                announcement_id,
+                text,
+                author_id: user_id,
+                author_name,
+                created_at,
+            });
+            canvas_state.announcement = announcement.clone();
+            let message = ServerMessage::Announcement {
+                canvas_id: canvas_uuid.to_string(),
+                announcement: announcement.clone(),
+            };
+            canvas_state.publish(CanvasBroadcast::new(&message, Recipients::All));
+            announcement
+        });
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        let announcement = announcement.map_err(|e| {
+            tracing::error!("Failed to change the announcement of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+
+        let (action, details) = match &announcement {
+            Some(announcement) => {
+                tracing::info!("User {} pinned announcement {} on canvas {}", user_id, announcement.announcement_id, canvas_uuid);
+                let details = serde_json::json!({ "announcementId": announcement.announcement_id, "text": announcement.text });
+                (ModerationAction::Announce, details)
+            }
+            None => {
+                tracing::info!("User {} cleared the announcement of canvas {}", user_id, canvas_uuid);
+                (ModerationAction::ClearAnnouncement, serde_json::json!({}))
+            }
+        };
+        moderation_log::record(pool, canvas_uuid, Some(user_id), action, None, &details).await;
+        Ok(announcement)
+    }
+
+    /// Deactivates the active announcement of a canvas and stores the new one, if any.
+    /// Returns the id and creation time of the new announcement.
+    async fn store_announcement(
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+        user_id: i64,
+        text: Option<&str>,
+    ) -> Result<Option<(i64, u64)>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        query!("UPDATE canvas_announcements SET active = FALSE WHERE canvas_id = ? AND active", canvas_uuid)
+            .execute(&mut *tx)
+            .await?;
+        let stored = match text {
+            Some(text) => {
+                let created_at = event_log::timestamp_ms();
+                let created_at_db = created_at as i64;
+                let announcement_id = query!(
+                    "INSERT INTO canvas_announcements (canvas_id, text, author_id, created_at) VALUES (?, ?, ?, ?)",
+                    canvas_uuid,
+                    text,
+                    user_id,
+                    created_at_db
+                )
+                .execute(&mut *tx)
+                .await?
+                .last_insert_rowid();
+                Some((announcement_id, created_at))
+            }
+            None => None,
+        };
+        tx.commit().await?;
+        Ok(stored)
+    }
+
+    /// Handles an `announce` or `clearAnnouncement` command from a WebSocket client.
+    pub async fn handle_announcement(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        text: Option<String>,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid });
+
+        match self.set_announcement(&state.pool, &permission, user_id, canvas_uuid, text).await {
+            Ok(_) => {}
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("announce_not_allowed", "Only moderators can change announcements.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(AppendEventsError::ContentFiltered) => {
+                connection
+                    .send_error("content_filtered", "The announcement contains blocked words.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to change the announcement of canvas {}: {:?}", canvas_uuid, e);
+                connection
+                    .send_error("announce_failed", "The announcement could not be changed.", Some(details))
+                    .await;
+            }
+        }
+    }
+
+    /// Sets the slow mode interval of a canvas, 0 turning it off. Stored on the canvas and, if it is loaded,
+    /// mirrored in its state and sent to the subscribers. Moderators, owners and co-owners only.
+    pub async fn set_slow_mode(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        slow_mode_ms: u64,
+    ) -> Result<(), AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!("User {} tried to change the slow mode of canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        if slow_mode_ms > MAX_SLOW_MODE_MS {
+            return Err(AppendEventsError::InvalidPayload(format!(
+                "The slow mode interval must be at most {} ms.",
+                MAX_SLOW_MODE_MS
+            )));
+        }
+
+        // Under the write lock of a loaded canvas, so the mirror follows the DB
+        let canvas_state = self.write_canvas(canvas_uuid).await;
+        let slow_mode_db = slow_mode_ms as i64;
+        let updated = query!("UPDATE Canvas SET slow_mode_ms = ? WHERE canvas_id = ?", slow_mode_db, canvas_uuid)
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to update slow mode of canvas {}: {}", canvas_uuid, e);
+                AppendEventsError::Storage(e.to_string())
+            })?;
+        if updated.rows_affected() == 0 {
+            return Err(AppendEventsError::NotFound);
+        }
+        if let Some(mut canvas_state) = canvas_state {
+            canvas_state.slow_mode_ms = slow_mode_ms;
+            canvas_state.last_batches.get_mut().unwrap().clear();
+            let message = ServerMessage::SlowMode { canvas_id: canvas_uuid.to_string(), slow_mode_ms };
+            canvas_state.publish(CanvasBroadcast::new(&message, Recipients::All));
+        }
+
+        tracing::info!("User {} set slow mode of canvas {} to {} ms", user_id, canvas_uuid, slow_mode_ms);
+        let details = serde_json::json!({ "slowModeMs": slow_mode_ms });
+        moderation_log::record(pool, canvas_uuid, Some(user_id), ModerationAction::SlowMode, None, &details).await;
+        Ok(())
+    }
+
+    /// Handles a `setSlowMode` command from a WebSocket client.
+    pub async fn handle_slow_mode(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        slow_mode_ms: u64,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid });
+
+        match self.set_slow_mode(&state.pool, &permission, user_id, canvas_uuid, slow_mode_ms).await {
+            Ok(()) => {}
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("slow_mode_not_allowed", "Only moderators can change the slow mode.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(_) => {
+                connection
+                    .send_error("slow_mode_failed", "The slow mode could not be changed.", Some(details))
+                    .await;
+            }
+        }
+    }
+
+    /// Starts presentation mode on a loaded canvas, or ends it if `presenters` is `None`. While it runs,
+    /// only the owner and the given presenters can draw. Every subscriber gets the change; late joiners
+    /// get it with the history. Owners only.
+    pub async fn set_presentation_mode(
+        &self,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+        presenters: Option<Vec<i64>>,
+    ) -> Result<(), AppendEventsError> {
+        if permission != "O" {
+            tracing::warn!("User {} tried to change the presentation mode of canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+        let presenters = presenters.map(|mut presenters| {
+            presenters.retain(|presenter| *presenter != user_id);
+            presenters.sort_unstable();
+            presenters.dedup();
+            presenters
+        });
+
+        // A presentation needs an audience, so the canvas is not loaded for it
+        let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await else {
+            return Err(AppendEventsError::NotFound);
+        };
+        canvas_state.presenters = presenters.clone();
+        let message = ServerMessage::PresentationMode {
+            canvas_id: canvas_uuid.to_string(),
+            enabled: presenters.is_some(),
+            presenters: presenters.clone().unwrap_or_default(),
+        };
+        canvas_state.publish(CanvasBroadcast::new(&message, Recipients::All));
+        drop(canvas_state);
+
+        match presenters {
+            Some(presenters) => tracing::info!("User {} started presenting on canvas {} with presenters {:?}", user_id, canvas_uuid, presenters),
+            None => tracing::info!("User {} ended presentation mode on canvas {}", user_id, canvas_uuid),
+        }
+        Ok(())
+    }
+
+    /// Handles a `setPresentationMode` command from a WebSocket client.
+    pub async fn handle_presentation_mode(
+        &self,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        presenters: Option<Vec<i64>>,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid });
+
+        match self.set_presentation_mode(&permission, user_id, canvas_uuid, presenters).await {
+            Ok(()) => {}
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("presentation_not_allowed", "Only the owner can change the presentation mode.", Some(details))
+                    .await;
+            }
+            Err(_) => {
+                connection
+                    .notify_client("Register for the canvas before changing its presentation mode.")
+                    .await;
+            }
+        }
+    }
+
+    /// Handles a `banUser` or `unbanUser` command from a WebSocket client.
+    pub async fn handle_ban(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        target: i64,
+        change: BanChange,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid, "userId": target });
+
+        match self.set_ban(&state.pool, &permission, user_id, canvas_uuid, target, change).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                connection.send_error("not_banned", "The user is not banned from this canvas.", Some(details)).await;
+            }
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("ban_not_allowed", "You can't change the ban of this user.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to change the ban of user {} on canvas {}: {:?}", target, canvas_uuid, e);
+                connection.send_error("ban_failed", "The ban could not be changed.", Some(details)).await;
+            }
+        }
+    }
+
+    /// Handles a `muteUser` or `unmuteUser` command from a WebSocket client.
+    pub async fn handle_mute(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        target: i64,
+        change: MuteChange,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid, "userId": target });
+
+        match self.set_mute(&state.pool, &permission, user_id, canvas_uuid, target, change).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                connection.send_error("not_muted", "The user is not muted on this canvas.", Some(details)).await;
+            }
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("mute_not_allowed", "Only moderators can mute users.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to change the mute of user {} on canvas {}: {:?}", target, canvas_uuid, e);
+                connection.send_error("mute_failed", "The mute could not be changed.", Some(details)).await;
+            }
+        }
+    }
+
+    /// Removes a held batch from the review queue, queueing its events for the writer if approved.
+    /// A batch whose events could not be queued stays held.
+    async fn take_held_batch(
+        &self,
+        canvas_uuid: &CanvasId,
+        canvas_state: &CanvasState,
+        batch_id: &str,
+        approve: bool,
+    ) -> Result<(HeldBatch, Option<WriteAck>), AppendEventsError> {
+        let storage_error = |e: std::io::Error| {
+            tracing::error!("Failed to update the held events of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        };
+
+        let mut review = canvas_state.review.lock().await;
+        let Some(index) = review.iter().position(|batch| batch.batch_id == batch_id) else {
+            return Err(AppendEventsError::BatchNotFound);
+        };
+        let mut remaining = review.clone();
+        let batch = remaining.remove(index);
+        // Removed from the file first, so a crash can't write the events twice
+        self.review.replace(canvas_uuid, &remaining).await.map_err(storage_error)?;
+
+        let persisted = if approve {
+            let lock_guard = canvas_state.file_mutex.lock().await;
+            let recipients = batch.origin.map_or(Recipients::All, Recipients::AllExceptConnection);
+            let queued = canvas_state
+                .queue_write(
+                    batch.events.clone(),
+                    |events| {
+                        let message = ServerMessage::Events {
+                            canvas_id: canvas_uuid.to_string(),
+                            events_for_canvas: events.to_vec(),
+                        };
+                        CanvasBroadcast::new(&message, recipients)
+                    },
+                    true,
+                )
+                .await;
+            drop(lock_guard);
+            match queued {
+                Ok(persisted) => Some(persisted),
+                Err(e) => {
+                    self.review.replace(canvas_uuid, &review).await.map_err(storage_error)?;
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+
+        *review = remaining;
+        let held_batches = review.len();
+        drop(review);
+        self.publish_held_count(canvas_uuid, canvas_state, held_batches).await;
+        Ok((batch, persisted))
+    }
+
+    /// Drops the batches held for longer than the held events TTL and returns them,
+    /// so their authors can be told once the canvas lock is released (see `notify_expired_authors`).
+    async fn expire_held_batches(&self, canvas_uuid: &CanvasId, canvas_state: &CanvasState) -> Vec<HeldBatch> {
+        if self.held_events_ttl.is_zero() {
+            return Vec::new();
+        }
+        let cutoff = event_log::timestamp_ms().saturating_sub(self.held_events_ttl.as_millis() as u64);
+
+        let mut review = canvas_state.review.lock().await;
+        if review.iter().all(|batch| batch.held_at >= cutoff) {
+            return Vec::new();
+        }
+        let (expired, remaining): (Vec<HeldBatch>, Vec<HeldBatch>) =
+            review.iter().cloned().partition(|batch| batch.held_at < cutoff);
+        if let Err(e) = self.review.replace(canvas_uuid, &remaining).await {
+            tracing::error!("Failed to expire held events of canvas {}: {}", canvas_uuid, e);
+            return Vec::new();
+        }
+        *review = remaining;
+        let held_batches = review.len();
+        drop(review);
+
+        tracing::info!("Expired {} held batches on canvas {}", expired.len(), canvas_uuid);
+        self.publish_held_count(canvas_uuid, canvas_state, held_batches).await;
+        expired
+    }
+
+    async fn notify_expired_authors(&self, canvas_uuid: &CanvasId, expired: &[HeldBatch]) {
+        for batch in expired {
+            self.notify_held_author(canvas_uuid, batch, false, Some(HELD_EVENTS_EXPIRED_REASON.to_string())).await;
+        }
+    }
+
+    /// Expires held batches on every loaded canvas. Unloaded canvases are checked once they are used again.
+    pub async fn expire_held_events(&self) {
+        let canvases: Vec<(CanvasId, SharedCanvas)> = self
+            .inner
+            .read()
+            .await
+            .iter()
+            .map(|(canvas_uuid, canvas)| (*canvas_uuid, canvas.clone()))
+            .collect();
+
+        for (canvas_uuid, canvas) in canvases {
+            let canvas_state = canvas.read().await;
+            if canvas_state.unloaded {
+                continue;
+            }
+            let expired = self.expire_held_batches(&canvas_uuid, &canvas_state).await;
+            drop(canvas_state);
+            self.notify_expired_authors(&canvas_uuid, &expired).await;
+        }
+    }
+
+    /// Handles an `approveHeldEvents` or `rejectHeldEvents` command from a WebSocket client.
+    pub async fn handle_held_review(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        batch_id: &str,
+        review: HeldReview,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid, "batchId": batch_id });
+
+        match self.review_held_events(&state.pool, &permission, user_id, canvas_uuid, batch_id, review).await {
+            Ok(AppendedEvents { count, persisted: Some(persisted), .. }) => {
+                report_persisted(persisted, canvas_uuid, vec![(connection.clone(), count)]);
+            }
+            Ok(_) => {}
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("review_not_allowed", "Only moderators can review held events.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::BatchNotFound) => {
+                connection
+                    .send_error("unknown_batch", "The held events were already reviewed or do not exist.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::InvalidPayload(reason)) => {
+                connection.send_error("invalid_payload", &reason, Some(details)).await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(_) => connection.notify_client("Failed to review held events.").await,
+        }
+    }
+
+    /// Reads the events of a canvas with a sequence number above `since_seq`.
+    /// Without `since_seq`, the whole log is returned, including events written before
+    /// sequence numbers were introduced. Any permission on the canvas allows reading it.
+    pub async fn events_since(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        canvas_uuid: &CanvasId,
+        since_seq: Option<u64>,
+    ) -> Result<Vec<serde_json::Value>, AppendEventsError> {
+        if permission.is_empty() {
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        if self.read_canvas(canvas_uuid).await.is_none() {
+            // Makes sure the canvas exists
+            Self::get_canvas_info(pool, canvas_uuid).await?;
+        }
+
+        match self.store.read_since(&canvas_uuid.to_string(), since_seq).try_collect().await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to read event log of canvas {}: {}", canvas_uuid, e);
+                self.recover_missing_log(canvas_uuid).await;
+                Ok(Vec::new())
+            }
+            result => result.map_err(|e| {
+                tracing::error!("Failed to read event log of canvas {}: {}", canvas_uuid, e);
+                AppendEventsError::Storage(e.to_string())
+            }),
+        }
+    }
+
+    /// Streams the events of a canvas within `range` as NDJSON lines, for debugging and session replays.
+    /// The log is read while the client consumes the lines, at most `REPLAY_QUEUE_LINES` ahead.
+    ///
+    /// A trailing `replayMeta` line tells how many events were sent and, if `limit` cut the replay short,
+    /// the `next_from_seq` to continue from. Events from before sequence numbers are only replayed
+    /// without `from_seq`, and a continuation skips those not sent yet.
+    /// Any permission on the canvas allows a replay.
+    pub async fn replay_events(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        canvas_uuid: &CanvasId,
+        range: EventRange,
+    ) -> Result<impl Stream<Item = std::io::Result<String>> + Send + 'static, AppendEventsError> {
+        if permission.is_empty() {
+            return Err(AppendEventsError::Forbidden);
+        }
+        if self.read_canvas(canvas_uuid).await.is_none() {
+            // Makes sure the canvas exists
+            Self::get_canvas_info(pool, canvas_uuid).await?;
+        }
+
+        let (lines, receiver) = mpsc::channel::<std::io::Result<String>>(REPLAY_QUEUE_LINES);
+        let manager = self.clone();
+        let canvas_uuid = *canvas_uuid;
+        tokio::spawn(async move {
+            // Sequence bounds are inclusive, `read_since` is not
+            let since_seq = range.from_seq.map(|from_seq| from_seq.saturating_sub(1));
+            let log_id = canvas_uuid.to_string();
+            let mut events = manager.store.read_since(&log_id, since_seq);
+            let mut sent = 0;
+            let mut last_sent_seq = None;
+            let mut next_from_seq = None;
+
+            loop {
+                let event = match events.try_next().await {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        tracing::warn!("Failed to read event log of canvas {}: {}", canvas_uuid, e);
+                        manager.recover_missing_log(&canvas_uuid).await;
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to replay event log of canvas {}: {}", canvas_uuid, e);
+                        let _ = lines.send(Err(e)).await;
+                        return;
+                    }
+                };
+                if range.is_past_end(&event) {
+                    break;
+                }
+                if !range.matches(&event) {
+                    continue;
+                }
+                if range.limit.is_some_and(|limit| sent >= limit) {
+                    next_from_seq = event_seq(&event).or(last_sent_seq.map(|seq| seq + 1)).or(Some(1));
+                    break;
+                }
+
+                last_sent_seq = event_seq(&event).or(last_sent_seq);
+                sent += 1;
+                if lines.send(Ok(event.to_string() + "\n")).await.is_err() {
+                    // The client is gone
+                    return;
+                }
+            }
+
+            let meta = serde_json::json!({"type": "replayMeta", "count": sent, "next_from_seq": next_from_seq});
+            let _ = lines.send(Ok(meta.to_string() + "\n")).await;
+        });
+
+        Ok(stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|line| (line, receiver))
+        }))
+    }
+
+    /// Recreates the log of a canvas whose event file went missing, e.g. after restoring
+    /// a backup without it, so the canvas is usable again instead of failing every read.
+    ///
+    /// The history is lost: owners and co-owners are told, and all subscribers are asked
+    /// to resync to the empty log.
+    async fn recover_missing_log(&self, canvas_uuid: &CanvasId) {
+        let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await else {
+            // Not loaded, so nothing is cached or queued
+            match self.store.create(&canvas_uuid.to_string()).await {
+                Ok(true) => tracing::warn!("Event log of canvas {} was missing. Created an empty one.", canvas_uuid),
+                Ok(false) => {}
+                Err(e) => tracing::error!("Failed to recreate event log of canvas {}: {}", canvas_uuid, e),
+            }
+            return;
+        };
+
+        // The writer may still hold the missing file open
+        self.write_pending(canvas_uuid, &canvas_state).await;
+        Self::stop_writer(&mut canvas_state).await;
+        let created = {
+            let _lock_guard = canvas_state.file_mutex.lock().await;
+            self.store.create(&canvas_uuid.to_string()).await
+        };
+        if let Ok(true) = created {
+            canvas_state.reset_log(Vec::new(), 0);
+        }
+        self.start_writer(canvas_uuid, &mut canvas_state);
+
+        match created {
+            Ok(true) => {}
+            // Recovered by someone else in the meantime
+            Ok(false) => return,
+            Err(e) => {
+                tracing::error!("Failed to recreate event log of canvas {}: {}", canvas_uuid, e);
+                return;
+            }
+        }
+        tracing::warn!("Event log of canvas {} was missing. Created an empty one.", canvas_uuid);
+
+        let resync = ServerMessage::Resync { canvas_id: canvas_uuid.to_string() };
+        canvas_state.publish(CanvasBroadcast::new(&resync, Recipients::All));
+        let subscribers: Vec<ConnectionInfo> = canvas_state.subscribers.values().cloned().collect();
+        drop(canvas_state);
+
+        for info in subscribers {
+            let permission = self.permissions.permission_level(info.user_id, canvas_uuid).await;
+            if matches!(permission.as_str(), "O" | "C") {
+                info.connection
+                    .notify_client("The drawing history of this canvas was lost. The canvas starts over empty.")
+                    .await;
+            }
+        }
+    }
+
+    /// Rewrites the event log of a canvas without deleted events and tombstones,
+    /// renumbering the surviving events from 1.
+    ///
+    /// The store keeps the old log as a backup. Subscribers are asked to resync, since
+    /// the sequence numbers they know are no longer valid.
+    pub async fn compact(&self, pool: &SqlitePool, canvas_uuid: &CanvasId) -> Result<CompactionStats, AppendEventsError> {
+        // Exclusive, so no history snapshot or append overlaps the rewrite
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+
+        // Coalesced events still waiting are part of the log to compact.
+        // The writer holds the log open, so it is stopped for the rewrite.
+        self.write_pending(canvas_uuid, &canvas_state).await;
+        Self::stop_writer(&mut canvas_state).await;
+        let rewrite = self.rewrite_compacted(canvas_uuid, &canvas_state).await;
+        self.start_writer(canvas_uuid, &mut canvas_state);
+        let stats = rewrite?;
+
+        tracing::info!(
+            "Compacted canvas {} from {} to {} events.",
+            canvas_uuid,
+            stats.events_before,
+            stats.events_after
+        );
+
+        let resync = ServerMessage::Resync { canvas_id: canvas_uuid.to_string() };
+        canvas_state.publish(CanvasBroadcast::new(&resync, Recipients::All));
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        Ok(stats)
+    }
+
+    /// Checks the log of a canvas for corrupt entries (see `EventStore::verify`).
+    ///
+    /// Quarantining rewrites the log like compaction: the writer is stopped meanwhile,
+    /// and if entries were removed the cache is reloaded and subscribers are asked to resync.
+    pub async fn verify(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+        quarantine: bool,
+    ) -> Result<VerifyReport, AppendEventsError> {
+        let storage_error = |e: std::io::Error| {
+            tracing::error!("Failed to verify event log of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        };
+
+        // Only reads, appends may go on meanwhile
+        if !quarantine {
+            Self::get_canvas_info(pool, canvas_uuid).await?;
+            return self.store.verify(&canvas_uuid.to_string(), false).await.map_err(storage_error);
+        }
+
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+        self.write_pending(canvas_uuid, &canvas_state).await;
+        Self::stop_writer(&mut canvas_state).await;
+        let report = self.store.verify(&canvas_uuid.to_string(), true).await;
+        if matches!(&report, Ok(report) if report.quarantined) {
+            match self.store.read_all(&canvas_uuid.to_string()).await {
+                Ok(events) => {
+                    let bytes = encoded_len(&events);
+                    canvas_state.reset_log(events, bytes);
+                }
+                Err(e) => {
+                    tracing::warn!("Could not reload event log of canvas {}: {}", canvas_uuid, e);
+                    canvas_state.cache.lock().unwrap().invalidate();
+                }
+            }
+        }
+        self.start_writer(canvas_uuid, &mut canvas_state);
+        let report = report.map_err(storage_error)?;
+
+        if report.quarantined {
+            let resync = ServerMessage::Resync { canvas_id: canvas_uuid.to_string() };
+            canvas_state.publish(CanvasBroadcast::new(&resync, Recipients::All));
+        }
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        Ok(report)
+    }
+
+    /// Compresses the event log of a canvas that was not appended to for at least `idle_for`
+    /// (see `EventStore::compress`). Loaded canvases are skipped, since their writer may append
+    /// at any time. Returns whether the log was compressed.
+    pub async fn compress(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+        idle_for: Duration,
+    ) -> Result<bool, AppendEventsError> {
+        Self::get_canvas_info(pool, canvas_uuid).await?;
+
+        // Holding the load lock keeps the canvas from being loaded while its log is compressed
+        let load_lock = self
+            .loading
+            .lock()
+            .unwrap()
+            .entry(*canvas_uuid)
+            .or_default()
+            .clone();
+        let load_guard = load_lock.lock().await;
+        let result = if self.inner.read().await.contains_key(canvas_uuid) {
+            Ok(false)
+        } else {
+            self.store.compress(&canvas_uuid.to_string(), idle_for).await
+        };
+        drop(load_guard);
+        self.loading.lock().unwrap().remove(canvas_uuid);
+
+        result.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                return AppendEventsError::Busy;
+            }
+            tracing::error!("Failed to compress event log of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })
+    }
+
+    /// Restores the plain event log of a compressed canvas ahead of its next append.
+    /// Returns whether the log was compressed.
+    pub async fn decompress(&self, pool: &SqlitePool, canvas_uuid: &CanvasId) -> Result<bool, AppendEventsError> {
+        Self::get_canvas_info(pool, canvas_uuid).await?;
+        self.store.decompress(&canvas_uuid.to_string()).await.map_err(|e| {
+            tracing::error!("Failed to decompress event log of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })
+    }
+
+    /// Runs `work` while nothing is appended to the event log of a canvas, e.g. to copy the log file.
+    /// A loaded canvas' pending events are written and its writer is stopped meanwhile,
+    /// an unloaded canvas is kept from loading.
+    pub async fn with_log_idle<T>(&self, canvas_uuid: &CanvasId, work: impl Future<Output = T>) -> T {
+        loop {
+            if let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await {
+                self.write_pending(canvas_uuid, &canvas_state).await;
+                Self::stop_writer(&mut canvas_state).await;
+                let result = work.await;
+                self.start_writer(canvas_uuid, &mut canvas_state);
+                return result;
+            }
+
+            let load_lock = self
+                .loading
+                .lock()
+                .unwrap()
+                .entry(*canvas_uuid)
+                .or_default()
+                .clone();
+            let load_guard = load_lock.lock().await;
+            if !self.inner.read().await.contains_key(canvas_uuid) {
+                let result = work.await;
+                drop(load_guard);
+                self.loading.lock().unwrap().remove(canvas_uuid);
+                return result;
+            }
+            // Loaded between the two checks, stop its writer instead
+            drop(load_guard);
+            self.loading.lock().unwrap().remove(canvas_uuid);
+        }
+    }
+
+    /// Compresses the logs of all canvases that were not appended to for at least `idle_for`.
+    pub async fn compress_idle(&self, pool: &SqlitePool, idle_for: Duration) {
+        let canvases = match query!(r#"SELECT canvas_id AS "canvas_id: CanvasId" FROM Canvas WHERE compressed = FALSE"#)
+            .fetch_all(pool)
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::error!("Failed to list canvases for compression: {}", e);
+                return;
+            }
+        };
+
+        let mut compressed = 0;
+        for canvas in canvases {
+            match self.compress(pool, &canvas.canvas_id, idle_for).await {
+                Ok(true) => compressed += 1,
+                Ok(false) => {}
+                // Left for the next run
+                Err(AppendEventsError::Busy) => {
+                    tracing::debug!("Skipping compression of canvas {}, the blocking pool is busy.", canvas.canvas_id)
+                }
+                Err(e) => tracing::error!("Scheduled compression of canvas {} failed: {:?}", canvas.canvas_id, e),
+            }
+        }
+        if compressed > 0 {
+            tracing::info!("Compressed the event logs of {} idle canvases.", compressed);
+        }
+    }
+
+    /// Replaces the log of a canvas with its compacted events. Call only while the writer task is stopped.
+    async fn rewrite_compacted(
+        &self,
+        canvas_uuid: &CanvasId,
+        canvas_state: &CanvasState,
+    ) -> Result<CompactionStats, AppendEventsError> {
+        let events = self.store.read_all(&canvas_uuid.to_string()).await.map_err(|e| {
+            tracing::error!("Failed to read event log of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })?;
+        let events_before = events.len();
+        if events_before == 0 {
+            return Ok(CompactionStats { events_before: 0, events_after: 0 });
+        }
+        let compacted = compact_events(events);
+
+        if let Err(e) = self.store.replace_log(&canvas_uuid.to_string(), &compacted).await {
+            tracing::error!("Failed to compact event log of canvas {}: {}", canvas_uuid, e);
+            return Err(AppendEventsError::Storage(e.to_string()));
+        }
+
+        let stats = CompactionStats {
+            events_before,
+            events_after: compacted.len(),
+        };
+        let bytes = encoded_len(&compacted);
+        canvas_state.reset_log(compacted, bytes);
+        Ok(stats)
+    }
+
+    /// Lists the archived event logs of a canvas, newest first.
+    pub async fn archives(&self, pool: &SqlitePool, canvas_uuid: &CanvasId) -> Result<Vec<LogArchive>, AppendEventsError> {
+        Self::get_canvas_info(pool, canvas_uuid).await?;
+        self.store.list_archives(&canvas_uuid.to_string()).await.map_err(|e| {
+            tracing::error!("Failed to list archives of canvas {}: {}", canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        })
+    }
+
+    /// Swaps an archived log back in. The current log is archived in turn, so a restore can be undone.
+    /// Subscribers are asked to resync, like after compaction.
+    /// Returns the number of restored events, or `None` if the canvas has no such archive.
+    pub async fn restore_archive(
+        &self,
+        pool: &SqlitePool,
+        canvas_uuid: &CanvasId,
+        archive_id: u64,
+    ) -> Result<Option<usize>, AppendEventsError> {
+        let storage_error = |e: std::io::Error| {
+            tracing::error!("Failed to restore archive {} of canvas {}: {}", archive_id, canvas_uuid, e);
+            AppendEventsError::Storage(e.to_string())
+        };
+
+        // Archives are never changed, so they can be read before locking the canvas
+        Self::get_canvas_info(pool, canvas_uuid).await?;
+        let events = match self.store.read_archive(&canvas_uuid.to_string(), archive_id).await {
+            Ok(events) => events,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(storage_error(e)),
+        };
+
+        let mut canvas_state = self.write_loaded(pool, canvas_uuid).await?;
+        self.write_pending(canvas_uuid, &canvas_state).await;
+        Self::stop_writer(&mut canvas_state).await;
+        let replaced = self.store.replace_log(&canvas_uuid.to_string(), &events).await;
+        let restored = events.len();
+        if replaced.is_ok() {
+            let bytes = encoded_len(&events);
+            canvas_state.reset_log(events, bytes);
+        }
+        self.start_writer(canvas_uuid, &mut canvas_state);
+        replaced.map_err(storage_error)?;
+
+        tracing::info!("Restored archive {} of canvas {} with {} events.", archive_id, canvas_uuid, restored);
+
+        let resync = ServerMessage::Resync { canvas_id: canvas_uuid.to_string() };
+        canvas_state.publish(CanvasBroadcast::new(&resync, Recipients::All));
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+        Ok(Some(restored))
+    }
+
+    /// Removes archived event logs older than `retention`.
+    pub async fn prune_archives(&self, retention: Duration) {
+        match self.store.prune_archives(retention).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!("Removed {} archived event logs past the retention.", removed),
+            Err(e) => tracing::error!("Failed to remove old archived event logs: {}", e),
+        }
+    }
+
+    /// Compacts every canvas whose share of deleted events and tombstones exceeds `min_garbage_ratio`.
+    pub async fn compact_garbage_heavy(&self, pool: &SqlitePool, min_garbage_ratio: f64) {
+        let canvases = match query!(r#"SELECT canvas_id AS "canvas_id: CanvasId" FROM Canvas"#).fetch_all(pool).await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::error!("Failed to list canvases for compaction: {}", e);
+                return;
+            }
+        };
+
+        for canvas in canvases {
+            let Ok(events) = self.store.read_all(&canvas.canvas_id.to_string()).await else {
+                continue;
+            };
+            let ratio = garbage_ratio(&events);
+            if ratio <= min_garbage_ratio {
+                continue;
+            }
+
+            tracing::info!("Canvas {} has a garbage ratio of {:.2}. Compacting.", canvas.canvas_id, ratio);
+            if let Err(e) = self.compact(pool, &canvas.canvas_id).await {
+                tracing::error!("Scheduled compaction of canvas {} failed: {:?}", canvas.canvas_id, e);
+            }
+        }
+    }
+
+    /// Lets a canvas that was loaded only to append events go idle, if it has no subscribers.
+    async fn release_if_unsubscribed(&self, canvas_uuid: &CanvasId) {
+        if let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await {
+            self.release_if_empty(canvas_uuid, &mut canvas_state).await;
+        }
+    }
+
+    /// Called when a canvas may have lost its last subscriber. Without an idle TTL the canvas
+    /// is unloaded right away, otherwise it stays loaded until the idle sweep finds it expired.
+    async fn release_if_empty(&self, canvas_uuid: &CanvasId, canvas_state: &mut CanvasState) {
+        if !canvas_state.subscribers.is_empty() {
+            return;
+        }
+        if self.idle_ttl.is_zero() {
+            self.unload(canvas_uuid, canvas_state).await;
+        } else if canvas_state.last_empty_at.is_none() {
+            canvas_state.last_empty_at = Some(Instant::now());
+        }
+    }
+
+    /// Returns true if the cached event logs exceed their total budget.
+    fn cache_over_budget(&self) -> bool {
+        let budget = self.event_cache.max_total_bytes;
+        budget > 0 && self.metrics.event_cache_bytes.load(Ordering::Relaxed) > budget
+    }
+
+    /// The event caches of the loaded canvases.
+    async fn loaded_caches(&self) -> Vec<Arc<StdMutex<EventCache>>> {
+        let canvases: Vec<SharedCanvas> = self.inner.read().await.values().cloned().collect();
+        let mut caches = Vec::with_capacity(canvases.len());
+        for canvas in canvases {
+            caches.push(canvas.read().await.cache.clone());
+        }
+        caches
+    }
+
+    /// Drops the cached logs of the least recently used canvases until the cached logs
+    /// fit their total budget again. Only the cached copies go: subscribers, writer tasks
+    /// and queued writes stay, and the history of an evicted canvas is read from the store.
+    pub async fn enforce_cache_budget(&self) {
+        if !self.cache_over_budget() {
+            return;
+        }
+        let mut caches: Vec<(Instant, Arc<StdMutex<EventCache>>)> = self
+            .loaded_caches()
+            .await
+            .into_iter()
+            .map(|cache| {
+                let last_used = cache.lock().unwrap().last_used;
+                (last_used, cache)
+            })
+            .collect();
+        caches.sort_by_key(|(last_used, _)| *last_used);
+
+        let mut evicted = 0;
+        for (_, cache) in caches {
+            if !self.cache_over_budget() {
+                break;
+            }
+            if cache.lock().unwrap().invalidate() > 0 {
+                evicted += 1;
+                WsMetrics::inc(&self.metrics.event_cache_evictions);
+            }
+        }
+        if evicted > 0 {
+            tracing::info!(
+                "Evicted the cached logs of {} canvases, {} bytes remain cached",
+                evicted,
+                self.metrics.event_cache_bytes.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    /// Drops the cached logs of all loaded canvases, e.g. to free memory right away.
+    /// Like eviction, this leaves queued writes alone.
+    pub async fn flush_caches(&self) -> CacheFlushStats {
+        let mut stats = CacheFlushStats { canvases: 0, bytes: 0 };
+        for cache in self.loaded_caches().await {
+            let freed = cache.lock().unwrap().invalidate();
+            if freed > 0 {
+                stats.canvases += 1;
+                stats.bytes += freed;
+            }
+        }
+        stats
+    }
+
+    /// Unloads the canvases that have had no subscribers for longer than the idle TTL.
+    /// Pending writes are flushed first (see `unload`).
+    pub async fn unload_idle(&self) {
+        let canvases: Vec<(CanvasId, SharedCanvas)> = self
+            .inner
+            .read()
+            .await
+            .iter()
+            .map(|(canvas_uuid, canvas)| (*canvas_uuid, canvas.clone()))
+            .collect();
+
+        for (canvas_uuid, canvas) in canvases {
+            let mut canvas_state = canvas.write().await;
+            let expired = canvas_state
+                .last_empty_at
+                .is_some_and(|since| since.elapsed() >= self.idle_ttl);
+            if !canvas_state.unloaded && canvas_state.subscribers.is_empty() && expired {
+                self.unload(&canvas_uuid, &mut canvas_state).await;
+            }
+        }
+    }
+
+    /// Deletes events for everyone by appending a tombstone record to the log
+    /// and broadcasting it, so clients remove the events locally.
+    ///
+    /// Users may delete events they authored as long as they can draw;
+    /// moderators, owners and co-owners may delete any event.
+    pub async fn delete_events(
+        &self,
+        sender_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        seqs: Vec<u64>,
+    ) {
+        self.delete_events_as(sender_id, connection, canvas_uuid, seqs, None).await;
+    }
+
+    /// Handles a `moderatorDelete` command: a moderator, owner or co-owner deletes events of anyone.
+    /// The tombstone is marked as a moderator's and the deletion is recorded in the moderation log.
+    /// With `quarantine`, the deleted events are kept in a sidecar file for audit.
+    pub async fn moderator_delete_events(
+        &self,
+        pool: &SqlitePool,
+        sender_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        seqs: Vec<u64>,
+        quarantine: bool,
+    ) {
+        let moderation = ModeratorDeletion { pool, quarantine };
+        self.delete_events_as(sender_id, connection, canvas_uuid, seqs, Some(moderation)).await;
+    }
+
+    async fn delete_events_as(
+        &self,
+        sender_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        mut seqs: Vec<u64>,
+        moderation: Option<ModeratorDeletion<'_>>,
+    ) {
+        seqs.sort_unstable();
+        seqs.dedup();
+        if seqs.is_empty() {
+            connection
+                .send_error("invalid_payload", "seqs must not be empty.", None)
+                .await;
+            return;
+        }
+
+        let permission = self.permissions.permission_level(sender_id, canvas_uuid).await;
+
+        let deleted = {
+            let Some(canvas_state) = self.read_canvas(canvas_uuid).await else {
+                tracing::warn!("Delete received for canvas {} with no active manager entry. Dropping.", canvas_uuid);
+                return;
+            };
+
+            if !canvas_state.subscribers.contains_key(&connection.id) {
+                connection
+                    .notify_client("Register for the canvas before deleting events.")
+                    .await;
+                return;
+            }
+
+            let is_moderator = can_moderate(&permission);
+            if moderation.is_some() && !is_moderator {
+                tracing::warn!("User {} denied moderator deletion on canvas {}", sender_id, canvas_uuid);
+                connection
+                    .send_error(
+                        "moderator_delete_not_allowed",
+                        "Only moderators, owners and co-owners can delete events of others.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "seqs": seqs })),
+                    )
+                    .await;
+                return;
+            }
+            if !is_moderator && !can_draw(&permission, canvas_state.is_moderated) {
+                tracing::warn!(
+                    "User {} denied deleting events on canvas {}, their permission level is {}",
+                    sender_id,
+                    canvas_uuid,
+                    permission
+                );
+                connection
+                    .send_error(
+                        "delete_not_allowed",
+                        "You are not allowed to delete events on this canvas.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "seqs": seqs })),
+                    )
+                    .await;
+                return;
+            }
+
+            let lock_guard = canvas_state.file_mutex.lock().await;
+
+            let events = match canvas_state.cached_events() {
+                Some(cached) => Arc::unwrap_or_clone(cached),
+                None => match self.store.read_all(&canvas_uuid.to_string()).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        tracing::error!("Failed to read event log of canvas {}: {}", canvas_uuid, e);
+                        connection.notify_client("Failed to delete events.").await;
+                        return;
+                    }
+                },
+            };
+
+            // The targeted events that still exist. System events cannot be deleted.
+            let targets: Vec<serde_json::Value> = apply_tombstones(events)
+                .into_iter()
+                .filter(|event| !is_system_event(event))
+                .filter(|event| event_seq(event).is_some_and(|seq| seqs.binary_search(&seq).is_ok()))
+                .collect();
+            let authors: HashMap<u64, Option<i64>> = targets
+                .iter()
+                .filter_map(|event| event_seq(event).map(|seq| (seq, event_author(event))))
+                .collect();
+
+            let unknown: Vec<u64> = seqs.iter().copied().filter(|seq| !authors.contains_key(seq)).collect();
+            if !unknown.is_empty() {
+                connection
+                    .send_error(
+                        "unknown_events",
+                        "Some of the events do not exist or were already deleted.",
+                        Some(serde_json::json!({ "canvasId": canvas_uuid, "seqs": unknown })),
+                    )
+                    .await;
+                return;
+            }
+
+            if !is_moderator {
+                let foreign: Vec<u64> = seqs
+                    .iter()
+                    .copied()
+                    .filter(|seq| authors.get(seq) != Some(&Some(sender_id)))
+                    .collect();
+
+                if !foreign.is_empty() {
+                    tracing::warn!("User {} tried to delete events of other users on canvas {}", sender_id, canvas_uuid);
+                    connection
+                        .send_error(
+                            "delete_not_allowed",
+                            "You can only delete your own events.",
+                            Some(serde_json::json!({ "canvasId": canvas_uuid, "seqs": foreign })),
+                        )
+                        .await;
+                    return;
+                }
+            }
+
+            // Everyone, including the sender, removes the events when receiving the tombstone
+            let mut tombstone = event_log::tombstone(&seqs, sender_id);
+            if moderation.is_some() {
+                tombstone["moderator"] = true.into();
+            }
+            let queued = canvas_state
+                .queue_write(
+                    vec![tombstone],
+                    |events| {
+                        let message = ServerMessage::Events {
+                            canvas_id: canvas_uuid.to_string(),
+                            events_for_canvas: events.to_vec(),
+                        };
+                        CanvasBroadcast::new(&message, Recipients::All)
+                    },
+                    false,
+                )
+                .await;
+            drop(lock_guard);
+
+            match queued {
+                Ok(_) => {
+                    tracing::info!("User {} deleted events {:?} on canvas {}", sender_id, seqs, canvas_uuid);
+                    targets
+                }
+                Err(AppendEventsError::QueueFull) => {
+                    connection
+                        .send_error(
+                            "write_queue_full",
+                            "The canvas is busy. No events were deleted, try again shortly.",
+                            Some(serde_json::json!({ "canvasId": canvas_uuid, "seqs": seqs })),
+                        )
+                        .await;
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to queue tombstone of canvas {}: {:?}", canvas_uuid, e);
+                    connection.notify_client("Failed to delete events.").await;
+                    return;
+                }
+            }
+        };
+
+        let Some(ModeratorDeletion { pool, quarantine }) = moderation else {
+            return;
+        };
+
+        if quarantine {
+            let entry = serde_json::json!({
+                "seqs": seqs,
+                "by": sender_id,
+                "deletedAt": event_log::timestamp_ms(),
+                "events": deleted,
+            });
+            if let Err(e) = self.review.quarantine(canvas_uuid, &entry).await {
+                tracing::error!("Failed to quarantine deleted events {:?} of canvas {}: {}", seqs, canvas_uuid, e);
+            }
+        }
+
+        // Attributed to the author if all deleted events are theirs
+        let mut authors = deleted.iter().map(event_author);
+        let first_author = authors.next().flatten();
+        let target_id = first_author.filter(|author| authors.all(|other| other == Some(*author)));
+        let details = serde_json::json!({ "seqs": seqs, "quarantined": quarantine });
+        moderation_log::record(pool, canvas_uuid, Some(sender_id), ModerationAction::DeleteEvents, target_id, &details).await;
+    }
+
+    /// Spawns a task flushing the coalesced events of a canvas after the coalescing interval.
+    fn schedule_flush(&self, canvas_uuid: CanvasId) {
+        let manager = self.clone();
+        let interval = self.coalesce.interval;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(interval).await;
+            manager.flush_pending(&canvas_uuid).await;
+        });
+    }
+
+    /// Writes the coalesced events of a canvas.
+    async fn flush_pending(&self, canvas_uuid: &CanvasId) {
+        // An unloaded canvas had its events written on unload
+        if let Some(canvas_state) = self.read_canvas(canvas_uuid).await {
+            self.write_pending(canvas_uuid, &canvas_state).await;
+        }
+    }
+
+    /// Takes the coalesced events of a canvas and queues them as one write, broadcast as one frame.
+    /// Connections that sent some of the events get a frame without their own events,
+    /// or an error if the write fails.
+    async fn write_pending(&self, canvas_uuid: &CanvasId, canvas_state: &CanvasState) {
+        let _lock_guard = canvas_state.file_mutex.lock().await;
+
+        let batch = {
+            let mut pending = canvas_state.pending.lock().unwrap();
+            pending.flush_scheduled = false;
+            std::mem::take(&mut pending.events)
+        };
+        if batch.is_empty() {
+            return;
+        }
+        let (events, senders): (Vec<serde_json::Value>, Vec<Uuid>) = batch.into_iter().unzip();
+        let count = events.len();
+        let origins: Vec<(IdentifiableWebSocket, usize)> = canvas_state
+            .subscribers
+            .values()
+            .map(|info| (info.connection.clone(), senders.iter().filter(|sender| **sender == info.connection.id).count()))
+            .filter(|(_, sent)| *sent > 0)
+            .collect();
+
+        let build_broadcast = |events: &[serde_json::Value]| {
+            let events_message = |events: Vec<serde_json::Value>| ServerMessage::Events {
+                canvas_id: canvas_uuid.to_string(),
+                events_for_canvas: events,
+            };
+            let mut broadcast = CanvasBroadcast::new(&events_message(events.to_vec()), Recipients::All);
+            for sender in senders.iter().collect::<HashSet<_>>() {
+                let others: Vec<serde_json::Value> = events
+                    .iter()
+                    .zip(senders.iter())
+                    .filter(|(_, event_sender)| *event_sender != sender)
+                    .map(|(event, _)| event.clone())
+                    .collect();
+                let message = (!others.is_empty()).then(|| events_message(others).to_ws_message());
+                broadcast.overrides.insert(*sender, message);
+            }
+            broadcast
+        };
+
+        // Internal flushes wait for room in the queue instead of dropping the batch
+        match canvas_state.queue_write(events, build_broadcast, true).await {
+            Ok(persisted) => report_persisted(persisted, canvas_uuid, origins),
+            Err(e) => {
+                tracing::error!("Failed to queue {} coalesced events for canvas {}: {:?}", count, canvas_uuid, e);
+            }
+        }
+    }
+
+    /// Writes the coalesced and queued events of every loaded canvas, stops the writer tasks
+    /// and uploads changed logs to remote storage. Called on shutdown.
+    pub async fn flush_all(&self) {
+        let canvases: Vec<(CanvasId, SharedCanvas)> = self
+            .inner
+            .read()
+            .await
+            .iter()
+            .map(|(canvas_uuid, canvas)| (*canvas_uuid, canvas.clone()))
+            .collect();
+
+        for (canvas_uuid, canvas) in canvases {
+            let mut canvas_state = canvas.write().await;
+            self.write_pending(&canvas_uuid, &canvas_state).await;
+            Self::stop_writer(&mut canvas_state).await;
+        }
+        if let Err(e) = self.store.sync_all().await {
+            tracing::error!("Failed to upload event logs on shutdown: {}", e);
+        }
+    }
+
+    /// The sync state of the event logs with remote storage (see `EventStore::sync_status`).
+    pub fn store_sync_status(&self) -> Vec<RemoteSyncStatus> {
+        self.store.sync_status()
+    }
+
+    /// Writes the event counts and sequence numbers that changed since the last flush to the DB:
+    /// those of loaded canvases and those left behind by unloaded ones.
+    pub async fn flush_log_counters(&self, pool: &SqlitePool) {
+        let mut changed: Vec<(CanvasId, LogCounters)> = self.unflushed_counters.lock().unwrap().drain().collect();
+        let canvases: Vec<(CanvasId, SharedCanvas)> = self
+            .inner
+            .read()
+            .await
+            .iter()
+            .map(|(canvas_uuid, canvas)| (*canvas_uuid, canvas.clone()))
+            .collect();
+        for (canvas_uuid, canvas) in canvases {
+            let canvas_state = canvas.read().await;
+            let counters = canvas_state.log_counters();
+            if std::mem::replace(&mut *canvas_state.stored_counters.lock().unwrap(), counters) != counters {
+                changed.push((canvas_uuid, counters));
+            }
+        }
+
+        for (canvas_uuid, counters) in changed {
+            let event_count = counters.event_count as i64;
+            let last_seq = counters.last_seq as i64;
+            if let Err(e) = query!(
+                "UPDATE Canvas SET event_count = ?, last_seq = ? WHERE canvas_id = ?",
+                event_count,
+                last_seq,
+                canvas_uuid
+            )
+            .execute(pool)
+            .await
+            {
+                tracing::error!("Failed to store the log counters of canvas {}: {}", canvas_uuid, e);
+                // Retried with the next flush, unless newer counters are already waiting
+                self.unflushed_counters.lock().unwrap().entry(canvas_uuid).or_insert(counters);
+            }
+        }
+    }
+
+    /// Changes the coalescing mode of a loaded canvas. Events already buffered are still flushed.
+    pub async fn set_coalesce(&self, canvas_uuid: &CanvasId, coalesce: Option<bool>) {
+        if let Some(mut canvas_state) = self.write_canvas(canvas_uuid).await {
+            canvas_state.coalesce = coalesce.unwrap_or(self.coalesce.enabled_by_default);
+        }
+    }
+
+    /// Relays an ephemeral message (e.g. a cursor position) to all other subscribers
+    /// of a canvas. The message is built by `stamp` from the sender's user id and
+    /// display name, and is never written to the event file.
+    pub async fn relay_ephemeral(
+        &self,
+        sender_id: i64,
+        sender_connection: &Uuid,
+        canvas_uuid: &CanvasId,
+        stamp: impl FnOnce(i64, String) -> ServerMessage,
+    ) {
+        let Some(canvas_state) = self.read_canvas(canvas_uuid).await else {
+            tracing::debug!("Ephemeral message for canvas {} with no active manager entry. Dropping.", canvas_uuid);
+            return;
+        };
+
+        // Only connections subscribed to the canvas may relay to it
+        let Some(sender_info) = canvas_state.subscribers.get(sender_connection) else {
+            tracing::debug!("Connection {} is not subscribed to canvas {}. Dropping ephemeral message.", sender_connection, canvas_uuid);
+            return;
+        };
+
+        let permission = self.permissions.permission_level(sender_id, canvas_uuid).await;
+
+        if !can_draw(&permission, canvas_state.is_moderated) {
+            tracing::debug!(
+                "User {} denied ephemeral relay on canvas {}, their permission level is {}",
+                sender_id,
+                canvas_uuid,
+                permission
+            );
+            return;
+        }
+
+        let message = stamp(sender_id, sender_info.display_name.clone());
+
+        // Ephemeral messages are best effort: relays drop them for clients that can't keep up
+        canvas_state.publish(CanvasBroadcast::new(&message, Recipients::AllExceptConnection(*sender_connection)));
+    }
+
+    /// Handles an incoming chat message: validates it, appends it to the canvas chat log
+    /// and broadcasts it to all subscribers. Anyone with access to the canvas may chat,
+    /// regardless of its moderation state.
+    pub async fn handle_chat(
+        &self,
+        sender_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+        text: &str,
+    ) {
+        let text = text.trim();
+        if text.is_empty() {
+            connection.notify_client("Chat message cannot be empty.").await;
+            return;
+        }
+        if text.chars().count() > MAX_CHAT_MESSAGE_CHARS {
+            connection
+                .notify_client(&format!(
+                    "Chat message is too long (max {} characters).",
+                    MAX_CHAT_MESSAGE_CHARS
+                ))
+                .await;
+            return;
+        }
+
+        let Ok(text) = self.content_filter.apply(text) else {
+            tracing::info!("Content filter rejected a chat message of user {} on canvas {}", sender_id, canvas_uuid);
+            connection
+                .send_error(
+                    "content_filtered",
+                    "The message contains blocked words.",
+                    Some(serde_json::json!({ "canvasId": canvas_uuid })),
+                )
+                .await;
+            return;
+        };
+
+        let permission = self.permissions.permission_level(sender_id, canvas_uuid).await;
+
+        if permission.is_empty() {
+            tracing::warn!(
+                "User {} tried to chat on canvas {} without permission",
+                sender_id,
+                canvas_uuid
+            );
+            return;
+        }
+
+        let Some(canvas_state) = self.read_canvas(canvas_uuid).await else {
+            tracing::warn!(
+                "Chat message received for canvas {} with no active manager entry. Dropping.",
+                canvas_uuid
+            );
+            return;
+        };
+
+        let Some(sender_info) = canvas_state.subscribers.get(&connection.id) else {
+            connection
+                .notify_client("Register for the canvas before sending chat messages.")
+                .await;
+            return;
+        };
+
+        let chat_message = ChatMessage {
+            user_id: sender_id,
+            display_name: sender_info.display_name.clone(),
+            text,
+            timestamp: jsonwebtoken::get_current_timestamp(),
+        };
+
+        // Persist before broadcasting so every delivered message is in the log
+        let lock_guard = canvas_state.chat_mutex.lock().await;
+        if let Err(e) = append_chat_message(canvas_uuid, &chat_message).await {
+            tracing::error!("Failed to write chat message for canvas {}: {}", canvas_uuid, e);
+            connection.notify_client("Failed to send chat message.").await;
+            return;
+        }
+        drop(lock_guard);
+
+        let message = ServerMessage::Chat {
+            canvas_id: canvas_uuid.to_string(),
+            chat: chat_message,
+        };
+
+        canvas_state.publish(CanvasBroadcast::new(&message, Recipients::All));
+    }
+
+    /// Flips the moderation state of a canvas and returns the new state. The DB is updated first;
+    /// only once that succeeded does the in-memory flag of a loaded canvas follow and its subscribers
+    /// get the new state, so the server never enforces a state the DB does not have.
+    /// Canvases that are not loaded are only changed in the DB. Moderators, owners and co-owners only.
+    pub async fn toggle_moderated(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+    ) -> Result<bool, AppendEventsError> {
+        if !can_moderate(permission) {
+            tracing::warn!(
+                "User {} denied moderation toggle on canvas {} (permission: {})",
+                user_id,
+                canvas_uuid,
+                permission
+            );
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        // The write lock of a loaded canvas keeps others from toggling meanwhile
+        let mut canvas_state = self.write_canvas(canvas_uuid).await;
+        let moderated = match &canvas_state {
+            Some(canvas_state) => canvas_state.is_moderated,
+            None => Self::get_canvas_info(pool, canvas_uuid).await?.is_moderated,
+        };
+
+        let new_state = !moderated;
+        let action = if new_state { ModerationAction::ModerationOn } else { ModerationAction::ModerationOff };
+        query!("UPDATE Canvas SET moderated = ? WHERE canvas_id = ?", new_state, canvas_uuid)
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to update moderated state for canvas {} in DB: {}", canvas_uuid, e);
+                AppendEventsError::Storage(e.to_string())
+            })?;
+        tracing::info!("User {} toggled moderation for canvas {} -> {}", user_id, canvas_uuid, new_state);
+
+        // Only now flip the flag of a loaded canvas and tell its subscribers who toggled it
+        if let Some(canvas_state) = canvas_state.as_mut() {
+            canvas_state.is_moderated = new_state;
+            let display_name = self.permissions.display_name(user_id).await.unwrap_or_default();
+            let msg = ServerMessage::Moderated {
+                canvas_id: canvas_uuid.to_string(),
+                moderated: new_state,
+                slow_mode_ms: canvas_state.slow_mode_ms,
+                changed_by: Some(PresenceEntry { user_id, display_name }),
+            };
+            // Publishing never waits on clients, so it can happen under the canvas lock
+            canvas_state.publish(CanvasBroadcast::new(&msg, Recipients::All));
+
+            // Record the toggle in the log, so replays show when moderation changed.
+            // Canvases that are not loaded are not loaded just for that.
+            Self::record_action(canvas_state, canvas_uuid, action, user_id, None).await;
+        }
+        drop(canvas_state);
+
+        let details = serde_json::json!({});
+        moderation_log::record(pool, canvas_uuid, Some(user_id), action, None, &details).await;
+        Ok(new_state)
+    }
+
+    /// Handles a `toggleModerated` command from a WebSocket client. The sender always gets the resulting
+    /// state, even if the canvas is not loaded, or an error.
+    pub async fn toggle_moderated_state(
+        &self,
+        state: &AppState,
+        user_id: i64,
+        connection: &IdentifiableWebSocket,
+        canvas_uuid: &CanvasId,
+    ) {
+        let permission = self.permissions.permission_level(user_id, canvas_uuid).await;
+        let details = serde_json::json!({ "canvasId": canvas_uuid });
+
+        match self.toggle_moderated(&state.pool, &permission, user_id, canvas_uuid).await {
+            Ok(moderated) => {
+                let display_name = self.permissions.display_name(user_id).await.unwrap_or_default();
+                let slow_mode_ms = match self.read_canvas(canvas_uuid).await {
+                    Some(canvas_state) => canvas_state.slow_mode_ms,
+                    None => Self::get_canvas_info(&state.pool, canvas_uuid).await.map_or(0, |info| info.slow_mode_ms),
+                };
+                let msg = ServerMessage::Moderated {
+                    canvas_id: canvas_uuid.to_string(),
+                    moderated,
+                    slow_mode_ms,
+                    changed_by: Some(PresenceEntry { user_id, display_name }),
+                };
+                if let Err(e) = connection.send_msg(&msg).await {
+                    tracing::error!("Failed to confirm moderation toggle to client {}: {}", connection.id, e);
+                }
+            }
+            Err(AppendEventsError::Forbidden) => {
+                connection
+                    .send_error("moderation_toggle_not_allowed", "Only moderators can toggle moderation.", Some(details))
+                    .await;
+            }
+            Err(AppendEventsError::NotFound) => {
+                connection
+                    .notify_client(&format!("Canvas ID '{}' is invalid or does not exist.", canvas_uuid))
+                    .await;
+            }
+            Err(_) => {
+                connection
+                    .send_error("moderation_toggle_failed", "The moderation state could not be changed.", Some(details))
+                    .await;
+            }
+        }
+    }
+
+    /// Clears a canvas for everyone by appending a `clear` system event. The log is kept as is,
+    /// but histories start at the last clear (exports and replays still see the whole log).
+    /// Coalesced events waiting for their flush are written before the clear.
+    /// Owners and co-owners only; resolves once the clear is written.
+    pub async fn clear_canvas(
+        &self,
+        pool: &SqlitePool,
+        permission: &str,
+        user_id: i64,
+        canvas_uuid: &CanvasId,
+    ) -> Result<(), AppendEventsError> {
+        if !matches!(permission, "O" | "C") {
+            tracing::warn!("User {} tried to clear canvas {} without permission", user_id, canvas_uuid);
+            return Err(AppendEventsError::Forbidden);
+        }
+
+        let canvas_state = self.read_loaded(pool, canvas_uuid).await?;
+        self.write_pending(canvas_uuid, &canvas_state).await;
+        let queued = Self::record_system_event(&canvas_state, canvas_uuid, event_log::CLEAR_ACTION, user_id, None).await;
+        let unsubscribed = canvas_state.subscribers.is_empty();
+        drop(canvas_state);
+        if unsubscribed {
+            self.release_if_unsubscribed(canvas_uuid).await;
+        }
+
+        queued?
+            .await
+            .unwrap_or_else(|_| Err("The canvas writer stopped.".to_string()))
+            .map_err(AppendEventsError::Storage)?;
+        tracing::info!("User {} cleared canvas {}", user_id, canvas_uuid);
+        let details = serde_json::json!({});
+        moderation_log::record(pool, canvas_uuid, Some(user_id), ModerationAction::Clear, None, &details).await;
+        Ok(())
+    }
+
+    /// Queues a system event recording an action on a canvas, on the user `target` if given.
+    /// It is broadcast like drawn events once written.
+    async fn record_system_event(
+        canvas_state: &CanvasState,
+        canvas_uuid: &CanvasId,
+        action: &str,
+        by: i64,
+        target: Option<i64>,
+    ) -> Result<WriteAck, AppendEventsError> {
+        let lock_guard = canvas_state.file_mutex.lock().await;
+        let queued = canvas_state
+            .queue_write(
+                vec![event_log::system_event(action, by, target)],
+                |events| {
+                    let message = ServerMessage::Events {
+                        canvas_id: canvas_uuid.to_string(),
+                        events_for_canvas: events.to_vec(),
+                    };
+                    CanvasBroadcast::new(&message, Recipients::All)
+                },
+                false,
+            )
+            .await;
+        drop(lock_guard);
+        queued
+    }
+
+    /// Records a moderation action in the log, so replays show it. The action already took effect,
+    /// so a failure to queue the record is only logged.
+    async fn record_action(
+        canvas_state: &CanvasState,
+        canvas_uuid: &CanvasId,
+        action: ModerationAction,
+        by: i64,
+        target: Option<i64>,
+    ) {
+        if let Err(e) = Self::record_system_event(canvas_state, canvas_uuid, action.as_str(), by, target).await {
+            tracing::warn!("Could not record {} on canvas {}: {:?}", action.as_str(), canvas_uuid, e);
+        }
+    }
+}
+
+/// Periodically removes ended bans and mutes from the DB. Loaded canvases ignore them already.
+pub async fn start_moderation_cleanup_task(pool: SqlitePool) {
+    let interval = Duration::from_secs(MODERATION_CLEANUP_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let now_ms = event_log::timestamp_ms() as i64;
+        match query!("DELETE FROM canvas_bans WHERE expires_at <= ?", now_ms).execute(&pool).await {
+            Ok(result) if result.rows_affected() > 0 => {
+                tracing::info!("Removed {} ended canvas bans", result.rows_affected());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to remove ended canvas bans: {}", e),
+        }
+        if let Err(e) = query!("DELETE FROM canvas_mutes WHERE until <= ?", now_ms).execute(&pool).await {
+            tracing::error!("Failed to remove ended canvas mutes: {}", e);
+        }
+    }
+}
+
+/// Periodically drops events held for review longer than the held events TTL.
+pub async fn start_held_events_expiry_task(manager: CanvasManager) {
+    let interval = Duration::from_secs(HELD_EVENTS_EXPIRY_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        manager.expire_held_events().await;
+    }
+}
+
+/// Periodically unloads canvases that stayed without subscribers for the idle TTL.
+pub async fn start_idle_sweep_task(manager: CanvasManager) {
+    let interval = Duration::from_secs(IDLE_SWEEP_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        manager.unload_idle().await;
+    }
+}
+
+/// Periodically evicts cached event logs while they exceed their total budget,
+/// since caches of loaded canvases keep growing as events are written.
+pub async fn start_cache_budget_task(manager: CanvasManager) {
+    let interval = Duration::from_secs(CACHE_BUDGET_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        manager.enforce_cache_budget().await;
+    }
+}
+
+/// Periodically writes the event counts and sequence numbers of the canvases to the DB.
+pub async fn start_log_counter_flush_task(manager: CanvasManager, pool: SqlitePool) {
+    let interval = Duration::from_secs(LOG_COUNTER_FLUSH_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        manager.flush_log_counters(&pool).await;
+    }
+}
+
+/// Periodically compresses the event logs of canvases nobody appended to for `idle_for`.
+pub async fn start_log_compression_task(manager: CanvasManager, pool: SqlitePool, idle_for: Duration) {
+    let interval = Duration::from_secs(COMPRESSION_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        tracing::debug!("running scheduled event log compression");
+        manager.compress_idle(&pool, idle_for).await;
+    }
+}
+
+/// Periodically compacts canvases whose logs consist mostly of deleted events.
+pub async fn start_compaction_task(manager: CanvasManager, pool: SqlitePool, min_garbage_ratio: f64) {
+    let interval = Duration::from_secs(COMPACTION_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        tracing::debug!("running scheduled event log compaction");
+        manager.compact_garbage_heavy(&pool, min_garbage_ratio).await;
+    }
+}
+
+/// Periodically removes archived event logs older than `retention`.
+pub async fn start_archive_gc_task(manager: CanvasManager, retention: Duration) {
+    let interval = Duration::from_secs(ARCHIVE_GC_INTERVAL_SECONDS);
+
+    loop {
+        manager.prune_archives(retention).await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        app::{build_state, test_app, test_app_with},
+        auth::Claims,
+        config::AppConfig,
+        content_filter::{DenylistFilter, FilterAction},
+        event_store::{canvases_dir, event_file_name, MemoryEventStore, WriteFault},
+        permission_source::MemoryPermissions,
+        tests::{claims_of, connect, create_canvas, eventually, receive_until, register, set_permission},
+    };
+
+    fn stroke(id: &str) -> serde_json::Value {
+        json!({ "type": "shapeAdded", "shape": { "id": id, "borderColor": "black", "from": { "x": 1, "y": 2 }, "to": { "x": 3, "y": 4 } } })
+    }
+
+    /// A manager on the in-memory event store and permissions, without disk or database.
+    struct MemoryManager {
+        manager: CanvasManager,
+        permissions: Arc<MemoryPermissions>,
+        store: MemoryEventStore,
+        /// Never connected, its database does not exist: any query fails the test.
+        pool: SqlitePool,
+    }
+
+    async fn memory_manager() -> MemoryManager {
+        let permissions = Arc::new(MemoryPermissions::default());
+        let store = MemoryEventStore::default();
+        let manager = CanvasManager::new(
+            Arc::new(WsMetrics::new(false)),
+            Arc::new(store.clone()),
+            permissions.clone(),
+            ReviewQueue::new("/nonexistent/canvases".into()),
+            Arc::new(DenylistFilter::new(None, FilterAction::Reject).await),
+            CanvasManagerConfig::default(),
+        );
+        let pool = SqlitePool::connect_lazy("sqlite:///nonexistent/db.sqlite").unwrap();
+        MemoryManager { manager, permissions, store, pool }
+    }
+
+    impl MemoryManager {
+        /// Puts a new, empty canvas in memory as if it had been loaded from the DB.
+        async fn load(&self, is_moderated: bool) -> CanvasId {
+            let canvas_uuid = CanvasId::random();
+            let info =
+                CanvasDBInfo { is_moderated, coalesce_events: Some(false), event_count: 0, last_seq: 0, slow_mode_ms: 0 };
+            let metrics = self.manager.metrics.clone();
+            let cache = EventCache::new(Some(Vec::new()), 0, self.manager.event_cache.max_bytes, 0, metrics);
+            let moderation = CanvasModeration { mutes: HashMap::new(), bans: HashMap::new(), announcement: None };
+            let mut canvas_state = CanvasState::new(info, 0, Some(0), false, cache, Vec::new(), moderation);
+            self.manager.start_writer(&canvas_uuid, &mut canvas_state);
+            self.manager.inner.write().await.insert(canvas_uuid, Arc::new(RwLock::new(canvas_state)));
+            canvas_uuid
+        }
+
+        /// A new connection of a user, registered to a canvas with the given permission.
+        async fn join(
+            &self,
+            canvas_uuid: CanvasId,
+            user_id: i64,
+            permission: &str,
+        ) -> (IdentifiableWebSocket, mpsc::Receiver<Message>) {
+            let (tx, rx) = mpsc::channel(128);
+            let connection = IdentifiableWebSocket::new(tx);
+            self.permissions.grant(user_id, canvas_uuid, permission);
+            self.permissions.connect(user_id, connection.clone());
+            self.manager
+                .register(&self.pool, canvas_uuid, user_id, connection.clone(), false, HistoryOptions::default())
+                .await;
+            (connection, rx)
+        }
+
+        async fn subscribers(&self, canvas_uuid: &CanvasId) -> usize {
+            self.manager.read_canvas(canvas_uuid).await.unwrap().subscribers.len()
+        }
+    }
+
+    /// The ids of the strokes in the history and live messages a connection received, in order.
+    fn drawn_ids(received: &[String]) -> Vec<String> {
+        let mut ids = Vec::new();
+        for text in received {
+            let message: serde_json::Value = serde_json::from_str(text).unwrap();
+            if matches!(message["type"].as_str(), Some("history" | "events")) {
+                let events = message["eventsForCanvas"].as_array().unwrap();
+                ids.extend(events.iter().filter_map(|event| event["shape"]["id"].as_str().map(str::to_string)));
+            }
+        }
+        ids
+    }
+
+    /// Drains a connection's messages in the background until one mentions `needle`, so its channel never fills up.
+    fn receive_in_background(mut rx: mpsc::Receiver<Message>, needle: String) -> JoinHandle<Vec<String>> {
+        tokio::spawn(async move { receive_until(&mut rx, &needle).await })
+    }
+
+    #[tokio::test]
+    async fn registering_and_unregistering_edge_cases() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+
+        // Without a permission the connection is turned away
+        let (outsider, mut outsider_rx) = fakes.join(canvas_uuid, 1, "").await;
+        receive_until(&mut outsider_rx, "do not have permission").await;
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 0);
+        assert!(fakes.manager.subscriptions(outsider.id).await.is_empty());
+
+        // Registering twice keeps a single subscription
+        let (member, _member_rx) = fakes.join(canvas_uuid, 2, "W").await;
+        fakes.manager.register(&fakes.pool, canvas_uuid, 2, member.clone(), false, HistoryOptions::default()).await;
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 1);
+        assert_eq!(fakes.manager.subscriptions(member.id).await, HashSet::from([canvas_uuid]));
+
+        // Unknown connections and canvases that aren't loaded are left alone
+        assert!(!fakes.manager.unregister_connection(&canvas_uuid, &outsider.id).await);
+        assert!(!fakes.manager.unregister_connection(&CanvasId::random(), &member.id).await);
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 1);
+
+        // Only the first unregistration removes the connection; the idle canvas stays loaded
+        assert!(fakes.manager.unregister_connection(&canvas_uuid, &member.id).await);
+        assert!(!fakes.manager.unregister_connection(&canvas_uuid, &member.id).await);
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 0);
+        assert!(fakes.manager.subscriptions(member.id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_with_several_tabs_join_and_leave_once() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let (_observer, mut observer_rx) = fakes.join(canvas_uuid, 1, "W").await;
+        let (joined, left) = (r#""joined":{"userId":2,"#, r#""left":{"userId":2,"#);
+        // The users present, and how many tabs user 2 has open
+        let users_by_tabs = || async {
+            let canvas_state = fakes.manager.read_canvas(&canvas_uuid).await.unwrap();
+            let present: Vec<i64> = canvas_state.presence_list().iter().map(|entry| entry.user_id).collect();
+            let tabs = canvas_state.subscribers_by_user.get(&2).map_or(0, HashSet::len);
+            (present, tabs)
+        };
+
+        // Only the first tab joins; another user joining marks where the observer's messages end
+        let (first, _first_rx) = fakes.join(canvas_uuid, 2, "W").await;
+        let (second, _second_rx) = fakes.join(canvas_uuid, 2, "W").await;
+        let (third, _third_rx) = fakes.join(canvas_uuid, 2, "W").await;
+        let (_marker, _marker_rx) = fakes.join(canvas_uuid, 3, "W").await;
+        let received = receive_until(&mut observer_rx, r#""joined":{"userId":3,"#).await;
+        assert_eq!(received.iter().filter(|text| text.contains(joined)).count(), 1);
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 5);
+        assert_eq!(users_by_tabs().await, (vec![1, 2, 3], 3));
+        let online = fakes.manager.online_users(&canvas_uuid, true).await;
+        let user = online.iter().find(|user| user.user_id == 2).unwrap();
+        assert_eq!(user.connection_count, Some(3));
+
+        // Closing tabs keeps the user present while one is left
+        assert!(fakes.manager.unregister_connection(&canvas_uuid, &first.id).await);
+        assert!(fakes.manager.unregister_connection(&canvas_uuid, &second.id).await);
+        assert_eq!(users_by_tabs().await, (vec![1, 2, 3], 1));
+        let (fourth, _fourth_rx) = fakes.join(canvas_uuid, 2, "W").await;
+        let (_marker, _marker_rx) = fakes.join(canvas_uuid, 4, "W").await;
+        let received = receive_until(&mut observer_rx, r#""joined":{"userId":4,"#).await;
+        assert!(!received.iter().any(|text| text.contains(joined) || text.contains(left)));
+
+        // Unregistering the user closes every remaining tab and announces one departure
+        let removed = fakes.manager.unregister_user(&canvas_uuid, 2).await;
+        let removed: HashSet<Uuid> = removed.iter().map(|connection| connection.id).collect();
+        assert_eq!(removed, HashSet::from([third.id, fourth.id]));
+        assert_eq!(users_by_tabs().await, (vec![1, 3, 4], 0));
+        assert!(!fakes.manager.read_canvas(&canvas_uuid).await.unwrap().subscribers_by_user.contains_key(&2));
+        for tab in [&first, &second, &third, &fourth] {
+            assert!(fakes.manager.subscriptions(tab.id).await.is_empty());
+        }
+        let (_marker, _marker_rx) = fakes.join(canvas_uuid, 5, "W").await;
+        let received = receive_until(&mut observer_rx, r#""joined":{"userId":5,"#).await;
+        assert_eq!(received.iter().filter(|text| text.contains(left)).count(), 1);
+        assert!(fakes.manager.unregister_user(&canvas_uuid, 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn moderation_gates_who_may_draw() {
+        let fakes = memory_manager().await;
+        let append = |canvas_uuid: CanvasId, permission: &'static str| {
+            let fakes = &fakes;
+            async move {
+                let events = json!([stroke(permission)]);
+                fakes.manager.append_events(&fakes.pool, permission, 1, &canvas_uuid, events, None).await
+            }
+        };
+
+        let open = fakes.load(false).await;
+        for permission in ["", "R"] {
+            let result = append(open, permission).await;
+            assert!(matches!(result, Err(AppendEventsError::Forbidden)), "{:?}", permission);
+        }
+        for permission in ["W", "V", "M"] {
+            append(open, permission).await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        }
+
+        // On a moderated canvas only moderators, owners and co-owners draw directly
+        let moderated = fakes.load(true).await;
+        for permission in ["", "R", "V"] {
+            let result = append(moderated, permission).await;
+            assert!(matches!(result, Err(AppendEventsError::Forbidden)), "{:?}", permission);
+        }
+        for permission in ["M", "O", "C"] {
+            append(moderated, permission).await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        }
+        assert_eq!(fakes.store.log(&moderated.to_string()).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_skip_the_sending_connection() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let (sender, mut sender_rx) = fakes.join(canvas_uuid, 1, "W").await;
+        let (_same_user, mut same_user_rx) = fakes.join(canvas_uuid, 1, "W").await;
+        let (other, mut other_rx) = fakes.join(canvas_uuid, 2, "W").await;
+
+        let events = json!([stroke("stroke-1")]);
+        fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, events, Some(sender.id)).await.unwrap();
+        // The sender's other tab gets it too, only the sending connection is skipped
+        receive_until(&mut same_user_rx, "stroke-1").await;
+        receive_until(&mut other_rx, "stroke-1").await;
+
+        let events = json!([stroke("stroke-2")]);
+        fakes.manager.append_events(&fakes.pool, "W", 2, &canvas_uuid, events, Some(other.id)).await.unwrap();
+        let received = receive_until(&mut sender_rx, "stroke-2").await;
+        assert!(!received.iter().any(|text| text.contains("stroke-1")), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn drawing_events_reach_every_connection_but_the_senders() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "painter@example.com", "Painter").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Broadcast").await;
+        let canvas_id: CanvasId = canvas_id.parse().unwrap();
+        let claims = claims_of(&state, "painter@example.com").await;
+        let user_id = claims.user_id;
+
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let (connection, rx) = connect(&state, &claims).await;
+            state
+                .canvas_manager
+                .register(&state.pool, canvas_id, user_id, connection.clone(), false, HistoryOptions::default())
+                .await;
+            connections.push((connection, rx));
+        }
+
+        let sender = connections[0].0.id;
+        state
+            .canvas_manager
+            .append_events(&state.pool, "O", user_id, &canvas_id, json!([stroke("stroke-1")]), Some(sender))
+            .await
+            .unwrap();
+        for (_, rx) in &mut connections[1..] {
+            receive_until(rx, "stroke-1").await;
+        }
+
+        // Messages arrive in order, so once the sender sees the next stroke it would have seen the first
+        let second_sender = connections[1].0.id;
+        state
+            .canvas_manager
+            .append_events(&state.pool, "O", user_id, &canvas_id, json!([stroke("stroke-2")]), Some(second_sender))
+            .await
+            .unwrap();
+        let received = receive_until(&mut connections[0].1, "stroke-2").await;
+        assert!(!received.iter().any(|text| text.contains("stroke-1")));
+    }
+
+    #[tokio::test]
+    async fn the_sender_gets_an_ack_once_its_events_are_written() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "painter@example.com", "Painter").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Acked").await;
+        let canvas_id: CanvasId = canvas_id.parse().unwrap();
+        let claims = claims_of(&state, "painter@example.com").await;
+        let (connection, mut rx) = connect(&state, &claims).await;
+        state
+            .canvas_manager
+            .register(&state.pool, canvas_id, claims.user_id, connection.clone(), false, HistoryOptions::default())
+            .await;
+
+        let events = WebSocketEvents { canvas_id, events_for_canvas: json!([stroke("stroke-1"), stroke("stroke-2")]) };
+        state.canvas_manager.handle_event(&state, claims.user_id, &connection, events).await;
+        let received = receive_until(&mut rx, r#""type":"ack""#).await;
+        assert_eq!(
+            received.last().unwrap(),
+            &format!(r#"{{"type":"ack","canvasId":"{}","count":2}}"#, canvas_id)
+        );
+
+        // Sent after the write, so the events are in the log by now
+        let log = std::fs::read_to_string(canvases_dir(&state.data_dir).join(event_file_name(&canvas_id.to_string()))).unwrap();
+        assert!(log.contains("stroke-1") && log.contains("stroke-2"));
+    }
+
+    /// An app whose canvases unload as soon as nobody is subscribed, so every access is a cold load,
+    /// and a canvas of its owner.
+    async fn cold_app(max_cache_bytes: u64) -> (AppState, CanvasId, Claims) {
+        let mut config = AppConfig::test_default();
+        config.canvas_manager.idle_ttl = Duration::ZERO;
+        config.canvas_manager.event_cache.max_bytes = max_cache_bytes;
+        let (app, state) = test_app_with(config).await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Cold").await;
+        let claims = claims_of(&state, "owner@example.com").await;
+        (state, canvas_id.parse().unwrap(), claims)
+    }
+
+    async fn draw(state: &AppState, canvas_uuid: &CanvasId, user_id: i64, ids: &[String]) {
+        let events = serde_json::Value::Array(ids.iter().map(|id| stroke(id)).collect());
+        let appended = state.canvas_manager.append_events(&state.pool, "O", user_id, canvas_uuid, events, None).await;
+        appended.unwrap().persisted.unwrap().await.unwrap().unwrap();
+    }
+
+    fn ids(prefix: &str, count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{}-{}", prefix, i)).collect()
+    }
+
+    /// The cached events, clear and counters of a loaded canvas.
+    async fn cached_state(state: &AppState, canvas_uuid: &CanvasId) -> (Option<Vec<serde_json::Value>>, u64, u64, u64) {
+        let canvas_state = state.canvas_manager.read_canvas(canvas_uuid).await.unwrap();
+        let cache = canvas_state.cache.lock().unwrap();
+        (
+            cache.events.as_deref().cloned(),
+            cache.clear_seq,
+            canvas_state.event_count.load(Ordering::SeqCst),
+            canvas_state.next_seq.load(Ordering::SeqCst),
+        )
+    }
+
+    #[tokio::test]
+    async fn cold_loads_start_histories_at_the_last_clear() {
+        // A log of 23 strokes is well over 1 KiB, but fits the default budget
+        for max_cache_bytes in [1024, EventCacheConfig::default().max_bytes] {
+            let (state, canvas_uuid, claims) = cold_app(max_cache_bytes).await;
+            draw(&state, &canvas_uuid, claims.user_id, &ids("before", 20)).await;
+            state.canvas_manager.clear_canvas(&state.pool, "O", claims.user_id, &canvas_uuid).await.unwrap();
+            draw(&state, &canvas_uuid, claims.user_id, &ids("after", 2)).await;
+            assert!(state.canvas_manager.read_canvas(&canvas_uuid).await.is_none(), "the canvas unloaded");
+
+            let (connection, mut rx) = connect(&state, &claims).await;
+            state
+                .canvas_manager
+                .register(&state.pool, canvas_uuid, claims.user_id, connection, false, HistoryOptions::default())
+                .await;
+            let received = receive_until(&mut rx, "after-1").await;
+            assert!(!received.iter().any(|text| text.contains("before-")), "{:?}", received);
+
+            let log = state.canvas_manager.store.read_all(&canvas_uuid.to_string()).await.unwrap();
+            let (cached, clear_seq, event_count, next_seq) = cached_state(&state, &canvas_uuid).await;
+            assert_eq!(cached.is_some(), encoded_len(&log) <= max_cache_bytes, "budget {}", max_cache_bytes);
+            if let Some(cached) = cached {
+                assert_eq!(cached, log);
+            }
+            assert_eq!((clear_seq, event_count, next_seq), (21, 23, 24), "budget {}", max_cache_bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn ten_thousand_events_cleared_before_five_more_replay_six() {
+        for max_cache_bytes in [1024, EventCacheConfig::default().max_bytes] {
+            let (state, canvas_uuid, claims) = cold_app(max_cache_bytes).await;
+            for chunk in ids("before", 10_000).chunks(1000) {
+                draw(&state, &canvas_uuid, claims.user_id, chunk).await;
+            }
+            state.canvas_manager.clear_canvas(&state.pool, "O", claims.user_id, &canvas_uuid).await.unwrap();
+            draw(&state, &canvas_uuid, claims.user_id, &ids("after", 5)).await;
+
+            // The first registration loads the canvas, the second finds it loaded
+            for _ in 0..2 {
+                let (connection, mut rx) = connect(&state, &claims).await;
+                let history = HistoryOptions::default();
+                state.canvas_manager.register(&state.pool, canvas_uuid, claims.user_id, connection, false, history).await;
+                let mut replayed = Vec::new();
+                for text in receive_until(&mut rx, "historyComplete").await {
+                    let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if message["type"] == "history" {
+                        replayed.extend(message["eventsForCanvas"].as_array().unwrap().iter().cloned());
+                    }
+                }
+                assert_eq!(replayed.len(), 6, "budget {}", max_cache_bytes);
+                assert!(event_log::is_clear(&replayed[0]), "{}", replayed[0]);
+                let drawn: Vec<_> = replayed[1..].iter().map(|event| event["shape"]["id"].as_str().unwrap()).collect();
+                assert_eq!(drawn, ids("after", 5));
+            }
+
+            // The log itself keeps everything, e.g. for exports
+            let log = state.canvas_manager.store.read_all(&canvas_uuid.to_string()).await.unwrap();
+            assert_eq!(log.len(), 10_006);
+        }
+    }
+
+    #[tokio::test]
+    async fn cold_loads_after_compaction_match_the_rewritten_log() {
+        for max_cache_bytes in [1024, EventCacheConfig::default().max_bytes] {
+            let (state, canvas_uuid, claims) = cold_app(max_cache_bytes).await;
+            draw(&state, &canvas_uuid, claims.user_id, &ids("before", 20)).await;
+            state.canvas_manager.clear_canvas(&state.pool, "O", claims.user_id, &canvas_uuid).await.unwrap();
+            draw(&state, &canvas_uuid, claims.user_id, &ids("after", 3)).await;
+
+            // Deletes 22 (after-0) and 23 (after-1); the tombstone is 25
+            let (connection, mut rx) = connect(&state, &claims).await;
+            let register = |connection: IdentifiableWebSocket| {
+                let state = &state;
+                async move {
+                    state
+                        .canvas_manager
+                        .register(&state.pool, canvas_uuid, claims.user_id, connection, false, HistoryOptions::default())
+                        .await
+                }
+            };
+            register(connection.clone()).await;
+            receive_until(&mut rx, "after-2").await;
+            state.canvas_manager.delete_events(claims.user_id, &connection, &canvas_uuid, vec![22, 23]).await;
+            receive_until(&mut rx, "targets").await;
+            state.canvas_manager.unregister_connection(&canvas_uuid, &connection.id).await;
+
+            // 25 events, 3 of them garbage: renumbered from 1, the clear becomes 21 and after-2 becomes 22
+            let stats = state.canvas_manager.compact(&state.pool, &canvas_uuid).await.unwrap();
+            assert_eq!((stats.events_before, stats.events_after), (25, 22));
+
+            let (connection, mut rx) = connect(&state, &claims).await;
+            register(connection).await;
+            let received = receive_until(&mut rx, "after-2").await;
+            assert!(!received.iter().any(|text| text.contains("before-") || text.contains("after-0")), "{:?}", received);
+
+            let log = state.canvas_manager.store.read_all(&canvas_uuid.to_string()).await.unwrap();
+            assert_eq!(log.len(), 22);
+            let (cached, clear_seq, event_count, next_seq) = cached_state(&state, &canvas_uuid).await;
+            assert_eq!(cached.is_some(), encoded_len(&log) <= max_cache_bytes, "budget {}", max_cache_bytes);
+            if let Some(cached) = cached {
+                assert_eq!(cached, log);
+            }
+            // Sequence numbers continue after the highest ever taken, not the rewritten log
+            assert_eq!((clear_seq, event_count, next_seq), (21, 22, 26), "budget {}", max_cache_bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_keep_growing_across_restarts() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Restarted").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let user_id = claims_of(&state, "owner@example.com").await.user_id;
+        let (config, pool) = (state.config.clone(), state.pool.clone());
+        let log_path = canvases_dir(&state.data_dir).join(event_file_name(&canvas_id));
+        let logged_seqs = || -> Vec<u64> {
+            let log = std::fs::read_to_string(&log_path).unwrap();
+            log.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["_seq"].as_u64().unwrap()).collect()
+        };
+
+        // A graceful shutdown stores the last sequence number
+        draw(&state, &canvas_uuid, user_id, &ids("before", 3)).await;
+        state.canvas_manager.flush_all().await;
+        state.canvas_manager.flush_log_counters(&pool).await;
+        drop(state);
+        assert_eq!(logged_seqs(), vec![1, 2, 3]);
+        // Keep only the first line, as a compaction dropping deleted strokes would
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        std::fs::write(&log_path, format!("{}\n", log.lines().next().unwrap())).unwrap();
+
+        // Numbering resumes behind the stored number, not the log's highest
+        let restarted = build_state(config.clone(), pool.clone()).await;
+        draw(&restarted, &canvas_uuid, user_id, &ids("after-restart", 2)).await;
+        assert_eq!(logged_seqs(), vec![1, 4, 5]);
+
+        // A crash before the next flush leaves the stored number behind, the log has the written ones
+        drop(restarted);
+        let recovered = build_state(config, pool).await;
+        draw(&recovered, &canvas_uuid, user_id, &ids("after-crash", 1)).await;
+        assert_eq!(logged_seqs(), vec![1, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn moderation_actions_are_recorded_in_the_log() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, cookie) = create_canvas(&app, &cookie, "Moderated").await;
+        register(&app, "member@example.com", "Member").await;
+        let member_id = claims_of(&state, "member@example.com").await.user_id;
+        set_permission(&app, &cookie, &canvas_id, member_id, "W").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let owner_id = claims_of(&state, "owner@example.com").await.user_id;
+        let manager = &state.canvas_manager;
+
+        let (connection, _rx) = connect(&state, &claims_of(&state, "member@example.com").await).await;
+        manager.register(&state.pool, canvas_uuid, member_id, connection, false, HistoryOptions::default()).await;
+        manager.toggle_moderated(&state.pool, "O", owner_id, &canvas_uuid).await.unwrap();
+        for review in [HeldReview::Approve, HeldReview::Reject(None)] {
+            let appended = manager.append_events(&state.pool, "W", member_id, &canvas_uuid, json!([stroke("held")]), None);
+            let batch_id = appended.await.unwrap().held.unwrap();
+            manager.review_held_events(&state.pool, "O", owner_id, &canvas_uuid, &batch_id, review).await.unwrap();
+        }
+        for change in [MuteChange::Mute(None), MuteChange::Unmute] {
+            manager.set_mute(&state.pool, "O", owner_id, &canvas_uuid, member_id, change).await.unwrap();
+        }
+        // Unmuting or unbanning a user who isn't records nothing
+        manager.set_mute(&state.pool, "O", owner_id, &canvas_uuid, member_id, MuteChange::Unmute).await.unwrap();
+        // The member is still subscribed, so the ban also kicks them
+        for change in [BanChange::Ban(None, None), BanChange::Unban, BanChange::Unban] {
+            manager.set_ban(&state.pool, "O", owner_id, &canvas_uuid, member_id, change).await.unwrap();
+        }
+        // Writes are in order, so the records are in the log once this is
+        draw(&state, &canvas_uuid, owner_id, &ids("after", 1)).await;
+
+        let log = manager.store.read_all(&canvas_id).await.unwrap();
+        let recorded: Vec<_> = log
+            .iter()
+            .filter(|event| is_system_event(event))
+            .map(|event| (event["action"].as_str().unwrap(), event["by"].as_i64(), event["target"].as_i64()))
+            .collect();
+        let on_member = |action| (action, Some(owner_id), Some(member_id));
+        assert_eq!(
+            recorded,
+            [
+                ("moderation_on", Some(owner_id), None),
+                on_member("approve_held_events"),
+                on_member("reject_held_events"),
+                on_member("mute"),
+                on_member("unmute"),
+                on_member("ban"),
+                on_member("kick"),
+                on_member("unban"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn held_events_are_reviewed_while_their_author_is_disconnected() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, cookie) = create_canvas(&app, &cookie, "Moderated").await;
+        register(&app, "member@example.com", "Member").await;
+        let member_id = claims_of(&state, "member@example.com").await.user_id;
+        set_permission(&app, &cookie, &canvas_id, member_id, "W").await;
+        let member = claims_of(&state, "member@example.com").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let owner = claims_of(&state, "owner@example.com").await;
+        let manager = &state.canvas_manager;
+        let (watcher, mut watcher_rx) = connect(&state, &owner).await;
+        manager.register(&state.pool, canvas_uuid, owner.user_id, watcher, false, HistoryOptions::default()).await;
+        manager.toggle_moderated(&state.pool, "O", owner.user_id, &canvas_uuid).await.unwrap();
+
+        let (connection, _rx) = connect(&state, &member).await;
+        manager.register(&state.pool, canvas_uuid, member.user_id, connection.clone(), false, HistoryOptions::default()).await;
+        let mut batches = Vec::new();
+        for id in ["approved", "rejected"] {
+            let appended = manager.append_events(&state.pool, "W", member.user_id, &canvas_uuid, json!([stroke(id)]), None);
+            batches.push(appended.await.unwrap().held.unwrap());
+        }
+        // Gone without a trace: no subscription, no open connection to notify
+        assert!(manager.unregister_connection(&canvas_uuid, &connection.id).await);
+        state.socket_claims_manager.remove_connection(member.user_id, &connection).await;
+        assert!(state.socket_claims_manager.get_connections(member.user_id).await.is_empty());
+
+        let approved = manager.review_held_events(&state.pool, "O", owner.user_id, &canvas_uuid, &batches[0], HeldReview::Approve);
+        approved.await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        let rejected = HeldReview::Reject(Some("Off topic".to_string()));
+        manager.review_held_events(&state.pool, "O", owner.user_id, &canvas_uuid, &batches[1], rejected).await.unwrap();
+        assert!(manager.held_batches(&state.pool, "O", owner.user_id, &canvas_uuid).await.unwrap().is_empty());
+
+        // Written as the author's, and broadcast like any other stroke
+        receive_until(&mut watcher_rx, r#""id":"approved""#).await;
+        let log = manager.store.read_all(&canvas_id).await.unwrap();
+        let approved = log.iter().find(|event| event["shape"]["id"] == "approved").unwrap();
+        assert_eq!(approved["_uid"], member.user_id);
+        assert!(approved["_seq"].as_u64().is_some());
+        assert!(!log.iter().any(|event| event["shape"]["id"] == "rejected"));
+
+        // Back online, the author finds the approved stroke in the history
+        let (connection, mut rx) = connect(&state, &member).await;
+        manager.register(&state.pool, canvas_uuid, member.user_id, connection, false, HistoryOptions::default()).await;
+        assert_eq!(drawn_ids(&receive_until(&mut rx, "historyComplete").await), ["approved"]);
+    }
+
+    #[tokio::test]
+    async fn presentation_mode_comes_after_bans_and_mutes_and_before_moderation() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Presented").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let mut user_ids = HashMap::new();
+        for name in ["owner", "presenter", "muted", "banned", "moderator", "writer"] {
+            if name != "owner" {
+                register(&app, &format!("{}@example.com", name), name).await;
+            }
+            user_ids.insert(name, claims_of(&state, &format!("{}@example.com", name)).await.user_id);
+        }
+        let owner = claims_of(&state, "owner@example.com").await;
+        let manager = &state.canvas_manager;
+        let (watcher, _watcher_rx) = connect(&state, &owner).await;
+        manager.register(&state.pool, canvas_uuid, owner.user_id, watcher, false, HistoryOptions::default()).await;
+        let draw_as = |name: &'static str, permission: &'static str| {
+            let events = json!([stroke(name)]);
+            manager.append_events(&state.pool, permission, user_ids[name], &canvas_uuid, events, None)
+        };
+
+        manager.toggle_moderated(&state.pool, "O", owner.user_id, &canvas_uuid).await.unwrap();
+        let presenters = ["presenter", "muted", "banned"].map(|name| user_ids[name]).to_vec();
+        manager.set_presentation_mode("O", owner.user_id, &canvas_uuid, Some(presenters)).await.unwrap();
+        manager.set_mute(&state.pool, "O", owner.user_id, &canvas_uuid, user_ids["muted"], MuteChange::Mute(None)).await.unwrap();
+        let ban = BanChange::Ban(None, None);
+        manager.set_ban(&state.pool, "O", owner.user_id, &canvas_uuid, user_ids["banned"], ban).await.unwrap();
+
+        // Bans and mutes apply to presenters too
+        assert!(matches!(draw_as("banned", "W").await, Err(AppendEventsError::Banned(_))));
+        assert!(matches!(draw_as("muted", "W").await, Err(AppendEventsError::Muted(None))));
+        // Everyone but the owner and the presenters is turned away, rather than held, even moderators
+        assert!(matches!(draw_as("writer", "W").await, Err(AppendEventsError::Presenting)));
+        assert!(matches!(draw_as("moderator", "M").await, Err(AppendEventsError::Presenting)));
+        // Then moderation applies as usual: presenting writers are held, the owner draws directly
+        assert!(draw_as("presenter", "W").await.unwrap().held.is_some());
+        draw_as("owner", "O").await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+
+        // Writing doesn't end the presentation, ending moderation doesn't either
+        manager.toggle_moderated(&state.pool, "O", owner.user_id, &canvas_uuid).await.unwrap();
+        assert!(manager.read_canvas(&canvas_uuid).await.unwrap().presenters.is_some());
+        draw_as("presenter", "W").await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        assert!(matches!(draw_as("writer", "W").await, Err(AppendEventsError::Presenting)));
+
+        manager.set_presentation_mode("O", owner.user_id, &canvas_uuid, None).await.unwrap();
+        draw_as("writer", "W").await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        assert!(matches!(draw_as("muted", "W").await, Err(AppendEventsError::Muted(None))));
+    }
+
+    #[tokio::test]
+    async fn failed_moderation_toggles_leave_the_flag_untouched() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Toggled").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let owner = claims_of(&state, "owner@example.com").await;
+        let manager = &state.canvas_manager;
+        let (connection, mut rx) = connect(&state, &owner).await;
+        manager.register(&state.pool, canvas_uuid, owner.user_id, connection.clone(), false, HistoryOptions::default()).await;
+        receive_until(&mut rx, "historyComplete").await;
+        let stored = || async {
+            sqlx::query_scalar::<_, bool>("SELECT moderated FROM Canvas WHERE canvas_id = ?")
+                .bind(canvas_uuid)
+                .fetch_one(&state.pool)
+                .await
+                .unwrap()
+        };
+
+        // A poisoned table: the UPDATE fails, the pool keeps working
+        sqlx::query("CREATE TRIGGER poisoned BEFORE UPDATE OF moderated ON Canvas BEGIN SELECT RAISE(ABORT, 'poisoned'); END")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        manager.toggle_moderated_state(&state, owner.user_id, &connection, &canvas_uuid).await;
+        let received = receive_until(&mut rx, "moderation_toggle_failed").await;
+        assert!(!received.iter().any(|text| text.contains("changedBy")), "{:?}", received);
+        assert!(!manager.read_canvas(&canvas_uuid).await.unwrap().is_moderated);
+        assert!(!stored().await);
+        draw(&state, &canvas_uuid, owner.user_id, &ids("after", 1)).await;
+        let log = manager.store.read_all(&canvas_id).await.unwrap();
+        assert!(!log.iter().any(is_system_event), "{:?}", log);
+
+        // A closed pool fails every query
+        state.pool.close().await;
+        let result = manager.toggle_moderated(&state.pool, "O", owner.user_id, &canvas_uuid).await;
+        assert!(matches!(result, Err(AppendEventsError::Storage(_))), "{:?}", result);
+        assert!(!manager.read_canvas(&canvas_uuid).await.unwrap().is_moderated);
+    }
+
+    #[tokio::test]
+    async fn registrations_while_someone_draws_get_every_event_once() {
+        let fakes = Arc::new(memory_manager().await);
+        let canvas_uuid = fakes.load(false).await;
+        let strokes = ids("stroke", 200);
+        let last = strokes.last().unwrap().clone();
+
+        let writer = {
+            let (fakes, strokes) = (fakes.clone(), strokes.clone());
+            fakes.permissions.grant(1, canvas_uuid, "W");
+            tokio::spawn(async move {
+                for id in &strokes {
+                    let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, json!([stroke(id)]), None);
+                    appended.await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        // Each user joins mid-stream, one of them registering the same connection twice at once
+        let mut receivers = Vec::new();
+        for user_id in 2..12 {
+            let written = (user_id as u64 - 2) * 20;
+            while fakes.manager.read_canvas(&canvas_uuid).await.unwrap().event_count.load(Ordering::SeqCst) < written {
+                tokio::task::yield_now().await;
+            }
+            let (tx, rx) = mpsc::channel(128);
+            let connection = IdentifiableWebSocket::new(tx);
+            fakes.permissions.grant(user_id, canvas_uuid, "R");
+            let register = || {
+                fakes.manager.register(&fakes.pool, canvas_uuid, user_id, connection.clone(), false, HistoryOptions::default())
+            };
+            if user_id == 2 {
+                tokio::join!(register(), register());
+            } else {
+                register().await;
+            }
+            receivers.push(receive_in_background(rx, last.clone()));
+        }
+        writer.await.unwrap();
+
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 10);
+        for receiver in receivers {
+            // Whatever the history covered, live messages carry on from there without gaps or repeats
+            assert_eq!(drawn_ids(&receiver.await.unwrap()), strokes);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_joining_during_a_flood_see_every_sequence_number_once() {
+        const JOINERS: u64 = 20;
+        let fakes = Arc::new(memory_manager().await);
+        let canvas_uuid = fakes.load(false).await;
+        let strokes = ids("stroke", 1000);
+        let last = strokes.last().unwrap().clone();
+
+        // Messages of 5 strokes, queued back to back without waiting for the writes
+        let writer = {
+            let (fakes, strokes) = (fakes.clone(), strokes.clone());
+            tokio::spawn(async move {
+                let mut persisted = Vec::new();
+                for chunk in strokes.chunks(5) {
+                    let events = serde_json::Value::Array(chunk.iter().map(|id| stroke(id)).collect());
+                    let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, events, None).await;
+                    persisted.push(appended.unwrap().persisted.unwrap());
+                }
+                for persisted in persisted {
+                    persisted.await.unwrap().unwrap();
+                }
+            })
+        };
+
+        let joiners: Vec<_> = (0..JOINERS)
+            .map(|joiner| {
+                let (fakes, last) = (fakes.clone(), last.clone());
+                tokio::spawn(async move {
+                    let written = joiner * 1000 / JOINERS;
+                    while fakes.manager.read_canvas(&canvas_uuid).await.unwrap().event_count.load(Ordering::SeqCst) < written {
+                        tokio::task::yield_now().await;
+                    }
+                    let (_connection, rx) = fakes.join(canvas_uuid, joiner as i64 + 2, "R").await;
+                    receive_in_background(rx, last).await.unwrap()
+                })
+            })
+            .collect();
+        writer.await.unwrap();
+
+        for joiner in joiners {
+            let mut seqs = Vec::new();
+            for text in joiner.await.unwrap() {
+                let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if matches!(message["type"].as_str(), Some("history" | "events")) {
+                    let events = message["eventsForCanvas"].as_array().unwrap();
+                    seqs.extend(events.iter().map(|event| event["_seq"].as_u64().unwrap()));
+                }
+            }
+            assert_eq!(seqs, (1..=1000).collect::<Vec<u64>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_evict_connections_whose_receiver_is_gone() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let (_writer, mut writer_rx) = fakes.join(canvas_uuid, 1, "W").await;
+        let (gone, gone_rx) = fakes.join(canvas_uuid, 2, "R").await;
+        let (_other_tab, _other_tab_rx) = fakes.join(canvas_uuid, 2, "R").await;
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 3);
+
+        drop(gone_rx);
+        for id in ["stroke-1", "stroke-2"] {
+            let events = json!([stroke(id)]);
+            fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, events, None).await.unwrap();
+            receive_until(&mut writer_rx, id).await;
+        }
+        eventually("the dead connection to be evicted", || async { fakes.subscribers(&canvas_uuid).await == 2 }).await;
+
+        // Only the dead connection goes, the user's other tab stays
+        let connections = fakes.permissions.connections(2).await;
+        assert!(connections.len() == 1 && connections[0].id != gone.id);
+        assert!(fakes.manager.subscriptions(gone.id).await.is_empty());
+        assert!(fakes.manager.read_canvas(&canvas_uuid).await.unwrap().subscribers_by_user.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn a_hundred_subscribers_get_every_event_despite_a_stalled_one() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let strokes = ids("stroke", 300);
+        let last = strokes.last().unwrap().clone();
+        let mut receivers = Vec::new();
+        for user_id in 0..100 {
+            let (_connection, rx) = fakes.join(canvas_uuid, user_id, "R").await;
+            receivers.push(receive_in_background(rx, last.clone()));
+        }
+        // Never reads, so its channel is full long before the last stroke
+        let (_stalled, _stalled_rx) = fakes.join(canvas_uuid, 100, "R").await;
+
+        let started = Instant::now();
+        for id in &strokes {
+            let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, json!([stroke(id)]), None);
+            appended.await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+        }
+        for receiver in receivers {
+            assert_eq!(drawn_ids(&receiver.await.unwrap()), strokes);
+        }
+        // Generous for debug builds on a loaded machine; fan-out used to wait on each subscriber in turn
+        let elapsed = started.elapsed();
+        assert!(elapsed < Duration::from_secs(5), "fan-out of {} events took {:?}", strokes.len(), elapsed);
+
+        // The stalled subscriber missed messages but is still subscribed
+        assert!(fakes.manager.metrics.broadcast_failures.load(Ordering::SeqCst) > 0);
+        assert_eq!(fakes.subscribers(&canvas_uuid).await, 101);
+    }
+
+    #[tokio::test]
+    async fn cold_loads_waiting_on_the_database_leave_other_canvases_responsive() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (cold, cookie) = create_canvas(&app, &cookie, "Cold").await;
+        let (warm, _cookie) = create_canvas(&app, &cookie, "Warm").await;
+        let (cold, warm): (CanvasId, CanvasId) = (cold.parse().unwrap(), warm.parse().unwrap());
+        let claims = claims_of(&state, "owner@example.com").await;
+        let manager = &state.canvas_manager;
+        let (drawer, mut drawer_rx) = connect(&state, &claims).await;
+        manager.register(&state.pool, warm, claims.user_id, drawer.clone(), false, HistoryOptions::default()).await;
+
+        // The test pool has a single connection: holding it makes every query wait, like a slow database
+        let held = state.pool.acquire().await.unwrap();
+        let (cold_connection, mut cold_rx) = connect(&state, &claims).await;
+        let loading = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let history = HistoryOptions::default();
+                state.canvas_manager.register(&state.pool, cold, claims.user_id, cold_connection, false, history).await
+            }
+        });
+        eventually("the cold canvas to start loading", || async { manager.loading.lock().unwrap().contains_key(&cold) }).await;
+
+        // Registering, drawing and unregistering on the loaded canvas don't wait for the load
+        let responsive = async {
+            let (viewer, mut viewer_rx) = connect(&state, &claims).await;
+            manager.register(&state.pool, warm, claims.user_id, viewer.clone(), false, HistoryOptions::default()).await;
+            receive_until(&mut viewer_rx, "historyComplete").await;
+            let events = json!([stroke("while-loading")]);
+            manager.append_events(&state.pool, "O", claims.user_id, &warm, events, Some(drawer.id)).await.unwrap();
+            receive_until(&mut viewer_rx, "while-loading").await;
+            assert!(manager.unregister_connection(&warm, &viewer.id).await);
+        };
+        tokio::time::timeout(Duration::from_secs(2), responsive).await.expect("the loaded canvas stalled");
+        assert!(!loading.is_finished());
+
+        drop(held);
+        loading.await.unwrap();
+        receive_until(&mut cold_rx, "historyComplete").await;
+        assert_eq!(manager.read_canvas(&cold).await.unwrap().subscribers.len(), 1);
+        // The drawer still gets the other connections' events
+        manager.append_events(&state.pool, "O", claims.user_id, &warm, json!([stroke("after-load")]), None).await.unwrap();
+        receive_until(&mut drawer_rx, "after-load").await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_registrations_on_a_cold_canvas_load_it_once() {
+        const CONNECTIONS: usize = 20;
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Cold").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let claims = claims_of(&state, "owner@example.com").await;
+        let manager = &state.canvas_manager;
+        assert!(manager.canvas(&canvas_uuid).await.is_none());
+
+        // Holding the only pool connection keeps the first load in the DB until every registration waits on it
+        let held = state.pool.acquire().await.unwrap();
+        let barrier = Arc::new(tokio::sync::Barrier::new(CONNECTIONS + 1));
+        let mut registrations = Vec::new();
+        for _ in 0..CONNECTIONS {
+            let (connection, mut rx) = connect(&state, &claims).await;
+            let (state, barrier, user_id) = (state.clone(), barrier.clone(), claims.user_id);
+            registrations.push(tokio::spawn(async move {
+                barrier.wait().await;
+                let history = HistoryOptions::default();
+                state.canvas_manager.register(&state.pool, canvas_uuid, user_id, connection, false, history).await;
+                receive_until(&mut rx, "historyComplete").await;
+            }));
+        }
+        barrier.wait().await;
+        // The map holds the load slot once, and every registration a clone of it
+        let waiting = || async {
+            let slot = manager.loading.lock().unwrap().get(&canvas_uuid).map(Arc::strong_count);
+            slot == Some(CONNECTIONS + 1)
+        };
+        eventually("every registration to wait on the load", waiting).await;
+
+        drop(held);
+        for registration in registrations {
+            registration.await.unwrap();
+        }
+        assert_eq!(manager.read_canvas(&canvas_uuid).await.unwrap().subscribers.len(), CONNECTIONS);
+        assert_eq!(manager.metrics.canvas_loads_store.load(Ordering::SeqCst), 1);
+        assert!(manager.loading.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_slow_writer_only_holds_up_its_own_canvas() {
+        let fakes = memory_manager().await;
+        let (slow, fast) = (fakes.load(false).await, fakes.load(false).await);
+        let (_slow_viewer, mut slow_rx) = fakes.join(slow, 2, "R").await;
+        fakes.store.inject(&slow.to_string(), Some(WriteFault::Delay(Duration::from_secs(2))));
+
+        let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &slow, json!([stroke("slow")]), None).await;
+        let slow_write = tokio::spawn(appended.unwrap().persisted.unwrap());
+
+        let responsive = async {
+            let (viewer, mut viewer_rx) = fakes.join(fast, 2, "R").await;
+            receive_until(&mut viewer_rx, "historyComplete").await;
+            let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &fast, json!([stroke("fast")]), None).await;
+            appended.unwrap().persisted.unwrap().await.unwrap().unwrap();
+            receive_until(&mut viewer_rx, "fast").await;
+            assert!(fakes.manager.unregister_connection(&fast, &viewer.id).await);
+
+            // The slow canvas itself still takes registrations while its writer is busy
+            let (late, mut late_rx) = fakes.join(slow, 3, "R").await;
+            receive_until(&mut late_rx, "historyComplete").await;
+            assert!(fakes.manager.unregister_connection(&slow, &late.id).await);
+        };
+        tokio::time::timeout(Duration::from_millis(1500), responsive).await.expect("the other canvas stalled");
+        assert!(!slow_write.is_finished());
+
+        slow_write.await.unwrap().unwrap().unwrap();
+        receive_until(&mut slow_rx, "slow").await;
+        assert_eq!(fakes.store.log(&slow.to_string()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_log_deleted_before_registering_is_recreated_empty() {
+        let (state, canvas_uuid, claims) = cold_app(EventCacheConfig::default().max_bytes).await;
+        draw(&state, &canvas_uuid, claims.user_id, &ids("lost", 3)).await;
+        let path = canvases_dir(&state.data_dir).join(event_file_name(&canvas_uuid.to_string()));
+        std::fs::remove_file(&path).unwrap();
+
+        let (connection, mut rx) = connect(&state, &claims).await;
+        let history = HistoryOptions::default();
+        state.canvas_manager.register(&state.pool, canvas_uuid, claims.user_id, connection.clone(), false, history).await;
+        let received = receive_until(&mut rx, "historyComplete").await;
+        assert!(drawn_ids(&received).is_empty(), "{:?}", received);
+        assert!(!received.iter().any(|text| text.contains("Failed to load")), "{:?}", received);
+        // The owner learns why the canvas is empty
+        assert!(received.iter().any(|text| text.contains("history of this canvas was lost")), "{:?}", received);
+        assert!(path.exists());
+
+        // Drawing continues on the new log, and later histories match it
+        draw(&state, &canvas_uuid, claims.user_id, &ids("after", 2)).await;
+        receive_until(&mut rx, "after-1").await;
+        let (late, mut late_rx) = connect(&state, &claims).await;
+        state.canvas_manager.register(&state.pool, canvas_uuid, claims.user_id, late, false, history).await;
+        assert_eq!(drawn_ids(&receive_until(&mut late_rx, "historyComplete").await), ids("after", 2));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn unreadable_logs_fail_the_history_instead_of_being_replaced() {
+        let (state, canvas_uuid, claims) = cold_app(EventCacheConfig::default().max_bytes).await;
+        draw(&state, &canvas_uuid, claims.user_id, &ids("kept", 1)).await;
+        let path = canvases_dir(&state.data_dir).join(event_file_name(&canvas_uuid.to_string()));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+
+        let (connection, mut rx) = connect(&state, &claims).await;
+        state
+            .canvas_manager
+            .register(&state.pool, canvas_uuid, claims.user_id, connection, false, HistoryOptions::default())
+            .await;
+        receive_until(&mut rx, "Failed to load canvas history").await;
+        assert!(path.is_dir());
+    }
+
+    #[tokio::test]
+    async fn events_that_could_not_be_written_are_reported_to_the_sender_only() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "painter@example.com", "Painter").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Unwritable").await;
+        let canvas_uuid: CanvasId = canvas_id.parse().unwrap();
+        let claims = claims_of(&state, "painter@example.com").await;
+        let manager = &state.canvas_manager;
+        let (sender, mut sender_rx) = connect(&state, &claims).await;
+        let (viewer, mut viewer_rx) = connect(&state, &claims).await;
+        for connection in [&sender, &viewer] {
+            let history = HistoryOptions::default();
+            manager.register(&state.pool, canvas_uuid, claims.user_id, connection.clone(), false, history).await;
+        }
+
+        // Permission bits don't stop root, which tests often run as, so a directory takes the log's place.
+        // The writer opens the log with the first write.
+        let path = canvases_dir(&state.data_dir).join(event_file_name(&canvas_id));
+        let _ = std::fs::remove_file(&path);
+        std::fs::create_dir(&path).unwrap();
+        let lost = json!([stroke("lost-1"), stroke("lost-2")]);
+        let events = WebSocketEvents { canvas_id: canvas_uuid, events_for_canvas: lost };
+        manager.handle_event(&state, claims.user_id, &sender, events).await;
+        let received = receive_until(&mut sender_rx, "persist_failed").await;
+        let error: serde_json::Value = serde_json::from_str(received.last().unwrap()).unwrap();
+        assert_eq!(error["details"], json!({ "canvasId": canvas_id, "count": 2 }), "{}", error);
+
+        // Once the log is writable again, the next events go through, and only they reach the viewer
+        std::fs::remove_dir(&path).unwrap();
+        let events = WebSocketEvents { canvas_id: canvas_uuid, events_for_canvas: json!([stroke("saved")]) };
+        manager.handle_event(&state, claims.user_id, &sender, events).await;
+        receive_until(&mut sender_rx, r#""type":"ack""#).await;
+        let received = receive_until(&mut viewer_rx, "saved").await;
+        assert!(!received.iter().any(|text| text.contains("lost-")), "{:?}", received);
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains("saved") && !log.contains("lost-"));
+    }
+
+    #[tokio::test]
+    async fn a_failing_writer_persists_and_broadcasts_all_of_a_message_or_nothing() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let (_viewer, mut viewer_rx) = fakes.join(canvas_uuid, 2, "R").await;
+        let append = |ids: Vec<String>| {
+            let events = serde_json::Value::Array(ids.iter().map(|id| stroke(id)).collect());
+            async { fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, events, None).await.unwrap() }
+        };
+
+        fakes.store.inject(&canvas_uuid.to_string(), Some(WriteFault::Fail));
+        let failed = append(ids("failed", 10)).await.persisted.unwrap().await.unwrap();
+        assert!(failed.is_err());
+        assert!(fakes.store.log(&canvas_uuid.to_string()).is_empty());
+
+        fakes.store.inject(&canvas_uuid.to_string(), None);
+        append(ids("saved", 10)).await.persisted.unwrap().await.unwrap().unwrap();
+        let received = receive_until(&mut viewer_rx, "saved-9").await;
+        assert_eq!(drawn_ids(&received), ids("saved", 10));
+        let log = fakes.store.log(&canvas_uuid.to_string());
+        let logged: Vec<_> = log.iter().map(|event| event["shape"]["id"].as_str().unwrap()).collect();
+        assert_eq!(logged, ids("saved", 10));
+
+        // Nor do later histories have them
+        let (_late, mut late_rx) = fakes.join(canvas_uuid, 3, "R").await;
+        assert_eq!(drawn_ids(&receive_until(&mut late_rx, "historyComplete").await), ids("saved", 10));
+    }
+
+    #[tokio::test]
+    async fn drawing_records_samples_in_every_latency_histogram() {
+        let fakes = memory_manager().await;
+        let canvas_uuid = fakes.load(false).await;
+        let (_viewer, mut viewer_rx) = fakes.join(canvas_uuid, 2, "R").await;
+        let events = json!([stroke("stroke-1"), stroke("stroke-2"), stroke("stroke-3")]);
+        let appended = fakes.manager.append_events(&fakes.pool, "W", 1, &canvas_uuid, events, None).await.unwrap();
+        appended.persisted.unwrap().await.unwrap().unwrap();
+        receive_until(&mut viewer_rx, "stroke-3").await;
+
+        let rendered = fakes.manager.metrics.render_prometheus();
+        let sample = |line: &str| -> f64 {
+            let found = rendered.lines().find_map(|rendered| rendered.strip_prefix(line));
+            found.unwrap_or_else(|| panic!("no {} in\n{}", line, rendered)).trim().parse().unwrap()
+        };
+        assert_eq!(sample("event_append_duration_seconds_count"), 1.0);
+        assert!(sample("event_append_duration_seconds_sum") > 0.0);
+        assert_eq!(sample("event_append_bytes_count"), 1.0);
+        assert_eq!(sample("event_append_bytes_sum"), encoded_len(&fakes.store.log(&canvas_uuid.to_string())) as f64);
+        assert_eq!(sample("events_per_message_count"), 1.0);
+        assert_eq!(sample("events_per_message_sum"), 3.0);
+        assert_eq!(sample(r#"events_per_message_bucket{le="5"}"#), 1.0);
+        assert_eq!(sample(r#"events_per_message_bucket{le="2"}"#), 0.0);
+        // Once for the viewer; the writer never registered
+        assert_eq!(sample("broadcast_duration_seconds_count"), 1.0);
+        assert_eq!(sample("history_send_duration_seconds_count"), 1.0);
     }
 }
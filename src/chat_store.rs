@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::canvas_id::CanvasId;
+
+/// Directory holding one chat log per canvas.
+const CHAT_DIR: &str = "data/chat";
+
+/// Maximum number of characters allowed in a single chat message.
+pub const MAX_CHAT_MESSAGE_CHARS: usize = 2000;
+
+/// Number of chat lines delivered to a client when it registers for a canvas.
+pub const CHAT_HISTORY_LIMIT: usize = 50;
+
+/// A single persisted chat line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+    pub text: String,
+    /// Epoch seconds at which the server received the message.
+    pub timestamp: u64,
+}
+
+/// Returns the path of the chat log for a canvas.
+pub fn chat_file_path(canvas_uuid: &CanvasId) -> PathBuf {
+    PathBuf::from(CHAT_DIR).join(format!("{}.jsonl", canvas_uuid))
+}
+
+/// Appends a chat message as a single JSON line, creating the chat directory if needed.
+pub async fn append_chat_message(canvas_uuid: &CanvasId, message: &ChatMessage) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(CHAT_DIR).await?;
+
+    let line = serde_json::to_string(message)? + "\n";
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(chat_file_path(canvas_uuid))
+        .await?;
+    file.write_all(line.as_bytes()).await
+}
+
+/// Reads the last `limit` chat messages of a canvas.
+/// A missing chat log simply means nobody has chatted yet.
+pub async fn read_recent_chat(canvas_uuid: &CanvasId, limit: usize) -> std::io::Result<Vec<ChatMessage>> {
+    let content = match tokio::fs::read_to_string(chat_file_path(canvas_uuid)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut messages: Vec<ChatMessage> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                tracing::warn!("Skipping invalid line in canvas {} chat log: {}", canvas_uuid, e);
+                None
+            }
+        })
+        .collect();
+
+    let skip = messages.len().saturating_sub(limit);
+    Ok(messages.split_off(skip))
+}
@@ -0,0 +1,261 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::FORWARDED, request::Parts, HeaderMap},
+};
+
+use crate::AppState;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // An IPv4 peer on a dual-stack socket shows up as `::ffff:a.b.c.d`
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = s.split_once('/').map_or((s, None), |(network, prefix)| (network, Some(prefix)));
+        let network: IpAddr = network.trim().parse().map_err(|_| format!("'{}' is not an IP address", network))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("'{}' is not a prefix length up to {}", prefix, max))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The proxies whose `X-Forwarded-For` and `Forwarded` headers are believed, `TRUSTED_PROXIES` as a
+/// comma-separated list of ranges. Empty by default, so the headers are ignored unless configured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client behind `peer`. Only a trusted peer's headers count; their hops are walked from the
+    /// right, the first address that isn't a trusted proxy is the client. A client can put anything into
+    /// the header, so everything left of that address is ignored.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        // `Forwarded` is the standard, nginx and most load balancers still send only `X-Forwarded-For`
+        let hops = if headers.contains_key(FORWARDED) {
+            forwarded_hops(headers)
+        } else {
+            x_forwarded_for_hops(headers)
+        };
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            // An obfuscated or unparsable hop ends the chain, nothing left of it can be verified
+            let Some(ip) = hop else { break };
+            client = *ip;
+            if !self.contains(*ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl fmt::Display for TrustedProxies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(Cidr::to_string).collect();
+        f.write_str(&ranges.join(", "))
+    }
+}
+
+/// The hops of all `X-Forwarded-For` headers, the client first.
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|hop| parse_hop(hop.trim()))
+        .collect()
+}
+
+/// The `for=` hops of all `Forwarded` headers (RFC 7239), the client first.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_hop(value.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// An address as proxies write it: `1.2.3.4`, `1.2.3.4:5678`, `::1` or `[::1]:5678`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>())
+        .ok()
+}
+
+/// The address of the client, taken from the proxy headers if the peer is one of the `TRUSTED_PROXIES`
+/// and from the socket otherwise. Without connection info, e.g. when the router is called directly
+/// in tests, the address is `0.0.0.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Ok(Self(IpAddr::from([0, 0, 0, 0])));
+        };
+        Ok(Self(state.config.trusted_proxies.resolve(peer.ip(), &parts.headers)))
+    }
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn proxies(ranges: &str) -> TrustedProxies {
+        ranges.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_spoof_their_address() {
+        let proxies = proxies("10.0.0.0/8");
+        let spoofed = headers(X_FORWARDED_FOR, "1.2.3.4");
+        assert_eq!(proxies.resolve(ip("203.0.113.7"), &spoofed), ip("203.0.113.7"));
+
+        let spoofed = headers("forwarded", "for=1.2.3.4");
+        assert_eq!(proxies.resolve(ip("203.0.113.7"), &spoofed), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn headers_are_ignored_without_trusted_proxies() {
+        let spoofed = headers(X_FORWARDED_FOR, "1.2.3.4");
+        assert_eq!(TrustedProxies::default().resolve(ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn trusted_proxies_name_the_client() {
+        let proxies = proxies("10.0.0.0/8, 192.168.1.1");
+        let forwarded = headers(X_FORWARDED_FOR, "198.51.100.9, 192.168.1.1");
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &forwarded), ip("198.51.100.9"));
+
+        let forwarded = headers("forwarded", "for=\"[2001:db8::1]:4711\";proto=https, for=192.168.1.1");
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &forwarded), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn addresses_left_of_the_client_are_ignored() {
+        // The client prepended a fake hop; only the address the trusted proxy saw counts
+        let proxies = proxies("10.0.0.0/8");
+        let spoofed = headers(X_FORWARDED_FOR, "1.2.3.4, 198.51.100.9");
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &spoofed), ip("198.51.100.9"));
+    }
+
+    #[test]
+    fn unparsable_hops_end_the_chain() {
+        let proxies = proxies("10.0.0.0/8");
+        let obfuscated = headers("forwarded", "for=1.2.3.4, for=_hidden");
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &obfuscated), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.255.1")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        // IPv4 peers of a dual-stack socket
+        assert!(range.contains(ip("::ffff:10.1.0.1")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("198.51.100.9")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("proxy.local".parse::<TrustedProxies>().is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::{env, fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{
+    backup::DEFAULT_BACKUP_RETENTION,
+    blocking::DEFAULT_BLOCKING_CONCURRENCY,
+    canvas_manager::{CanvasManagerConfig, CoalesceConfig, EventCacheConfig, DEFAULT_ARCHIVE_RETENTION_DAYS},
+    client_ip::TrustedProxies,
+    content_filter::FilterAction,
+    drain::DrainConfig,
+    event_store::{Durability, EventStoreConfig, StoreKind, DEFAULT_SYNC_INTERVAL_MS},
+    limits::{PayloadLimits, DEFAULT_MAX_REQUEST_BODY_BYTES, DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION, DEFAULT_MAX_UPLOAD_BODY_BYTES},
+    origin_policy::OriginPolicy,
+    rate_limiter::RateLimitConfig,
+    socket_claims_manager::DEFAULT_AUTH_EXPIRY_WARNING_SECONDS,
+};
+#[cfg(feature = "s3")]
+use crate::s3_store::S3Config;
+
+/// Default lifetime of a JWT and its cookie: 7 days.
+pub const DEFAULT_TOKEN_EXPIRY_SECONDS: usize = 60 * 60 * 24 * 7;
+/// Default time after which the claims of a JWT are refreshed from the database.
+pub const DEFAULT_TOKEN_REISSUE_SECONDS: usize = 5 * 60;
+
+/// The server's configuration, read from the environment (and `.env`) once at startup.
+/// Only the logging setup (`LOG_FORMAT`, `SENTRY_DSN`) reads the environment before, to report errors in here.
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// `DATABASE_URL`, required.
+    pub database_url: String,
+    pub database: DatabaseConfig,
+    /// `SERVER_HOST` and `SERVER_PORT`, default `127.0.0.1:8080`.
+    pub addr: SocketAddr,
+    /// Root of the event files, `DATA_DIR` (default `data`).
+    pub data_dir: PathBuf,
+    /// The built frontend, served for every path outside the API, `PUBLIC_DIR` (default `./public`).
+    /// Relative to the working directory; checked at startup unless the server runs with `--no-frontend`.
+    pub public_dir: PathBuf,
+    pub jwt: JwtConfig,
+    pub cookie: CookieConfig,
+    /// `MAX_REQUEST_BODY_BYTES`, for every request to the API except uploads.
+    pub max_request_body_bytes: usize,
+    /// `MAX_UPLOAD_BODY_BYTES`, for routes taking bulk uploads (`POST /api/canvas/{id}/events`).
+    pub max_upload_body_bytes: usize,
+    /// `MAX_WS_MESSAGE_BYTES`, `MAX_EVENTS_PER_MESSAGE`, `MAX_EVENTS_PAYLOAD_BYTES` and `MAX_EVENT_BYTES`.
+    pub payload_limits: PayloadLimits,
+    /// `WS_RATE_WINDOW_SECS`, `WS_RATE_EVENT_MESSAGES`, `WS_RATE_PAYLOAD_BYTES`, `WS_RATE_COMMANDS`
+    /// and `WS_RATE_MAX_VIOLATIONS`.
+    pub rate_limits: RateLimitConfig,
+    /// `MAX_SUBSCRIPTIONS_PER_CONNECTION`
+    pub max_subscriptions_per_connection: usize,
+    /// `COALESCE_EVENTS`, `COALESCE_INTERVAL_MS`, `EVENT_CACHE_MAX_BYTES`, `EVENT_CACHE_TOTAL_MAX_BYTES`,
+    /// `CANVAS_IDLE_TTL_SECS`, `HISTORY_MAX_EVENTS` and `HELD_EVENTS_TTL_SECS`.
+    pub canvas_manager: CanvasManagerConfig,
+    /// `CANVAS_STORE` (`file`, `sqlite` or, with the `s3` feature, `s3`), `EVENT_DURABILITY` (`none`,
+    /// `per_batch` or `interval`), `EVENT_SYNC_INTERVAL_MS`, `BLOCKING_CONCURRENCY` and the `S3_*` settings.
+    pub event_store: EventStoreConfig,
+    /// `PUBLIC_ORIGIN` and the comma separated `ALLOWED_ORIGINS`, plus `WS_ALLOW_MISSING_ORIGIN`.
+    pub origin_policy: OriginPolicy,
+    /// `CONTENT_FILTER_DENYLIST`, the path of the denylist; without it nothing is filtered.
+    pub content_filter_denylist: Option<PathBuf>,
+    /// `CONTENT_FILTER_ACTION`, `reject` (the default) or `mask`.
+    pub content_filter_action: FilterAction,
+    /// `METRICS_PER_CANVAS`, labels the latency histograms by canvas. Off by default.
+    pub metrics_per_canvas: bool,
+    /// `API_DOCS`, serves Swagger UI at `/api/docs`. Off by default.
+    pub api_docs: bool,
+    pub drain: DrainConfig,
+    /// `TRUSTED_PROXIES`, the ranges of reverse proxies whose forwarding headers name the client.
+    pub trusted_proxies: TrustedProxies,
+    /// `VALIDATE_ON_START`, checks the event files of all canvases before serving. Off by default.
+    pub validate_on_start: bool,
+    /// `AUTH_EXPIRY_WARNING_SECS`, how long before their token expires WebSocket clients are warned.
+    pub auth_expiry_warning_seconds: usize,
+    /// `COMPACTION_GARBAGE_RATIO`, enables scheduled compaction of canvases above that ratio.
+    pub compaction_garbage_ratio: Option<f64>,
+    /// `COMPRESS_IDLE_DAYS`, enables compressing the logs of canvases idle for that long.
+    pub compress_idle_after: Option<Duration>,
+    /// `ARCHIVE_RETENTION_DAYS`, after which archived logs are removed.
+    pub archive_retention: Duration,
+    /// `BACKUP_INTERVAL_SECS`, enables scheduled backups. Zero disables them, like leaving it unset.
+    pub backup_interval: Option<Duration>,
+    /// `BACKUP_RETENTION`, the number of backups kept.
+    pub backup_retention: usize,
+}
+
+/// Pool and SQLite connection settings. Every connection runs in WAL mode with `synchronous=NORMAL`,
+/// so readers don't block the writer; concurrent writers wait up to the busy timeout for the lock.
+#[derive(Debug, Clone, Copy)]
+pub struct DatabaseConfig {
+    /// `DB_MAX_CONNECTIONS`
+    pub max_connections: u32,
+    /// `DB_ACQUIRE_TIMEOUT_SECS`, how long a query waits for a free connection.
+    pub acquire_timeout: Duration,
+    /// `DB_BUSY_TIMEOUT_MS`, how long a connection waits for a lock before failing with "database is locked".
+    pub busy_timeout: Duration,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(30),
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JwtConfig {
+    /// `JWT_SECRET`, required.
+    pub secret: String,
+    /// `JWT_EXPIRY_SECS`, the hard expiry of a token and the `Max-Age` of its cookie.
+    pub expiry_seconds: usize,
+    /// `JWT_REISSUE_SECS`, after which the claims are refreshed.
+    pub reissue_seconds: usize,
+}
+
+// Keeps the secret out of logs
+impl fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("secret", &"<redacted>")
+            .field("expiry_seconds", &self.expiry_seconds)
+            .field("reissue_seconds", &self.reissue_seconds)
+            .finish()
+    }
+}
+
+/// Attributes of the `auth_token` cookie.
+#[derive(Debug, Clone, Copy)]
+pub struct CookieConfig {
+    /// `COOKIE_SECURE`, adds `Secure` so the cookie is only sent over HTTPS. Off by default for local development.
+    pub secure: bool,
+    /// `COOKIE_SAME_SITE`, default `Strict`.
+    pub same_site: SameSite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lax" => Ok(Self::Lax),
+            "none" => Ok(Self::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        })
+    }
+}
+
+impl CookieConfig {
+    /// The `Set-Cookie` value for the auth token; an empty token with `max_age` 0 removes the cookie.
+    pub fn auth_cookie(&self, token: &str, max_age: usize) -> String {
+        let mut cookie = format!(
+            "auth_token={}; HttpOnly; Path=/; Max-Age={}; SameSite={}",
+            token, max_age, self.same_site
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+/// Every invalid or missing setting, so they can be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl AppConfig {
+    /// Reads and validates the configuration. A value that doesn't parse is an error instead of
+    /// falling back to the default, and all errors are reported together.
+    pub fn from_env() -> Result<Self, ConfigErrors> {
+        let mut errors = Vec::new();
+
+        let database_url = required("DATABASE_URL", &mut errors);
+        let database_defaults = DatabaseConfig::default();
+        let database = DatabaseConfig {
+            max_connections: parsed("DB_MAX_CONNECTIONS", database_defaults.max_connections, &mut errors),
+            acquire_timeout: Duration::from_secs(parsed(
+                "DB_ACQUIRE_TIMEOUT_SECS",
+                database_defaults.acquire_timeout.as_secs(),
+                &mut errors,
+            )),
+            busy_timeout: Duration::from_millis(parsed(
+                "DB_BUSY_TIMEOUT_MS",
+                database_defaults.busy_timeout.as_millis() as u64,
+                &mut errors,
+            )),
+        };
+        if database.max_connections == 0 {
+            errors.push("DB_MAX_CONNECTIONS: must be at least 1".to_string());
+        }
+        let host: String = parsed("SERVER_HOST", "127.0.0.1".to_string(), &mut errors);
+        let port: u16 = parsed("SERVER_PORT", 8080, &mut errors);
+        let addr = format!("{}:{}", host, port).parse().unwrap_or_else(|_| {
+            errors.push(format!("SERVER_HOST: '{}' is not an IP address", host));
+            SocketAddr::from(([127, 0, 0, 1], port))
+        });
+
+        let jwt = JwtConfig {
+            secret: required("JWT_SECRET", &mut errors),
+            expiry_seconds: parsed("JWT_EXPIRY_SECS", DEFAULT_TOKEN_EXPIRY_SECONDS, &mut errors),
+            reissue_seconds: parsed("JWT_REISSUE_SECS", DEFAULT_TOKEN_REISSUE_SECONDS, &mut errors),
+        };
+        if jwt.reissue_seconds == 0 || jwt.reissue_seconds >= jwt.expiry_seconds {
+            errors.push(format!(
+                "JWT_REISSUE_SECS: must be between 1 and JWT_EXPIRY_SECS ({}), got {}",
+                jwt.expiry_seconds, jwt.reissue_seconds
+            ));
+        }
+
+        let cookie = CookieConfig {
+            secure: parsed("COOKIE_SECURE", false, &mut errors),
+            same_site: parsed("COOKIE_SAME_SITE", SameSite::Strict, &mut errors),
+        };
+        // Browsers drop `SameSite=None` cookies without `Secure`
+        if cookie.same_site == SameSite::None && !cookie.secure {
+            errors.push("COOKIE_SAME_SITE: None requires COOKIE_SECURE=true".to_string());
+        }
+
+        let drain_defaults = DrainConfig::default();
+        let drain = DrainConfig {
+            deadline: Duration::from_secs(parsed("DRAIN_DEADLINE_SECS", drain_defaults.deadline.as_secs(), &mut errors)),
+            force_close: parsed("DRAIN_FORCE_CLOSE", drain_defaults.force_close, &mut errors),
+        };
+
+        let payload_defaults = PayloadLimits::default();
+        let payload_limits = PayloadLimits {
+            max_message_bytes: parsed("MAX_WS_MESSAGE_BYTES", payload_defaults.max_message_bytes, &mut errors),
+            max_events_per_message: parsed("MAX_EVENTS_PER_MESSAGE", payload_defaults.max_events_per_message, &mut errors),
+            max_events_payload_bytes: parsed(
+                "MAX_EVENTS_PAYLOAD_BYTES",
+                payload_defaults.max_events_payload_bytes,
+                &mut errors,
+            ),
+            max_event_bytes: parsed("MAX_EVENT_BYTES", payload_defaults.max_event_bytes, &mut errors),
+        };
+
+        let rate_defaults = RateLimitConfig::default();
+        let rate_limits = RateLimitConfig {
+            window: Duration::from_secs(parsed("WS_RATE_WINDOW_SECS", rate_defaults.window.as_secs(), &mut errors)),
+            event_messages: parsed("WS_RATE_EVENT_MESSAGES", rate_defaults.event_messages, &mut errors),
+            payload_bytes: parsed("WS_RATE_PAYLOAD_BYTES", rate_defaults.payload_bytes, &mut errors),
+            commands: parsed("WS_RATE_COMMANDS", rate_defaults.commands, &mut errors),
+            max_violations: parsed("WS_RATE_MAX_VIOLATIONS", rate_defaults.max_violations, &mut errors),
+        };
+        if let Err(e) = payload_limits.check_rate_limits(&rate_limits) {
+            errors.push(e);
+        }
+
+        let manager_defaults = CanvasManagerConfig::default();
+        let canvas_manager = CanvasManagerConfig {
+            coalesce: CoalesceConfig {
+                enabled_by_default: parsed("COALESCE_EVENTS", manager_defaults.coalesce.enabled_by_default, &mut errors),
+                interval: Duration::from_millis(parsed(
+                    "COALESCE_INTERVAL_MS",
+                    manager_defaults.coalesce.interval.as_millis() as u64,
+                    &mut errors,
+                )),
+            },
+            event_cache: EventCacheConfig {
+                max_bytes: parsed("EVENT_CACHE_MAX_BYTES", manager_defaults.event_cache.max_bytes, &mut errors),
+                max_total_bytes: parsed(
+                    "EVENT_CACHE_TOTAL_MAX_BYTES",
+                    manager_defaults.event_cache.max_total_bytes,
+                    &mut errors,
+                ),
+            },
+            idle_ttl: Duration::from_secs(parsed("CANVAS_IDLE_TTL_SECS", manager_defaults.idle_ttl.as_secs(), &mut errors)),
+            history_max_events: parsed("HISTORY_MAX_EVENTS", manager_defaults.history_max_events, &mut errors),
+            held_events_ttl: Duration::from_secs(parsed(
+                "HELD_EVENTS_TTL_SECS",
+                manager_defaults.held_events_ttl.as_secs(),
+                &mut errors,
+            )),
+        };
+
+        let durability = match parsed("EVENT_DURABILITY", "per_batch".to_string(), &mut errors).as_str() {
+            "none" => Durability::None,
+            "per_batch" => Durability::PerBatch,
+            "interval" => Durability::Interval(Duration::from_millis(parsed(
+                "EVENT_SYNC_INTERVAL_MS",
+                DEFAULT_SYNC_INTERVAL_MS,
+                &mut errors,
+            ))),
+            other => {
+                errors.push(format!("EVENT_DURABILITY: invalid value '{}'", other));
+                Durability::PerBatch
+            }
+        };
+        let event_store = EventStoreConfig {
+            kind: parsed("CANVAS_STORE", StoreKind::File, &mut errors),
+            durability,
+            blocking_concurrency: parsed("BLOCKING_CONCURRENCY", DEFAULT_BLOCKING_CONCURRENCY, &mut errors),
+            #[cfg(feature = "s3")]
+            s3: None,
+        };
+        #[cfg(feature = "s3")]
+        let event_store = EventStoreConfig {
+            s3: (event_store.kind == StoreKind::S3).then(|| s3_config(&mut errors)),
+            ..event_store
+        };
+
+        let origins = optional::<String>("ALLOWED_ORIGINS", &mut errors).unwrap_or_default();
+        let public_origin = optional::<String>("PUBLIC_ORIGIN", &mut errors);
+        let allow_missing_origin = parsed("WS_ALLOW_MISSING_ORIGIN", false, &mut errors);
+        let origin_policy = OriginPolicy::new(origins.split(',').chain(public_origin.as_deref()), allow_missing_origin)
+            .unwrap_or_else(|e| {
+                errors.push(format!("ALLOWED_ORIGINS: {}", e));
+                OriginPolicy::default()
+            });
+
+        let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+
+        let config = Self {
+            database_url,
+            database,
+            addr,
+            data_dir: parsed("DATA_DIR", PathBuf::from("data"), &mut errors),
+            public_dir: parsed("PUBLIC_DIR", PathBuf::from("./public"), &mut errors),
+            jwt,
+            cookie,
+            max_request_body_bytes: parsed("MAX_REQUEST_BODY_BYTES", DEFAULT_MAX_REQUEST_BODY_BYTES, &mut errors),
+            max_upload_body_bytes: parsed("MAX_UPLOAD_BODY_BYTES", DEFAULT_MAX_UPLOAD_BODY_BYTES, &mut errors),
+            payload_limits,
+            rate_limits,
+            max_subscriptions_per_connection: parsed(
+                "MAX_SUBSCRIPTIONS_PER_CONNECTION",
+                DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION,
+                &mut errors,
+            ),
+            canvas_manager,
+            event_store,
+            origin_policy,
+            content_filter_denylist: optional("CONTENT_FILTER_DENYLIST", &mut errors),
+            content_filter_action: parsed("CONTENT_FILTER_ACTION", FilterAction::Reject, &mut errors),
+            metrics_per_canvas: parsed("METRICS_PER_CANVAS", false, &mut errors),
+            api_docs: parsed("API_DOCS", false, &mut errors),
+            drain,
+            trusted_proxies: parsed("TRUSTED_PROXIES", TrustedProxies::default(), &mut errors),
+            validate_on_start: parsed("VALIDATE_ON_START", false, &mut errors),
+            auth_expiry_warning_seconds: parsed(
+                "AUTH_EXPIRY_WARNING_SECS",
+                DEFAULT_AUTH_EXPIRY_WARNING_SECONDS,
+                &mut errors,
+            ),
+            compaction_garbage_ratio: optional("COMPACTION_GARBAGE_RATIO", &mut errors),
+            compress_idle_after: optional("COMPRESS_IDLE_DAYS", &mut errors).map(days),
+            archive_retention: days(parsed("ARCHIVE_RETENTION_DAYS", DEFAULT_ARCHIVE_RETENTION_DAYS, &mut errors)),
+            backup_interval: optional("BACKUP_INTERVAL_SECS", &mut errors)
+                .filter(|&seconds| seconds > 0)
+                .map(Duration::from_secs),
+            backup_retention: parsed("BACKUP_RETENTION", DEFAULT_BACKUP_RETENTION, &mut errors),
+        };
+
+        if errors.is_empty() { Ok(config) } else { Err(ConfigErrors(errors)) }
+    }
+
+    /// A configuration for tests: an in-memory database, a fixed secret and the default limits.
+    #[cfg(test)]
+    pub fn test_default() -> Self {
+        Self {
+            database_url: "sqlite::memory:".to_string(),
+            // Every connection to `sqlite::memory:` opens a database of its own
+            database: DatabaseConfig { max_connections: 1, ..DatabaseConfig::default() },
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            data_dir: env::temp_dir().join(format!("drawing_app_test_{}", uuid::Uuid::new_v4())),
+            public_dir: PathBuf::from("./public"),
+            jwt: JwtConfig {
+                secret: "test-secret".to_string(),
+                expiry_seconds: DEFAULT_TOKEN_EXPIRY_SECONDS,
+                reissue_seconds: DEFAULT_TOKEN_REISSUE_SECONDS,
+            },
+            cookie: CookieConfig { secure: false, same_site: SameSite::Strict },
+            max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+            max_upload_body_bytes: DEFAULT_MAX_UPLOAD_BODY_BYTES,
+            payload_limits: PayloadLimits::default(),
+            rate_limits: RateLimitConfig::default(),
+            max_subscriptions_per_connection: DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION,
+            canvas_manager: CanvasManagerConfig::default(),
+            event_store: EventStoreConfig::default(),
+            origin_policy: OriginPolicy::default(),
+            content_filter_denylist: None,
+            content_filter_action: FilterAction::Reject,
+            metrics_per_canvas: false,
+            api_docs: false,
+            drain: DrainConfig::default(),
+            trusted_proxies: TrustedProxies::default(),
+            validate_on_start: false,
+            auth_expiry_warning_seconds: DEFAULT_AUTH_EXPIRY_WARNING_SECONDS,
+            compaction_garbage_ratio: None,
+            compress_idle_after: None,
+            archive_retention: Duration::from_secs(DEFAULT_ARCHIVE_RETENTION_DAYS * 24 * 60 * 60),
+            backup_interval: None,
+            backup_retention: DEFAULT_BACKUP_RETENTION,
+        }
+    }
+}
+
+fn required(key: &str, errors: &mut Vec<String>) -> String {
+    match env::var(key) {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => {
+            errors.push(format!("{}: must be set", key));
+            String::new()
+        }
+    }
+}
+
+fn parsed<T: FromStr>(key: &str, default: T, errors: &mut Vec<String>) -> T {
+    optional(key, errors).unwrap_or(default)
+}
+
+/// `None` if the variable isn't set; an invalid value is an error.
+fn optional<T: FromStr>(key: &str, errors: &mut Vec<String>) -> Option<T> {
+    let value = env::var(key).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            errors.push(format!("{}: invalid value '{}'", key, value));
+            None
+        }
+    }
+}
+
+/// The bucket settings for `CANVAS_STORE=s3`.
+#[cfg(feature = "s3")]
+fn s3_config(errors: &mut Vec<String>) -> S3Config {
+    let defaults = S3Config::default();
+    let config = S3Config {
+        bucket: required("S3_BUCKET", errors),
+        endpoint: optional("S3_ENDPOINT", errors),
+        region: parsed("S3_REGION", defaults.region, errors),
+        access_key_id: optional("S3_ACCESS_KEY_ID", errors),
+        secret_access_key: optional("S3_SECRET_ACCESS_KEY", errors),
+        prefix: parsed("S3_PREFIX", defaults.prefix, errors),
+        path_style: parsed("S3_PATH_STYLE", defaults.path_style, errors),
+        sync_interval: Duration::from_secs(parsed("S3_SYNC_INTERVAL_SECS", defaults.sync_interval.as_secs(), errors)),
+    };
+    if let Err(e) = config.region() {
+        errors.push(format!("S3_REGION: {}", e));
+    }
+    config
+}
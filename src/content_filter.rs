@@ -0,0 +1,280 @@
+use std::{io, path::PathBuf, str::FromStr, sync::RwLock};
+
+use futures::{future::BoxFuture, FutureExt};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Checks user-written text, e.g. chat messages and announcements, before it is stored or broadcast.
+pub trait ContentFilter: Send + Sync {
+    /// The text to store, possibly masked, or `Err` if it has to be rejected.
+    fn apply(&self, text: &str) -> Result<String, ContentRejected>;
+
+    /// Reloads the filter's rules. Returns how many there are now.
+    fn reload(&self) -> BoxFuture<'_, io::Result<usize>>;
+}
+
+/// A text was rejected by the content filter.
+#[derive(Debug)]
+pub struct ContentRejected;
+
+/// What happens to a text with denied terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Reject,
+    /// The matched words are replaced by `*`.
+    Mask,
+}
+
+impl FromStr for FilterAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "mask" => Ok(Self::Mask),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A `ContentFilter` backed by a denylist file with one term per line. Lines starting with `#` are comments.
+/// Terms are single words and may contain `*` for any run of characters, e.g. `bad*`.
+/// Words and terms are compared after Unicode compatibility normalization, with diacritics, invisible
+/// characters and common Cyrillic and Greek lookalikes folded away, so `ｂáԁ` matches `bad`.
+pub struct DenylistFilter {
+    path: Option<PathBuf>,
+    action: FilterAction,
+    /// The folded terms.
+    terms: RwLock<Vec<Vec<char>>>,
+}
+
+impl DenylistFilter {
+    /// Loads the denylist at `path`; without one nothing is filtered.
+    pub async fn new(path: Option<PathBuf>, action: FilterAction) -> Self {
+        let filter = Self {
+            path,
+            action,
+            terms: RwLock::new(Vec::new()),
+        };
+        match filter.load().await {
+            Ok(count) if filter.path.is_some() => tracing::info!("Loaded {} denied terms ({:?})", count, action),
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to load the content filter denylist: {}", e),
+        }
+        filter
+    }
+
+    async fn load(&self) -> io::Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let content = tokio::fs::read_to_string(path).await?;
+        let terms: Vec<Vec<char>> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter(|term| {
+                let single_word = !term.contains(char::is_whitespace);
+                if !single_word {
+                    tracing::warn!("Skipping denied term '{}': terms must be single words.", term);
+                }
+                single_word
+            })
+            .map(|term| fold(term).chars().collect())
+            .collect();
+
+        let count = terms.len();
+        *self.terms.write().unwrap() = terms;
+        Ok(count)
+    }
+}
+
+impl ContentFilter for DenylistFilter {
+    fn apply(&self, text: &str) -> Result<String, ContentRejected> {
+        let terms = self.terms.read().unwrap();
+        if terms.is_empty() {
+            return Ok(text.to_string());
+        }
+
+        let mut filtered = String::with_capacity(text.len());
+        let mut last_end = 0;
+        for (start, end) in words(text) {
+            let word = &text[start..end];
+            let folded: Vec<char> = fold(word).chars().collect();
+            if !terms.iter().any(|term| glob_match(term, &folded)) {
+                continue;
+            }
+            if self.action == FilterAction::Reject {
+                return Err(ContentRejected);
+            }
+            filtered.push_str(&text[last_end..start]);
+            filtered.extend(std::iter::repeat_n('*', word.chars().count()));
+            last_end = end;
+        }
+        filtered.push_str(&text[last_end..]);
+        Ok(filtered)
+    }
+
+    fn reload(&self) -> BoxFuture<'_, io::Result<usize>> {
+        self.load().boxed()
+    }
+}
+
+/// Characters that don't show, used to split words invisibly.
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{00AD}' | '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}')
+}
+
+/// Byte ranges of the words of a text: runs of letters and digits, including the marks and
+/// invisible characters within them.
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices() {
+        let in_word = c.is_alphanumeric() || is_combining_mark(c) || is_invisible(c);
+        match (in_word, start) {
+            (true, None) => start = Some(index),
+            (false, Some(word_start)) => {
+                words.push((word_start, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        words.push((word_start, text.len()));
+    }
+    words
+}
+
+/// Folds a word for comparison: compatibility decomposition without diacritics and invisible
+/// characters, lowercased, with lookalike letters of other scripts replaced by their Latin twins.
+fn fold(word: &str) -> String {
+    word.nfkd()
+        .filter(|c| !is_combining_mark(*c) && !is_invisible(*c))
+        .flat_map(char::to_lowercase)
+        .map(latin_lookalike)
+        .collect()
+}
+
+/// The Latin letter a Cyrillic or Greek lowercase letter is commonly mistaken for.
+fn latin_lookalike(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ς' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'н' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        other => other,
+    }
+}
+
+/// Whether a word matches a term, where `*` in the term matches any run of characters.
+fn glob_match(term: &[char], word: &[char]) -> bool {
+    let (mut t, mut w) = (0, 0);
+    // Where the last `*` was and how much of the word it covers so far
+    let mut backtrack: Option<(usize, usize)> = None;
+    while w < word.len() {
+        if t < term.len() && term[t] == '*' {
+            backtrack = Some((t, w));
+            t += 1;
+        } else if t < term.len() && term[t] == word[w] {
+            t += 1;
+            w += 1;
+        } else if let Some((star, covered)) = backtrack {
+            t = star + 1;
+            w = covered + 1;
+            backtrack = Some((star, covered + 1));
+        } else {
+            return false;
+        }
+    }
+    term[t..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A filter on a denylist with the given lines, in a fresh temporary directory.
+    async fn denylist(lines: &str, action: FilterAction) -> DenylistFilter {
+        let dir = std::env::temp_dir().join(format!("drawing_app_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("denylist.txt");
+        std::fs::write(&path, lines).unwrap();
+        DenylistFilter::new(Some(path), action).await
+    }
+
+    #[tokio::test]
+    async fn lookalikes_of_denied_terms_are_caught() {
+        let filter = denylist("# comment\nbad\nspam*\n", FilterAction::Reject).await;
+        let evasions = [
+            "BAD",
+            "Bad idea",
+            // Precomposed and combining diacritics
+            "bád",
+            "ba\u{0301}d\u{0308}",
+            // Fullwidth, mathematical and circled forms
+            "ｂａｄ",
+            "𝐛𝐚𝐝",
+            "ⓑⓐⓓ",
+            // Cyrillic and Greek lookalikes
+            "bаd",
+            "bаԁ",
+            "βаd",
+            // Invisible characters within the word
+            "b\u{200B}ad",
+            "b\u{00AD}a\u{2060}d",
+            "\u{FEFF}bad",
+            // Wildcards match after folding too
+            "Spammer",
+            "ＳＰＡＭ",
+            "ѕраm",
+        ];
+        for text in evasions {
+            assert!(filter.apply(text).is_err(), "{:?} got through", text);
+        }
+
+        let innocent = ["badge", "abad", "b ad", "good", "spa", "bed", "bаdminton"];
+        for text in innocent {
+            assert_eq!(filter.apply(text).ok().as_deref(), Some(text), "{:?} was filtered", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn masking_covers_every_character_of_the_written_word() {
+        let filter = denylist("bad\n", FilterAction::Mask).await;
+        let cases = [
+            ("so bad!", "so ***!"),
+            ("so bаd!", "so ***!"),
+            ("ｂａｄ and ｇｏｏｄ", "*** and ｇｏｏｄ"),
+            ("ba\u{0301}d", "****"),
+            ("b\u{200B}ad, bad.", "****, ***."),
+        ];
+        for (text, masked) in cases {
+            assert_eq!(filter.apply(text).ok().as_deref(), Some(masked), "{:?}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_terms_are_folded_like_the_text() {
+        let filter = denylist("BÁD\nｓｐａｍ\n", FilterAction::Reject).await;
+        for text in ["bad", "spam", "ѕраm"] {
+            assert!(filter.apply(text).is_err(), "{:?} got through", text);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{identifiable_web_socket::CLOSE_GOING_AWAY, socket_claims_manager::SocketClaimsManager};
+
+/// How often the remaining connections are logged while draining.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long force-closed connections get to run their cleanup before the server stops.
+const FORCE_CLOSE_GRACE: Duration = Duration::from_secs(2);
+
+/// What happens on SIGTERM (or Ctrl+C) before the server stops.
+#[derive(Debug, Clone, Copy)]
+pub struct DrainConfig {
+    /// `DRAIN_DEADLINE_SECS`, how long open WebSockets get to close on their own.
+    /// Keep it below the orchestrator's grace period (30 s on Kubernetes).
+    pub deadline: Duration,
+    /// `DRAIN_FORCE_CLOSE`, closes the connections still open at the deadline with 1001 (going away),
+    /// so their cleanup runs and clients reconnect to another instance. Otherwise they are cut off with the process.
+    pub force_close: bool,
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self { deadline: Duration::from_secs(25), force_close: true }
+    }
+}
+
+/// Whether the server is shutting down. While draining, `/readyz` answers 503 so load balancers stop
+/// routing to the instance, WebSocket upgrades are refused and open connections can't register for
+/// more canvases, while everything already running may finish.
+#[derive(Debug, Clone, Default)]
+pub struct DrainState {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainState {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the open WebSockets are closed or the deadline passes, logging how many are left.
+    /// HTTP requests in flight are left to axum's graceful shutdown, which starts once this returns.
+    pub async fn drain(&self, sockets: &SocketClaimsManager, config: DrainConfig) {
+        self.draining.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + config.deadline;
+        tracing::info!("Draining: refusing new WebSockets, waiting up to {:?} for open ones.", config.deadline);
+
+        loop {
+            let open = sockets.all_connections().await;
+            if open.is_empty() {
+                tracing::info!("Drained: no WebSocket connections left.");
+                return;
+            }
+            if Instant::now() >= deadline {
+                if config.force_close {
+                    tracing::warn!("Drain deadline passed, closing {} WebSocket connections.", open.len());
+                    for connection in &open {
+                        connection.request_close(CLOSE_GOING_AWAY, "server shutting down");
+                    }
+                    tokio::time::sleep(FORCE_CLOSE_GRACE).await;
+                } else {
+                    tracing::warn!("Drain deadline passed, leaving {} WebSocket connections open.", open.len());
+                }
+                return;
+            }
+            tracing::info!("Draining: {} WebSocket connections still open.", open.len());
+            tokio::time::sleep(REPORT_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))).await;
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::{auth::AuthError, canvas_manager::AppendEventsError};
+
+/// The envelope of every JSON error response: `{"error": {"code", "message", "details"?}}`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable and snake_case, e.g. `not_found`; the same codes as the WebSocket `error` messages where both exist.
+    #[schema(value_type = String)]
+    pub code: &'static str,
+    /// For humans, may change.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<Value>,
+}
+
+/// A field of a payload that failed validation.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    /// Why, e.g. `required`.
+    #[schema(value_type = String)]
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The error of every handler. Turns into the JSON envelope of `ErrorResponse`; internal causes are
+/// logged and never sent.
+#[derive(Debug)]
+pub enum AppError {
+    /// 401, not logged in or wrong credentials.
+    Unauthorized(String),
+    /// 422, some fields of the payload are invalid. Lists all of them.
+    Validation(Vec<FieldError>),
+    /// 400, the request as a whole can't be processed.
+    BadRequest { code: &'static str, message: String, details: Option<Value> },
+    /// 403
+    Forbidden { code: &'static str, message: String, details: Option<Value> },
+    /// 404
+    NotFound(String),
+    /// 409
+    Conflict(String),
+    /// 413
+    PayloadTooLarge { message: String, details: Value },
+    /// 415, the body isn't JSON.
+    UnsupportedMediaType(String),
+    /// 422, the payload is well-formed but refused, e.g. by the content filter.
+    Unprocessable { code: &'static str, message: String },
+    /// 429, with `Retry-After`.
+    RateLimited { code: &'static str, message: String, retry_after: Duration },
+    /// 503, with `Retry-After`. Nothing was done, the client should retry.
+    Unavailable { code: &'static str, message: String, retry_after: Duration },
+    /// 500
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::Forbidden { code: "forbidden", message: message.into(), details: None }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound(message.into())
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self::RateLimited {
+            code: "rate_limited",
+            message: "Too many requests. Slow down.".to_string(),
+            retry_after,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut retry_after = None;
+        let (status, code, message, details) = match self {
+            AppError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, "unauthorized", message, None),
+            AppError::Validation(fields) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_failed",
+                "Some fields are invalid.".to_string(),
+                Some(json!({ "fields": fields })),
+            ),
+            AppError::BadRequest { code, message, details } => (StatusCode::BAD_REQUEST, code, message, details),
+            AppError::Forbidden { code, message, details } => (StatusCode::FORBIDDEN, code, message, details),
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, "not_found", message, None),
+            AppError::Conflict(message) => (StatusCode::CONFLICT, "conflict", message, None),
+            AppError::PayloadTooLarge { message, details } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message, Some(details))
+            }
+            AppError::UnsupportedMediaType(message) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", message, None)
+            }
+            AppError::Unprocessable { code, message } => (StatusCode::UNPROCESSABLE_ENTITY, code, message, None),
+            AppError::RateLimited { code, message, retry_after: wait } => {
+                retry_after = Some(wait);
+                let details = json!({ "retryAfterMs": wait.as_millis() as u64 });
+                (StatusCode::TOO_MANY_REQUESTS, code, message, Some(details))
+            }
+            AppError::Unavailable { code, message, retry_after: wait } => {
+                retry_after = Some(wait);
+                (StatusCode::SERVICE_UNAVAILABLE, code, message, None)
+            }
+            AppError::Internal(cause) => {
+                // Within the request's span, so the cause can be found by the request id
+                tracing::error!("{:#}", cause);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal", "Internal server error.".to_string(), None)
+            }
+        };
+
+        let mut response = (status, Json(ErrorResponse { error: ErrorBody { code, message, details } })).into_response();
+        if let Some(retry_after) = retry_after {
+            // Whole seconds, rounded up so the client doesn't come back too early
+            let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Internal(error)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        Self::Internal(error.into())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Internal(error.into())
+    }
+}
+
+// Until the auth code returns `AppError` itself
+impl From<AuthError> for AppError {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::WrongCredentials => Self::Unauthorized("Wrong credentials.".to_string()),
+            // 401 as well, so clients can't tell a missing cookie from a malformed one
+            AuthError::MissingCredentials => Self::Unauthorized("Missing credentials.".to_string()),
+            AuthError::UserExists => Self::Conflict("User already exists.".to_string()),
+            AuthError::UserInfoNotFound => Self::NotFound("User information not found.".to_string()),
+            AuthError::TokenCreation => Self::Internal(anyhow::anyhow!("Token creation failed")),
+            AuthError::PasswordHashingFailed => Self::Internal(anyhow::anyhow!("Password hashing failed")),
+            AuthError::DbError => Self::Internal(anyhow::anyhow!("Database error")),
+        }
+    }
+}
+
+impl From<AppendEventsError> for AppError {
+    fn from(error: AppendEventsError) -> Self {
+        match error {
+            AppendEventsError::NotFound => Self::not_found("Canvas not found."),
+            AppendEventsError::Forbidden => Self::forbidden("Insufficient permissions."),
+            AppendEventsError::InvalidPayload(reason) => {
+                Self::BadRequest { code: "invalid_payload", message: reason, details: None }
+            }
+            AppendEventsError::InvalidEvents(invalid) => Self::BadRequest {
+                code: "invalid_events",
+                message: "Some events are invalid. None of the events were saved.".to_string(),
+                details: Some(json!({ "invalidEvents": invalid })),
+            },
+            AppendEventsError::QueueFull => Self::Unavailable {
+                code: "write_queue_full",
+                message: "The canvas is busy. None of the events were saved, try again shortly.".to_string(),
+                retry_after: Duration::from_secs(1),
+            },
+            AppendEventsError::Busy => Self::Unavailable {
+                code: "busy",
+                message: "The server is busy with other maintenance work. Try again shortly.".to_string(),
+                retry_after: Duration::from_secs(5),
+            },
+            AppendEventsError::BatchNotFound => {
+                Self::not_found("The held events were already reviewed or do not exist.")
+            }
+            AppendEventsError::Banned(ban) => Self::Forbidden {
+                code: "banned",
+                message: "You are banned from this canvas.".to_string(),
+                details: Some(json!({ "expiresAt": ban.expires_at, "reason": ban.reason })),
+            },
+            AppendEventsError::Muted(until) => Self::Forbidden {
+                code: "muted",
+                message: "You are muted on this canvas. None of the events were saved.".to_string(),
+                details: Some(json!({ "mutedUntil": until })),
+            },
+            AppendEventsError::SlowMode(retry_after_ms) => Self::RateLimited {
+                code: "slow_mode",
+                message: "Slow mode is on. None of the events were saved, wait before sending more.".to_string(),
+                retry_after: Duration::from_millis(retry_after_ms),
+            },
+            AppendEventsError::ContentFiltered => Self::Unprocessable {
+                code: "content_filtered",
+                message: "The text contains blocked words.".to_string(),
+            },
+            AppendEventsError::Presenting => Self::Forbidden {
+                code: "presentation_mode",
+                message: "Only the presenters can draw while the canvas is in presentation mode.".to_string(),
+                details: None,
+            },
+            AppendEventsError::Storage(cause) => {
+                Self::Internal(anyhow::anyhow!("Failed to access the canvas events: {}", cause))
+            }
+        }
+    }
+}
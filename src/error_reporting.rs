@@ -0,0 +1,174 @@
+// Optional error reporting to Sentry (or a compatible service), built with the `sentry` feature and
+// enabled by `SENTRY_DSN`. Without either, every function here does nothing.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::canvas_id::CanvasId;
+
+/// Keeps the client alive; dropping it at the end of `main` sends the events still queued.
+#[cfg(feature = "sentry")]
+pub type Guard = sentry::ClientInitGuard;
+#[cfg(not(feature = "sentry"))]
+pub type Guard = ();
+
+/// Starts reporting if `SENTRY_DSN` is set: `error!` events and panics are sent, `warn!` and `info!`
+/// events come along as breadcrumbs. Call after `setup_tracing`, which reads `.env`.
+#[cfg(feature = "sentry")]
+pub fn init() -> Option<Guard> {
+    use std::sync::Arc;
+
+    let dsn = std::env::var("SENTRY_DSN").ok().filter(|dsn| !dsn.trim().is_empty())?;
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: Some(format!("{}@{}+{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("GIT_COMMIT")).into()),
+            environment: std::env::var("SENTRY_ENVIRONMENT").ok().map(Into::into),
+            attach_stacktrace: true,
+            send_default_pii: false,
+            before_send: Some(Arc::new(scrub::event)),
+            before_breadcrumb: Some(Arc::new(scrub::breadcrumb)),
+            ..Default::default()
+        },
+    ));
+    install_panic_hook();
+    tracing::info!("Reporting errors to Sentry.");
+    Some(guard)
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init() -> Option<Guard> {
+    None
+}
+
+/// The tracing layer that turns events into Sentry events and breadcrumbs, if `SENTRY_DSN` is set.
+#[cfg(feature = "sentry")]
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    std::env::var("SENTRY_DSN")
+        .is_ok_and(|dsn| !dsn.trim().is_empty())
+        .then(sentry::integrations::tracing::layer)
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    None::<tracing_subscriber::layer::Identity>
+}
+
+/// Spawns a task whose panics and errors are reported with the user and canvas it works for.
+/// The task keeps its tracing span as with `tokio::spawn`, so instrument the future as before.
+#[cfg(feature = "sentry")]
+pub fn spawn<F>(task: &'static str, user_id: Option<i64>, canvas_id: Option<&CanvasId>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    use std::sync::Arc;
+    use sentry::SentryFutureExt;
+
+    if sentry::Hub::current().client().is_none() {
+        return tokio::spawn(future);
+    }
+    let hub = Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("task", task);
+        if let Some(user_id) = user_id {
+            scope.set_user(Some(sentry::User { id: Some(user_id.to_string()), ..Default::default() }));
+        }
+        if let Some(canvas_id) = canvas_id {
+            scope.set_tag("canvas_id", canvas_id);
+        }
+    });
+    tokio::spawn(future.bind_hub(hub))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn spawn<F>(_task: &'static str, _user_id: Option<i64>, _canvas_id: Option<&CanvasId>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future)
+}
+
+/// Logs panics as `error!` events, which the Sentry layer reports with the scope of the panicking task
+/// (see `spawn`). The previous hook still prints the panic.
+#[cfg(feature = "sentry")]
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info.location().map(|location| location.to_string()).unwrap_or_default();
+        tracing::error!(panic.location = %location, "Panic: {}", message);
+        previous(info);
+    }));
+}
+
+/// Removes passwords, tokens and cookies before anything leaves the process.
+#[cfg(feature = "sentry")]
+mod scrub {
+    use sentry::protocol::{Breadcrumb, Context, Event, Map, Value};
+
+    const FILTERED: &str = "[Filtered]";
+
+    /// Parts of field and header names whose values are never sent.
+    const SENSITIVE: [&str; 7] = ["password", "pwd", "token", "secret", "cookie", "authorization", "jwt"];
+
+    fn is_sensitive(key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        SENSITIVE.iter().any(|sensitive| key.contains(sensitive))
+    }
+
+    fn fields(map: &mut Map<String, Value>) {
+        for (key, value) in map.iter_mut() {
+            if is_sensitive(key) {
+                *value = Value::String(FILTERED.to_string());
+            }
+        }
+    }
+
+    pub fn event(mut event: Event<'static>) -> Option<Event<'static>> {
+        if let Some(request) = event.request.as_mut() {
+            request.cookies = None;
+            request.data = None;
+            for (name, value) in request.headers.iter_mut() {
+                if is_sensitive(name) {
+                    *value = FILTERED.to_string();
+                }
+            }
+        }
+        fields(&mut event.extra);
+        for context in event.contexts.values_mut() {
+            if let Context::Other(map) = context {
+                fields(map);
+            }
+        }
+        for (key, value) in event.tags.iter_mut() {
+            if is_sensitive(key) {
+                *value = FILTERED.to_string();
+            }
+        }
+        for breadcrumb in event.breadcrumbs.values.iter_mut() {
+            fields(&mut breadcrumb.data);
+        }
+        Some(event)
+    }
+
+    pub fn breadcrumb(mut breadcrumb: Breadcrumb) -> Option<Breadcrumb> {
+        fields(&mut breadcrumb.data);
+        Some(breadcrumb)
+    }
+}
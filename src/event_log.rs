@@ -0,0 +1,510 @@
+use std::{
+    collections::HashSet,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Map, Value};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+};
+
+/// `type` of the tombstone records written when events are deleted.
+pub const TOMBSTONE_TYPE: &str = "delete";
+
+/// `type` of the records of moderation and administrative actions. Only written by the server.
+pub const SYSTEM_TYPE: &str = "system";
+
+/// `action` of the system event that clears a canvas. Histories start at the last one.
+pub const CLEAR_ACTION: &str = "clear";
+
+/// Suffix of the sidecar file torn lines are moved to, e.g. `canvas.jsonl.corrupt`.
+const CORRUPT_SUFFIX: &str = ".corrupt";
+
+/// Suffix of the sidecar file `verify_log` moves corrupt lines to.
+const QUARANTINE_SUFFIX: &str = ".quarantine";
+
+/// Suffix of a compressed event log, e.g. `canvas.jsonl.gz`.
+pub const GZIP_SUFFIX: &str = ".gz";
+
+/// Size of the blocks read while looking for the start of a torn last line.
+const TAIL_SCAN_BLOCK_BYTES: u64 = 8 * 1024;
+
+/// Field holding the CRC-32 of an event, computed over the event without this field.
+pub const CRC_FIELD: &str = "_crc";
+
+/// Opens an event log for reading. If the log is missing, its gzip-compressed form
+/// (`GZIP_SUFFIX`) is read instead and decompressed on the fly.
+/// A plain log takes precedence, since appends always go to the plain file.
+pub async fn open_log(file_path: &Path) -> std::io::Result<Box<dyn AsyncBufRead + Send + Unpin>> {
+    let not_found = match File::open(file_path).await {
+        Ok(file) => return Ok(Box::new(BufReader::new(file))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => e,
+        Err(e) => return Err(e),
+    };
+
+    match File::open(path_with_suffix(file_path, GZIP_SUFFIX)).await {
+        Ok(file) => Ok(Box::new(BufReader::new(GzipDecoder::new(BufReader::new(file))))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(not_found),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads all events of a canvas event log line by line. Corrupt lines are skipped.
+pub async fn read_events(file_path: &Path) -> std::io::Result<Vec<Value>> {
+    let mut lines = open_log(file_path).await?.split(b'\n');
+    let mut events = Vec::new();
+
+    while let Some(line) = lines.next_segment().await? {
+        events.extend(parse_line(&line, file_path));
+    }
+
+    Ok(events)
+}
+
+/// Parses one line of an event log. Empty lines are skipped, corrupt ones are skipped with a warning.
+pub fn parse_line(line: &[u8], file_path: &Path) -> Option<Value> {
+    decode_line(line).unwrap_or_else(|reason| {
+        tracing::warn!("Skipping corrupt line in event log {}: {}", file_path.display(), reason);
+        None
+    })
+}
+
+/// Decodes one line of an event log, `None` for an empty line.
+/// A line is corrupt if it is not valid JSON or does not match its checksum.
+/// Events written before checksums were introduced have none and are taken as they are.
+pub fn decode_line(line: &[u8]) -> Result<Option<Value>, String> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    let event: Value = serde_json::from_slice(line).map_err(|e| format!("invalid JSON: {}", e))?;
+    match event.get(CRC_FIELD).map(Value::as_u64) {
+        None => Ok(Some(event)),
+        Some(Some(crc)) if crc == u64::from(checksum(&event)) => Ok(Some(event)),
+        Some(_) => Err("checksum mismatch".to_string()),
+    }
+}
+
+/// Stamps an event with the checksum of its content. Call once every other field is set.
+pub fn stamp_crc(event: &mut Value) {
+    let crc = checksum(event);
+    if let Some(object) = event.as_object_mut() {
+        object.insert(CRC_FIELD.to_string(), crc.into());
+    }
+}
+
+/// CRC-32 of an event's JSON without its checksum field.
+/// Object keys are serialized in sorted order, so equal events have equal checksums.
+fn checksum(event: &Value) -> u32 {
+    let mut crc = Crc32::new();
+    // Writing into the checksum cannot fail
+    let _ = match event.as_object() {
+        Some(object) => serde_json::to_writer(&mut crc, &WithoutCrc(object)),
+        None => serde_json::to_writer(&mut crc, event),
+    };
+    crc.finish()
+}
+
+/// Serializes an event object without its checksum field.
+struct WithoutCrc<'a>(&'a Map<String, Value>);
+
+impl Serialize for WithoutCrc<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().filter(|(key, _)| key.as_str() != CRC_FIELD))
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib), computed bytewise as data is written into it.
+struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl std::io::Write for Crc32 {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        for &byte in bytes {
+            self.state ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A corrupt entry found while verifying an event log.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CorruptLine {
+    /// Line number in the log, from 1.
+    pub line: u64,
+    /// Byte offset of the line in the file. The database store reports the row id instead.
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// Result of verifying an event log.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    /// Number of lines in the log.
+    pub lines: u64,
+    pub corrupt: Vec<CorruptLine>,
+    /// Whether the corrupt lines were moved out of the log.
+    pub quarantined: bool,
+}
+
+/// Checks every line of an event log (see `decode_line`).
+/// With `quarantine`, corrupt lines are appended to a `.quarantine` sidecar file
+/// and the log is rewritten without them. Do not run it while a writer has the log open.
+/// Compressed logs can be checked, but have to be decompressed before quarantining.
+/// Offsets refer to the decompressed log.
+pub async fn verify_log(file_path: &Path, quarantine: bool) -> std::io::Result<VerifyReport> {
+    let mut reader = open_log(file_path).await?;
+    let mut report = VerifyReport::default();
+    // Only filled when quarantining
+    let mut intact = Vec::new();
+    let mut corrupt = Vec::new();
+
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            break;
+        }
+        report.lines += 1;
+
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let target = match decode_line(content) {
+            Ok(_) => &mut intact,
+            Err(reason) => {
+                report.corrupt.push(CorruptLine { line: report.lines, offset, reason });
+                &mut corrupt
+            }
+        };
+        if quarantine {
+            target.extend_from_slice(content);
+            target.push(b'\n');
+        }
+        offset += read as u64;
+    }
+
+    if !quarantine || report.corrupt.is_empty() {
+        return Ok(report);
+    }
+
+    let sidecar_path = path_with_suffix(file_path, QUARANTINE_SUFFIX);
+    let mut sidecar = OpenOptions::new().append(true).create(true).open(&sidecar_path).await?;
+    sidecar.write_all(&corrupt).await?;
+    sidecar.sync_data().await?;
+
+    let tmp_path = path_with_suffix(file_path, ".verify.tmp");
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(&intact).await?;
+    tmp.sync_data().await?;
+    tokio::fs::rename(&tmp_path, file_path).await?;
+
+    tracing::warn!(
+        "Moved {} corrupt lines of event log {} to {}.",
+        report.corrupt.len(),
+        file_path.display(),
+        sidecar_path.display()
+    );
+    report.quarantined = true;
+    Ok(report)
+}
+
+/// Makes sure an event log ends with a complete line. A crash in the middle of an append
+/// can leave a partial last line, and the next append would continue it, corrupting both events.
+///
+/// A partial last line is moved to a `.corrupt` sidecar file next to the log and cut off.
+/// Returns true if the log had to be repaired.
+pub async fn repair_torn_tail(file_path: &Path) -> std::io::Result<bool> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(file_path).await {
+        Ok(file) => file,
+        // Nobody has drawn on the canvas yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata().await?.len();
+    if len == 0 {
+        return Ok(false);
+    }
+
+    let mut last_byte = [0u8];
+    file.seek(SeekFrom::Start(len - 1)).await?;
+    file.read_exact(&mut last_byte).await?;
+    if last_byte[0] == b'\n' {
+        return Ok(false);
+    }
+
+    // Scan backwards for the newline ending the last complete line
+    let mut line_start = 0;
+    let mut block_end = len;
+    let mut block = vec![0u8; TAIL_SCAN_BLOCK_BYTES as usize];
+    while block_end > 0 {
+        let block_start = block_end.saturating_sub(TAIL_SCAN_BLOCK_BYTES);
+        let chunk = &mut block[..(block_end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start)).await?;
+        file.read_exact(chunk).await?;
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            line_start = block_start + newline as u64 + 1;
+            break;
+        }
+        block_end = block_start;
+    }
+
+    let mut torn = Vec::new();
+    file.seek(SeekFrom::Start(line_start)).await?;
+    file.read_to_end(&mut torn).await?;
+
+    let sidecar_path = path_with_suffix(file_path, CORRUPT_SUFFIX);
+    let mut sidecar = OpenOptions::new().append(true).create(true).open(&sidecar_path).await?;
+    sidecar.write_all(&torn).await?;
+    sidecar.write_all(b"\n").await?;
+    sidecar.sync_data().await?;
+
+    file.set_len(line_start).await?;
+    file.sync_data().await?;
+
+    tracing::warn!(
+        "Event log {} ended with a torn line of {} bytes. Moved it to {}.",
+        file_path.display(),
+        torn.len(),
+        sidecar_path.display()
+    );
+    Ok(true)
+}
+
+/// Appends a suffix to the file name of a path, e.g. `canvas.jsonl` -> `canvas.jsonl.bak`.
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Appends events to a canvas event log, one JSON line per event, with a single write.
+/// With `sync`, the data is flushed to disk before returning.
+pub async fn append_events(file_path: &Path, events: &[Value], sync: bool) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(file_path).await?;
+
+    file.write_all(encode_lines(events).as_bytes()).await?;
+    file.flush().await?;
+    if sync {
+        file.sync_data().await?;
+    }
+    Ok(())
+}
+
+/// Encodes events as JSON lines, the format of the event logs.
+pub fn encode_lines(events: &[Value]) -> String {
+    events.iter().map(|event| event.to_string() + "\n").collect()
+}
+
+/// The server assigned sequence number of an event, if it has one.
+/// Events written before sequence numbers were introduced have none.
+pub fn event_seq(event: &Value) -> Option<u64> {
+    event.get("_seq").and_then(Value::as_u64)
+}
+
+/// The id of the user who sent an event, if it was stamped with one.
+pub fn event_author(event: &Value) -> Option<i64> {
+    event.get("_uid").and_then(Value::as_i64)
+}
+
+pub fn is_tombstone(event: &Value) -> bool {
+    event.get("type").and_then(Value::as_str) == Some(TOMBSTONE_TYPE)
+}
+
+pub fn is_system_event(event: &Value) -> bool {
+    event.get("type").and_then(Value::as_str) == Some(SYSTEM_TYPE)
+}
+
+pub fn is_clear(event: &Value) -> bool {
+    is_system_event(event) && event.get("action").and_then(Value::as_str) == Some(CLEAR_ACTION)
+}
+
+/// Sequence number of the last clear in the events, or 0 if there is none.
+pub fn last_clear_seq(events: &[Value]) -> u64 {
+    events.iter().rev().find(|event| is_clear(event)).and_then(event_seq).unwrap_or(0)
+}
+
+/// The current server time in milliseconds since the Unix epoch, as stamped into `_ts`.
+pub fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The highest sequence number in the log, or 0 if no event has one.
+pub fn last_seq(events: &[Value]) -> u64 {
+    events.iter().filter_map(event_seq).max().unwrap_or(0)
+}
+
+/// Builds the tombstone record deleting the events with the given sequence numbers.
+/// Its own sequence number is stamped when it is written.
+pub fn tombstone(targets: &[u64], by: i64) -> Value {
+    json!({
+        "type": TOMBSTONE_TYPE,
+        "targets": targets,
+        "by": by,
+    })
+}
+
+/// Builds the record of an action taken on a canvas, e.g. `moderation_on`, so replays can show
+/// when and by whom it was taken, and on whom for actions on a user (`target`).
+/// Its own sequence number is stamped when it is written.
+pub fn system_event(action: &str, by: i64, target: Option<i64>) -> Value {
+    let mut event = json!({
+        "type": SYSTEM_TYPE,
+        "action": action,
+        "by": by,
+        "_ts": timestamp_ms(),
+    });
+    if let Some(target) = target {
+        event["target"] = target.into();
+    }
+    event
+}
+
+/// Pre-applies tombstones: removes deleted events and the tombstones themselves.
+pub fn apply_tombstones(events: Vec<Value>) -> Vec<Value> {
+    let deleted: HashSet<u64> = events
+        .iter()
+        .filter(|event| is_tombstone(event))
+        .filter_map(|event| event.get("targets").and_then(Value::as_array))
+        .flatten()
+        .filter_map(Value::as_u64)
+        .collect();
+
+    events
+        .into_iter()
+        .filter(|event| !is_tombstone(event))
+        .filter(|event| event_seq(event).is_none_or(|seq| !deleted.contains(&seq)))
+        .collect()
+}
+
+/// Share of the log that compaction would drop: tombstones and the events they delete.
+pub fn garbage_ratio(events: &[Value]) -> f64 {
+    if events.is_empty() {
+        return 0.0;
+    }
+    let garbage = events.len() - apply_tombstones(events.to_vec()).len();
+    garbage as f64 / events.len() as f64
+}
+
+/// Applies the tombstones of a log and renumbers the surviving events from 1,
+/// in their original order.
+pub fn compact_events(events: Vec<Value>) -> Vec<Value> {
+    let mut events = apply_tombstones(events);
+    for (seq, event) in (1u64..).zip(events.iter_mut()) {
+        if let Some(object) = event.as_object_mut() {
+            object.insert("_seq".to_string(), Value::from(seq));
+        }
+        stamp_crc(event);
+    }
+    events
+}
+
+/// Size of events in the log format: one JSON line per event.
+pub fn encoded_len(events: &[Value]) -> u64 {
+    events.iter().map(|event| event.to_string().len() as u64 + 1).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` to a log in a fresh temporary directory and returns its path.
+    fn fixture(content: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("drawing_app_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("canvas.jsonl");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn sidecar(path: &Path) -> String {
+        std::fs::read_to_string(path_with_suffix(path, CORRUPT_SUFFIX)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn torn_last_lines_are_moved_to_the_sidecar() {
+        let long_id = "x".repeat(3 * TAIL_SCAN_BLOCK_BYTES as usize);
+        let long_line = format!(r#"{{"type":"shapeAdded","shape":{{"id":"{}"}}"#, long_id);
+        let fixtures = [
+            // Log, what stays in the log, what goes to the sidecar
+            ("{\"_seq\":1}\n{\"_seq\":2}\n{\"_seq\":", "{\"_seq\":1}\n{\"_seq\":2}\n", "{\"_seq\":"),
+            ("{\"_seq\":1}\n{\"_seq\":2}", "{\"_seq\":1}\n", "{\"_seq\":2}"),
+            ("{\"_seq\":1", "", "{\"_seq\":1"),
+            ("{\"_seq\":1}\n\n  ", "{\"_seq\":1}\n\n", "  "),
+            // A torn line spanning several scan blocks
+            (&format!("{{\"_seq\":1}}\n{}", long_line), "{\"_seq\":1}\n", &long_line),
+        ];
+        for (log, kept, torn) in fixtures {
+            let path = fixture(log.as_bytes());
+            assert!(repair_torn_tail(&path).await.unwrap(), "{:?}", log);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), kept);
+            assert_eq!(sidecar(&path), format!("{}\n", torn));
+            // Repaired logs are left alone
+            assert!(!repair_torn_tail(&path).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn complete_empty_and_missing_logs_need_no_repair() {
+        for log in ["{\"_seq\":1}\n{\"_seq\":2}\n", "\n", ""] {
+            let path = fixture(log.as_bytes());
+            assert!(!repair_torn_tail(&path).await.unwrap(), "{:?}", log);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+            assert!(!path_with_suffix(&path, CORRUPT_SUFFIX).exists());
+        }
+
+        let missing = fixture(b"").with_file_name("missing.jsonl");
+        assert!(!repair_torn_tail(&missing).await.unwrap());
+        assert!(!missing.exists());
+    }
+
+    #[tokio::test]
+    async fn torn_lines_of_later_crashes_are_added_to_the_sidecar() {
+        let path = fixture(b"{\"_seq\":1}\n{\"_se");
+        repair_torn_tail(&path).await.unwrap();
+        append_events(&path, &[json!({ "_seq": 2 })], false).await.unwrap();
+        // Crashed in the middle of the next append
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"{\"_seq\":3,").unwrap();
+        repair_torn_tail(&path).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"_seq\":1}\n{\"_seq\":2}\n");
+        assert_eq!(sidecar(&path), "{\"_se\n{\"_seq\":3,\n");
+    }
+
+    #[tokio::test]
+    async fn reads_skip_a_torn_last_line() {
+        let path = fixture(b"{\"_seq\":1}\n{\"_seq\":2}\n{\"_seq\":3,\"shape\":{\"id\"");
+        let seqs: Vec<_> = read_events(&path).await.unwrap().iter().filter_map(event_seq).collect();
+        assert_eq!(seqs, [1, 2]);
+    }
+}
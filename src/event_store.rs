@@ -0,0 +1,1454 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{query, SqliteConnection, SqlitePool};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    sync::{watch, Mutex as AsyncMutex},
+};
+
+use crate::{
+    blocking::{BlockingPool, DEFAULT_BLOCKING_CONCURRENCY},
+    event_log::{
+        append_events, decode_line, encode_lines, event_seq, last_seq, open_log, path_with_suffix, read_events,
+        repair_torn_tail, verify_log, CorruptLine, VerifyReport, GZIP_SUFFIX,
+    },
+};
+
+/// Where the event logs of the canvases are stored.
+///
+/// Events are stamped with their sequence number before they are appended,
+/// the store keeps them in the order they were appended.
+pub trait EventStore: Send + Sync {
+    /// Opens the log of a canvas for appending.
+    fn open_writer<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Box<dyn EventWriter>>>;
+
+    /// Streams the events of a canvas with a sequence number above `since_seq`, in log order.
+    /// Without `since_seq`, the whole log is streamed, including events written before
+    /// sequence numbers were introduced. A canvas nobody has drawn on yet has no events;
+    /// a canvas whose log went missing fails with `io::ErrorKind::NotFound`.
+    fn read_since<'a>(&'a self, canvas_id: &'a str, since_seq: Option<u64>) -> BoxStream<'a, io::Result<Value>>;
+
+    /// Replaces the whole log of a canvas, e.g. after compaction or to restore an archive.
+    /// The old log is archived (see `list_archives`). Call only while the canvas' writer is stopped.
+    fn replace_log<'a>(&'a self, canvas_id: &'a str, events: &'a [Value]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Lists the archived logs of a canvas, newest first.
+    fn list_archives<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<LogArchive>>>;
+
+    /// Reads an archived log. Fails with `io::ErrorKind::NotFound` if there is no such archive.
+    fn read_archive<'a>(&'a self, canvas_id: &'a str, archive_id: u64) -> BoxFuture<'a, io::Result<Vec<Value>>>;
+
+    /// Removes the archives of all canvases that are older than `retention`. Returns how many were removed.
+    fn prune_archives(&self, retention: Duration) -> BoxFuture<'_, io::Result<usize>>;
+
+    /// Creates an empty log for a canvas that has none, e.g. because its event file was lost.
+    /// Returns whether a log was created.
+    fn create<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Checks the log of a canvas for corrupt entries: invalid JSON or a checksum mismatch.
+    /// With `quarantine`, corrupt entries are moved out of the log. Call only while the canvas' writer is stopped.
+    fn verify<'a>(&'a self, canvas_id: &'a str, quarantine: bool) -> BoxFuture<'a, io::Result<VerifyReport>>;
+
+    /// Compresses the log of a canvas that was not appended to for at least `idle_for`.
+    /// Reads keep working on the compressed log; the first append decompresses it again.
+    /// Call only while the canvas has no writer. Returns whether the log was compressed.
+    fn compress<'a>(&'a self, canvas_id: &'a str, idle_for: Duration) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Restores the plain log of a compressed canvas. Returns whether it was compressed.
+    fn decompress<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Number of corrupt entries skipped by the last full read of a canvas' log.
+    fn corrupt_entries(&self, _canvas_id: &str) -> u64 {
+        0
+    }
+
+    /// Uploads the log of a canvas to remote storage if it changed, e.g. when the canvas unloads.
+    /// Stores without remote storage have nothing to do.
+    fn sync<'a>(&'a self, _canvas_id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Uploads every changed log to remote storage, e.g. on shutdown.
+    fn sync_all(&self) -> BoxFuture<'_, io::Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// The sync state of the logs with remote storage, empty for stores without one.
+    fn sync_status(&self) -> Vec<RemoteSyncStatus> {
+        Vec::new()
+    }
+
+    /// Reads the whole log of a canvas.
+    fn read_all<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<Value>>> {
+        self.read_since(canvas_id, None).try_collect().boxed()
+    }
+}
+
+/// A log replaced by `EventStore::replace_log`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogArchive {
+    /// When the log was archived, in milliseconds since the Unix epoch. Identifies the archive.
+    pub id: u64,
+    pub bytes: u64,
+}
+
+/// The sync state of a canvas' log with remote storage (see `EventStore::sync_status`).
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteSyncStatus {
+    pub canvas_id: String,
+    /// Appended to since the last upload.
+    pub dirty: bool,
+    /// When the log was last uploaded, in milliseconds since the Unix epoch.
+    pub last_upload_ms: Option<u64>,
+    /// Why the last upload failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// Milliseconds since the Unix epoch, the id of an archive created now.
+fn archive_id_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The id of the oldest archive kept with `retention`.
+fn archive_cutoff(retention: Duration) -> u64 {
+    archive_id_now().saturating_sub(retention.as_millis() as u64)
+}
+
+/// The open log of one canvas, owned by the canvas' writer task.
+pub trait EventWriter: Send {
+    /// Appends events to the log. Returns once they reached the store's durability point,
+    /// with the highest sequence number among the appended events, 0 if none has one.
+    fn append<'a>(&'a mut self, events: &'a [Value]) -> BoxFuture<'a, io::Result<u64>>;
+}
+
+/// The available event stores, selected with `CANVAS_STORE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// One `.jsonl` file per canvas in the canvases directory, named by the canvas' `event_file_path`.
+    File,
+    /// The `canvas_events` table.
+    Sqlite,
+    /// Local event files, backed by an S3-compatible bucket (see `S3EventStore`).
+    #[cfg(feature = "s3")]
+    S3,
+}
+
+impl FromStr for StoreKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "file" => Ok(StoreKind::File),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            #[cfg(feature = "s3")]
+            "s3" => Ok(StoreKind::S3),
+            _ => Err(()),
+        }
+    }
+}
+
+impl StoreKind {
+    /// The value of `CANVAS_STORE` that selects this store.
+    pub fn name(self) -> &'static str {
+        match self {
+            StoreKind::File => "file",
+            StoreKind::Sqlite => "sqlite",
+            #[cfg(feature = "s3")]
+            StoreKind::S3 => "s3",
+        }
+    }
+}
+
+/// Settings of the event store, read by `AppConfig::from_env`.
+#[derive(Debug, Clone)]
+pub struct EventStoreConfig {
+    pub kind: StoreKind,
+    /// Durability of the event files, for the file store and the local files of the bucket store.
+    pub durability: Durability,
+    /// Heavy file jobs, e.g. compressing logs, running at once (see `BlockingPool`).
+    pub blocking_concurrency: usize,
+    /// The bucket, for `StoreKind::S3`.
+    #[cfg(feature = "s3")]
+    pub s3: Option<crate::s3_store::S3Config>,
+}
+
+impl Default for EventStoreConfig {
+    fn default() -> Self {
+        Self {
+            kind: StoreKind::File,
+            durability: Durability::PerBatch,
+            blocking_concurrency: DEFAULT_BLOCKING_CONCURRENCY,
+            #[cfg(feature = "s3")]
+            s3: None,
+        }
+    }
+}
+
+/// Creates the configured event store, running its heavy file jobs on `blocking`.
+pub fn from_config(
+    pool: SqlitePool,
+    data_dir: &Path,
+    config: &EventStoreConfig,
+    blocking: BlockingPool,
+) -> Arc<dyn EventStore> {
+    match config.kind {
+        StoreKind::File => {
+            tracing::info!("Storing canvas events in files. Durability: {:?}", config.durability);
+            Arc::new(FileEventStore::new(pool, canvases_dir(data_dir), config.durability, blocking))
+        }
+        StoreKind::Sqlite => {
+            tracing::info!("Storing canvas events in the database.");
+            Arc::new(SqliteEventStore::new(pool))
+        }
+        #[cfg(feature = "s3")]
+        StoreKind::S3 => {
+            let s3 = config.s3.as_ref().expect("AppConfig reads the bucket settings for CANVAS_STORE=s3");
+            crate::s3_store::open(pool, canvases_dir(data_dir), s3, config.durability, blocking)
+        }
+    }
+}
+
+// ============================= Files =============================
+
+/// Directory of the event files, below the data directory.
+const CANVASES_DIR: &str = "canvases";
+
+/// Directory of the archived event files, below the canvases directory.
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// Returns the directory holding the event files.
+pub fn canvases_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(CANVASES_DIR)
+}
+
+/// Returns the file name of a canvas' event log, as stored in `event_file_path`.
+pub fn event_file_name(canvas_id: &str) -> String {
+    format!("{}.jsonl", canvas_id)
+}
+
+/// Resolves a stored `event_file_path`.
+/// Current rows hold only the file name, relative to the canvases directory.
+/// Rows created before `DATA_DIR` hold a full path, which is used as is.
+fn resolve_event_file(canvases_dir: &Path, stored: &str) -> PathBuf {
+    let stored = Path::new(stored);
+    if stored.parent().is_some_and(|parent| !parent.as_os_str().is_empty()) {
+        stored.to_path_buf()
+    } else {
+        canvases_dir.join(stored)
+    }
+}
+
+/// Default interval of the periodic sync in `Durability::Interval` mode.
+pub const DEFAULT_SYNC_INTERVAL_MS: u64 = 100;
+
+/// When appended events are flushed to disk (`fsync`).
+/// An append returns only once its events reached this point, so acknowledged events
+/// survive a power loss unless durability is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync, the OS writes the data back eventually.
+    None,
+    /// Sync after every append, i.e. once per message batch.
+    PerBatch,
+    /// A flusher task per canvas syncs the file periodically; appends wait for the next sync.
+    Interval(Duration),
+}
+
+/// Result of the last periodic sync of a file: the number of appends on disk, or the number of appends
+/// the failed sync was to cover and the error. Appends written after a failed sync wait for the next one.
+type SyncState = Result<u64, (u64, String)>;
+
+/// Periodic sync bookkeeping of one event file in `Durability::Interval` mode.
+struct PeriodicSync {
+    /// Number of appends written to the file since the flusher task started.
+    written: u64,
+    synced: Arc<watch::Sender<SyncState>>,
+}
+
+type PeriodicSyncs = Arc<Mutex<HashMap<PathBuf, PeriodicSync>>>;
+
+/// Stores each canvas log in its own `.jsonl` file, one JSON event per line.
+/// Logs of cold canvases may be gzip-compressed to a `.jsonl.gz` file next to it (see `compress`).
+pub struct FileEventStore {
+    pool: SqlitePool,
+    canvases_dir: PathBuf,
+    /// Event file paths by canvas id. The path of a canvas never changes once it is created.
+    paths: Mutex<HashMap<String, PathBuf>>,
+    durability: Durability,
+    /// Files with a running flusher task, in `Durability::Interval` mode.
+    syncs: PeriodicSyncs,
+    /// Corrupt lines skipped by the last full read, by canvas id.
+    corrupt: Mutex<HashMap<String, u64>>,
+    /// Held while a log is compressed or decompressed, so two of them never work on the same files.
+    compression: AsyncMutex<()>,
+    /// Runs the gzip work off the async threads.
+    blocking: BlockingPool,
+}
+
+impl FileEventStore {
+    pub fn new(pool: SqlitePool, canvases_dir: PathBuf, durability: Durability, blocking: BlockingPool) -> Self {
+        Self {
+            pool,
+            canvases_dir,
+            paths: Mutex::new(HashMap::new()),
+            durability,
+            syncs: Arc::new(Mutex::new(HashMap::new())),
+            corrupt: Mutex::new(HashMap::new()),
+            compression: AsyncMutex::new(()),
+            blocking,
+        }
+    }
+
+    /// Looks up the event file of a canvas in the DB, the first time it is needed.
+    pub(crate) async fn path(&self, canvas_id: &str) -> io::Result<PathBuf> {
+        if let Some(path) = self.paths.lock().unwrap().get(canvas_id) {
+            return Ok(path.clone());
+        }
+
+        let row = query!("SELECT event_file_path FROM Canvas WHERE canvas_id = ?", canvas_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("canvas {} does not exist", canvas_id)))?;
+
+        let path = resolve_event_file(&self.canvases_dir, &row.event_file_path);
+        self.paths.lock().unwrap().insert(canvas_id.to_string(), path.clone());
+        Ok(path)
+    }
+
+    /// Path of an archived log: `archive/{canvas_id}-{archive_id}.jsonl`.
+    fn archive_path(&self, canvas_id: &str, archive_id: u64) -> PathBuf {
+        self.canvases_dir
+            .join(ARCHIVE_DIR)
+            .join(format!("{}-{}.jsonl", canvas_id, archive_id))
+    }
+
+    /// Lists the archived logs as (canvas id, archive id, path). A missing archive directory has none.
+    async fn archive_files(&self) -> io::Result<Vec<(String, u64, PathBuf)>> {
+        let mut entries = match tokio::fs::read_dir(self.canvases_dir.join(ARCHIVE_DIR)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut archives = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some((canvas_id, archive_id)) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .and_then(|stem| stem.rsplit_once('-'))
+            else {
+                continue;
+            };
+            if let Ok(archive_id) = archive_id.parse::<u64>() {
+                archives.push((canvas_id.to_string(), archive_id, entry.path()));
+            }
+        }
+        Ok(archives)
+    }
+
+    async fn set_compressed(&self, canvas_id: &str, compressed: bool) -> io::Result<()> {
+        query!("UPDATE Canvas SET compressed = ? WHERE canvas_id = ?", compressed, canvas_id)
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Restores the plain log from the compressed one, if there is no plain log.
+    /// A plain log next to a compressed one is always the newer one (see `compress`),
+    /// so the compressed file is then just removed.
+    async fn decompress_log(&self, canvas_id: &str) -> io::Result<bool> {
+        let _compression = self.compression.lock().await;
+        let path = self.path(canvas_id).await?;
+        let gz_path = path_with_suffix(&path, GZIP_SUFFIX);
+        if !tokio::fs::try_exists(&gz_path).await? {
+            return Ok(false);
+        }
+
+        let decompressed = !tokio::fs::try_exists(&path).await?;
+        if decompressed {
+            let tmp_path = path_with_suffix(&path, ".decompress.tmp");
+            // Appends wait for the log, so this queues for a slot instead of failing
+            let restore = {
+                let (gz_path, tmp_path, path) = (gz_path.clone(), tmp_path.clone(), path.clone());
+                self.blocking
+                    .run(move || {
+                        let mut decoder = GzDecoder::new(io::BufReader::new(std::fs::File::open(&gz_path)?));
+                        let mut tmp = std::fs::File::create(&tmp_path)?;
+                        io::copy(&mut decoder, &mut tmp)?;
+                        tmp.sync_all()?;
+                        std::fs::rename(&tmp_path, &path)
+                    })
+                    .await
+            };
+            if let Err(e) = restore {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(e);
+            }
+        }
+
+        self.set_compressed(canvas_id, false).await?;
+        tokio::fs::remove_file(&gz_path).await?;
+        if decompressed {
+            tracing::info!("Decompressed event log {}.", path.display());
+        }
+        Ok(decompressed)
+    }
+}
+
+impl EventStore for FileEventStore {
+    fn open_writer<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Box<dyn EventWriter>>> {
+        async move {
+            // Appends go to the plain log
+            self.decompress_log(canvas_id).await?;
+            let path = self.path(canvas_id).await?;
+            // Appends must not continue a line torn by a crash
+            repair_torn_tail(&path).await?;
+            let file = OpenOptions::new().append(true).create(true).open(&path).await?;
+            let len = file.metadata().await?.len();
+            Ok(Box::new(FileEventWriter {
+                file,
+                path,
+                len,
+                durability: self.durability,
+                syncs: self.syncs.clone(),
+            }) as Box<dyn EventWriter>)
+        }
+        .boxed()
+    }
+
+    /// Reads the file line by line, so large logs are never held in memory as a whole.
+    /// Compressed logs are decompressed while reading.
+    /// Every canvas gets an empty file when it is created, so a missing file is an error.
+    /// Corrupt lines are skipped and counted (see `corrupt_entries`).
+    fn read_since<'a>(&'a self, canvas_id: &'a str, since_seq: Option<u64>) -> BoxStream<'a, io::Result<Value>> {
+        let open = async move {
+            let path = self.path(canvas_id).await?;
+            let reader = open_log(&path).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("event log {} could not be opened: {}", path.display(), e))
+            })?;
+            Ok::<_, io::Error>((reader.split(b'\n'), path))
+        };
+
+        stream::once(open)
+            .map_ok(move |(lines, path)| {
+                stream::try_unfold((lines, path, 0), move |(mut lines, path, mut skipped)| async move {
+                    while let Some(line) = lines.next_segment().await? {
+                        match decode_line(&line) {
+                            Ok(Some(event)) if is_after(&event, since_seq) => {
+                                return Ok(Some((event, (lines, path, skipped))));
+                            }
+                            Ok(_) => {}
+                            Err(reason) => {
+                                tracing::warn!("Skipping corrupt line in event log {}: {}", path.display(), reason);
+                                skipped += 1;
+                            }
+                        }
+                    }
+                    // Only a full read sees every line
+                    if since_seq.is_none() {
+                        self.corrupt.lock().unwrap().insert(canvas_id.to_string(), skipped);
+                    }
+                    Ok(None)
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    fn verify<'a>(&'a self, canvas_id: &'a str, quarantine: bool) -> BoxFuture<'a, io::Result<VerifyReport>> {
+        async move {
+            if quarantine {
+                self.decompress_log(canvas_id).await?;
+            }
+            let path = self.path(canvas_id).await?;
+            let report = verify_log(&path, quarantine).await?;
+            if report.quarantined {
+                self.corrupt.lock().unwrap().remove(canvas_id);
+            }
+            Ok(report)
+        }
+        .boxed()
+    }
+
+    fn corrupt_entries(&self, canvas_id: &str) -> u64 {
+        self.corrupt.lock().unwrap().get(canvas_id).copied().unwrap_or_default()
+    }
+
+    /// Writes the compressed log to a temporary file, renames it to `.jsonl.gz`,
+    /// then sets the canvas' `compressed` flag and removes the plain log.
+    /// Readers find one of the two files at every step. The modification time of the plain log
+    /// tells when it was last appended to.
+    /// Fails with `io::ErrorKind::WouldBlock` if the blocking pool has no free slot.
+    fn compress<'a>(&'a self, canvas_id: &'a str, idle_for: Duration) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let _compression = self.compression.lock().await;
+            let path = self.path(canvas_id).await?;
+            let modified = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata.modified()?,
+                // Already compressed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            if modified.elapsed().unwrap_or_default() < idle_for {
+                return Ok(false);
+            }
+
+            let gz_path = path_with_suffix(&path, GZIP_SUFFIX);
+            let tmp_path = path_with_suffix(&gz_path, ".tmp");
+            let write = {
+                let (path, tmp_path, gz_path) = (path.clone(), tmp_path.clone(), gz_path.clone());
+                self.blocking
+                    .try_run(move || {
+                        let mut plain = io::BufReader::new(std::fs::File::open(&path)?);
+                        let mut encoder = GzEncoder::new(std::fs::File::create(&tmp_path)?, Compression::default());
+                        io::copy(&mut plain, &mut encoder)?;
+                        encoder.finish()?.sync_all()?;
+                        std::fs::rename(&tmp_path, &gz_path)
+                    })
+                    .await
+            };
+            match write {
+                Ok(()) => {}
+                // Nothing was written
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(e),
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(e);
+                }
+            }
+
+            self.set_compressed(canvas_id, true).await?;
+            tokio::fs::remove_file(&path).await?;
+            tracing::info!("Compressed event log {} to {}.", path.display(), gz_path.display());
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn decompress<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        self.decompress_log(canvas_id).boxed()
+    }
+
+    fn create<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let path = self.path(canvas_id).await?;
+            // A compressed log is not missing
+            if tokio::fs::try_exists(path_with_suffix(&path, GZIP_SUFFIX)).await? {
+                return Ok(false);
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            match OpenOptions::new().write(true).create_new(true).open(&path).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+
+    /// Writes the new log to a synced temporary file in the same directory and renames it over the old one.
+    /// The old log is linked into the archive directory first, so the log path holds a complete log at every step.
+    fn replace_log<'a>(&'a self, canvas_id: &'a str, events: &'a [Value]) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            // The archive is a copy of the plain log
+            self.decompress_log(canvas_id).await?;
+            let path = self.path(canvas_id).await?;
+            let tmp_path = path_with_suffix(&path, ".replace.tmp");
+            let archive_path = self.archive_path(canvas_id, archive_id_now());
+
+            let rewrite = async {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                append_events(&tmp_path, events, true).await?;
+                if tokio::fs::try_exists(&path).await? {
+                    if let Some(parent) = archive_path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    // Logs outside the canvases directory may be on another file system
+                    if tokio::fs::hard_link(&path, &archive_path).await.is_err() {
+                        tokio::fs::copy(&path, &archive_path).await?;
+                    }
+                }
+                tokio::fs::rename(&tmp_path, &path).await
+            }
+            .await;
+
+            if let Err(e) = rewrite {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(e);
+            }
+            tracing::info!("Replaced event log {}. Archive: {}", path.display(), archive_path.display());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list_archives<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<LogArchive>>> {
+        async move {
+            let mut archives = Vec::new();
+            for (archived_canvas, id, path) in self.archive_files().await? {
+                if archived_canvas == canvas_id {
+                    let bytes = tokio::fs::metadata(&path).await?.len();
+                    archives.push(LogArchive { id, bytes });
+                }
+            }
+            archives.sort_by_key(|archive| std::cmp::Reverse(archive.id));
+            Ok(archives)
+        }
+        .boxed()
+    }
+
+    fn read_archive<'a>(&'a self, canvas_id: &'a str, archive_id: u64) -> BoxFuture<'a, io::Result<Vec<Value>>> {
+        async move { read_events(&self.archive_path(canvas_id, archive_id)).await }.boxed()
+    }
+
+    fn prune_archives(&self, retention: Duration) -> BoxFuture<'_, io::Result<usize>> {
+        async move {
+            let cutoff = archive_cutoff(retention);
+            let mut removed = 0;
+            for (_, id, path) in self.archive_files().await? {
+                if id < cutoff {
+                    tokio::fs::remove_file(&path).await?;
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        }
+        .boxed()
+    }
+}
+
+/// An event file kept open by the canvas' writer task.
+struct FileEventWriter {
+    file: File,
+    path: PathBuf,
+    /// Length of the file after the last complete append.
+    len: u64,
+    durability: Durability,
+    syncs: PeriodicSyncs,
+}
+
+impl FileEventWriter {
+    async fn write_and_sync(&mut self, lines: &[u8]) -> io::Result<()> {
+        self.file.write_all(lines).await?;
+        self.file.flush().await?;
+
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::PerBatch => self.file.sync_data().await,
+            Durability::Interval(interval) => wait_for_sync(&self.syncs, &self.path, interval).await,
+        }
+    }
+}
+
+impl EventWriter for FileEventWriter {
+    /// Writes all events with a single write. If anything fails, the file is cut back
+    /// to its previous length, so a failed append leaves none of its events behind.
+    fn append<'a>(&'a mut self, events: &'a [Value]) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let lines = encode_lines(events);
+            if let Err(e) = self.write_and_sync(lines.as_bytes()).await {
+                if let Err(truncate_error) = self.file.set_len(self.len).await {
+                    tracing::error!(
+                        "Failed to roll back a failed append to {}: {}",
+                        self.path.display(),
+                        truncate_error
+                    );
+                }
+                return Err(e);
+            }
+            self.len += lines.len() as u64;
+            Ok(last_seq(events))
+        }
+        .boxed()
+    }
+}
+
+/// Waits until the next periodic sync of the file covers an append that was just written.
+/// Starts the file's flusher task if it is not running.
+async fn wait_for_sync(syncs: &PeriodicSyncs, path: &Path, interval: Duration) -> io::Result<()> {
+    let (target, mut synced) = {
+        let mut files = syncs.lock().unwrap();
+        let sync = files.entry(path.to_path_buf()).or_insert_with(|| {
+            tokio::spawn(run_periodic_sync(syncs.clone(), path.to_path_buf(), interval));
+            PeriodicSync {
+                written: 0,
+                synced: Arc::new(watch::channel(Ok(0)).0),
+            }
+        });
+        sync.written += 1;
+        (sync.written, sync.synced.subscribe())
+    };
+
+    let state = synced
+        .wait_for(|state| match state {
+            Ok(synced) => *synced >= target,
+            Err((attempted, _)) => *attempted >= target,
+        })
+        .await
+        .map_err(|_| io::Error::other("event file flusher stopped"))?;
+    match &*state {
+        Ok(_) => Ok(()),
+        Err((_, e)) => Err(io::Error::other(e.clone())),
+    }
+}
+
+/// Syncs an event file every `interval` while appends are waiting for it.
+/// Stops once an interval passes without new appends.
+async fn run_periodic_sync(syncs: PeriodicSyncs, path: PathBuf, interval: Duration) {
+    let mut synced_appends = 0;
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let (written, synced) = {
+            let mut syncs = syncs.lock().unwrap();
+            let Some(sync) = syncs.get(&path) else {
+                return;
+            };
+            if sync.written == synced_appends {
+                syncs.remove(&path);
+                return;
+            }
+            (sync.written, sync.synced.clone())
+        };
+
+        let result = match tokio::fs::File::open(&path).await {
+            Ok(file) => file.sync_data().await,
+            // The canvas was deleted, there is nothing left to sync
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                synced_appends = written;
+                synced.send_modify(|state| *state = Ok(written));
+            }
+            // Retried with the next interval
+            Err(e) => {
+                tracing::error!("Failed to sync event file {}: {}", path.display(), e);
+                synced.send_modify(|state| *state = Err((written, e.to_string())));
+            }
+        }
+    }
+}
+
+/// Whether an event belongs to a read starting after `since_seq`.
+fn is_after(event: &Value, since_seq: Option<u64>) -> bool {
+    since_seq.is_none_or(|since_seq| event_seq(event).is_some_and(|seq| seq > since_seq))
+}
+
+// ============================= SQLite =============================
+
+/// Stores the logs of all canvases in the `canvas_events` table, one row per event.
+/// Replaced logs are archived in `canvas_events_backup`, grouped by `archive_id`.
+pub struct SqliteEventStore {
+    pool: SqlitePool,
+}
+
+impl SqliteEventStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Inserts events in order. The rowid keeps the log order, also for events without a sequence number.
+async fn insert_events(conn: &mut SqliteConnection, canvas_id: &str, events: &[Value]) -> sqlx::Result<()> {
+    for event in events {
+        let seq = event_seq(event).map(|seq| seq as i64);
+        let payload = event.to_string();
+        query!(
+            "INSERT INTO canvas_events (canvas_id, seq, payload) VALUES (?, ?, ?)",
+            canvas_id,
+            seq,
+            payload
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+impl EventStore for SqliteEventStore {
+    fn open_writer<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Box<dyn EventWriter>>> {
+        let writer = SqliteEventWriter {
+            pool: self.pool.clone(),
+            canvas_id: canvas_id.to_string(),
+        };
+        async move { Ok(Box::new(writer) as Box<dyn EventWriter>) }.boxed()
+    }
+
+    fn read_since<'a>(&'a self, canvas_id: &'a str, since_seq: Option<u64>) -> BoxStream<'a, io::Result<Value>> {
+        let since_seq = since_seq.map(|seq| seq as i64);
+
+        sqlx::query_scalar::<_, String>(
+            "SELECT payload FROM canvas_events WHERE canvas_id = ? AND (? IS NULL OR seq > ?) ORDER BY rowid",
+        )
+        .bind(canvas_id)
+        .bind(since_seq)
+        .bind(since_seq)
+        .fetch(&self.pool)
+        .map(|row| {
+            let payload = row.map_err(io::Error::other)?;
+            serde_json::from_str(&payload).map_err(io::Error::other)
+        })
+        .boxed()
+    }
+
+    /// Checks the payload of every row. Offsets in the report are row ids;
+    /// quarantined rows are moved to `canvas_events_backup`.
+    fn verify<'a>(&'a self, canvas_id: &'a str, quarantine: bool) -> BoxFuture<'a, io::Result<VerifyReport>> {
+        async move {
+            let rows = sqlx::query_as::<_, (i64, String)>(
+                "SELECT rowid, payload FROM canvas_events WHERE canvas_id = ? ORDER BY rowid",
+            )
+            .bind(canvas_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+
+            let mut report = VerifyReport::default();
+            for (line, (rowid, payload)) in (1..).zip(rows) {
+                report.lines = line;
+                if let Err(reason) = decode_line(payload.as_bytes()) {
+                    report.corrupt.push(CorruptLine { line, offset: rowid as u64, reason });
+                }
+            }
+            if !quarantine || report.corrupt.is_empty() {
+                return Ok(report);
+            }
+
+            let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+            for corrupt in &report.corrupt {
+                let rowid = corrupt.offset as i64;
+                sqlx::query(
+                    "INSERT INTO canvas_events_backup (canvas_id, seq, payload, created_at)
+                     SELECT canvas_id, seq, payload, created_at FROM canvas_events WHERE rowid = ?",
+                )
+                .bind(rowid)
+                .execute(&mut *tx)
+                .await
+                .map_err(io::Error::other)?;
+                sqlx::query("DELETE FROM canvas_events WHERE rowid = ?")
+                    .bind(rowid)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(io::Error::other)?;
+            }
+            tx.commit().await.map_err(io::Error::other)?;
+
+            tracing::warn!(
+                "Moved {} corrupt rows of canvas {} to canvas_events_backup.",
+                report.corrupt.len(),
+                canvas_id
+            );
+            report.quarantined = true;
+            Ok(report)
+        }
+        .boxed()
+    }
+
+    /// The database handles its own storage, rows are never compressed.
+    fn compress<'a>(&'a self, _canvas_id: &'a str, _idle_for: Duration) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+
+    fn decompress<'a>(&'a self, _canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+
+    /// The rows of a canvas need no setup, its log always exists.
+    fn create<'a>(&'a self, _canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+
+    fn replace_log<'a>(&'a self, canvas_id: &'a str, events: &'a [Value]) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let archive_id = archive_id_now() as i64;
+            let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+            query!(
+                "INSERT INTO canvas_events_backup (canvas_id, seq, payload, created_at, archive_id)
+                 SELECT canvas_id, seq, payload, created_at, ? FROM canvas_events WHERE canvas_id = ? ORDER BY rowid",
+                archive_id,
+                canvas_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(io::Error::other)?;
+            query!("DELETE FROM canvas_events WHERE canvas_id = ?", canvas_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(io::Error::other)?;
+            insert_events(&mut tx, canvas_id, events).await.map_err(io::Error::other)?;
+            tx.commit().await.map_err(io::Error::other)?;
+
+            tracing::info!("Replaced event log of canvas {}. Archive: {}", canvas_id, archive_id);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list_archives<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<LogArchive>>> {
+        async move {
+            let rows = sqlx::query_as::<_, (i64, i64)>(
+                "SELECT archive_id, SUM(LENGTH(payload)) FROM canvas_events_backup
+                 WHERE canvas_id = ? AND archive_id IS NOT NULL GROUP BY archive_id ORDER BY archive_id DESC",
+            )
+            .bind(canvas_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(id, bytes)| LogArchive { id: id as u64, bytes: bytes as u64 })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn read_archive<'a>(&'a self, canvas_id: &'a str, archive_id: u64) -> BoxFuture<'a, io::Result<Vec<Value>>> {
+        async move {
+            let payloads = sqlx::query_scalar::<_, String>(
+                "SELECT payload FROM canvas_events_backup WHERE canvas_id = ? AND archive_id = ? ORDER BY rowid",
+            )
+            .bind(canvas_id)
+            .bind(archive_id as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+
+            // An archived log always has rows, an empty log is not archived
+            if payloads.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("canvas {} has no archive {}", canvas_id, archive_id),
+                ));
+            }
+            payloads
+                .iter()
+                .map(|payload| serde_json::from_str(payload).map_err(io::Error::other))
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn prune_archives(&self, retention: Duration) -> BoxFuture<'_, io::Result<usize>> {
+        async move {
+            let cutoff = archive_cutoff(retention) as i64;
+            let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+            let removed = sqlx::query_scalar::<_, i64>(
+                "SELECT COUNT(*) FROM (SELECT DISTINCT canvas_id, archive_id FROM canvas_events_backup WHERE archive_id < ?)",
+            )
+            .bind(cutoff)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(io::Error::other)?;
+            sqlx::query("DELETE FROM canvas_events_backup WHERE archive_id < ?")
+                .bind(cutoff)
+                .execute(&mut *tx)
+                .await
+                .map_err(io::Error::other)?;
+            tx.commit().await.map_err(io::Error::other)?;
+            Ok(removed as usize)
+        }
+        .boxed()
+    }
+}
+
+/// Appends the events of one canvas to the table, one transaction per append.
+struct SqliteEventWriter {
+    pool: SqlitePool,
+    canvas_id: String,
+}
+
+impl EventWriter for SqliteEventWriter {
+    fn append<'a>(&'a mut self, events: &'a [Value]) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+            insert_events(&mut tx, &self.canvas_id, events).await.map_err(io::Error::other)?;
+            tx.commit().await.map_err(io::Error::other)?;
+            Ok(last_seq(events))
+        }
+        .boxed()
+    }
+}
+
+// ============================= Memory =============================
+
+/// Keeps the logs in memory, for tests of the canvas manager that need neither disk nor database.
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct MemoryEventStore {
+    logs: Arc<Mutex<HashMap<String, Vec<Value>>>>,
+    archives: Arc<Mutex<HashMap<String, Vec<MemoryArchive>>>>,
+    faults: Arc<Mutex<HashMap<String, WriteFault>>>,
+}
+
+/// A fault injected into the appends to a log of a `MemoryEventStore`.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub enum WriteFault {
+    /// Appends take this long, like on a slow disk.
+    Delay(Duration),
+    /// Appends fail without writing anything.
+    Fail,
+}
+
+/// An archived log of a `MemoryEventStore`: its id and its events.
+#[cfg(test)]
+type MemoryArchive = (u64, Vec<Value>);
+
+#[cfg(test)]
+impl MemoryEventStore {
+    /// The log of a canvas, empty if nothing was written to it.
+    pub fn log(&self, canvas_id: &str) -> Vec<Value> {
+        self.logs.lock().unwrap().get(canvas_id).cloned().unwrap_or_default()
+    }
+
+    /// Injects a fault into the appends to a log, or removes it with `None`.
+    pub fn inject(&self, canvas_id: &str, fault: Option<WriteFault>) {
+        let mut faults = self.faults.lock().unwrap();
+        match fault {
+            Some(fault) => faults.insert(canvas_id.to_string(), fault),
+            None => faults.remove(canvas_id),
+        };
+    }
+}
+
+#[cfg(test)]
+impl EventStore for MemoryEventStore {
+    fn open_writer<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Box<dyn EventWriter>>> {
+        let writer =
+            MemoryEventWriter { logs: self.logs.clone(), faults: self.faults.clone(), canvas_id: canvas_id.to_string() };
+        future::ready(Ok(Box::new(writer) as Box<dyn EventWriter>)).boxed()
+    }
+
+    fn read_since<'a>(&'a self, canvas_id: &'a str, since_seq: Option<u64>) -> BoxStream<'a, io::Result<Value>> {
+        let events: Vec<io::Result<Value>> =
+            self.log(canvas_id).into_iter().filter(|event| is_after(event, since_seq)).map(Ok).collect();
+        stream::iter(events).boxed()
+    }
+
+    fn replace_log<'a>(&'a self, canvas_id: &'a str, events: &'a [Value]) -> BoxFuture<'a, io::Result<()>> {
+        let old = self.logs.lock().unwrap().insert(canvas_id.to_string(), events.to_vec());
+        if let Some(old) = old.filter(|old| !old.is_empty()) {
+            self.archives.lock().unwrap().entry(canvas_id.to_string()).or_default().push((archive_id_now(), old));
+        }
+        future::ready(Ok(())).boxed()
+    }
+
+    fn list_archives<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<LogArchive>>> {
+        let archives = self.archives.lock().unwrap();
+        let listed = archives
+            .get(canvas_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .map(|(id, events)| LogArchive { id: *id, bytes: encode_lines(events).len() as u64 })
+            .collect();
+        future::ready(Ok(listed)).boxed()
+    }
+
+    fn read_archive<'a>(&'a self, canvas_id: &'a str, archive_id: u64) -> BoxFuture<'a, io::Result<Vec<Value>>> {
+        let archives = self.archives.lock().unwrap();
+        let archive = archives.get(canvas_id).into_iter().flatten().find(|(id, _)| *id == archive_id);
+        let result = archive.map(|(_, events)| events.clone()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("canvas {} has no archive {}", canvas_id, archive_id))
+        });
+        future::ready(result).boxed()
+    }
+
+    fn prune_archives(&self, retention: Duration) -> BoxFuture<'_, io::Result<usize>> {
+        let cutoff = archive_cutoff(retention);
+        let mut removed = 0;
+        for archives in self.archives.lock().unwrap().values_mut() {
+            let before = archives.len();
+            archives.retain(|(id, _)| *id >= cutoff);
+            removed += before - archives.len();
+        }
+        future::ready(Ok(removed)).boxed()
+    }
+
+    fn create<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        let mut logs = self.logs.lock().unwrap();
+        let created = !logs.contains_key(canvas_id);
+        logs.entry(canvas_id.to_string()).or_default();
+        future::ready(Ok(created)).boxed()
+    }
+
+    /// Events in memory can't be corrupt.
+    fn verify<'a>(&'a self, canvas_id: &'a str, _quarantine: bool) -> BoxFuture<'a, io::Result<VerifyReport>> {
+        let report = VerifyReport { lines: self.log(canvas_id).len() as u64, ..VerifyReport::default() };
+        future::ready(Ok(report)).boxed()
+    }
+
+    fn compress<'a>(&'a self, _canvas_id: &'a str, _idle_for: Duration) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+
+    fn decompress<'a>(&'a self, _canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+}
+
+/// Appends to a log of a `MemoryEventStore`.
+#[cfg(test)]
+struct MemoryEventWriter {
+    logs: Arc<Mutex<HashMap<String, Vec<Value>>>>,
+    faults: Arc<Mutex<HashMap<String, WriteFault>>>,
+    canvas_id: String,
+}
+
+#[cfg(test)]
+impl EventWriter for MemoryEventWriter {
+    fn append<'a>(&'a mut self, events: &'a [Value]) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let fault = self.faults.lock().unwrap().get(&self.canvas_id).copied();
+            match fault {
+                Some(WriteFault::Delay(delay)) => tokio::time::sleep(delay).await,
+                Some(WriteFault::Fail) => return Err(io::Error::other("injected write failure")),
+                None => {}
+            }
+            self.logs.lock().unwrap().entry(self.canvas_id.clone()).or_default().extend_from_slice(events);
+            Ok(last_seq(events))
+        }
+        .boxed()
+    }
+}
+
+// ============================= Import =============================
+
+/// Copies the event files of all canvases into the `canvas_events` table,
+/// to switch an instance to `CANVAS_STORE=sqlite`. The files are left in place.
+///
+/// Canvases that already have events in the table are skipped, so the import can be rerun.
+/// Returns the number of imported canvases.
+pub async fn import_file_logs(pool: &SqlitePool, data_dir: &Path) -> sqlx::Result<usize> {
+    let canvases_dir = canvases_dir(data_dir);
+    let canvases = query!("SELECT canvas_id, event_file_path FROM Canvas")
+        .fetch_all(pool)
+        .await?;
+    let mut imported = 0;
+
+    for canvas in canvases {
+        let existing = query!(
+            r#"SELECT COUNT(*) AS "count: i64" FROM canvas_events WHERE canvas_id = ?"#,
+            canvas.canvas_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if existing.count > 0 {
+            tracing::info!("Canvas {} already has events in the database. Skipping.", canvas.canvas_id);
+            continue;
+        }
+
+        let path = resolve_event_file(&canvases_dir, &canvas.event_file_path);
+        let events = match read_events(&path).await {
+            Ok(events) => events,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                tracing::warn!("Could not read event log {}: {}. Skipping.", path.display(), e);
+                continue;
+            }
+        };
+        if events.is_empty() {
+            continue;
+        }
+
+        let mut tx = pool.begin().await?;
+        insert_events(&mut tx, &canvas.canvas_id, &events).await?;
+        tx.commit().await?;
+
+        tracing::info!("Imported {} events of canvas {}.", events.len(), canvas.canvas_id);
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+/// Rewrites `event_file_path` values holding a full path to the file name alone,
+/// for event files that are in the canvases directory below `data_dir`.
+/// Files elsewhere are left alone; they have to be moved into the directory first.
+///
+/// Returns the number of rewritten rows.
+pub async fn relativize_event_paths(pool: &SqlitePool, data_dir: &Path) -> sqlx::Result<usize> {
+    let canvases_dir = canvases_dir(data_dir);
+    // Compares real paths, so `./data` and `/app/data` count as the same directory
+    let real_canvases_dir = tokio::fs::canonicalize(&canvases_dir).await.unwrap_or_else(|_| canvases_dir.clone());
+    let canvases = query!("SELECT canvas_id, event_file_path FROM Canvas")
+        .fetch_all(pool)
+        .await?;
+    let mut rewritten = 0;
+
+    for canvas in canvases {
+        let stored = Path::new(&canvas.event_file_path);
+        let (Some(parent), Some(file_name)) = (stored.parent(), stored.file_name().and_then(|name| name.to_str())) else {
+            continue;
+        };
+        if parent.as_os_str().is_empty() {
+            continue;
+        }
+
+        let real_parent = tokio::fs::canonicalize(parent).await.unwrap_or_else(|_| parent.to_path_buf());
+        if real_parent != real_canvases_dir {
+            tracing::warn!(
+                "Event file {} of canvas {} is outside {}. Move it there and rerun.",
+                canvas.event_file_path,
+                canvas.canvas_id,
+                canvases_dir.display()
+            );
+            continue;
+        }
+
+        query!("UPDATE Canvas SET event_file_path = ? WHERE canvas_id = ?", file_name, canvas.canvas_id)
+            .execute(pool)
+            .await?;
+        rewritten += 1;
+    }
+
+    Ok(rewritten)
+}
+
+/// Counts the events in the log of every canvas and stores the counts in `event_count`,
+/// for canvases created before the column existed. Run it while the server is stopped,
+/// a running server would overwrite the counts of loaded canvases with its own.
+///
+/// Returns the number of updated canvases.
+pub async fn backfill_event_counts(pool: &SqlitePool, store: &dyn EventStore) -> sqlx::Result<usize> {
+    let canvases = query!("SELECT canvas_id FROM Canvas").fetch_all(pool).await?;
+    let mut updated = 0;
+
+    for canvas in canvases {
+        let events = store.read_since(&canvas.canvas_id, None);
+        let count = match events.try_fold(0i64, |count, _| future::ready(Ok(count + 1))).await {
+            Ok(count) => count,
+            Err(e) => {
+                tracing::warn!("Could not read event log of canvas {}: {}. Skipping.", canvas.canvas_id, e);
+                continue;
+            }
+        };
+
+        query!("UPDATE Canvas SET event_count = ? WHERE canvas_id = ?", count, canvas.canvas_id)
+            .execute(pool)
+            .await?;
+        updated += 1;
+    }
+
+    Ok(updated)
+}
+
+/// Result of `validate_event_files`.
+#[derive(Debug, Default)]
+pub struct EventFileCheck {
+    pub canvases: usize,
+    /// Canvases without an event file name. `create_canvas` never stores one,
+    /// so these point at a bug; the server refuses to start with them.
+    pub empty_paths: Vec<String>,
+    /// Canvases whose missing event file was created empty. They had no events, so nothing was lost.
+    pub created: Vec<String>,
+    /// Canvases whose event file is missing although they had events. Left alone;
+    /// opening the canvas recreates the file and tells its owners (see `CanvasManager::recover_missing_log`).
+    pub missing: Vec<String>,
+}
+
+/// Checks that every canvas has an event file name and, with the file store, that the file exists
+/// (plain or compressed). Missing files are created empty only where that loses nothing:
+/// the canvas had no events and the file's directory exists.
+pub async fn validate_event_files(pool: &SqlitePool, data_dir: &Path, store: StoreKind) -> sqlx::Result<EventFileCheck> {
+    let canvases_dir = canvases_dir(data_dir);
+    let canvases = query!("SELECT canvas_id, event_file_path, event_count FROM Canvas")
+        .fetch_all(pool)
+        .await?;
+    let mut check = EventFileCheck { canvases: canvases.len(), ..Default::default() };
+
+    for canvas in canvases {
+        if canvas.event_file_path.trim().is_empty() {
+            tracing::error!("Canvas {} has no event file path.", canvas.canvas_id);
+            check.empty_paths.push(canvas.canvas_id);
+            continue;
+        }
+        if store != StoreKind::File {
+            continue;
+        }
+
+        let path = resolve_event_file(&canvases_dir, &canvas.event_file_path);
+        let exists = tokio::fs::try_exists(&path).await.unwrap_or(false)
+            || tokio::fs::try_exists(path_with_suffix(&path, GZIP_SUFFIX)).await.unwrap_or(false);
+        if exists {
+            continue;
+        }
+
+        let parent_exists = match path.parent() {
+            Some(parent) => tokio::fs::try_exists(parent).await.unwrap_or(false),
+            None => false,
+        };
+        if canvas.event_count > 0 || !parent_exists {
+            tracing::warn!("Event file {} of canvas {} is missing.", path.display(), canvas.canvas_id);
+            check.missing.push(canvas.canvas_id);
+            continue;
+        }
+
+        match OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(_) => {
+                tracing::info!("Created missing empty event file {} of canvas {}.", path.display(), canvas.canvas_id);
+                check.created.push(canvas.canvas_id);
+            }
+            Err(e) => {
+                tracing::warn!("Could not create event file {} of canvas {}: {}", path.display(), canvas.canvas_id, e);
+                check.missing.push(canvas.canvas_id);
+            }
+        }
+    }
+
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A file store in a fresh temporary directory. Its canvases are added with `add_canvas`, without a DB.
+    fn temp_file_store(durability: Durability) -> FileEventStore {
+        let dir = std::env::temp_dir().join(format!("drawing_app_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = SqlitePool::connect_lazy("sqlite:///nonexistent/db.sqlite").unwrap();
+        FileEventStore::new(pool, dir, durability, BlockingPool::new(DEFAULT_BLOCKING_CONCURRENCY))
+    }
+
+    /// Adds a canvas to a file store as if its path had been looked up, and returns the path.
+    fn add_canvas(store: &FileEventStore, canvas_id: &str) -> PathBuf {
+        let path = store.canvases_dir.join(event_file_name(canvas_id));
+        store.paths.lock().unwrap().insert(canvas_id.to_string(), path.clone());
+        path
+    }
+
+    fn numbered(from_seq: u64, count: u64) -> Vec<Value> {
+        (from_seq..from_seq + count).map(|seq| json!({ "type": "shapeRemovedWithId", "shapeId": "s", "_seq": seq })).collect()
+    }
+
+    /// The latency an append takes with each durability setting, reported with `--nocapture`.
+    /// The numbers in the architecture documentation come from a run with `APPENDS = 2000`.
+    #[tokio::test]
+    async fn durability_latency_benchmark() {
+        const APPENDS: u64 = 100;
+        const EVENTS_PER_APPEND: u64 = 5;
+        let interval = Duration::from_millis(5);
+
+        for durability in [Durability::None, Durability::PerBatch, Durability::Interval(interval)] {
+            let store = temp_file_store(durability);
+            let path = add_canvas(&store, "bench");
+            let mut writer = store.open_writer("bench").await.unwrap();
+
+            let started = Instant::now();
+            for append in 0..APPENDS {
+                let events = numbered(append * EVENTS_PER_APPEND + 1, EVENTS_PER_APPEND);
+                assert_eq!(writer.append(&events).await.unwrap(), (append + 1) * EVENTS_PER_APPEND);
+            }
+            let per_append = started.elapsed() / APPENDS as u32;
+            eprintln!("{:?}: {:?} per append of {} events", durability, per_append, EVENTS_PER_APPEND);
+
+            // Every acknowledged append is in the file
+            let lines = std::fs::read_to_string(&path).unwrap().lines().count() as u64;
+            assert_eq!(lines, APPENDS * EVENTS_PER_APPEND, "{:?}", durability);
+            // Appends wait for the next periodic sync
+            if let Durability::Interval(interval) = durability {
+                assert!(per_append >= interval / 2, "{:?} per append", per_append);
+            }
+            std::fs::remove_dir_all(&store.canvases_dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn writers_cut_off_a_torn_last_line_before_appending() {
+        let store = temp_file_store(Durability::None);
+        let path = add_canvas(&store, "torn");
+        std::fs::write(&path, "{\"_seq\":1}\n{\"_seq\":2,\"shape\"").unwrap();
+
+        let mut writer = store.open_writer("torn").await.unwrap();
+        writer.append(&numbered(2, 2)).await.unwrap();
+
+        let seqs: Vec<_> = store.read_all("torn").await.unwrap().iter().filter_map(event_seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        std::fs::remove_dir_all(&store.canvases_dir).unwrap();
+    }
+
+    /// Makes the periodic flusher fail to reopen a log, by putting a symlink loop in its place.
+    /// Returns where the log was moved to.
+    fn break_log(path: &Path) -> PathBuf {
+        let moved = path.with_extension("moved");
+        std::fs::rename(path, &moved).unwrap();
+        std::os::unix::fs::symlink(path, path).unwrap();
+        moved
+    }
+
+    fn restore_log(path: &Path, moved: &Path) {
+        std::fs::remove_file(path).unwrap();
+        std::fs::rename(moved, path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn appends_after_a_failed_periodic_sync_wait_for_the_next_one() {
+        let store = temp_file_store(Durability::Interval(Duration::from_millis(5)));
+        let path = add_canvas(&store, "retried");
+        let mut writer = store.open_writer("retried").await.unwrap();
+        writer.append(&numbered(1, 1)).await.unwrap();
+
+        let moved = break_log(&path);
+        assert!(writer.append(&numbered(2, 1)).await.is_err());
+        restore_log(&path, &moved);
+
+        // The failed sync doesn't fail appends it didn't cover
+        writer.append(&numbered(2, 1)).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), encode_lines(&numbered(1, 2)));
+        std::fs::remove_dir_all(&store.canvases_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_appends_leave_none_of_their_events_behind() {
+        let store = temp_file_store(Durability::Interval(Duration::from_millis(5)));
+        let path = add_canvas(&store, "failing");
+        let mut writer = store.open_writer("failing").await.unwrap();
+        writer.append(&numbered(1, 2)).await.unwrap();
+
+        // The whole batch is written before the sync fails, and then cut off again
+        let moved = break_log(&path);
+        assert!(writer.append(&numbered(3, 100)).await.is_err());
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), encode_lines(&numbered(1, 2)));
+
+        restore_log(&path, &moved);
+        writer.append(&numbered(3, 1)).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), encode_lines(&numbered(1, 3)));
+        std::fs::remove_dir_all(&store.canvases_dir).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::event_log::SYSTEM_TYPE;
+
+/// Largest absolute coordinate (and radius) accepted in a shape.
+const MAX_COORDINATE: f64 = 1_000_000.0;
+
+/// Maximum length of shape ids.
+const MAX_ID_LENGTH: usize = 128;
+
+/// Maximum length of named colors, e.g. `"black"`.
+const MAX_COLOR_NAME_LENGTH: usize = 32;
+
+/// An event of an `eventsForCanvas` array that failed validation.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventValidationError {
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Validates drawing events before they are persisted and broadcast.
+/// Returns every offending event, so the client can see all problems at once.
+///
+/// Only the fields the drawer relies on are checked. Unknown fields are allowed,
+/// so newer clients can add optional data without a server update.
+pub fn validate_events(events: &[Value]) -> Result<(), Vec<EventValidationError>> {
+    let errors: Vec<EventValidationError> = events
+        .iter()
+        .enumerate()
+        .filter_map(|(index, event)| {
+            validate_event(event)
+                .err()
+                .map(|reason| EventValidationError { index, reason })
+        })
+        .collect();
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Validates a single event against the shapes the drawer sends.
+fn validate_event(event: &Value) -> Result<(), String> {
+    let Some(object) = event.as_object() else {
+        return Err("event must be an object".to_string());
+    };
+
+    optional_bool(object, "redraw")?;
+
+    let event_type = object
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or("type must be a string")?;
+
+    match event_type {
+        "shapeAdded" | "shapeRemoved" => validate_shape(object.get("shape")),
+        "shapeReplaced" => {
+            id(object, "oldId")?;
+            validate_shape(object.get("shape"))
+        }
+        "shapeRemovedWithId" => id(object, "shapeId"),
+        "shapeSelected" => {
+            id(object, "id")?;
+            optional_bool(object, "additive")
+        }
+        "selectedBroughtToFront" | "selectedBroughtToBack" => Ok(()),
+        SYSTEM_TYPE => Err("system events are written by the server only".to_string()),
+        other => Err(format!("unknown event type '{}'", other)),
+    }
+}
+
+/// Validates a serialized shape: its id, colors and the geometry of one of the shape kinds.
+fn validate_shape(shape: Option<&Value>) -> Result<(), String> {
+    let Some(shape) = shape.and_then(Value::as_object) else {
+        return Err("shape must be an object".to_string());
+    };
+
+    id(shape, "id")?;
+    color(shape, "borderColor", false)?;
+    color(shape, "backgroundColor", true)?;
+
+    if shape.contains_key("center") || shape.contains_key("radius") {
+        point(shape, "center")?;
+        let radius = number(shape, "radius")?;
+        if radius < 0.0 {
+            return Err("radius must not be negative".to_string());
+        }
+        Ok(())
+    } else if shape.contains_key("start") || shape.contains_key("end") {
+        point(shape, "start")?;
+        point(shape, "end")
+    } else if shape.contains_key("from") || shape.contains_key("to") {
+        point(shape, "from")?;
+        point(shape, "to")
+    } else if shape.contains_key("p1") {
+        point(shape, "p1")?;
+        point(shape, "p2")?;
+        point(shape, "p3")
+    } else {
+        Err("shape must be a line, circle, rectangle or triangle".to_string())
+    }
+}
+
+fn id(object: &Map<String, Value>, field: &str) -> Result<(), String> {
+    match object.get(field).and_then(Value::as_str) {
+        Some(id) if !id.is_empty() && id.len() <= MAX_ID_LENGTH => Ok(()),
+        _ => Err(format!("{} must be a string of 1 to {} characters", field, MAX_ID_LENGTH)),
+    }
+}
+
+fn optional_bool(object: &Map<String, Value>, field: &str) -> Result<(), String> {
+    match object.get(field) {
+        None | Some(Value::Bool(_)) => Ok(()),
+        Some(_) => Err(format!("{} must be a boolean", field)),
+    }
+}
+
+/// A finite number within `MAX_COORDINATE`.
+fn number(object: &Map<String, Value>, field: &str) -> Result<f64, String> {
+    match object.get(field).and_then(Value::as_f64) {
+        Some(n) if n.is_finite() && n.abs() <= MAX_COORDINATE => Ok(n),
+        _ => Err(format!("{} must be a number between -{} and {}", field, MAX_COORDINATE, MAX_COORDINATE)),
+    }
+}
+
+/// A point serialized as `{"x": .., "y": ..}`.
+fn point(object: &Map<String, Value>, field: &str) -> Result<(), String> {
+    let Some(point) = object.get(field).and_then(Value::as_object) else {
+        return Err(format!("{} must be a point", field));
+    };
+    number(point, "x").map_err(|e| format!("{}.{}", field, e))?;
+    number(point, "y").map_err(|e| format!("{}.{}", field, e))?;
+    Ok(())
+}
+
+/// Colors are hex strings (`#rgb`, `#rrggbb`, `#rrggbbaa`) or CSS color names.
+/// Missing colors fall back to the drawer's defaults; `nullable` colors may also be `null`.
+fn color(object: &Map<String, Value>, field: &str, nullable: bool) -> Result<(), String> {
+    let value = match object.get(field) {
+        None => return Ok(()),
+        Some(Value::Null) if nullable => return Ok(()),
+        Some(value) => value,
+    };
+
+    let valid = value.as_str().is_some_and(|color| match color.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => {
+            !color.is_empty()
+                && color.len() <= MAX_COLOR_NAME_LENGTH
+                && color.chars().all(|c| c.is_ascii_alphabetic())
+        }
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{} must be a hex color or a color name", field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn line(id: &str) -> Value {
+        json!({ "id": id, "borderColor": "black", "backgroundColor": null, "start": { "x": 0, "y": 0 }, "end": { "x": 10.5, "y": -3 } })
+    }
+
+    fn added(shape: Value) -> Value {
+        json!({ "type": "shapeAdded", "shape": shape })
+    }
+
+    /// A circle with one of its colors set to `color`.
+    fn colored(field: &str, color: Value) -> Value {
+        let mut shape = json!({ "id": "a", "center": { "x": 0, "y": 0 }, "radius": 1 });
+        shape[field] = color;
+        added(shape)
+    }
+
+    #[test]
+    fn valid_events_pass() {
+        let corpus = [
+            added(line("line-1")),
+            added(json!({ "id": "circle-1", "center": { "x": 5, "y": 5 }, "radius": 0 })),
+            added(json!({ "id": "rect-1", "from": { "x": -MAX_COORDINATE, "y": 0 }, "to": { "x": MAX_COORDINATE, "y": 1 } })),
+            added(json!({ "id": "tri-1", "p1": { "x": 0, "y": 0 }, "p2": { "x": 1, "y": 0 }, "p3": { "x": 0, "y": 1 } })),
+            colored("borderColor", json!("#abc")),
+            colored("backgroundColor", json!("#A0B1C2")),
+            colored("backgroundColor", json!("#a0b1c2ff")),
+            colored("borderColor", json!("Gold")),
+            json!({ "type": "shapeAdded", "redraw": false, "shape": line(&"x".repeat(MAX_ID_LENGTH)) }),
+            json!({ "type": "shapeRemoved", "redraw": true, "shape": line("line-1") }),
+            json!({ "type": "shapeReplaced", "oldId": "line-1", "shape": line("line-2") }),
+            json!({ "type": "shapeRemovedWithId", "shapeId": "line-2", "redraw": true }),
+            json!({ "type": "shapeSelected", "id": "line-2" }),
+            json!({ "type": "shapeSelected", "id": "line-2", "additive": true }),
+            json!({ "type": "selectedBroughtToFront" }),
+            json!({ "type": "selectedBroughtToBack", "redraw": false }),
+            // Unknown fields are kept for newer clients, on events and shapes alike
+            json!({ "type": "shapeAdded", "layer": 2, "shape": { "id": "a", "dash": [4, 2], "center": { "x": 0, "y": 0, "z": 1 }, "radius": 1 } }),
+        ];
+        for event in &corpus {
+            assert!(validate_events(std::slice::from_ref(event)).is_ok(), "{}", event);
+        }
+        assert!(validate_events(&corpus).is_ok());
+        assert!(validate_events(&[]).is_ok());
+    }
+
+    #[test]
+    fn invalid_events_are_rejected_with_their_reason() {
+        let too_far = MAX_COORDINATE + 1.0;
+        let corpus = [
+            // Malformed events
+            (json!(null), "event must be an object"),
+            (json!("shapeAdded"), "event must be an object"),
+            (json!([{ "type": "shapeAdded" }]), "event must be an object"),
+            (json!({}), "type must be a string"),
+            (json!({ "type": 1 }), "type must be a string"),
+            (json!({ "type": "shapeErased" }), "unknown event type 'shapeErased'"),
+            (json!({ "type": "shapeAdded", "redraw": "yes", "shape": line("a") }), "redraw must be a boolean"),
+            (json!({ "type": "shapeSelected", "id": "a", "additive": 1 }), "additive must be a boolean"),
+            // Only the server writes system events, e.g. a forged clear
+            (json!({ "type": "system", "action": "clear", "by": 1 }), "system events are written by the server only"),
+            // Malformed shapes
+            (json!({ "type": "shapeAdded" }), "shape must be an object"),
+            (added(json!([1, 2])), "shape must be an object"),
+            (added(json!({ "id": "a" })), "shape must be a line, circle, rectangle or triangle"),
+            (added(json!({ "start": { "x": 0, "y": 0 }, "end": { "x": 1, "y": 1 } })), "id must be"),
+            (added(json!({ "id": 7, "center": { "x": 0, "y": 0 }, "radius": 1 })), "id must be"),
+            (added(json!({ "id": "", "center": { "x": 0, "y": 0 }, "radius": 1 })), "id must be"),
+            (added(json!({ "id": "a", "start": { "x": 0, "y": 0 } })), "end must be a point"),
+            (added(json!({ "id": "a", "start": [0, 0], "end": { "x": 1, "y": 1 } })), "start must be a point"),
+            (added(json!({ "id": "a", "from": { "x": "0", "y": 0 }, "to": { "x": 1, "y": 1 } })), "from.x must be a number"),
+            (added(json!({ "id": "a", "from": { "x": 0 }, "to": { "x": 1, "y": 1 } })), "from.y must be a number"),
+            (added(json!({ "id": "a", "p1": { "x": 0, "y": 0 }, "p2": { "x": 1, "y": 0 } })), "p3 must be a point"),
+            (added(json!({ "id": "a", "center": { "x": 0, "y": 0 } })), "radius must be a number"),
+            (added(json!({ "id": "a", "center": { "x": 0, "y": 0 }, "radius": -1 })), "radius must not be negative"),
+            (json!({ "type": "shapeReplaced", "shape": line("a") }), "oldId must be"),
+            (json!({ "type": "shapeRemovedWithId" }), "shapeId must be"),
+            (json!({ "type": "shapeSelected" }), "id must be"),
+            // Oversized shapes
+            (added(line(&"x".repeat(MAX_ID_LENGTH + 1))), "id must be a string of 1 to 128 characters"),
+            (added(json!({ "id": "a", "start": { "x": too_far, "y": 0 }, "end": { "x": 1, "y": 1 } })), "start.x must be a number"),
+            (added(json!({ "id": "a", "start": { "x": 0, "y": -too_far }, "end": { "x": 1, "y": 1 } })), "start.y must be a number"),
+            (added(json!({ "id": "a", "center": { "x": 0, "y": 0 }, "radius": too_far })), "radius must be a number"),
+            (added(json!({ "id": "a", "center": { "x": 0, "y": 0 }, "radius": 1e308 })), "radius must be a number"),
+            // Colors the drawer can't use, or that smuggle CSS
+            (colored("borderColor", json!(null)), "borderColor must be"),
+            (colored("borderColor", json!("#abcd")), "borderColor must be"),
+            (colored("borderColor", json!("#ggg")), "borderColor must be"),
+            (colored("borderColor", json!("")), "borderColor must be"),
+            (colored("borderColor", json!("x".repeat(MAX_COLOR_NAME_LENGTH + 1))), "borderColor must be"),
+            (colored("backgroundColor", json!("url(x)")), "backgroundColor must be"),
+            (colored("backgroundColor", json!(255)), "backgroundColor must be"),
+        ];
+        for (event, reason) in &corpus {
+            let errors = validate_events(std::slice::from_ref(event)).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}", event);
+            assert_eq!(errors[0].index, 0);
+            assert!(errors[0].reason.starts_with(reason), "{}: {}", event, errors[0].reason);
+        }
+    }
+
+    #[test]
+    fn every_offending_event_is_reported_by_index() {
+        let events = [
+            added(line("ok")),
+            json!({ "type": "system", "action": "clear" }),
+            json!({ "type": "selectedBroughtToFront" }),
+            json!({ "type": "shapeRemovedWithId", "shapeId": "x".repeat(MAX_ID_LENGTH + 1) }),
+        ];
+        let errors = validate_events(&events).unwrap_err();
+        let indices: Vec<_> = errors.iter().map(|error| error.index).collect();
+        assert_eq!(indices, [1, 3]);
+        assert_eq!(
+            serde_json::to_value(&errors[0]).unwrap(),
+            json!({ "index": 1, "reason": "system events are written by the server only" })
+        );
+    }
+}
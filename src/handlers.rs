@@ -1,172 +1,313 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, time::Duration};
 use tokio::fs; 
 
 use axum::{
-    extract::{Path, State},
+    body::Body,
+    extract::{Query, State},
     http::{header, HeaderMap, HeaderValue, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::{Deserialize, Serialize};
+use anyhow::Context;
 use serde_json::json;
-use sqlx::{query, Error as SqlxError, SqlitePool};
+use sqlx::{query, sqlite::SqliteRow, Encode, Error as SqlxError, QueryBuilder, Sqlite, SqlitePool, Type};
 use sqlx::{Row};
-use uuid::Uuid;
+use utoipa::{IntoParams, ToSchema};
 
 // Import types and functions from the auth module
 use crate::{auth::{
-    authorize_user, create_cookie_header, get_claims, get_cookie_from_claims, hash_password, AuthError, Claims, PartialClaims
-}, AppState};
+    authorize_user, create_cookie_header, get_claims, get_cookie_from_claims, hash_password, logout_cookie, AuthError, Claims, PartialClaims
+}, canvas_id::CanvasId, canvas_manager::{AppendEventsError, BanChange, EventRange, HeldReview, MuteChange}, event_log::timestamp_ms, event_store::{canvases_dir, event_file_name}, build_info::BuildInfo, error::AppError, idempotency, client_ip::ClientIp, identifiable_web_socket::CLOSE_PERMISSION_REVOKED, moderation_log::{self, ModerationAction, DEFAULT_MODERATION_LOG_PAGE, MAX_MODERATION_LOG_PAGE}, openapi::{ErrorResponse, MessageResponse}, reports::{self, Report, ReportResolution}, validation::{AppJson, AppPath, FieldErrors, Validate, ValidJson, MAX_CANVAS_NAME_CHARS, MAX_DISPLAY_NAME_CHARS, MAX_PASSWORD_CHARS, MIN_PASSWORD_CHARS}, AppState};
 
 
 
 // ====================== canvas stuff ======================
 
-// A struct to represent a single canvas item in the response
-#[derive(Debug, Serialize)]
-pub struct CanvasListResponseItem {
-    pub canvas_id: String,
+// A canvas as the API returns it: in the list, from GET /api/canvas/{canvas_id} and after creating it.
+// One type for all of them, so they can't drift apart.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CanvasResponse {
+    #[schema(value_type = String)]
+    pub canvas_id: CanvasId,
     pub name: String,
+    pub owner_user_id: i64,
+    pub owner_display_name: String,
+    /// The caller's permission level on the canvas.
     pub permission_level: String,
+    /// Number of events in the canvas' log. Approximate: the server writes it back periodically.
+    pub event_count: i64,
+    /// Milliseconds since the Unix epoch. Null for canvases created before it was recorded.
+    pub created_at: Option<i64>,
+}
+
+/// Where a canvas can be fetched, for the `Location` header of the response creating it.
+pub fn canvas_location(canvas_id: &CanvasId) -> String {
+    format!("/api/canvas/{}", canvas_id)
+}
+
+/// The permission levels from least to most, each including what the ones before it allow.
+const PERMISSION_LEVELS: [&str; 6] = ["R", "W", "V", "M", "C", "O"];
+
+fn permission_rank(level: &str) -> Option<usize> {
+    PERMISSION_LEVELS.iter().position(|known| *known == level)
+}
+
+/// The caller's permission level on a canvas, if it is at least `min_level`. Checked against the database,
+/// not the claims, so a revoked permission counts at once.
+///
+/// Callers without any permission get the same 404 whether the canvas exists or not, so canvas ids can't be
+/// probed. Only callers who can see the canvas learn that their level is too low, as a 403 naming both levels.
+pub async fn require_canvas_permission(
+    claims: &Claims,
+    pool: &SqlitePool,
+    canvas_id: &CanvasId,
+    min_level: &str,
+) -> Result<String, AppError> {
+    // Permissions are deleted with their canvas, so a row means the canvas exists
+    let permission = query!(
+        "SELECT permission_level FROM Canvas_Permissions WHERE canvas_id = ? AND user_id = ?",
+        canvas_id,
+        claims.user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .with_context(|| format!("Failed to look up the permission of user {} on canvas {}", claims.user_id, canvas_id))?
+    .map(|row| row.permission_level);
+
+    let Some(permission) = permission else {
+        return Err(AppError::not_found("Canvas not found."));
+    };
+    if permission_rank(&permission) < permission_rank(min_level) {
+        tracing::warn!(
+            "User {} with permission {} on canvas {} needs at least {}.",
+            claims.user_id, permission, canvas_id, min_level
+        );
+        return Err(AppError::Forbidden {
+            code: "forbidden",
+            message: format!("This needs permission level {} or higher, you have {}.", min_level, permission),
+            details: Some(json!({ "required": min_level, "permission": permission })),
+        });
+    }
+    Ok(permission)
 }
 
 // The handler for the GET /api/canvases/list route
+#[utoipa::path(
+    get,
+    path = "/api/canvases/list",
+    tag = "canvases",
+    responses(
+        (status = 200, description = "Canvases the user has a permission on", body = [CanvasResponse]),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn get_canvas_list(
     State(state): State<AppState>,
     claims: Claims,
-) -> impl IntoResponse {
+) -> Result<Json<Vec<CanvasResponse>>, AppError> {
     let pool = state.pool;
 
     // The claims already contain the canvas IDs and their permission levels.
     let canvas_permissions = claims.canvas_permissions;
 
-    // Extract the canvas IDs from the claims' HashMap.
-    let canvas_ids: Vec<&str> = canvas_permissions.keys().map(|id| id.as_str()).collect();
-    
     // Check if there are any canvas IDs to query. If not, return an empty list immediately.
-    if canvas_ids.is_empty() {
-        return (StatusCode::OK, Json(Vec::<CanvasListResponseItem>::new())).into_response();
+    if canvas_permissions.is_empty() {
+        return Ok(Json(Vec::new()));
     }
 
-    // The `sqlx` macro doesn't support dynamically-sized `IN` clauses directly,
-    // so we need to build the query dynamically.
-    let in_clause = format!(
-        "('{}')",
-        canvas_ids.join("','")
-    );
-
-    // SQL query to fetch the canvas name for each canvas_id
-    let query_string = format!(
-        "SELECT canvas_id, name FROM Canvas WHERE canvas_id IN {}",
-        in_clause
-    );
-
-    let canvas_rows = match sqlx::query(&query_string)
-        .fetch_all(&pool) 
+    let canvas_rows = fetch_canvas_rows(&pool, canvas_permissions.keys().copied())
         .await
-    {
-        Ok(rows) => rows,
-        Err(e) => {
-            tracing::error!("Database query failed: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to retrieve canvas list."}))
-            ).into_response();
-        }
-    };
+        .context("Failed to retrieve the canvas list")?;
     
     // Build the final list of canvases to return.
-    let mut response_list: Vec<CanvasListResponseItem> = Vec::new();
+    let mut response_list: Vec<CanvasResponse> = Vec::new();
 
     for row in canvas_rows {
-        let canvas_id: String = row.get("canvas_id");
-        let name: String = row.get("name");
+        let canvas_id: CanvasId = row.get("canvas_id");
         
         // Find the permission level in the claims HashMap.
         // It's safe to unwrap here because the query was built from the keys of this map.
         let permission_level = canvas_permissions.get(&canvas_id).unwrap().clone();
 
-        response_list.push(CanvasListResponseItem {
+        response_list.push(CanvasResponse {
             canvas_id,
-            name,
+            name: row.get("name"),
+            owner_user_id: row.get("owner_user_id"),
+            owner_display_name: row.get("owner_display_name"),
             permission_level,
+            event_count: row.get("event_count"),
+            created_at: row.get("created_at"),
         });
     }
 
-    (
-        StatusCode::OK,
-        Json(response_list)
-    ).into_response()
+    Ok(Json(response_list))
+}
+
+/// The rows of the given canvases with their owner's display name.
+async fn fetch_canvas_rows<'a, T>(pool: &SqlitePool, canvas_ids: impl IntoIterator<Item = T>) -> Result<Vec<SqliteRow>, SqlxError>
+where
+    T: 'a + Encode<'a, Sqlite> + Type<Sqlite> + Send,
+{
+    // The `sqlx` macro doesn't support dynamically-sized `IN` clauses directly,
+    // so the query gets one bound `?` per canvas ID.
+    let mut query_builder = QueryBuilder::<Sqlite>::new(
+        "SELECT c.canvas_id, c.name, c.owner_user_id, u.display_name AS owner_display_name, c.event_count, c.created_at
+         FROM Canvas c JOIN users u ON u.user_id = c.owner_user_id
+         WHERE c.canvas_id IN (",
+    );
+    let mut separated = query_builder.separated(", ");
+    for canvas_id in canvas_ids {
+        separated.push_bind(canvas_id);
+    }
+    separated.push_unseparated(")");
+    query_builder.build().fetch_all(pool).await
+}
+
+// The handler for the GET /api/canvas/{canvas_id} route. Any permission on the canvas allows reading it.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}",
+    tag = "canvases",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "The canvas", body = CanvasResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<Json<CanvasResponse>, AppError> {
+    let permission_level = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+
+    let canvas = fetch_canvas(&state.pool, &canvas_id, &permission_level)
+        .await
+        .with_context(|| format!("Failed to fetch canvas {}", canvas_id))?
+        .ok_or_else(|| AppError::not_found("Canvas not found."))?;
+    Ok(Json(canvas))
+}
+
+/// A canvas as `CanvasResponse`, `permission_level` being the caller's.
+async fn fetch_canvas(pool: &SqlitePool, canvas_id: &CanvasId, permission_level: &str) -> Result<Option<CanvasResponse>, sqlx::Error> {
+    let row = query!(
+        r#"SELECT c.name, c.owner_user_id, u.display_name AS owner_display_name, c.event_count, c.created_at
+           FROM Canvas c JOIN users u ON u.user_id = c.owner_user_id
+           WHERE c.canvas_id = ?"#,
+        canvas_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| CanvasResponse {
+        canvas_id: *canvas_id,
+        name: row.name,
+        owner_user_id: row.owner_user_id,
+        owner_display_name: row.owner_display_name,
+        permission_level: permission_level.to_string(),
+        event_count: row.event_count,
+        created_at: row.created_at,
+    }))
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateCanvasPayload {
     pub name: String,
 }
 
+impl Validate for CreateCanvasPayload {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.length("name", &self.name, 1, MAX_CANVAS_NAME_CHARS);
+    }
+}
 
+
+#[utoipa::path(
+    post,
+    path = "/api/canvases/create",
+    tag = "canvases",
+    params(("Idempotency-Key" = Option<String>, Header, description = "Makes retries safe: a retry with the same key returns the first canvas instead of creating another")),
+    request_body = CreateCanvasPayload,
+    responses(
+        (status = 201, description = "The created canvas, its URL in `Location`. The same again for a retry with the same `Idempotency-Key` within 24 hours", body = CanvasResponse),
+        (status = 400, description = "Invalid `Idempotency-Key`", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 422, description = "Invalid fields", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn create_canvas(
     State(state): State<AppState>,
     claims: Claims,
-    Json(payload): Json<CreateCanvasPayload>,
-) -> impl IntoResponse {
-
-    let pool = state.pool;
-
-    if payload.name.trim().is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "Canvas name cannot be empty."})),
-        ).into_response();
+    headers: HeaderMap,
+    ValidJson(payload): ValidJson<CreateCanvasPayload>,
+) -> Result<Response, AppError> {
+
+    let pool = &state.pool;
+
+    // A retry of a request that already created its canvas gets the same response again
+    let idempotency_key = idempotency::idempotency_key(&headers)?;
+    if let Some(key) = &idempotency_key {
+        let existing = idempotency::find_canvas(pool, claims.user_id, key)
+            .await
+            .context("Failed to look up the idempotency key")?;
+        if let Some(canvas_id) = existing {
+            tracing::info!("User {} retried the creation of canvas {}", claims.user_id, canvas_id);
+            return replay_canvas_creation(&state, &claims, &canvas_id).await;
+        }
     }
 
-    let canvas_id = Uuid::new_v4().to_string();
+    let canvas_id = CanvasId::random();
     let owner_user_id = claims.user_id;
     let canvas_name = payload.name.trim().to_string();
+    let created_at = timestamp_ms() as i64;
     
-    let data_dir = PathBuf::from("data");
-    let canvases_dir = data_dir.join("canvases");
-    let file_path = canvases_dir.join(format!("{}.jsonl", canvas_id));
-
-    if let Err(e) = fs::create_dir_all(&canvases_dir).await {
-        tracing::error!("Failed to create canvases directory: {:?}", e);
-        return AuthError::DbError.into_response();
-    }
-
-    if let Err(e) = fs::File::create(&file_path).await {
-        tracing::error!("Failed to create event file at {}: {:?}", file_path.display(), e);
-        return AuthError::DbError.into_response();
-    }
-    
-    let mut tx = match pool.begin().await {
-        Ok(t) => t,
-        Err(e) => {
-            tracing::error!("Failed to begin transaction for new canvas: {:?}", e);
-            return AuthError::DbError.into_response();
+    // Only the file name is stored, so the data directory can move
+    let file_name = event_file_name(&canvas_id.to_string());
+    let canvases_dir = canvases_dir(&state.data_dir);
+    let file_path = canvases_dir.join(&file_name);
+
+    // Rolled back when dropped on any of the early returns
+    let mut tx = pool.begin().await.context("Failed to begin transaction for new canvas")?;
+
+    // First, so a concurrent duplicate stops here before it created anything
+    if let Some(key) = &idempotency_key {
+        let claimed = idempotency::claim(&mut tx, claims.user_id, key, &canvas_id)
+            .await
+            .context("Failed to store the idempotency key")?;
+        if !claimed {
+            drop(tx);
+            let canvas_id = idempotency::find_canvas(pool, claims.user_id, key)
+                .await
+                .context("Failed to look up the idempotency key")?
+                .ok_or_else(|| anyhow::anyhow!("Idempotency key of user {} vanished", claims.user_id))?;
+            tracing::info!("User {} created canvas {} concurrently twice", claims.user_id, canvas_id);
+            return replay_canvas_creation(&state, &claims, &canvas_id).await;
         }
-    };
-
-    // Fix for the temporary value dropped while borrowed error
-    let file_path_str = file_path.to_str().unwrap_or("");
+    }
 
-    if let Err(e) = sqlx::query!(
-        "INSERT INTO Canvas (canvas_id, name, owner_user_id, moderated, event_file_path) VALUES (?, ?, ?, ?, ?)",
+    sqlx::query!(
+        "INSERT INTO Canvas (canvas_id, name, owner_user_id, moderated, event_file_path, created_at) VALUES (?, ?, ?, ?, ?, ?)",
         canvas_id,
         canvas_name,
         owner_user_id,
         false,
-        file_path_str // Use the new variable here
+        file_name,
+        created_at
     )
     .execute(&mut *tx)
     .await
-    {
-        tx.rollback().await.ok();
-        tracing::error!("Failed to create canvas: {:?}", e);
-        return AuthError::DbError.into_response();
-    }
+    .context("Failed to create canvas")?;
 
-    if let Err(e) = sqlx::query!(
+    sqlx::query!(
         "INSERT INTO Canvas_Permissions (user_id, canvas_id, permission_level) VALUES (?, ?, ?)",
         owner_user_id,
         canvas_id,
@@ -174,19 +315,44 @@ pub async fn create_canvas(
     )
     .execute(&mut *tx)
     .await
-    {
-        tx.rollback().await.ok();
-        tracing::error!("Failed to set owner permissions for canvas ID {}: {:?}", canvas_id, e);
-        return AuthError::DbError.into_response();
-    }
+    .with_context(|| format!("Failed to set owner permissions for canvas ID {}", canvas_id))?;
 
-    if let Err(e) = tx.commit().await {
-        tracing::error!("Failed to commit transaction for canvas ID {}: {:?}", canvas_id, e);
-        return AuthError::DbError.into_response();
-    }
-    
+    // Only once the rows are in, so a rejected duplicate leaves no stray file behind
+    fs::create_dir_all(&canvases_dir).await.context("Failed to create the canvases directory")?;
+    fs::File::create(&file_path)
+        .await
+        .with_context(|| format!("Failed to create event file at {}", file_path.display()))?;
+
+    tx.commit()
+        .await
+        .with_context(|| format!("Failed to commit transaction for canvas ID {}", canvas_id))?;
+
+    let canvas = CanvasResponse {
+        canvas_id,
+        name: canvas_name,
+        owner_user_id,
+        owner_display_name: claims.display_name.clone(),
+        permission_level: "O".to_string(),
+        event_count: 0,
+        created_at: Some(created_at),
+    };
+    canvas_created(&state, &claims, canvas).await
+}
+
+/// Answers a retried creation with the canvas its first request created.
+async fn replay_canvas_creation(state: &AppState, claims: &Claims, canvas_id: &CanvasId) -> Result<Response, AppError> {
+    let canvas = fetch_canvas(&state.pool, canvas_id, "O")
+        .await
+        .with_context(|| format!("Failed to fetch canvas {}", canvas_id))?
+        .ok_or_else(|| AppError::not_found("Canvas not found."))?;
+    canvas_created(state, claims, canvas).await
+}
+
+/// The 201 of a created canvas. The first response may have been lost, so a replay sets the cookie
+/// with the owner permission again as well.
+async fn canvas_created(state: &AppState, claims: &Claims, canvas: CanvasResponse) -> Result<Response, AppError> {
     let mut updated_canvas_permissions = claims.canvas_permissions.clone();
-    updated_canvas_permissions.insert(canvas_id.clone(), "O".to_string());
+    updated_canvas_permissions.insert(canvas.canvas_id, "O".to_string());
 
     let updated_partial_claims = PartialClaims {
         email: claims.email.clone(),
@@ -196,49 +362,42 @@ pub async fn create_canvas(
         exp: claims.exp,
     };
 
-    let updated_claims = match get_claims(&pool, updated_partial_claims).await {
-        Ok(c) => c,
-        Err(e) => {
-            tracing::error!("Failed to get updated claims after canvas creation: {:?}", e);
-            return AuthError::DbError.into_response();
-        }
-    };
-    
+    let updated_claims = get_claims(&state.pool, updated_partial_claims)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get updated claims after canvas creation: {:?}", e))?;
+
     state.socket_claims_manager.update_claims(claims.user_id, updated_claims.clone()).await;
 
-    match get_cookie_from_claims(updated_claims).await {
-        Ok(cookie) => {
-            let headers = create_cookie_header(cookie);
-            (
-                StatusCode::CREATED,
-                headers,
-                Json(json!({
-                    "message": "Canvas created successfully",
-                    "canvas_id": canvas_id,
-                })),
-            ).into_response()
-        }
-        Err(e) => e.into_response(),
-    }
+    let cookie = get_cookie_from_claims(updated_claims).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, canvas_location(&canvas.canvas_id))],
+        create_cookie_header(cookie),
+        Json(canvas),
+    )
+        .into_response())
 }
 
 // ====================== Permissions ======================
 
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct UpdatePermissionRequest {
     pub user_id: i64,
+    /// A permission level, or empty to remove the user's permission. Ownership can't be given away.
     pub permission: String,
 }
 
-#[derive(Serialize)]
-struct GenericResponse {
-    message: String,
+impl Validate for UpdatePermissionRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.one_of("permission", &self.permission, &["", "R", "W", "V", "M", "C"]);
+    }
 }
+
 // New helper function to remove a user's permissions from a canvas
 async fn remove_user_canvas_permissions(
     pool: &SqlitePool,
-    canvas_id: &str,
+    canvas_id: &CanvasId,
     user_id: i64,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
@@ -253,14 +412,30 @@ async fn remove_user_canvas_permissions(
 }
 
 
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/permissions",
+    tag = "permissions",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    request_body = UpdatePermissionRequest,
+    responses(
+        (status = 200, description = "Permission set", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 422, description = "Unknown permission level", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn update_canvas_permissions(
     claims: Claims,
     State(state): State<AppState>,
-    Path(canvas_id): Path<String>,
-    Json(payload): Json<UpdatePermissionRequest>,
-) -> impl IntoResponse {
-    // 1. Get acting user's permission
-    let acting_user_permission = claims.canvas_permissions.get(&canvas_id);
+    AppPath(canvas_id): AppPath<CanvasId>,
+    ValidJson(payload): ValidJson<UpdatePermissionRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // 1. Get acting user's permission, moderators and up can change permissions
+    let acting_user_permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
 
     // 2. Prevent self-modification
     if claims.user_id == payload.user_id {
@@ -268,13 +443,7 @@ pub async fn update_canvas_permissions(
             "User {} tried to change their own permissions on canvas {}.",
             claims.user_id, canvas_id
         );
-        return (
-            axum::http::StatusCode::FORBIDDEN,
-            Json(GenericResponse {
-                message: "Cannot change your own permissions.".to_string(),
-            }),
-        )
-            .into_response();
+        return Err(AppError::forbidden("Cannot change your own permissions."));
     }
 
     // 3. Get target user's current permission
@@ -288,40 +457,20 @@ pub async fn update_canvas_permissions(
                 "User {} tried to change the owner's permissions on canvas {}.",
                 claims.user_id, canvas_id
             );
-            return (
-                axum::http::StatusCode::FORBIDDEN,
-                Json(GenericResponse {
-                    message: "Cannot change the owner's permissions.".to_string(),
-                }),
-            )
-                .into_response();
+            return Err(AppError::forbidden("Cannot change the owner's permissions."));
         }
     }
 
     // 5. Permission check
-    let can_change = match acting_user_permission.map(|p| p.as_str()) {
-        Some("C") | Some("O") => true,
-        Some("M") => {
+    let can_change = match acting_user_permission.as_str() {
+        "C" | "O" => true,
+        _ => {
             !matches!(payload.permission.as_str(), "C" | "M")
                 && !matches!(
                     target_user_permission.as_deref(),
                     Some("C") | Some("O") | Some("M")
                 )
         }
-        _ => {
-            tracing::warn!(
-                "User {} does not have sufficient permission to change permissions on canvas {}.",
-                claims.user_id,
-                canvas_id
-            );
-            return (
-                axum::http::StatusCode::FORBIDDEN,
-                Json(GenericResponse {
-                    message: "Insufficient permissions.".to_string(),
-                }),
-            )
-                .into_response();
-        }
     };
 
     if !can_change {
@@ -332,76 +481,30 @@ pub async fn update_canvas_permissions(
             payload.permission,
             target_user_permission
         );
-        return (
-            axum::http::StatusCode::FORBIDDEN,
-            Json(GenericResponse {
-                message: "Insufficient permissions for this action.".to_string(),
-            }),
-        )
-            .into_response();
+        return Err(AppError::forbidden("Insufficient permissions for this action."));
     }
 
     // 6. Update/remove DB permissions
-    let mut removed = false;
-    if payload.permission.is_empty() {
-        match remove_user_canvas_permissions(&state.pool, &canvas_id, payload.user_id).await {
-            Ok(_) => {
-                tracing::info!(
-                    "Permissions for user {} on canvas {} removed.",
-                    payload.user_id,
-                    canvas_id
-                );
-                removed = true;
-            }
-            Err(e) => {
-                tracing::error!(
-                    "Failed to remove permissions for user {} on canvas {}: {}",
-                    payload.user_id,
-                    canvas_id,
-                    e
-                );
-                return (
-                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(GenericResponse {
-                        message: "Failed to remove permissions.".to_string(),
-                    }),
-                )
-                    .into_response();
-            }
-        }
+    let removed = payload.permission.is_empty();
+    if removed {
+        remove_user_canvas_permissions(&state.pool, &canvas_id, payload.user_id)
+            .await
+            .with_context(|| format!("Failed to remove permissions for user {} on canvas {}", payload.user_id, canvas_id))?;
+        tracing::info!(
+            "Permissions for user {} on canvas {} removed.",
+            payload.user_id,
+            canvas_id
+        );
     } else {
-        match update_user_canvas_permissions(
-            &state.pool,
-            &canvas_id,
+        update_user_canvas_permissions(&state.pool, &canvas_id, payload.user_id, &payload.permission)
+            .await
+            .with_context(|| format!("Failed to update permissions for user {} on canvas {}", payload.user_id, canvas_id))?;
+        tracing::info!(
+            "Permissions for user {} on canvas {} updated to {}.",
             payload.user_id,
-            &payload.permission,
-        )
-        .await
-        {
-            Ok(_) => {
-                tracing::info!(
-                    "Permissions for user {} on canvas {} updated to {}.",
-                    payload.user_id,
-                    canvas_id,
-                    payload.permission
-                );
-            }
-            Err(e) => {
-                tracing::error!(
-                    "Failed to update permissions for user {} on canvas {}: {}",
-                    payload.user_id,
-                    canvas_id,
-                    e
-                );
-                return (
-                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(GenericResponse {
-                        message: "Failed to update permissions.".to_string(),
-                    }),
-                )
-                    .into_response();
-            }
-        }
+            canvas_id,
+            payload.permission
+        );
     }
 
     // 7. Mark user for refresh
@@ -413,22 +516,20 @@ pub async fn update_canvas_permissions(
         .update_permissions(&state, payload.user_id)
         .await;
 
-    // 9. Unregister only if permissions were removed
+    // 9. Unregister and disconnect only if permissions were removed
     if removed {
-        state
+        let connections = state
             .canvas_manager
             .unregister_user(&canvas_id, payload.user_id)
             .await;
+
+        for connection in connections {
+            connection.close(CLOSE_PERMISSION_REVOKED, "permission revoked").await;
+        }
     }
 
     // 10. Return success
-    (
-        axum::http::StatusCode::OK,
-        Json(GenericResponse {
-            message: "Permissions updated successfully.".to_string(),
-        }),
-    )
-        .into_response()
+    Ok(Json(json!({"message": "Permissions updated successfully."})))
 }
 
 
@@ -436,7 +537,7 @@ pub async fn update_canvas_permissions(
 
 pub async fn get_user_canvas_permissions_from_db(
     pool: &SqlitePool,
-    canvas_id: &str,
+    canvas_id: &CanvasId,
     user_id: i64,
 ) -> Option<String> {
     let result = query!(
@@ -458,7 +559,7 @@ pub async fn get_user_canvas_permissions_from_db(
 
 pub async fn update_user_canvas_permissions(
     pool: &SqlitePool,
-    canvas_id: &str,
+    canvas_id: &CanvasId,
     user_id: i64,
     permission_level: &str,
 ) -> Result<(), SqlxError> { // Corrected function signature
@@ -479,49 +580,81 @@ pub async fn update_user_canvas_permissions(
 
 
 // A new struct to represent a user for the JSON response
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct CanvasUser {
     pub user_id: i64,
     pub display_name: String,
+    /// Only included for O/C/M callers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted: Option<bool>,
+    /// End of the mute in milliseconds since the Unix epoch. Left out for users muted until unmuted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<i64>,
 }
 
 /// Retrieves all users and their permissions for a given canvas.
+/// Owners, co-owners and moderators also see who is muted.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/permissions",
+    tag = "permissions",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Users by permission level", body = HashMap<String, Vec<CanvasUser>>),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn get_canvas_permissions(
     State(state): State<AppState>,
-    Path(canvas_id): Path<String>,
-) -> Result<Json<HashMap<String, Vec<CanvasUser>>>, StatusCode> {
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<Json<HashMap<String, Vec<CanvasUser>>>, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+    let include_mutes = matches!(permission.as_str(), "M" | "O" | "C");
+    let now_ms = timestamp_ms() as i64;
+
     // Perform a SQL query to get all users and their permissions for the canvas
     let rows = sqlx::query!(
         r#"
         SELECT
             T1.permission_level,
             T2.user_id,
-            T2.display_name
+            T2.display_name,
+            T3.user_id AS "muted_user_id?",
+            T3.until AS "muted_until?"
         FROM
             Canvas_Permissions AS T1
         JOIN
             users AS T2
         ON
             T1.user_id = T2.user_id
+        LEFT JOIN
+            canvas_mutes AS T3
+        ON
+            T3.canvas_id = T1.canvas_id AND T3.user_id = T1.user_id AND (T3.until IS NULL OR T3.until > ?)
         WHERE
             T1.canvas_id = ?
         "#,
+        now_ms,
         canvas_id
     )
     .fetch_all(&state.pool)
     .await
-    .map_err(|e| {
-        tracing::error!("Database query error fetching canvas permissions: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    .context("Failed to fetch canvas permissions")?;
 
     // Use a HashMap to group users by their permission level
     let mut permissions_map: HashMap<String, Vec<CanvasUser>> = HashMap::new();
 
     for row in rows {
+        let muted = row.muted_user_id.is_some();
         let user = CanvasUser {
             user_id: row.user_id,
             display_name: row.display_name,
+            muted: include_mutes.then_some(muted),
+            muted_until: if include_mutes && muted { row.muted_until } else { None },
         };
 
         // Get the vector for the current permission level, or create a new one if it doesn't exist.
@@ -535,120 +668,1026 @@ pub async fn get_canvas_permissions(
 }
 
 
+/// Lists the users currently connected to a canvas via WebSocket.
+/// Connection counts are only included for owners, co-owners and moderators.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/online",
+    tag = "canvases",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Users connected via WebSocket", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_online_users(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+
+    let include_connection_counts = matches!(permission.as_str(), "M" | "O" | "C");
+    let online_users = state
+        .canvas_manager
+        .online_users(&canvas_id, include_connection_counts)
+        .await;
+
+    Ok(Json(online_users))
+}
+
+
+// Payload for the POST /api/canvas/{canvas_id}/settings route.
+// Fields that are left out stay unchanged.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateCanvasSettingsRequest {
+    pub coalesce_events: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/settings",
+    tag = "canvases",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    request_body = UpdateCanvasSettingsRequest,
+    responses(
+        (status = 200, description = "Settings updated", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn update_canvas_settings(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    AppJson(payload): AppJson<UpdateCanvasSettingsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "C").await?;
+
+    if let Some(coalesce_events) = payload.coalesce_events {
+        query!(
+            "UPDATE Canvas SET coalesce_events = ? WHERE canvas_id = ?",
+            coalesce_events,
+            canvas_id
+        )
+        .execute(&state.pool)
+        .await
+        .with_context(|| format!("Failed to update settings of canvas {}", canvas_id))?;
+
+        state.canvas_manager.set_coalesce(&canvas_id, Some(coalesce_events)).await;
+        tracing::info!("User {} set coalesce_events={} on canvas {}", claims.user_id, coalesce_events, canvas_id);
+    }
+
+    Ok(Json(json!({"message": "Canvas settings updated."})))
+}
+
+
+// Deletes a canvas and its permissions. Owners only.
+// Subscribers are told and the canvas is evicted from memory; the event file is kept on disk.
+#[utoipa::path(
+    delete,
+    path = "/api/canvas/{canvas_id}",
+    tag = "canvases",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Canvas deleted", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn delete_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "O").await?;
+
+    // Users with access need fresh claims without the canvas
+    let users = query!("SELECT user_id FROM Canvas_Permissions WHERE canvas_id = ?", canvas_id)
+        .fetch_all(&state.pool)
+        .await
+        .with_context(|| format!("Failed to list users of canvas {}", canvas_id))?;
+
+    // Permissions and stored events are removed with the row
+    query!("DELETE FROM Canvas WHERE canvas_id = ?", canvas_id)
+        .execute(&state.pool)
+        .await
+        .with_context(|| format!("Failed to delete canvas {}", canvas_id))?;
+
+    state.canvas_manager.evict(&canvas_id, "deleted").await;
+    for user in users {
+        state.permission_refresh_list.mark_user_for_refresh(user.user_id).await;
+    }
+    tracing::info!("User {} deleted canvas {}", claims.user_id, canvas_id);
+
+    Ok(Json(json!({"message": "Canvas deleted."})))
+}
+
+
+// Compacts the event log of a canvas: deleted events and tombstones are dropped.
+// Subscribers are asked to resync. Owners and co-owners only.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/compact",
+    tag = "maintenance",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Compaction statistics", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn compact_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "C").await?;
+
+    let stats = state.canvas_manager.compact(&state.pool, &canvas_id).await?;
+    Ok(Json(stats))
+}
+
+
+// Clears a canvas for everyone. Owners and co-owners only.
+// The log keeps the events before the clear, only histories start after it.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/clear",
+    tag = "canvases",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Canvas cleared", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn clear_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "C").await?;
+    state
+        .canvas_manager
+        .clear_canvas(&state.pool, &permission, claims.user_id, &canvas_id)
+        .await?;
+    Ok(Json(json!({"message": "Canvas cleared."})))
+}
+
+
+// Lists the batches of events held for review on a moderated canvas, oldest first.
+// Moderators, owners and co-owners only.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/pending",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Held batches, oldest first", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_held_events(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    let batches = state
+        .canvas_manager
+        .held_batches(&state.pool, &permission, claims.user_id, &canvas_id)
+        .await?;
+    Ok(Json(batches))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RejectHeldEventsRequest {
+    pub reason: Option<String>,
+}
+
+// Payload of the POST /api/canvas/{canvas_id}/report route. Without `seqs` the whole canvas is reported.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReportRequest {
+    #[serde(default)]
+    pub seqs: Vec<u64>,
+    pub reason: String,
+}
+
+// Reports a canvas or some of its events. Anyone with access to the canvas can report.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/report",
+    tag = "reports",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    request_body = ReportRequest,
+    responses(
+        (status = 201, description = "Report created, its id as `reportId`", body = serde_json::Value),
+        (status = 400, description = "Invalid payload", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn report_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    AppJson(payload): AppJson<ReportRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+    let report = state
+        .canvas_manager
+        .report(&state.pool, &permission, claims.user_id, &canvas_id, payload.seqs, &payload.reason)
+        .await?;
+    Ok((StatusCode::CREATED, Json(json!({"reportId": report.report_id}))))
+}
+
+// Query of the report listings. Resolved reports are only included with `?all=true`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportsQuery {
+    #[serde(default)]
+    pub all: bool,
+}
+
+// Lists the reports of a canvas, newest first. Owners and co-owners only.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/reports",
+    tag = "reports",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ReportsQuery),
+    responses(
+        (status = 200, description = "Reports, newest first", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_canvas_reports(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    Query(query): Query<ReportsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "C").await?;
+
+    let reports = reports::list(&state.pool, Some(&canvas_id), !query.all)
+        .await
+        .with_context(|| format!("Failed to list the reports of canvas {}", canvas_id))?;
+    Ok(Json(reports))
+}
+
+// Payload for resolving a report: `{"resolution": "dismissed" | "actioned"}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResolveReportRequest {
+    pub resolution: ReportResolution,
+}
+
+// Closes an open report of a canvas. Owners and co-owners only.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/reports/{report_id}/resolve",
+    tag = "reports",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("report_id" = i64, Path, description = "Id of the report")),
+    request_body = ResolveReportRequest,
+    responses(
+        (status = 200, description = "Report closed", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or no permission on it, or the report is not open", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn resolve_canvas_report(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, report_id)): AppPath<(CanvasId, i64)>,
+    AppJson(payload): AppJson<ResolveReportRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "C").await?;
+
+    resolve_report(&state, claims.user_id, report_id, Some(&canvas_id), payload.resolution).await
+}
+
+// Closes an open report, optionally only one of the given canvas, and records it in the canvas' moderation log.
+pub async fn resolve_report(
+    state: &AppState,
+    user_id: i64,
+    report_id: i64,
+    canvas_id: Option<&CanvasId>,
+    resolution: ReportResolution,
+) -> Result<Json<Report>, AppError> {
+    let report = reports::resolve(&state.pool, report_id, canvas_id, user_id, resolution)
+        .await
+        .with_context(|| format!("Failed to resolve report {}", report_id))?
+        .ok_or_else(|| AppError::not_found("The report was already resolved or does not exist."))?;
+
+    tracing::info!("User {} resolved report {} as {}", user_id, report_id, report.status);
+    let details = json!({ "reportId": report_id, "resolution": report.status });
+    moderation_log::record(
+        &state.pool,
+        &report.canvas_id,
+        Some(user_id),
+        ModerationAction::ResolveReport,
+        Some(report.reporter_id),
+        &details,
+    )
+    .await;
+    Ok(Json(report))
+}
+
+// Query of the GET /api/canvas/{canvas_id}/moderation_log route.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ModerationLogQuery {
+    /// Only entries older than this `logId`, to page backwards.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// Lists the moderation log of a canvas, newest first. Moderators, owners and co-owners only.
+// `nextBefore` pages to older entries and is null on the last page.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/moderation_log",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ModerationLogQuery),
+    responses(
+        (status = 200, description = "Entries, newest first, and `nextBefore` for the next page", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_moderation_log(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    Query(query): Query<ModerationLogQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_MODERATION_LOG_PAGE).clamp(1, MAX_MODERATION_LOG_PAGE);
+    let entries = moderation_log::list(&state.pool, &canvas_id, query.before, limit)
+        .await
+        .with_context(|| format!("Failed to read the moderation log of canvas {}", canvas_id))?;
+    let next_before = entries.last().filter(|_| entries.len() as i64 == limit).map(|entry| entry.log_id);
+    Ok(Json(json!({"entries": entries, "nextBefore": next_before})))
+}
+
+// Flips the moderation state of a canvas, whether it is loaded or not. Moderators, owners and co-owners only.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/moderated",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "The new state as `moderated`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn toggle_canvas_moderated(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    let moderated = state
+        .canvas_manager
+        .toggle_moderated(&state.pool, &permission, claims.user_id, &canvas_id)
+        .await?;
+    Ok(Json(json!({"moderated": moderated})))
+}
+
+// Payload of the POST /api/canvas/{canvas_id}/slow_mode route. 0 turns slow mode off.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SlowModeRequest {
+    pub slow_mode_ms: u64,
+}
+
+// Sets how long each user has to wait between two batches of events. Moderators, owners and co-owners
+// only, who are also exempt from it.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/slow_mode",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    request_body = SlowModeRequest,
+    responses(
+        (status = 200, description = "The new delay as `slowModeMs`", body = serde_json::Value),
+        (status = 400, description = "Invalid payload", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn set_slow_mode(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    AppJson(payload): AppJson<SlowModeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    state
+        .canvas_manager
+        .set_slow_mode(&state.pool, &permission, claims.user_id, &canvas_id, payload.slow_mode_ms)
+        .await?;
+    Ok(Json(json!({"slowModeMs": payload.slow_mode_ms})))
+}
+
+// Payload for muting a user; without a duration the user stays muted until unmuted.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MuteUserRequest {
+    pub duration_secs: Option<u64>,
+}
+
+// Mutes a user on a canvas: they keep their permission and can still view it, but can't draw.
+// Moderators, owners and co-owners only.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/mutes/{user_id}",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("user_id" = i64, Path, description = "Id of the user")),
+    request_body = Option<MuteUserRequest>,
+    responses(
+        (status = 200, description = "User muted", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn mute_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, user_id)): AppPath<(CanvasId, i64)>,
+    payload: Option<AppJson<MuteUserRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    let duration = payload.and_then(|AppJson(payload)| payload.duration_secs).map(Duration::from_secs);
+    let mute = state
+        .canvas_manager
+        .set_mute(&state.pool, &permission, claims.user_id, &canvas_id, user_id, MuteChange::Mute(duration))
+        .await?;
+    Ok(Json(json!({"message": "User muted.", "mutedUntil": mute.and_then(|mute| mute.until)})))
+}
+
+// Lifts the mute of a user on a canvas. Moderators, owners and co-owners only.
+#[utoipa::path(
+    delete,
+    path = "/api/canvas/{canvas_id}/mutes/{user_id}",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("user_id" = i64, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "User unmuted", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or no permission on it, or the user is not muted", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn unmute_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, user_id)): AppPath<(CanvasId, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    state
+        .canvas_manager
+        .set_mute(&state.pool, &permission, claims.user_id, &canvas_id, user_id, MuteChange::Unmute)
+        .await?
+        .ok_or_else(|| AppError::not_found("The user is not muted on this canvas."))?;
+    Ok(Json(json!({"message": "User unmuted."})))
+}
+
+
+// Payload for banning a user; without a duration the ban is permanent.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BanUserRequest {
+    pub duration_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+// Bans a user from a canvas: their connections are removed from it and they can't subscribe or draw
+// until the ban ends. Moderators can only ban users below them, owners and co-owners anyone but the owner.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/bans/{user_id}",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("user_id" = i64, Path, description = "Id of the user")),
+    request_body = Option<BanUserRequest>,
+    responses(
+        (status = 200, description = "User banned", body = serde_json::Value),
+        (status = 400, description = "Invalid payload", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn ban_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, user_id)): AppPath<(CanvasId, i64)>,
+    payload: Option<AppJson<BanUserRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    let (duration, reason) = match payload {
+        Some(AppJson(payload)) => (payload.duration_secs.map(Duration::from_secs), payload.reason),
+        None => (None, None),
+    };
+    let ban = state
+        .canvas_manager
+        .set_ban(&state.pool, &permission, claims.user_id, &canvas_id, user_id, BanChange::Ban(duration, reason))
+        .await?;
+    Ok(Json(json!({"message": "User banned.", "expiresAt": ban.and_then(|ban| ban.expires_at)})))
+}
+
+// Lifts the ban of a user from a canvas, following the same hierarchy as banning.
+#[utoipa::path(
+    delete,
+    path = "/api/canvas/{canvas_id}/bans/{user_id}",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("user_id" = i64, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "User unbanned", body = MessageResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or no permission on it, or the user is not banned", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn unban_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, user_id)): AppPath<(CanvasId, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "M").await?;
+    state
+        .canvas_manager
+        .set_ban(&state.pool, &permission, claims.user_id, &canvas_id, user_id, BanChange::Unban)
+        .await?
+        .ok_or_else(|| AppError::not_found("The user is not banned from this canvas."))?;
+    Ok(Json(json!({"message": "User unbanned."})))
+}
+
+
+// Approves a batch of events held for review on a moderated canvas. Moderators, owners and co-owners only.
+// Responds once the events are written.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/pending/{batch_id}/approve",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("batch_id" = String, Path, description = "Id of the held batch")),
+    responses(
+        (status = 200, description = "Number of approved events as `approved`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or batch, or no permission on the canvas", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn approve_held_events(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, batch_id)): AppPath<(CanvasId, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    review_held_events(&state, &claims, &canvas_id, &batch_id, HeldReview::Approve).await
+}
+
+// Rejects a batch of events held for review; the events are dropped. Moderators, owners and co-owners only.
+// The optional `reason` is told to the author.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/pending/{batch_id}/reject",
+    tag = "moderation",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("batch_id" = String, Path, description = "Id of the held batch")),
+    request_body = Option<RejectHeldEventsRequest>,
+    responses(
+        (status = 200, description = "Number of rejected events as `rejected`", body = serde_json::Value),
+        (status = 400, description = "Invalid payload", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or batch, or no permission on the canvas", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn reject_held_events(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, batch_id)): AppPath<(CanvasId, String)>,
+    payload: Option<AppJson<RejectHeldEventsRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let reason = payload.and_then(|AppJson(payload)| payload.reason);
+    review_held_events(&state, &claims, &canvas_id, &batch_id, HeldReview::Reject(reason)).await
+}
+
+async fn review_held_events(
+    state: &AppState,
+    claims: &Claims,
+    canvas_id: &CanvasId,
+    batch_id: &str,
+    review: HeldReview,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let permission = require_canvas_permission(claims, &state.pool, canvas_id, "M").await?;
+    let approve = matches!(review, HeldReview::Approve);
+    let reviewed = state
+        .canvas_manager
+        .review_held_events(&state.pool, &permission, claims.user_id, canvas_id, batch_id, review)
+        .await?;
+
+    if let Some(persisted) = reviewed.persisted {
+        persisted
+            .await
+            .unwrap_or_else(|_| Err("The canvas writer stopped.".to_string()))
+            .map_err(AppendEventsError::Storage)?;
+    }
+    let body = if approve { json!({"approved": reviewed.count}) } else { json!({"rejected": reviewed.count}) };
+    Ok(Json(body))
+}
+
+
+// Lists the archived event logs of a canvas, newest first. Owners only.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/archives",
+    tag = "maintenance",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "Archived logs, newest first", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_canvas_archives(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "O").await?;
+
+    let archives = state.canvas_manager.archives(&state.pool, &canvas_id).await?;
+    Ok(Json(archives))
+}
+
+
+// Swaps an archived event log back in; the current log is archived in turn.
+// Subscribers are asked to resync. Owners only.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/archives/{archive_id}/restore",
+    tag = "maintenance",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), ("archive_id" = u64, Path, description = "Id of the archived log")),
+    responses(
+        (status = 200, description = "Archive restored, the number of its events as `restoredEvents`", body = serde_json::Value),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas or archive, or no permission on the canvas", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn restore_canvas_archive(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath((canvas_id, archive_id)): AppPath<(CanvasId, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    require_canvas_permission(&claims, &state.pool, &canvas_id, "O").await?;
+
+    let events = state
+        .canvas_manager
+        .restore_archive(&state.pool, &canvas_id, archive_id)
+        .await?
+        .ok_or_else(|| AppError::not_found("Archive not found."))?;
+    tracing::info!("User {} restored archive {} of canvas {}", claims.user_id, archive_id, canvas_id);
+    Ok(Json(json!({"restoredEvents": events})))
+}
+
+
+// ====================== REST event transport ======================
+
+// Payload for the POST /api/canvas/{canvas_id}/events route.
+// Same shape as the WebSocket `events` message, without the canvas id.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AppendEventsRequest {
+    #[schema(value_type = Object)]
+    pub events_for_canvas: serde_json::Value,
+}
+
+// Query of the GET /api/canvas/{canvas_id}/events route.
+// Any of the range parameters turns the request into a replay (see `CanvasManager::replay_events`).
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsSinceQuery {
+    pub since_seq: Option<u64>,
+    pub from_seq: Option<u64>,
+    pub to_seq: Option<u64>,
+    pub from_ts: Option<u64>,
+    pub to_ts: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl EventsSinceQuery {
+    fn replay_range(&self) -> Option<EventRange> {
+        let range = EventRange {
+            from_seq: self.from_seq,
+            to_seq: self.to_seq,
+            from_ts: self.from_ts,
+            to_ts: self.to_ts,
+            limit: self.limit,
+        };
+        let is_replay = range.from_seq.is_some()
+            || range.to_seq.is_some()
+            || range.from_ts.is_some()
+            || range.to_ts.is_some()
+            || range.limit.is_some();
+        is_replay.then_some(range)
+    }
+}
+
+// Appends events for clients that cannot hold a WebSocket open.
+// The events go through the same checks as on the WebSocket and are broadcast to live subscribers.
+#[utoipa::path(
+    post,
+    path = "/api/canvas/{canvas_id}/events",
+    tag = "events",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    request_body = AppendEventsRequest,
+    responses(
+        (status = 200, description = "Events appended", body = serde_json::Value),
+        (status = 202, description = "Held for review on a moderated canvas", body = serde_json::Value),
+        (status = 400, description = "Invalid payload", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 413, description = "Payload too large", body = ErrorResponse),
+        (status = 429, description = "Rate limit or slow mode", body = ErrorResponse),
+        (status = 503, description = "Storage unavailable", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn append_canvas_events(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    AppJson(payload): AppJson<AppendEventsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(event_list) = payload.events_for_canvas.as_array() else {
+        return Err(AppendEventsError::InvalidPayload("eventsForCanvas must be an array.".to_string()).into());
+    };
+    let payload_bytes = payload.events_for_canvas.to_string().len();
+
+    if let Err(retry_after) = state.rest_rate_limiter.check_events(claims.user_id, payload_bytes) {
+        tracing::warn!("User {} exceeded the REST events rate limit", claims.user_id);
+        return Err(AppError::rate_limited(retry_after));
+    }
+
+    if let Err(violation) = state.payload_limits.validate_events(event_list, payload_bytes) {
+        tracing::warn!("Rejected events from user {} on canvas {}: {}", claims.user_id, canvas_id, violation);
+        state.rest_rate_limiter.record_violation(claims.user_id);
+        return Err(AppError::PayloadTooLarge {
+            message: violation.to_string(),
+            details: violation.to_json(&state.payload_limits),
+        });
+    }
+
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+    let appended = state
+        .canvas_manager
+        .append_events(&state.pool, &permission, claims.user_id, &canvas_id, payload.events_for_canvas, None)
+        .await?;
+
+    // Respond only once the events reached the configured durability point
+    // Held for review on a moderated canvas, written once a moderator approves them
+    if let Some(batch_id) = appended.held {
+        return Ok((StatusCode::ACCEPTED, Json(json!({"held": appended.count, "batchId": batch_id}))));
+    }
+    if let Some(persisted) = appended.persisted {
+        persisted
+            .await
+            .unwrap_or_else(|_| Err("The canvas writer stopped.".to_string()))
+            .map_err(AppendEventsError::Storage)?;
+    }
+    Ok((StatusCode::OK, Json(json!({"appended": appended.count}))))
+}
+
+// Returns the events of a canvas after `since_seq` as newline delimited JSON.
+// With range parameters, the matching events are streamed instead, followed by a `replayMeta` line.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/events",
+    tag = "events",
+    params(("canvas_id" = String, Path, description = "Id of the canvas"), EventsSinceQuery),
+    responses(
+        (status = 200, description = "Events as NDJSON, for replays followed by a `replayMeta` line", content_type = "application/x-ndjson"),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Insufficient permissions", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+        (status = 429, description = "Too many requests", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn get_canvas_events(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+    Query(query): Query<EventsSinceQuery>,
+) -> Result<Response, AppError> {
+    if let Err(retry_after) = state.rest_rate_limiter.check_poll(claims.user_id) {
+        tracing::warn!("User {} exceeded the REST poll rate limit", claims.user_id);
+        return Err(AppError::rate_limited(retry_after));
+    }
+
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+    if let Some(range) = query.replay_range() {
+        let lines = state.canvas_manager.replay_events(&state.pool, &permission, &canvas_id, range).await?;
+        return Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(lines),
+        )
+            .into_response());
+    }
+
+    let events = state
+        .canvas_manager
+        .events_since(&state.pool, &permission, &canvas_id, query.since_seq)
+        .await?;
+
+    let body: String = events.iter().map(|event| event.to_string() + "\n").collect();
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        body,
+    )
+        .into_response())
+}
+
+
 // ====================== User Profile ======================
 
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    tag = "users",
+    responses(
+        (status = 200, description = "The logged in user", body = UserResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn get_user_info(
     claims: Claims, 
 ) -> impl IntoResponse {
-    Json(json!({
-        "user_id": claims.user_id,
-        "email": claims.email,
-        "display_name": claims.display_name,
-    }))
+    Json(UserResponse::from(&claims))
+}
+
+/// The user as returned by `/api/me` and `/api/user/update`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserResponse {
+    pub user_id: i64,
+    pub email: String,
+    pub display_name: String,
+}
+
+impl From<&Claims> for UserResponse {
+    fn from(claims: &Claims) -> Self {
+        Self {
+            user_id: claims.user_id,
+            email: claims.email.clone(),
+            display_name: claims.display_name.clone(),
+        }
+    }
 }
 
 
 // Handler for updating a user's profile information.
-#[derive(Debug, Deserialize)]
+/// Only the given fields are changed. Unknown fields are rejected (`422 invalid_body`),
+/// so a misspelled field doesn't pass as an update that changes nothing.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
 pub struct UpdateUserPayload {
     pub email: Option<String>,
     pub display_name: Option<String>,
 }
 
+impl Validate for UpdateUserPayload {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(email) = &self.email {
+            errors.email("email", email);
+        }
+        if let Some(display_name) = &self.display_name {
+            errors.length("display_name", display_name, 1, MAX_DISPLAY_NAME_CHARS);
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/update",
+    tag = "users",
+    request_body = UpdateUserPayload,
+    responses(
+        (status = 200, description = "The updated user, unchanged if no fields were given", body = UserResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 409, description = "Email already taken", body = ErrorResponse),
+        (status = 422, description = "Invalid or unknown fields", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn update_profile(
     State(state): State<AppState>,
     claims: Claims,
-    Json(payload): Json<UpdateUserPayload>, 
-) -> impl IntoResponse {
+    ValidJson(payload): ValidJson<UpdateUserPayload>, 
+) -> Result<Response, AppError> {
 
     let pool = state.pool;
 
     if payload.email.is_none() && payload.display_name.is_none() {
         tracing::debug!("No fields provided for profile update for user {}", claims.user_id);
-        return (StatusCode::NO_CONTENT, Json(json!({"message": "No fields to update"}))).into_response();
+        return Ok(Json(UserResponse::from(&claims)).into_response());
     }
 
-    let mut tx = match pool.begin().await {
-        Ok(t) => t,
-        Err(e) => {
-            tracing::error!("Failed to begin transaction for profile update: {:?}", e);
-            return AuthError::DbError.into_response();
-        }
-    };
+    // Rolled back when dropped on any of the early returns
+    let mut tx = pool.begin().await.context("Failed to begin transaction for profile update")?;
 
     let mut updated_email = claims.email.clone();
     let mut updated_display_name = claims.display_name.clone();
 
     if let Some(new_email) = payload.email {
-        if new_email.is_empty() {
-            tx.rollback().await.ok();
-            return (StatusCode::BAD_REQUEST, Json(json!({"error": "Email cannot be empty."}))).into_response();
-        }
-        match sqlx::query!(
+        let taken = sqlx::query!(
             "SELECT user_id FROM users WHERE email = ? AND user_id != ?",
             new_email,
             claims.user_id
         )
         .fetch_optional(&mut *tx)
         .await
-        {
-            Ok(Some(_)) => {
-                tx.rollback().await.ok();
-                tracing::info!("Profile update failed: Email '{}' already taken by another user.", new_email);
-                return AuthError::UserExists.into_response();
-            }
-            Ok(None) => {
-                if let Err(e) = sqlx::query!(
-                    "UPDATE users SET email = ? WHERE user_id = ?",
-                    new_email,
-                    claims.user_id
-                )
-                .execute(&mut *tx)
-                .await
-                {
-                    tx.rollback().await.ok();
-                    tracing::error!("Failed to update email for user {}: {:?}", claims.user_id, e);
-                    return AuthError::DbError.into_response();
-                }
-                tracing::info!("User {} (ID: {}) updated email to '{}'.", claims.email, claims.user_id, new_email);
-                updated_email = new_email;
-            }
-            Err(e) => {
-                tx.rollback().await.ok();
-                tracing::error!("DB error checking email uniqueness for user {}: {:?}", claims.user_id, e);
-                return AuthError::DbError.into_response();
-            }
+        .with_context(|| format!("Failed to check email uniqueness for user {}", claims.user_id))?;
+        if taken.is_some() {
+            tracing::info!("Profile update failed: Email '{}' already taken by another user.", new_email);
+            return Err(AuthError::UserExists.into());
         }
+        sqlx::query!(
+            "UPDATE users SET email = ? WHERE user_id = ?",
+            new_email,
+            claims.user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to update email for user {}", claims.user_id))?;
+        tracing::info!("User {} (ID: {}) updated email to '{}'.", claims.email, claims.user_id, new_email);
+        updated_email = new_email;
     }
 
     if let Some(new_display_name) = payload.display_name {
-        if new_display_name.is_empty() {
-            tx.rollback().await.ok();
-            return (StatusCode::BAD_REQUEST, Json(json!({"error": "Display name cannot be empty."}))).into_response();
-        }
-        if let Err(e) = sqlx::query!(
+        sqlx::query!(
             "UPDATE users SET display_name = ? WHERE user_id = ?",
             new_display_name,
             claims.user_id
         )
         .execute(&mut *tx)
         .await
-        {
-            tx.rollback().await.ok();
-            tracing::error!("Failed to update display name for user {}: {:?}", claims.user_id, e);
-            return AuthError::DbError.into_response();
-        }
+        .with_context(|| format!("Failed to update display name for user {}", claims.user_id))?;
         tracing::info!("User {} (ID: {}) updated display name to '{}'.", claims.email, claims.user_id, new_display_name);
         updated_display_name = new_display_name;
     }
 
-    match tx.commit().await {
-        Ok(_) => tracing::debug!("Transaction committed for user {}", claims.user_id),
-        Err(e) => {
-            tracing::error!("Failed to commit transaction for user {}: {:?}", claims.user_id, e);
-            return AuthError::DbError.into_response();
-        }
-    }
+    tx.commit()
+        .await
+        .with_context(|| format!("Failed to commit transaction for user {}", claims.user_id))?;
+    tracing::debug!("Transaction committed for user {}", claims.user_id);
 
     // Step 1: Build new partial claims with updated info
     let updated_partial_claims = PartialClaims {
@@ -660,30 +1699,18 @@ pub async fn update_profile(
     };
 
     // Step 2: Fetch full updated claims from DB
-    let updated_claims = match get_claims(&pool, updated_partial_claims).await {
-        Ok(c) => c,
-        Err(e) => {
-            tracing::error!("Failed to get updated claims after profile update: {:?}", e);
-            return AuthError::DbError.into_response();
-        }
-    };
+    let updated_claims = get_claims(&pool, updated_partial_claims)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get updated claims after profile update: {:?}", e))?;
 
     // Step 3: Update claims in active WebSocket connections
     state.socket_claims_manager.update_claims(claims.user_id, updated_claims.clone()).await;
 
     // Step 4: Create new cookie from updated claims
-    match get_cookie_from_claims(updated_claims).await {
-        Ok(cookie) => {
-            let headers = create_cookie_header(cookie);
-            (
-                StatusCode::OK,
-                headers,
-                Json(json!({"message": "Profile updated successfully."})),
-            )
-                .into_response()
-        }
-        Err(e) => e.into_response(),
-    }
+    let user = UserResponse::from(&updated_claims);
+    let cookie = get_cookie_from_claims(updated_claims).await?;
+    let headers = create_cookie_header(cookie);
+    Ok((StatusCode::OK, headers, Json(user)).into_response())
 }
 
 
@@ -691,15 +1718,21 @@ pub async fn update_profile(
 
 // ====================== login logout ======================
 
+#[utoipa::path(
+    post,
+    path = "/api/logout",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Removes the auth_token cookie", body = MessageResponse),
+    ),
+)]
 pub async fn logout() -> impl IntoResponse {
     let mut headers = HeaderMap::new();
 
     // Invalidate the cookie
     headers.insert(
         header::SET_COOKIE,
-        HeaderValue::from_static(
-            "auth_token=; HttpOnly; Path=/; Max-Age=0; SameSite=Strict"
-        ),
+        HeaderValue::from_str(&logout_cookie()).unwrap(),
     );
 
     // Return a success status code and a simple JSON message
@@ -709,53 +1742,97 @@ pub async fn logout() -> impl IntoResponse {
 
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct LoginPayload {
     pub email: String,
     pub password: String,
 }
 
+// Only presence: accounts from before the registration rules must still be able to log in
+impl Validate for LoginPayload {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.email.trim().is_empty() {
+            errors.add("email", "required", "email is required.");
+        }
+        if self.password.is_empty() {
+            errors.add("password", "required", "password is required.");
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/login",
+    tag = "auth",
+    request_body = LoginPayload,
+    responses(
+        (status = 200, description = "Logged in, sets the auth_token cookie", body = MessageResponse),
+        (status = 401, description = "Wrong credentials", body = ErrorResponse),
+        (status = 422, description = "Missing email or password", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+)]
 pub async fn login(
     State(state): State<AppState>,
-    // Change from `Form(payload)` to `Json(payload)`
-    Json(payload): Json<LoginPayload>,
-) -> impl IntoResponse {
+    client_ip: ClientIp,
+    ValidJson(payload): ValidJson<LoginPayload>,
+) -> Result<impl IntoResponse, AppError> {
 
-    tracing::debug!("login called: user {}; pwd {}", payload.email, payload.password);
+    tracing::debug!("login called: user {}", payload.email);
     
-    match authorize_user(&state.pool, &payload.email, &payload.password).await {
-        Ok(cookie) => {
-            let headers = create_cookie_header(cookie);
-            (StatusCode::OK, headers, Json(json!({"message": "Login successful"}))).into_response()
-        }
-        Err(e) => {
-            e.into_response()
-        }
-    }
+    let cookie = authorize_user(&state.pool, &payload.email, &payload.password)
+        .await
+        .inspect_err(|e| {
+            // With the client's address, so repeated failures can be acted on, e.g. by fail2ban
+            if matches!(e, AuthError::WrongCredentials) {
+                tracing::warn!("Failed login for {} from {}", payload.email, client_ip);
+            }
+        })?;
+    let headers = create_cookie_header(cookie);
+    Ok((StatusCode::OK, headers, Json(json!({"message": "Login successful"}))))
 }
 
 
 
 // Handler for user registration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct RegisterPayload {
     pub email: String,
     pub password: String,
     pub display_name: String,
 }
 
-pub async fn register(
-    State(state): State<AppState>,
-    Json(payload): Json<RegisterPayload>,
-) -> impl IntoResponse {
-    if payload.email.is_empty() || payload.password.is_empty() || payload.display_name.is_empty() {
-        return AuthError::MissingCredentials.into_response();
+impl Validate for RegisterPayload {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.email("email", &self.email);
+        // Not trimmed, spaces are part of a password
+        let password_chars = self.password.chars().count();
+        if password_chars < MIN_PASSWORD_CHARS {
+            errors.add("password", "too_short", format!("password must be at least {} characters long.", MIN_PASSWORD_CHARS));
+        } else if password_chars > MAX_PASSWORD_CHARS {
+            errors.add("password", "too_long", format!("password must be at most {} characters long.", MAX_PASSWORD_CHARS));
+        }
+        errors.length("display_name", &self.display_name, 1, MAX_DISPLAY_NAME_CHARS);
     }
+}
 
-    let password_hash = match hash_password(&payload.password) {
-        Ok(hash) => hash,
-        Err(_) => return AuthError::PasswordHashingFailed.into_response(),
-    };
+#[utoipa::path(
+    post,
+    path = "/api/register",
+    tag = "auth",
+    request_body = RegisterPayload,
+    responses(
+        (status = 201, description = "Registered and logged in, sets the auth_token cookie", body = MessageResponse),
+        (status = 409, description = "User already exists", body = ErrorResponse),
+        (status = 422, description = "Invalid fields", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    ),
+)]
+pub async fn register(
+    State(state): State<AppState>,
+    ValidJson(payload): ValidJson<RegisterPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let password_hash = hash_password(&payload.password).map_err(|_| AuthError::PasswordHashingFailed)?;
 
     match sqlx::query!(
         "INSERT INTO users (email, password_hash, display_name) VALUES (?, ?, ?)",
@@ -770,41 +1847,124 @@ pub async fn register(
             tracing::info!("User {} registered successfully.", payload.email);
 
             // Fetch full claims from DB for this user by email
-            let claims = match get_claims(&state.pool, PartialClaims {
+            let claims = get_claims(&state.pool, PartialClaims {
                 email: payload.email.clone(),
                 user_id: None,
                 display_name: Some(payload.display_name.clone()),
                 ..PartialClaims::default()
-            }).await {
-                Ok(c) => c,
-                Err(e) => {
-                    tracing::error!("Failed to fetch claims after registration: {:?}", e);
-                    return AuthError::DbError.into_response();
-                }
-            };
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch claims after registration: {:?}", e))?;
 
             // Generate the cookie string from full claims
-            let cookie_str = match get_cookie_from_claims(claims).await {
-                Ok(cookie) => cookie,
-                Err(e) => {
-                    tracing::error!("Failed to create cookie after registration: {:?}", e);
-                    return AuthError::TokenCreation.into_response();
-                }
-            };
+            let cookie_str = get_cookie_from_claims(claims)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to create cookie after registration: {:?}", e))?;
 
             // Build cookie header
             let headers = create_cookie_header(cookie_str);
 
             // Return success with the cookie header, logging the user in automatically
-            (StatusCode::CREATED, headers, Json(json!({"message": "Registration successful"}))).into_response()
+            Ok((StatusCode::CREATED, headers, Json(json!({"message": "Registration successful"}))))
         }
         Err(SqlxError::Database(db_error)) if db_error.code() == Some("2067".into()) => {
             tracing::info!("Registration failed: User {} already exists.", payload.email);
-            AuthError::UserExists.into_response()
+            Err(AuthError::UserExists.into())
         }
+        Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to register user {}", payload.email)).into()),
+    }
+}
+
+// ====================== build info ======================
+
+// The handler for the GET /api/version route. Public, but rate limited per client address and across all clients.
+#[utoipa::path(
+    get,
+    path = "/api/version",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Version, commit and backends of the server", body = BuildInfo),
+        (status = 429, description = "Too many requests", body = ErrorResponse),
+    ),
+)]
+pub async fn get_version(State(state): State<AppState>, client_ip: ClientIp) -> Result<Json<BuildInfo>, AppError> {
+    state.public_rate_limiter.check(client_ip.0).map_err(AppError::rate_limited)?;
+    Ok(Json(state.build_info))
+}
+
+// The handler for the GET /healthz route, for load balancers and container health checks.
+// Answers 503 if the database doesn't respond.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "meta",
+    responses(
+        (status = 200, description = "The server and its database are up", body = serde_json::Value),
+        (status = 503, description = "The database doesn't respond", body = serde_json::Value),
+    ),
+)]
+pub async fn healthz(State(state): State<AppState>) -> Response {
+    match query!("SELECT 1 as ok").fetch_one(&state.pool).await {
+        Ok(_) => Json(json!({ "status": "ok", "build": state.build_info })).into_response(),
         Err(e) => {
-            tracing::error!("Failed to register user {}: {:?}", payload.email, e);
-            AuthError::DbError.into_response()
+            tracing::error!("Health check failed: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "unavailable", "error": "database unavailable", "build": state.build_info })),
+            )
+                .into_response()
+        }
+    }
+}
+
+// The handler for the GET /readyz route. 503 once the server is draining, so load balancers stop
+// routing new clients to it while the open connections finish.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Accepting new clients", body = serde_json::Value),
+        (status = 503, description = "Shutting down", body = serde_json::Value),
+    ),
+)]
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    if state.drain.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "draining" }))).into_response()
+    } else {
+        Json(json!({ "status": "ready" })).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_app;
+
+    #[tokio::test]
+    async fn canvas_ids_with_quotes_are_bound_not_spliced() {
+        let (_app, state) = test_app().await;
+        let owner_user_id = query!("INSERT INTO users (email, password_hash, display_name) VALUES ('q@example.com', '', 'Q')")
+            .execute(&state.pool)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        for canvas_id in ["it's", "plain"] {
+            sqlx::query("INSERT INTO Canvas (canvas_id, name, owner_user_id, event_file_path) VALUES (?, ?, ?, ?)")
+                .bind(canvas_id)
+                .bind(canvas_id)
+                .bind(owner_user_id)
+                .bind(format!("{}.jsonl", owner_user_id))
+                .execute(&state.pool)
+                .await
+                .unwrap();
         }
+
+        let names = |rows: Vec<SqliteRow>| rows.iter().map(|row| row.get::<String, _>("name")).collect::<Vec<_>>();
+        let rows = fetch_canvas_rows(&state.pool, ["it's"]).await.unwrap();
+        assert_eq!(names(rows), ["it's"]);
+        // Spliced into the SQL this would match every canvas
+        let rows = fetch_canvas_rows(&state.pool, ["x') OR ('1' = '1"]).await.unwrap();
+        assert!(rows.is_empty());
     }
 }
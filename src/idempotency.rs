@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use sqlx::{query, SqliteConnection, SqlitePool};
+
+use crate::{canvas_id::CanvasId, error::AppError, event_log::timestamp_ms};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// How long a retry with the same key returns the first response.
+pub const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_IDEMPOTENCY_KEY_CHARS: usize = 255;
+const IDEMPOTENCY_CLEANUP_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// The `Idempotency-Key` header of a request, if it has one. Clients usually send a UUID; anything of
+/// visible ASCII up to 255 characters is accepted.
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_CHARS && key.bytes().all(|b| b.is_ascii_graphic()) => {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(AppError::BadRequest {
+            code: "invalid_idempotency_key",
+            message: format!("Idempotency-Key must be 1 to {} visible ASCII characters.", MAX_IDEMPOTENCY_KEY_CHARS),
+            details: None,
+        }),
+    }
+}
+
+fn cutoff_ms() -> i64 {
+    timestamp_ms() as i64 - IDEMPOTENCY_KEY_TTL.as_millis() as i64
+}
+
+/// The canvas created by an earlier request of the user with the same key, if that was within the TTL.
+pub async fn find_canvas(pool: &SqlitePool, user_id: i64, key: &str) -> Result<Option<CanvasId>, sqlx::Error> {
+    let cutoff = cutoff_ms();
+    let row = query!(
+        r#"SELECT canvas_id AS "canvas_id: CanvasId" FROM idempotency_keys WHERE user_id = ? AND idempotency_key = ? AND created_at > ?"#,
+        user_id,
+        key,
+        cutoff
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| row.canvas_id))
+}
+
+/// Records that the key creates `canvas_id`, in the transaction creating it. False if another request
+/// holds the key already: the unique constraint makes a concurrent duplicate wait for the first
+/// transaction and then fail here, before it created anything. An expired key that wasn't pruned yet
+/// is taken over.
+pub async fn claim(conn: &mut SqliteConnection, user_id: i64, key: &str, canvas_id: &CanvasId) -> Result<bool, sqlx::Error> {
+    let now = timestamp_ms() as i64;
+    let cutoff = cutoff_ms();
+    let result = query!(
+        "INSERT INTO idempotency_keys (user_id, idempotency_key, canvas_id, created_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT(user_id, idempotency_key) DO UPDATE SET canvas_id = excluded.canvas_id, created_at = excluded.created_at
+         WHERE idempotency_keys.created_at <= ?",
+        user_id,
+        key,
+        canvas_id,
+        now,
+        cutoff
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Periodically removes the keys older than the TTL.
+pub async fn start_idempotency_cleanup_task(pool: SqlitePool) {
+    let interval = Duration::from_secs(IDEMPOTENCY_CLEANUP_INTERVAL_SECONDS);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let cutoff = cutoff_ms();
+        match query!("DELETE FROM idempotency_keys WHERE created_at <= ?", cutoff).execute(&pool).await {
+            Ok(result) if result.rows_affected() > 0 => {
+                tracing::debug!("Removed {} expired idempotency keys", result.rows_affected());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to remove expired idempotency keys: {}", e),
+        }
+    }
+}
@@ -1,9 +1,60 @@
-use std::hash::{Hash, Hasher};
-use serde_json::json;
-use tokio::sync::mpsc;
-use axum::extract::ws::Message;
+use std::{
+    collections::HashSet,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch};
+use axum::extract::ws::{CloseFrame, Message};
 use uuid::Uuid;
 
+use crate::{canvas_id::CanvasId, server_message::ServerMessage};
+
+// ============================= Close codes =============================
+// Application close codes, so clients can tell "reconnect with a fresh login"
+// apart from "don't bother".
+
+/// The session expired or could not be refreshed. Log in again.
+pub const CLOSE_AUTH_EXPIRED: u16 = 4001;
+/// The user's permission on the canvas was revoked.
+pub const CLOSE_PERMISSION_REVOKED: u16 = 4003;
+/// An administrator disconnected the user.
+pub const CLOSE_ADMIN_DISCONNECT: u16 = 4004;
+/// The connection kept exceeding its rate limits.
+pub const CLOSE_RATE_LIMITED: u16 = 4008;
+/// The connection could not keep up with the messages sent to it.
+pub const CLOSE_SLOW_CONSUMER: u16 = 4009;
+/// The server is shutting down (the standard "going away"). Reconnect, e.g. to another instance.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Number of consecutive dropped messages after which a connection counts as lagging.
+const LAG_THRESHOLD: u32 = 32;
+
+/// How long a connection may stay lagging before it is closed.
+const LAG_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// A close request: close code and reason.
+pub type CloseRequest = Option<(u16, String)>;
+
+/// The outcome of a non-blocking delivery attempt.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    /// The connection's queue was full and the message was dropped.
+    Dropped,
+    /// The connection is gone (or has been closed) and should be removed.
+    Closed,
+}
+
+/// Backpressure bookkeeping for a connection that fails to keep up.
+#[derive(Debug, Default)]
+struct LagState {
+    consecutive_failures: u32,
+    lagging_since: Option<Instant>,
+    /// Canvases that had messages dropped and need a resync.
+    missed_canvases: HashSet<CanvasId>,
+}
+
 /// A wrapper around a WebSocket message sender that provides a unique ID.
 /// This allows us to track a specific connection instance independently of the user.
 #[derive(Clone, Debug)]
@@ -12,6 +63,9 @@ pub struct IdentifiableWebSocket {
     pub id: Uuid,
     /// The channel sender used to send messages back to the client.
     pub sender: mpsc::Sender<Message>,
+    /// Signals the connection tasks to close the socket.
+    close_signal: Arc<watch::Sender<CloseRequest>>,
+    lag: Arc<Mutex<LagState>>,
 }
 
 // Implement PartialEq and Eq based only on the ID
@@ -31,9 +85,12 @@ impl Hash for IdentifiableWebSocket {
 
 impl IdentifiableWebSocket {
     pub fn new(sender: mpsc::Sender<Message>) -> Self {
+        let (close_signal, _) = watch::channel(None);
         Self {
             id: Uuid::new_v4(),
             sender,
+            close_signal: Arc::new(close_signal),
+            lag: Arc::new(Mutex::new(LagState::default())),
         }
     }
 
@@ -44,16 +101,115 @@ impl IdentifiableWebSocket {
         self.sender.send(message).await
     }
 
+    /// Serializes and sends a typed server message.
+    pub async fn send_msg(&self, message: &ServerMessage) -> Result<(), mpsc::error::SendError<Message>> {
+        self.send(message.to_ws_message()).await
+    }
+
+    /// Sends a message without waiting for space in the connection's queue.
+    /// Used for broadcasts, so one slow client cannot hold up everyone else.
+    ///
+    /// Consecutive failures are counted; past a threshold the connection is marked as lagging.
+    /// Once space frees up, a lagging connection receives a `resync` message for every canvas
+    /// it missed messages on. If it stays lagging past the grace period it is closed.
+    pub fn try_deliver(&self, canvas_uuid: &CanvasId, message: Message) -> Delivery {
+        let mut lag = self.lag.lock().unwrap();
+
+        if let Some(since) = lag.lagging_since {
+            if since.elapsed() > LAG_GRACE_PERIOD {
+                drop(lag);
+                tracing::warn!("Connection {} lagged for too long. Closing as slow consumer.", self.id);
+                self.request_close(CLOSE_SLOW_CONSUMER, "slow consumer");
+                return Delivery::Closed;
+            }
+
+            // Space freed up: tell the client which canvases it has to re-register for
+            lag.missed_canvases.insert(*canvas_uuid);
+            while let Some(missed) = lag.missed_canvases.iter().next().copied() {
+                let resync = ServerMessage::Resync { canvas_id: missed.to_string() };
+                match self.sender.try_send(resync.to_ws_message()) {
+                    Ok(()) => {
+                        lag.missed_canvases.remove(&missed);
+                    }
+                    Err(mpsc::error::TrySendError::Full(_)) => return Delivery::Dropped,
+                    Err(mpsc::error::TrySendError::Closed(_)) => return Delivery::Closed,
+                }
+            }
+
+            tracing::info!("Connection {} caught up and was asked to resync.", self.id);
+            *lag = LagState::default();
+            // The client re-registers and receives this message as part of the history
+            return Delivery::Dropped;
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => {
+                lag.consecutive_failures = 0;
+                lag.missed_canvases.clear();
+                Delivery::Delivered
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                lag.consecutive_failures += 1;
+                lag.missed_canvases.insert(*canvas_uuid);
+                if lag.consecutive_failures >= LAG_THRESHOLD {
+                    tracing::warn!("Connection {} is lagging behind.", self.id);
+                    lag.lagging_since = Some(Instant::now());
+                }
+                Delivery::Dropped
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Delivery::Closed,
+        }
+    }
+
     /// Sends a simple JSON notification message to a specific connection.
     pub async fn notify_client(&self, message: &str) {
-        let notification = json!({
-            "notify": message
-        });
-        
-        let send_result = self.send(Message::Text(notification.to_string().into())).await;
-        
+        let notification = ServerMessage::Notify {
+            notify: message.to_string(),
+        };
+
+        let send_result = self.send_msg(&notification).await;
+
         if let Err(e) = send_result {
             tracing::error!("Failed to send notification to client {}: {}", self.id, e);
         }
     }
-}
\ No newline at end of file
+
+    /// Sends a structured error message with a machine readable code to a specific connection.
+    pub async fn send_error(&self, code: &str, message: &str, details: Option<serde_json::Value>) {
+        let error = ServerMessage::Error {
+            code: code.to_string(),
+            message: message.to_string(),
+            details,
+        };
+
+        let send_result = self.send_msg(&error).await;
+
+        if let Err(e) = send_result {
+            tracing::error!("Failed to send error to client {}: {}", self.id, e);
+        }
+    }
+
+    /// Asks the connection tasks to close the socket with the given close code.
+    /// Works even if the connection's queue is full.
+    pub fn request_close(&self, code: u16, reason: &str) {
+        self.close_signal.send_replace(Some((code, reason.to_string())));
+    }
+
+    /// Subscribes to close requests for this connection.
+    pub fn close_requests(&self) -> watch::Receiver<CloseRequest> {
+        self.close_signal.subscribe()
+    }
+
+    /// Sends a close frame through the regular queue, after any message already queued.
+    /// The forwarding task stops once the close frame is sent.
+    pub async fn close(&self, code: u16, reason: &str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        };
+
+        if let Err(e) = self.send(Message::Close(Some(frame))).await {
+            tracing::error!("Failed to send close frame to client {}: {}", self.id, e);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+
+use crate::{error::AppError, rate_limiter::RateLimitConfig};
+
+/// Default number of canvases a single WebSocket connection may be subscribed to.
+pub const DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 20;
+
+/// Default limit for HTTP request bodies to the API.
+pub const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 1024 * 1024;
+
+/// Default limit for HTTP request bodies to upload routes.
+pub const DEFAULT_MAX_UPLOAD_BODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// The body limit of the route handling a request, for the JSON 413 of body extractors.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+/// Size limits for incoming drawing payloads.
+/// Shared by the WebSocket handlers and any REST endpoint accepting events,
+/// so both enforce the same limits.
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadLimits {
+    /// Maximum size of a single WebSocket message (and frame) in bytes.
+    pub max_message_bytes: usize,
+    /// Maximum number of events in a single `eventsForCanvas` array.
+    pub max_events_per_message: usize,
+    /// Maximum size of the serialized events payload in bytes.
+    pub max_events_payload_bytes: usize,
+    /// Maximum size of a single serialized event in bytes.
+    pub max_event_bytes: usize,
+}
+
+/// A payload that exceeds one of the `PayloadLimits`. The whole payload is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitViolation {
+    /// Name of the exceeded limit, as in `PayloadLimits::to_json`.
+    pub limit: &'static str,
+    /// Index of the offending event, for limits on single events.
+    pub index: Option<usize>,
+    pub actual: usize,
+    pub max: usize,
+}
+
+impl LimitViolation {
+    /// The violation as JSON, together with all limits, for error details.
+    pub fn to_json(&self, limits: &PayloadLimits) -> Value {
+        json!({
+            "limit": self.limit,
+            "index": self.index,
+            "actual": self.actual,
+            "max": self.max,
+            "limits": limits.to_json(),
+        })
+    }
+}
+
+impl fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "Event {} exceeds {} ({} > {}).", index, self.limit, self.actual, self.max),
+            None => write!(f, "Events payload exceeds {} ({} > {}).", self.limit, self.actual, self.max),
+        }
+    }
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 1024 * 1024,
+            max_events_per_message: 500,
+            max_events_payload_bytes: 512 * 1024,
+            max_event_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl PayloadLimits {
+    /// Checks an events payload against the limits, the first exceeded limit is reported.
+    pub fn validate_events(&self, events: &[Value], payload_bytes: usize) -> Result<(), LimitViolation> {
+        let exceeds = |limit, actual, max, index| (actual > max).then_some(LimitViolation { limit, index, actual, max });
+
+        if let Some(violation) = exceeds("maxEventsPerMessage", events.len(), self.max_events_per_message, None)
+            .or_else(|| exceeds("maxEventsPayloadBytes", payload_bytes, self.max_events_payload_bytes, None))
+        {
+            return Err(violation);
+        }
+        events
+            .iter()
+            .enumerate()
+            .find_map(|(index, event)| {
+                exceeds("maxEventBytes", event.to_string().len(), self.max_event_bytes, Some(index))
+            })
+            .map_or(Ok(()), Err)
+    }
+
+    /// Checks that the largest accepted message fits the payload budget of the rate limits.
+    /// A message is charged against the budget as a whole, so a larger one could never pass.
+    pub fn check_rate_limits(&self, rate_limits: &RateLimitConfig) -> Result<(), String> {
+        let largest = self.max_message_bytes.max(self.max_events_payload_bytes);
+        if largest > rate_limits.payload_bytes as usize {
+            return Err(format!(
+                "WS_RATE_PAYLOAD_BYTES: {} is below the largest accepted message ({} bytes, MAX_WS_MESSAGE_BYTES \
+                 and MAX_EVENTS_PAYLOAD_BYTES), which could never pass the rate limit",
+                rate_limits.payload_bytes, largest
+            ));
+        }
+        Ok(())
+    }
+
+    /// The limits as JSON, sent to clients alongside `invalid_payload` errors.
+    pub fn to_json(self) -> serde_json::Value {
+        json!({
+            "maxEventsPerMessage": self.max_events_per_message,
+            "maxEventsPayloadBytes": self.max_events_payload_bytes,
+            "maxEventBytes": self.max_event_bytes,
+            "maxMessageBytes": self.max_message_bytes,
+        })
+    }
+}
+
+/// Replaces the plain-text 413 of a body limit (`RequestBodyLimitLayer` or a body extractor hitting it)
+/// with the JSON error envelope, including the limit. Has to wrap the limit layer.
+pub async fn payload_too_large_middleware(State(limit): State<usize>, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(BodyLimit(limit));
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return response;
+    }
+    AppError::PayloadTooLarge {
+        message: "Payload too large.".to_string(),
+        details: json!({ "limit": limit }),
+    }
+    .into_response()
+}
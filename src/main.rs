@@ -1,165 +1,389 @@
 //! Parts of this code have been adapted from https://github.com/tokio-rs/axum/blob/main/examples/jwt/src/main.rs
-use axum::{
-    routing::{ get, post}, Router
-};
+use axum::Router;
 use sqlx::sqlite::SqlitePool;
-use sqlx::migrate::Migrator;
-use tower_http::services::{ServeDir, ServeFile};
-use std::{env, net::SocketAddr};
-use std::sync::LazyLock; 
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::{env, net::SocketAddr, path::PathBuf};
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, Layer};
 use dotenvy::dotenv;
 
+mod access_log;
+mod admin_cli;
+mod app;
+mod audit;
 mod auth;
+mod backup;
+mod build_info;
+mod blocking;
+mod canvas_id;
+mod client_ip;
+mod config;
 mod handlers;
+mod admin_handlers;
 mod websocket_handlers;
+mod sse_handlers;
 mod socket_claims_manager;
 mod canvas_manager;
 mod identifiable_web_socket;
 mod permission_refresh_list;
+mod chat_store;
+mod content_filter;
+mod drain;
+mod error;
+mod error_reporting;
+mod event_log;
+mod event_store;
+mod events;
+mod idempotency;
+mod rate_limiter;
+mod reports;
+mod seed;
+mod request_id;
+mod review_queue;
+mod limits;
+mod maintenance;
+mod openapi;
+mod origin_policy;
+mod permission_source;
+#[cfg(feature = "s3")]
+mod s3_store;
+mod server_message;
+mod metrics;
+mod moderation_log;
+mod validation;
+#[cfg(test)]
+mod tests;
 
-// Re-export types from auth and handlers for main's use
-use auth::{auth_middleware }; 
-use handlers::{
-    get_user_info, update_profile};
 use std::sync::Arc;
 
 use crate::{
-    canvas_manager::CanvasManager, handlers::{create_canvas, get_canvas_list, get_canvas_permissions, login, logout, register, update_canvas_permissions}, permission_refresh_list::{start_cleanup_task, PermissionRefreshList}, socket_claims_manager::SocketClaimsManager, websocket_handlers::ws_handler
+    backup::{start_backup_task, Backups}, blocking::BlockingPool, build_info::BuildInfo, drain::{DrainConfig, DrainState}, canvas_manager::{start_archive_gc_task, start_cache_budget_task, start_compaction_task, start_held_events_expiry_task, start_idle_sweep_task, start_log_compression_task, start_log_counter_flush_task, start_moderation_cleanup_task, CanvasManager}, config::AppConfig, content_filter::ContentFilter, event_store::{backfill_event_counts, import_file_logs, relativize_event_paths, validate_event_files}, idempotency::start_idempotency_cleanup_task, limits::PayloadLimits, maintenance::DataDirLock, metrics::WsMetrics, origin_policy::OriginPolicy, permission_refresh_list::{start_cleanup_task, PermissionRefreshList}, rate_limiter::{RateLimitConfig, RestRateLimiter, SharedRateLimiter}, socket_claims_manager::{start_claims_sweep_task, SocketClaimsManager}
 };
 
-// ───── 1. Constants / statics ──────────────
-// Corrected LazyLock type annotation
-pub(crate) static KEYS: LazyLock<auth::Keys> = LazyLock::new(|| {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    auth::Keys::new(secret.as_bytes())
-});
-
-// Static Migrator instance (ensure your `migrations` directory exists at project root)
-static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
-
-
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<AppConfig>,
     pub pool: SqlitePool,
     pub permission_refresh_list: Arc<PermissionRefreshList>,
     // pub active_connections: WebSocketConnections,
     pub canvas_manager: CanvasManager,
     pub socket_claims_manager: SocketClaimsManager,
+    pub rate_limit_config: RateLimitConfig,
+    pub rest_rate_limiter: RestRateLimiter,
+    /// Shared by all clients of the public `/api/version`.
+    pub public_rate_limiter: SharedRateLimiter,
+    pub payload_limits: PayloadLimits,
+    pub max_subscriptions_per_connection: usize,
+    pub metrics: Arc<WsMetrics>,
+    pub origin_policy: OriginPolicy,
+    /// Root of the event files, `AppConfig::data_dir`.
+    pub data_dir: PathBuf,
+    pub backups: Backups,
+    /// Runs heavy file jobs, e.g. log compression and backups (see `BlockingPool`).
+    pub blocking: BlockingPool,
+    pub build_info: BuildInfo,
+    pub drain: DrainState,
+    /// Applied to chat messages and announcements, shared with the canvas manager.
+    pub content_filter: Arc<dyn ContentFilter>,
 }
 
 // ───── Main entrypoint ──────────────────
 #[tokio::main]
 async fn main() {
     let _ = setup_tracing();
-    let pool = setup_database().await;
-    let permission_refresh_list = Arc::new(PermissionRefreshList::new());
-
-    // Initialize the WebSocketConnections and CanvasManager structs
-    let canvas_manager = CanvasManager::new();
-    let socket_claims_manager = SocketClaimsManager::new();
-
-    let app_state = AppState {
-        pool: pool.clone(),
-        permission_refresh_list: permission_refresh_list.clone(),
-        canvas_manager: canvas_manager.clone(),
-        socket_claims_manager: socket_claims_manager.clone()
+    // Sends queued reports when dropped at the end of main
+    let _error_reporting = error_reporting::init();
+    let config = match AppConfig::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(errors) => {
+            tracing::error!("{}", errors);
+            std::process::exit(1);
+        }
     };
+    auth::init(&config);
+    let data_dir = config.data_dir.clone();
+    tracing::info!("DATA_DIR: {}", data_dir.display());
 
-    tokio::spawn(start_cleanup_task(permission_refresh_list.clone()));
+    // Held until the server or the offline command stops, so no two processes rewrite the same logs
+    let _data_dir_lock = match DataDirLock::acquire(&data_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            tracing::error!("Cannot lock DATA_DIR: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let pool = app::setup_database(&config).await;
 
-    let app = create_app_router(app_state);
-    start_server(app).await;
-}
+    match env::args().nth(1).as_deref() {
+        // User management, e.g. creating the first admin: `admin create-user --email .. --display-name .. --admin`
+        Some("admin") => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            if let Err(e) = admin_cli::run(&pool, &args).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        // Copies the event files into the database for CANVAS_STORE=sqlite and exits
+        Some("import-event-logs") => {
+            match import_file_logs(&pool, &data_dir).await {
+                Ok(imported) => tracing::info!("Imported the event logs of {} canvases.", imported),
+                Err(e) => {
+                    tracing::error!("Failed to import event logs: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        // Rewrites event file paths stored before DATA_DIR to file names and exits
+        Some("relativize-event-paths") => {
+            match relativize_event_paths(&pool, &data_dir).await {
+                Ok(rewritten) => tracing::info!("Rewrote the event file paths of {} canvases.", rewritten),
+                Err(e) => {
+                    tracing::error!("Failed to rewrite event file paths: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        // Counts the events of every canvas into Canvas.event_count and exits
+        Some("backfill-event-counts") => {
+            let blocking = BlockingPool::new(config.event_store.blocking_concurrency);
+            let store = event_store::from_config(pool.clone(), &data_dir, &config.event_store, blocking);
+            match backfill_event_counts(&pool, store.as_ref()).await {
+                Ok(updated) => tracing::info!("Stored the event counts of {} canvases.", updated),
+                Err(e) => {
+                    tracing::error!("Failed to backfill event counts: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        // Checks the event log of a canvas and exits
+        Some("verify-canvas") => {
+            let Some(canvas_id) = env::args().nth(2) else {
+                tracing::error!("Usage: verify-canvas <canvas_id> [--quarantine]");
+                std::process::exit(1);
+            };
+            let quarantine = env::args().skip(3).any(|arg| arg == "--quarantine");
+            let blocking = BlockingPool::new(config.event_store.blocking_concurrency);
+            let store = event_store::from_config(pool.clone(), &data_dir, &config.event_store, blocking);
+            match store.verify(&canvas_id, quarantine).await {
+                Ok(report) => tracing::info!(
+                    "Verified canvas {}: {}",
+                    canvas_id,
+                    serde_json::to_string_pretty(&report).unwrap_or_default()
+                ),
+                Err(e) => {
+                    tracing::error!("Failed to verify canvas {}: {}", canvas_id, e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
 
+    // Finds canvases with broken event files now instead of when someone opens them
+    if config.validate_on_start {
+        let check = validate_event_files(&pool, &data_dir, config.event_store.kind)
+            .await
+            .expect("Failed to validate the event files of the canvases.");
+        tracing::info!(
+            "Validated {} canvases: {} without event file path, {} missing event files created, {} event files missing.",
+            check.canvases,
+            check.empty_paths.len(),
+            check.created.len(),
+            check.missing.len()
+        );
+        if !check.empty_paths.is_empty() {
+            panic!("Canvases without an event file path: {}", check.empty_paths.join(", "));
+        }
+    }
 
-// ───── 3. Helper Functions for Main ───────
+    let state = app::build_state(config.clone(), pool.clone()).await;
+    let canvas_manager = state.canvas_manager.clone();
 
-fn setup_tracing() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-    tracing::info!("Tracing initialized.");
-}
+    // Compaction, verification, orphaned files and statistics without starting the server
+    if env::args().nth(1).as_deref() == Some("maintenance") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let result = maintenance::run(&canvas_manager, &pool, &data_dir, &args).await;
+        canvas_manager.flush_all().await;
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-async fn setup_database() -> SqlitePool {
-    dotenv().ok();
-    tracing::info!("Environment variables loaded.");
-    let database_url = env::var("DATABASE_URL")
-        .expect("JWT_SECRET must be set and DATABASE_URL must be set in .env or environment variables");
-    tracing::info!("DATABASE_URL: {}", database_url);
-
-    if database_url.starts_with("sqlite://") {
-        let db_path_str = database_url.trim_start_matches("sqlite://");
-        let db_path = std::path::Path::new(db_path_str);
-        if let Some(parent_dir) = db_path.parent() {
-            if !parent_dir.exists() {
-                tracing::info!("Creating database directory: {:?}", parent_dir);
-                std::fs::create_dir_all(parent_dir)
-                    .expect("Failed to create database directory.");
+    // Demo users and canvases for local development: `seed [--force]`
+    if env::args().nth(1).as_deref() == Some("seed") {
+        let force = env::args().skip(2).any(|arg| arg == "--force");
+        let result = seed::seed(&canvas_manager, &pool, &data_dir, force).await;
+        canvas_manager.flush_all().await;
+        canvas_manager.flush_log_counters(&pool).await;
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // The frontend is required unless the server runs API-only, so a wrong working directory fails here
+    // instead of answering every page with a 404
+    let public_dir = if env::args().any(|arg| arg == "--no-frontend") {
+        tracing::info!("Serving the API only (--no-frontend).");
+        None
+    } else {
+        match app::resolve_public_dir(&config.public_dir) {
+            Ok(public_dir) => {
+                tracing::info!("PUBLIC_DIR: {}", public_dir.display());
+                Some(public_dir)
+            }
+            Err(e) => {
+                tracing::error!("{}. Start with --no-frontend to serve the API only.", e);
+                std::process::exit(1);
             }
         }
+    };
+
+    tokio::spawn(start_cleanup_task(state.permission_refresh_list.clone(), config.jwt.reissue_seconds));
+    tokio::spawn(start_claims_sweep_task(
+        state.socket_claims_manager.clone(),
+        pool.clone(),
+        config.auth_expiry_warning_seconds,
+    ));
+
+    tokio::spawn(start_log_counter_flush_task(canvas_manager.clone(), pool.clone()));
+
+    // Canvases without subscribers stay loaded for the idle TTL; 0 unloads them right away
+    if !config.canvas_manager.idle_ttl.is_zero() {
+        tokio::spawn(start_idle_sweep_task(canvas_manager.clone()));
     }
 
-    tracing::info!("Connecting to database at: {}", database_url);
-    let pool = SqlitePool::connect(&database_url)
-        .await
-        .expect("Failed to create SQLite pool. Check DATABASE_URL and database file permissions.");
+    tokio::spawn(start_moderation_cleanup_task(pool.clone()));
+    tokio::spawn(start_idempotency_cleanup_task(pool.clone()));
 
-    tracing::info!("Running database migrations...");
-    MIGRATOR.run(&pool).await.expect("Failed to run database migrations.");
-    tracing::info!("Database migrations applied successfully.");
+    if !config.canvas_manager.held_events_ttl.is_zero() {
+        tokio::spawn(start_held_events_expiry_task(canvas_manager.clone()));
+    }
 
-    pool
-}
+    if config.canvas_manager.event_cache.max_total_bytes > 0 {
+        tokio::spawn(start_cache_budget_task(canvas_manager.clone()));
+    }
 
-fn create_app_router(state: AppState) -> Router {
-    // This service handles requests for files in the "./public" directory.
-    let spa_service = ServeDir::new("./public").not_found_service(
-        ServeFile::new("./public/index.html")
-    );
-
-    // Protected API routes that require authentication.
-    // We nest them under a `/api` path and apply the auth middleware.
-    let protected_routes = Router::new()
-        .route("/me", get(get_user_info))
-        .route("/user/update", post(update_profile))
-        .route("/canvases/create", post(create_canvas))
-        .route("/canvases/list", get(get_canvas_list))
-        .route("/canvas/{canvas_id}/permissions", post(update_canvas_permissions).get(get_canvas_permissions))
-        .layer(axum::middleware::from_fn_with_state(state.clone(), auth_middleware));
-
-    // Public API routes for authentication and other unauthenticated endpoints.
-    let public_api_routes = Router::new()
-        .route("/login", post(login))
-        .route("/logout", post(logout))
-        .route("/register", post(register));
-
-    // Combine all routes and services into the final application router.
-    Router::new()
-        .nest("/api", public_api_routes.merge(protected_routes))
-        .route("/ws", get(ws_handler))
-        .fallback_service(spa_service)
-        .with_state(state)
-}
+    // Scheduled compaction is opt-in: it runs only if a garbage ratio threshold is configured
+    if let Some(min_garbage_ratio) = config.compaction_garbage_ratio {
+        tracing::info!("Scheduled compaction enabled for canvases above a garbage ratio of {}", min_garbage_ratio);
+        tokio::spawn(start_compaction_task(canvas_manager.clone(), pool.clone(), min_garbage_ratio));
+    }
 
+    // Compressing cold logs is opt-in as well: it runs only if an idle period is configured
+    if let Some(idle_for) = config.compress_idle_after {
+        tracing::info!("Compressing event logs of canvases idle for {} days", idle_for.as_secs() / (24 * 60 * 60));
+        tokio::spawn(start_log_compression_task(canvas_manager.clone(), pool.clone(), idle_for));
+    }
 
+    // Logs replaced by compaction or a restore are archived; they are removed after the retention
+    tokio::spawn(start_archive_gc_task(canvas_manager.clone(), config.archive_retention));
 
+    // Scheduled backups are opt-in: they run only if an interval is configured
+    if let Some(interval) = config.backup_interval {
+        tracing::info!("Writing a backup every {} seconds", interval.as_secs());
+        tokio::spawn(start_backup_task(
+            state.backups.clone(),
+            canvas_manager.clone(),
+            pool.clone(),
+            interval,
+        ));
+    }
 
-async fn start_server(app: Router) {
-    let host = env::var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
+    tracing::info!("Starting {}", state.build_info);
+    let shutdown = shutdown_signal(state.drain.clone(), state.socket_claims_manager.clone(), config.drain);
+    let app = app::router(state, public_dir.as_deref());
+    start_server(app, &config, shutdown).await;
 
-    let addr_str = format!("{}:{}", host, port);
-    let addr: SocketAddr = addr_str.parse().expect("Invalid SERVER_HOST:SERVER_PORT provided");
+    // Write events still waiting in coalescing buffers and write queues
+    canvas_manager.flush_all().await;
+    canvas_manager.flush_log_counters(&pool).await;
+    tracing::info!("Server shut down.");
+}
 
-    let listener = tokio::net::TcpListener::bind(addr)
+
+// ───── 3. Helper Functions for Main ───────
+
+// `LOG_FORMAT=json` writes one JSON object per line, with the fields of the enclosing spans
+// (e.g. the request id); anything else, e.g. `pretty`, keeps the human-readable format.
+fn setup_tracing() {
+    // Before anything else reads the environment, so all settings can come from .env
+    dotenv().ok();
+    let registry = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("{}=debug,{}=info", env!("CARGO_CRATE_NAME"), access_log::TARGET).into()),
+        )
+        .with(error_reporting::layer());
+    let json = env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    if json {
+        registry.with(json_log_layer(std::io::stdout)).init();
+    } else {
+        registry.with(tracing_subscriber::fmt::layer()).init();
+    }
+    tracing::info!("Tracing initialized.");
+}
+
+/// The log layer of `LOG_FORMAT=json`: one JSON object per line, with the fields of the current span
+/// and its parents, e.g. the request id.
+fn json_log_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_writer(writer)
+}
+
+async fn start_server(app: Router, config: &AppConfig, shutdown: impl Future<Output = ()> + Send + 'static) {
+    let listener = tokio::net::TcpListener::bind(config.addr)
         .await
         .unwrap();
     tracing::info!("listening on http://{}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    if config.trusted_proxies.is_empty() {
+        tracing::info!("No TRUSTED_PROXIES, client addresses are taken from the socket.");
+    } else {
+        tracing::info!("Taking client addresses from the forwarding headers of {}", config.trusted_proxies);
+    }
+    // The peer address is what `ClientIp` falls back to, and how it decides whether to trust the headers
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await
+        .unwrap();
+}
+
+/// Resolves once the server should stop accepting connections: after SIGTERM or Ctrl+C,
+/// and after draining the open WebSockets (see `DrainState::drain`).
+async fn shutdown_signal(drain: DrainState, sockets: SocketClaimsManager, config: DrainConfig) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutdown signal received.");
+    drain.drain(&sockets, config).await;
 }
\ No newline at end of file
@@ -0,0 +1,276 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, TryLockError},
+    io,
+    path::Path,
+};
+
+use serde::Serialize;
+use sqlx::{query, SqlitePool};
+
+use crate::{
+    canvas_id::CanvasId,
+    canvas_manager::{AppendEventsError, CanvasManager, CompactionStats},
+    event_log::VerifyReport,
+    event_store::{canvases_dir, ARCHIVE_DIR},
+};
+
+/// The lock file in the data directory, held by the running server and by offline maintenance.
+const LOCK_FILE: &str = ".lock";
+
+/// Directory below the canvases directory that orphaned files are moved to.
+const ORPHANS_DIR: &str = "orphans";
+
+/// How many canvases `stats` lists as the largest.
+const LARGEST_CANVASES: usize = 10;
+
+const USAGE: &str = "Usage:
+  maintenance compact-canvas <canvas_id>
+  maintenance verify <canvas_id|--all> [--quarantine]
+  maintenance orphans [--relocate]
+  maintenance stats";
+
+/// An exclusive advisory lock on the data directory, released when dropped (or the process exits).
+/// Keeps offline maintenance from rewriting logs a running server has open, and vice versa.
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    /// Takes the lock, failing with `io::ErrorKind::WouldBlock` if another process holds it.
+    pub fn acquire(data_dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let file = File::options().create(true).truncate(false).write(true).open(data_dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is in use by another instance", data_dir.display()),
+            )),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/// Result of verifying one canvas in `verify_all`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasVerification {
+    pub canvas_id: CanvasId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<VerifyReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Verifies the logs of all canvases (see `CanvasManager::verify`), continuing past failures.
+pub async fn verify_all(
+    manager: &CanvasManager,
+    pool: &SqlitePool,
+    quarantine: bool,
+) -> Result<Vec<CanvasVerification>, sqlx::Error> {
+    let canvases = query!(r#"SELECT canvas_id AS "canvas_id: CanvasId" FROM Canvas ORDER BY canvas_id"#).fetch_all(pool).await?;
+    let mut results = Vec::with_capacity(canvases.len());
+    for canvas in canvases {
+        let (report, error) = match manager.verify(pool, &canvas.canvas_id, quarantine).await {
+            Ok(report) => (Some(report), None),
+            Err(e) => (None, Some(format!("{:?}", e))),
+        };
+        results.push(CanvasVerification { canvas_id: canvas.canvas_id, report, error });
+    }
+    Ok(results)
+}
+
+/// Files in the canvases directory that belong to no canvas, e.g. left behind by a crash during deletion.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanReport {
+    /// File names, relative to the canvases directory.
+    pub files: Vec<String>,
+    pub bytes: u64,
+    /// Whether they were moved to `canvases/orphans`.
+    pub relocated: bool,
+}
+
+/// Finds files in the canvases directory whose name doesn't start with the id or log file name of a
+/// canvas, i.e. logs, compressed logs and sidecars (`.pending.jsonl`, `.corrupt`, ...) of deleted canvases.
+/// With `relocate` they are moved to `canvases/orphans`, from where they can be inspected or removed.
+pub async fn orphans(pool: &SqlitePool, data_dir: &Path, relocate: bool) -> io::Result<OrphanReport> {
+    let rows = query!("SELECT canvas_id, event_file_path FROM Canvas")
+        .fetch_all(pool)
+        .await
+        .map_err(io::Error::other)?;
+    let mut known: HashSet<String> = HashSet::new();
+    for row in rows {
+        if let Some(name) = Path::new(&row.event_file_path).file_name().and_then(|name| name.to_str()) {
+            known.insert(file_prefix(name).to_string());
+        }
+        known.insert(row.canvas_id);
+    }
+
+    let dir = canvases_dir(data_dir);
+    let mut report = OrphanReport { files: Vec::new(), bytes: 0, relocated: relocate };
+    let orphans: Vec<(String, u64)> = canvas_files(&dir)
+        .await?
+        .into_iter()
+        .filter(|(name, _)| !known.contains(file_prefix(name)))
+        .collect();
+    if relocate && !orphans.is_empty() {
+        tokio::fs::create_dir_all(dir.join(ORPHANS_DIR)).await?;
+    }
+    for (name, bytes) in orphans {
+        if relocate {
+            tokio::fs::rename(dir.join(&name), dir.join(ORPHANS_DIR).join(&name)).await?;
+            tracing::info!("Moved orphaned file {} to {}", name, ORPHANS_DIR);
+        }
+        report.bytes += bytes;
+        report.files.push(name);
+    }
+    report.files.sort();
+    Ok(report)
+}
+
+/// Row counts and disk usage of the instance.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceStats {
+    /// Rows per table.
+    pub rows: HashMap<&'static str, i64>,
+    /// Bytes of all files directly in the canvases directory: logs, compressed logs and sidecars.
+    pub event_file_bytes: u64,
+    /// Bytes of the archived logs.
+    pub archive_bytes: u64,
+    /// The canvases taking the most space in the canvases directory, largest first.
+    pub largest_canvases: Vec<CanvasSize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasSize {
+    pub canvas_id: String,
+    pub bytes: u64,
+    pub event_count: i64,
+}
+
+/// Tables counted by `stats`. Fixed names, so they can be formatted into the query.
+const COUNTED_TABLES: [&str; 8] = [
+    "users",
+    "Canvas",
+    "Canvas_Permissions",
+    "canvas_events",
+    "moderation_log",
+    "reports",
+    "canvas_announcements",
+    "audit_log",
+];
+
+/// Counts the rows of the main tables and sums up the sizes of the event files.
+pub async fn stats(pool: &SqlitePool, data_dir: &Path) -> io::Result<InstanceStats> {
+    let mut rows = HashMap::new();
+    for table in COUNTED_TABLES {
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(pool)
+            .await
+            .map_err(io::Error::other)?;
+        rows.insert(table, count);
+    }
+
+    let dir = canvases_dir(data_dir);
+    let mut by_canvas: HashMap<String, u64> = HashMap::new();
+    let mut event_file_bytes = 0;
+    for (name, bytes) in canvas_files(&dir).await? {
+        event_file_bytes += bytes;
+        *by_canvas.entry(file_prefix(&name).to_string()).or_default() += bytes;
+    }
+    let archive_bytes = canvas_files(&dir.join(ARCHIVE_DIR)).await?.iter().map(|(_, bytes)| bytes).sum();
+
+    let event_counts: HashMap<String, i64> = query!("SELECT canvas_id, event_count FROM Canvas")
+        .fetch_all(pool)
+        .await
+        .map_err(io::Error::other)?
+        .into_iter()
+        .map(|row| (row.canvas_id, row.event_count))
+        .collect();
+    let mut largest_canvases: Vec<CanvasSize> = by_canvas
+        .into_iter()
+        .filter_map(|(canvas_id, bytes)| {
+            let event_count = *event_counts.get(&canvas_id)?;
+            Some(CanvasSize { canvas_id, bytes, event_count })
+        })
+        .collect();
+    largest_canvases.sort_by_key(|canvas| std::cmp::Reverse(canvas.bytes));
+    largest_canvases.truncate(LARGEST_CANVASES);
+
+    Ok(InstanceStats { rows, event_file_bytes, archive_bytes, largest_canvases })
+}
+
+/// Runs `maintenance <command> [options]` and prints the result as JSON.
+/// Call only while holding the `DataDirLock`, i.e. while no server uses the data directory.
+pub async fn run(manager: &CanvasManager, pool: &SqlitePool, data_dir: &Path, args: &[String]) -> Result<(), String> {
+    let options = args.get(1..).unwrap_or_default();
+    let flag = |name: &str| options.iter().any(|arg| arg == name);
+    let canvas_id = options.iter().find(|arg| !arg.starts_with("--"));
+
+    let output = match (args.first().map(String::as_str), canvas_id) {
+        (Some("compact-canvas"), Some(canvas_id)) => {
+            let canvas_id = parse_canvas_id(canvas_id)?;
+            let stats: CompactionStats = manager.compact(pool, &canvas_id).await.map_err(describe)?;
+            serde_json::to_value(stats)
+        }
+        (Some("verify"), _) if flag("--all") => {
+            let results = verify_all(manager, pool, flag("--quarantine")).await.map_err(|e| e.to_string())?;
+            serde_json::to_value(results)
+        }
+        (Some("verify"), Some(canvas_id)) => {
+            let canvas_id = parse_canvas_id(canvas_id)?;
+            let report = manager.verify(pool, &canvas_id, flag("--quarantine")).await.map_err(describe)?;
+            serde_json::to_value(report)
+        }
+        (Some("orphans"), _) => {
+            let report = orphans(pool, data_dir, flag("--relocate")).await.map_err(|e| e.to_string())?;
+            serde_json::to_value(report)
+        }
+        (Some("stats"), _) => serde_json::to_value(stats(pool, data_dir).await.map_err(|e| e.to_string())?),
+        _ => return Err(USAGE.to_string()),
+    };
+    println!("{}", serde_json::to_string_pretty(&output.map_err(|e| e.to_string())?).unwrap_or_default());
+    Ok(())
+}
+
+fn parse_canvas_id(arg: &str) -> Result<CanvasId, String> {
+    arg.parse().map_err(|_| format!("'{}' is not a canvas id, expected a UUID.", arg))
+}
+
+fn describe(error: AppendEventsError) -> String {
+    match error {
+        AppendEventsError::NotFound => "There is no such canvas.".to_string(),
+        AppendEventsError::Storage(e) => format!("Storage error: {}", e),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The part of a file name before the first `.`, the canvas id for files named after a canvas.
+fn file_prefix(name: &str) -> &str {
+    name.split_once('.').map_or(name, |(prefix, _)| prefix)
+}
+
+/// Names and sizes of the files directly in a directory; none if it doesn't exist.
+/// Temporary files of running writes are left out.
+async fn canvas_files(dir: &Path) -> io::Result<Vec<(String, u64)>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if metadata.is_file() && !name.ends_with(".tmp") {
+            files.push((name, metadata.len()));
+        }
+    }
+    Ok(files)
+}
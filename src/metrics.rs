@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{canvas_id::CanvasId, websocket_handlers::ClientMessage};
+
+/// Upper bounds of the latency buckets, in microseconds.
+const LATENCY_BUCKETS_MICROS: &[u64] = &[
+    250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+];
+
+/// Upper bounds of the append size buckets, in bytes.
+const BYTES_BUCKETS: &[u64] = &[256, 1_024, 4_096, 16_384, 65_536, 262_144, 1_048_576];
+
+/// Upper bounds of the events per message buckets.
+const EVENT_COUNT_BUCKETS: &[u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500];
+
+/// A Prometheus histogram over integer samples with fixed buckets.
+#[derive(Debug)]
+struct Histogram {
+    /// Samples per bucket, not cumulative. The last bucket counts samples above every bound.
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &[u64]) -> Self {
+        Self {
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, bounds: &[u64], value: u64) {
+        let bucket = bounds.iter().position(|&bound| value <= bound).unwrap_or(bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+/// A histogram recorded per canvas, or once for all canvases in low-cardinality mode.
+#[derive(Debug)]
+pub struct CanvasHistogram {
+    bounds: &'static [u64],
+    /// Samples are divided by this when rendered, e.g. to report microseconds as seconds.
+    unit: f64,
+    total: Histogram,
+    /// `None` in low-cardinality mode.
+    per_canvas: Option<Mutex<HashMap<CanvasId, Histogram>>>,
+}
+
+impl CanvasHistogram {
+    fn new(bounds: &'static [u64], unit: f64, per_canvas: bool) -> Self {
+        Self {
+            bounds,
+            unit,
+            total: Histogram::new(bounds),
+            per_canvas: per_canvas.then(|| Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn observe(&self, canvas_uuid: &CanvasId, value: u64) {
+        match &self.per_canvas {
+            None => self.total.observe(self.bounds, value),
+            Some(per_canvas) => per_canvas
+                .lock()
+                .unwrap()
+                .entry(*canvas_uuid)
+                .or_insert_with(|| Histogram::new(self.bounds))
+                .observe(self.bounds, value),
+        }
+    }
+
+    pub fn observe_duration(&self, canvas_uuid: &CanvasId, duration: Duration) {
+        self.observe(canvas_uuid, duration.as_micros() as u64);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        match &self.per_canvas {
+            None => self.render_samples(out, name, "", &self.total),
+            Some(per_canvas) => {
+                for (canvas_uuid, histogram) in per_canvas.lock().unwrap().iter() {
+                    self.render_samples(out, name, &format!("canvas=\"{}\",", canvas_uuid), histogram);
+                }
+            }
+        }
+    }
+
+    /// `labels` is empty or a list of labels ending in a comma.
+    fn render_samples(&self, out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+        let mut cumulative = 0;
+        for (index, bucket) in histogram.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(index) {
+                Some(&bound) => (bound as f64 / self.unit).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let sum = histogram.sum.load(Ordering::Relaxed) as f64 / self.unit;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count.load(Ordering::Relaxed));
+    }
+}
+
+/// Latency and size distributions of event persistence and fan-out.
+#[derive(Debug)]
+pub struct LatencyMetrics {
+    /// Time the writer task spends appending a batch to the store, including the sync.
+    pub append_duration: CanvasHistogram,
+    pub append_bytes: CanvasHistogram,
+    pub events_per_message: CanvasHistogram,
+    /// Time from publishing a message to handing it to a subscriber's queue.
+    pub broadcast_duration: CanvasHistogram,
+    /// Time to send the registration state of a canvas, history included.
+    pub history_duration: CanvasHistogram,
+}
+
+impl LatencyMetrics {
+    fn new(per_canvas: bool) -> Self {
+        Self {
+            append_duration: CanvasHistogram::new(LATENCY_BUCKETS_MICROS, 1e6, per_canvas),
+            append_bytes: CanvasHistogram::new(BYTES_BUCKETS, 1.0, per_canvas),
+            events_per_message: CanvasHistogram::new(EVENT_COUNT_BUCKETS, 1.0, per_canvas),
+            broadcast_duration: CanvasHistogram::new(LATENCY_BUCKETS_MICROS, 1e6, per_canvas),
+            history_duration: CanvasHistogram::new(LATENCY_BUCKETS_MICROS, 1e6, per_canvas),
+        }
+    }
+}
+
+impl Default for LatencyMetrics {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+/// Counters for WebSocket connections and message throughput.
+/// All counters use relaxed atomics, so updating them on the hot path is cheap.
+#[derive(Debug, Default)]
+pub struct WsMetrics {
+    pub connections_opened: AtomicU64,
+    pub connections_closed: AtomicU64,
+    pub messages_events: AtomicU64,
+    pub messages_cursor: AtomicU64,
+    pub messages_chat: AtomicU64,
+    pub messages_command: AtomicU64,
+    pub messages_invalid: AtomicU64,
+    pub events_persisted: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub broadcast_messages: AtomicU64,
+    pub broadcast_failures: AtomicU64,
+    /// Registrations served by a canvas that was still loaded, e.g. idle within its TTL.
+    pub canvas_loads_memory: AtomicU64,
+    /// Canvases loaded from the DB and the event store.
+    pub canvas_loads_store: AtomicU64,
+    /// Bytes of event logs cached in memory over all loaded canvases. A gauge, not a counter.
+    pub event_cache_bytes: AtomicU64,
+    /// Cached event logs dropped to stay within the total cache budget.
+    pub event_cache_evictions: AtomicU64,
+    pub latency: LatencyMetrics,
+}
+
+/// A point-in-time copy of the counters, returned by the JSON admin endpoint.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WsMetricsSnapshot {
+    pub connections_open: u64,
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub messages_events: u64,
+    pub messages_cursor: u64,
+    pub messages_chat: u64,
+    pub messages_command: u64,
+    pub messages_invalid: u64,
+    pub events_persisted: u64,
+    pub bytes_written: u64,
+    pub broadcast_messages: u64,
+    pub broadcast_failures: u64,
+    pub canvas_loads_memory: u64,
+    pub canvas_loads_store: u64,
+    pub event_cache_bytes: u64,
+    pub event_cache_evictions: u64,
+}
+
+impl WsMetrics {
+    /// With `per_canvas`, the latency histograms are labeled by canvas; keep it off on instances
+    /// with many canvases.
+    pub fn new(per_canvas: bool) -> Self {
+        Self {
+            latency: LatencyMetrics::new(per_canvas),
+            ..Self::default()
+        }
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn sub(gauge: &AtomicU64, value: u64) {
+        gauge.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    /// Counts an incoming message by its type.
+    pub fn record_message(&self, message: &ClientMessage) {
+        let counter = match message {
+            ClientMessage::Events(_) => &self.messages_events,
+            ClientMessage::Cursor(_) => &self.messages_cursor,
+            ClientMessage::Chat(_) => &self.messages_chat,
+            _ => &self.messages_command,
+        };
+        Self::inc(counter);
+    }
+
+    pub fn snapshot(&self) -> WsMetricsSnapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let opened = get(&self.connections_opened);
+        let closed = get(&self.connections_closed);
+
+        WsMetricsSnapshot {
+            connections_open: opened.saturating_sub(closed),
+            connections_opened: opened,
+            connections_closed: closed,
+            messages_events: get(&self.messages_events),
+            messages_cursor: get(&self.messages_cursor),
+            messages_chat: get(&self.messages_chat),
+            messages_command: get(&self.messages_command),
+            messages_invalid: get(&self.messages_invalid),
+            events_persisted: get(&self.events_persisted),
+            bytes_written: get(&self.bytes_written),
+            broadcast_messages: get(&self.broadcast_messages),
+            broadcast_failures: get(&self.broadcast_failures),
+            canvas_loads_memory: get(&self.canvas_loads_memory),
+            canvas_loads_store: get(&self.canvas_loads_store),
+            event_cache_bytes: get(&self.event_cache_bytes),
+            event_cache_evictions: get(&self.event_cache_evictions),
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let s = self.snapshot();
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric("ws_connections_open", "gauge", "Currently open WebSocket connections.", &[("", s.connections_open)]);
+        metric("ws_connections_opened_total", "counter", "WebSocket connections opened.", &[("", s.connections_opened)]);
+        metric("ws_connections_closed_total", "counter", "WebSocket connections closed.", &[("", s.connections_closed)]);
+        metric(
+            "ws_messages_received_total",
+            "counter",
+            "WebSocket messages received by type.",
+            &[
+                ("{type=\"events\"}", s.messages_events),
+                ("{type=\"cursor\"}", s.messages_cursor),
+                ("{type=\"chat\"}", s.messages_chat),
+                ("{type=\"command\"}", s.messages_command),
+                ("{type=\"invalid\"}", s.messages_invalid),
+            ],
+        );
+        metric("ws_events_persisted_total", "counter", "Drawing events written to event logs.", &[("", s.events_persisted)]);
+        metric("ws_event_bytes_written_total", "counter", "Bytes written to event logs.", &[("", s.bytes_written)]);
+        metric("ws_broadcast_messages_total", "counter", "Messages fanned out to subscribers.", &[("", s.broadcast_messages)]);
+        metric("ws_broadcast_failures_total", "counter", "Broadcast messages that could not be delivered.", &[("", s.broadcast_failures)]);
+        metric(
+            "canvas_loads_total",
+            "counter",
+            "Canvas registrations served from memory vs. canvases loaded from storage.",
+            &[
+                ("{source=\"memory\"}", s.canvas_loads_memory),
+                ("{source=\"store\"}", s.canvas_loads_store),
+            ],
+        );
+        metric("event_cache_bytes", "gauge", "Bytes of event logs cached in memory.", &[("", s.event_cache_bytes)]);
+        metric(
+            "event_cache_evictions_total",
+            "counter",
+            "Cached event logs dropped to stay within the total cache budget.",
+            &[("", s.event_cache_evictions)],
+        );
+
+        let latency = &self.latency;
+        latency.append_duration.render(
+            &mut out,
+            "event_append_duration_seconds",
+            "Time to append a batch of events to the store, including the sync.",
+        );
+        latency.append_bytes.render(&mut out, "event_append_bytes", "Bytes per append to the store.");
+        latency.events_per_message.render(&mut out, "events_per_message", "Drawing events per received message.");
+        latency.broadcast_duration.render(
+            &mut out,
+            "broadcast_duration_seconds",
+            "Time from publishing a message to queueing it for a subscriber.",
+        );
+        latency.history_duration.render(
+            &mut out,
+            "history_send_duration_seconds",
+            "Time to send the history and registration state of a canvas.",
+        );
+
+        out
+    }
+}
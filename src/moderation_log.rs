@@ -0,0 +1,145 @@
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{query, SqlitePool};
+
+use crate::{canvas_id::CanvasId, event_log::timestamp_ms};
+
+/// Default and maximum number of entries per page of the moderation log.
+pub const DEFAULT_MODERATION_LOG_PAGE: i64 = 50;
+pub const MAX_MODERATION_LOG_PAGE: i64 = 200;
+
+/// A moderation action on a canvas. New moderation features add their action here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    ModerationOn,
+    ModerationOff,
+    Mute,
+    Unmute,
+    Ban,
+    Unban,
+    /// Only recorded in the canvas log, the moderation log records it as part of the ban.
+    Kick,
+    ApproveHeldEvents,
+    RejectHeldEvents,
+    Clear,
+    Announce,
+    ClearAnnouncement,
+    ResolveReport,
+    DeleteEvents,
+    SlowMode,
+}
+
+impl ModerationAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModerationAction::ModerationOn => "moderation_on",
+            ModerationAction::ModerationOff => "moderation_off",
+            ModerationAction::Mute => "mute",
+            ModerationAction::Unmute => "unmute",
+            ModerationAction::Ban => "ban",
+            ModerationAction::Unban => "unban",
+            ModerationAction::Kick => "kick",
+            ModerationAction::ApproveHeldEvents => "approve_held_events",
+            ModerationAction::RejectHeldEvents => "reject_held_events",
+            ModerationAction::Clear => "clear",
+            ModerationAction::Announce => "announce",
+            ModerationAction::ClearAnnouncement => "clear_announcement",
+            ModerationAction::ResolveReport => "resolve_report",
+            ModerationAction::DeleteEvents => "delete_events",
+            ModerationAction::SlowMode => "slow_mode",
+        }
+    }
+}
+
+/// An entry of the moderation log as listed for moderators.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModerationLogEntry {
+    pub log_id: i64,
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target_id: Option<i64>,
+    pub target_name: Option<String>,
+    pub details: Value,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// Appends an entry to the moderation log of a canvas. `actor_id` is `None` for actions of the server itself,
+/// `target_id` names the user acted on, if any. A failed insert is only logged, the action has happened either way.
+pub async fn record(
+    pool: &SqlitePool,
+    canvas_id: &CanvasId,
+    actor_id: Option<i64>,
+    action: ModerationAction,
+    target_id: Option<i64>,
+    details: &Value,
+) {
+    let action = action.as_str();
+    let details = details.to_string();
+    let created_at = timestamp_ms() as i64;
+    if let Err(e) = query!(
+        "INSERT INTO moderation_log (canvas_id, actor_id, action, target_id, details, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        canvas_id,
+        actor_id,
+        action,
+        target_id,
+        details,
+        created_at
+    )
+    .execute(pool)
+    .await
+    {
+        tracing::error!("Failed to record {} on canvas {} in the moderation log: {}", action, canvas_id, e);
+    }
+}
+
+/// A page of the moderation log of a canvas, newest first. Pass the smallest `log_id` of a page
+/// as `before` to get the next one.
+pub async fn list(
+    pool: &SqlitePool,
+    canvas_id: &CanvasId,
+    before: Option<i64>,
+    limit: i64,
+) -> Result<Vec<ModerationLogEntry>, sqlx::Error> {
+    let before = before.unwrap_or(i64::MAX);
+    let rows = query!(
+        r#"
+        SELECT
+            L.log_id AS "log_id!",
+            L.actor_id,
+            A.display_name AS "actor_name?",
+            L.action,
+            L.target_id,
+            T.display_name AS "target_name?",
+            L.details,
+            L.created_at
+        FROM moderation_log AS L
+        LEFT JOIN users AS A ON A.user_id = L.actor_id
+        LEFT JOIN users AS T ON T.user_id = L.target_id
+        WHERE L.canvas_id = ? AND L.log_id < ?
+        ORDER BY L.log_id DESC
+        LIMIT ?
+        "#,
+        canvas_id,
+        before,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ModerationLogEntry {
+            log_id: row.log_id,
+            actor_id: row.actor_id,
+            actor_name: row.actor_name,
+            action: row.action,
+            target_id: row.target_id,
+            target_name: row.target_name,
+            details: serde_json::from_str(&row.details).unwrap_or_default(),
+            created_at: row.created_at,
+        })
+        .collect())
+}
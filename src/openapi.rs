@@ -0,0 +1,119 @@
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{admin_handlers, handlers, sse_handlers, websocket_handlers};
+
+pub use crate::error::ErrorResponse;
+
+/// The body of responses that only confirm an action.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
+/// The specification of the HTTP API, generated from the `#[utoipa::path]` attributes of the handlers.
+/// New routes have to be listed in `paths` as well.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Drawing app API",
+        description = "Routes marked with `auth_token` need the cookie set by `/api/login` or `/api/register`. \
+            Canvas permission levels: O (owner), C (co-owner), M (moderator), W (writer), V (voice), R (reader)."
+    ),
+    paths(
+        handlers::login,
+        handlers::logout,
+        handlers::register,
+        handlers::get_version,
+        handlers::healthz,
+        handlers::readyz,
+        handlers::get_user_info,
+        handlers::update_profile,
+        handlers::create_canvas,
+        handlers::get_canvas_list,
+        handlers::get_canvas,
+        handlers::update_canvas_permissions,
+        handlers::get_canvas_permissions,
+        handlers::get_online_users,
+        handlers::update_canvas_settings,
+        handlers::append_canvas_events,
+        handlers::get_canvas_events,
+        handlers::compact_canvas,
+        handlers::clear_canvas,
+        handlers::get_held_events,
+        handlers::approve_held_events,
+        handlers::reject_held_events,
+        handlers::get_moderation_log,
+        handlers::mute_user,
+        handlers::unmute_user,
+        handlers::toggle_canvas_moderated,
+        handlers::set_slow_mode,
+        handlers::report_canvas,
+        handlers::get_canvas_reports,
+        handlers::resolve_canvas_report,
+        handlers::ban_user,
+        handlers::unban_user,
+        handlers::get_canvas_archives,
+        handlers::restore_canvas_archive,
+        handlers::delete_canvas,
+        sse_handlers::stream_canvas,
+        websocket_handlers::ws_handler,
+        admin_handlers::get_ws_metrics,
+        admin_handlers::get_prometheus_metrics,
+        admin_handlers::force_disconnect_user,
+        admin_handlers::create_backup,
+        admin_handlers::list_backups,
+        admin_handlers::list_reports,
+        admin_handlers::resolve_any_report,
+        admin_handlers::verify_canvas,
+        admin_handlers::compress_canvas,
+        admin_handlers::flush_cache,
+        admin_handlers::reload_content_filter,
+        admin_handlers::get_store_sync_status,
+        admin_handlers::find_orphans,
+        admin_handlers::get_instance_stats,
+        get_openapi,
+    ),
+    modifiers(&CookieAuth),
+    tags(
+        (name = "auth", description = "Login, logout and registration"),
+        (name = "users", description = "The logged in user"),
+        (name = "canvases", description = "Creating, listing and managing canvases"),
+        (name = "permissions", description = "Who may do what on a canvas"),
+        (name = "events", description = "Drawing events over REST, SSE and WebSocket"),
+        (name = "moderation", description = "Held events, mutes, bans and slow mode"),
+        (name = "reports", description = "Reported canvases and events"),
+        (name = "maintenance", description = "Compaction and archives of a canvas' log"),
+        (name = "admin", description = "Instance admins only"),
+        (name = "meta", description = "Version, health and metrics"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the `auth_token` cookie as the security scheme the protected routes refer to.
+struct CookieAuth;
+
+impl Modify for CookieAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("auth_token", SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("auth_token"))));
+    }
+}
+
+// The handler for the GET /api/openapi.json route.
+#[utoipa::path(
+    get,
+    path = "/api/openapi.json",
+    tag = "meta",
+    responses(
+        (status = 200, description = "This document", content_type = "application/json"),
+    ),
+)]
+pub async fn get_openapi() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
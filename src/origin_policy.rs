@@ -0,0 +1,84 @@
+use axum::http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{idempotency::IDEMPOTENCY_KEY_HEADER, request_id::REQUEST_ID_HEADER};
+
+/// Which origins may open WebSocket connections and call the API from another origin (CORS).
+/// Without an allowlist only same-origin upgrades (Origin matches Host) are accepted and no CORS headers are sent.
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicy {
+    /// Allowed origins, e.g. `https://draw.example.com`, normalized to lowercase without a trailing slash.
+    pub allowed_origins: Vec<String>,
+    /// Whether upgrades without an Origin header (native clients) are accepted.
+    pub allow_missing_origin: bool,
+}
+
+impl OriginPolicy {
+    /// The policy for the given origins (normalized, empty ones skipped). Fails on an origin
+    /// with a wildcard: the auth cookie is sent with credentials, which browsers never allow for `*`.
+    pub fn new<'a>(origins: impl IntoIterator<Item = &'a str>, allow_missing_origin: bool) -> Result<Self, String> {
+        let mut allowed_origins = Vec::new();
+        for origin in origins.into_iter().map(normalize_origin).filter(|origin| !origin.is_empty()) {
+            if origin.contains('*') {
+                return Err(format!("'{}': wildcards are not supported", origin));
+            }
+            allowed_origins.push(origin);
+        }
+        allowed_origins.dedup();
+        Ok(Self { allowed_origins, allow_missing_origin })
+    }
+
+    /// Checks the Origin header of an upgrade request.
+    /// On failure, returns the offending origin for logging.
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), String> {
+        let Some(origin) = headers.get(header::ORIGIN) else {
+            return if self.allow_missing_origin {
+                Ok(())
+            } else {
+                Err("<missing>".to_string())
+            };
+        };
+
+        let origin = origin.to_str().map(normalize_origin).map_err(|_| "<invalid>".to_string())?;
+
+        let allowed = if self.allowed_origins.is_empty() {
+            // Same-origin: the origin's authority has to match the Host header
+            let host = headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(|host| host.to_ascii_lowercase());
+            let authority = origin.split_once("://").map(|(_, authority)| authority);
+            host.is_some() && authority == host.as_deref()
+        } else {
+            self.allowed_origins.contains(&origin)
+        };
+
+        if allowed { Ok(()) } else { Err(origin) }
+    }
+
+    /// CORS for the API, with credentials so the auth cookie is sent along.
+    /// `None` without an allowlist, the API is then same-origin only.
+    pub fn cors_layer(&self) -> Option<CorsLayer> {
+        if self.allowed_origins.is_empty() {
+            return None;
+        }
+        let allowed_origins = self.allowed_origins.clone();
+        let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
+        Some(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _: &Parts| {
+                    origin
+                        .to_str()
+                        .is_ok_and(|origin| allowed_origins.contains(&normalize_origin(origin)))
+                }))
+                .allow_credentials(true)
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([header::CONTENT_TYPE, request_id.clone(), HeaderName::from_static(IDEMPOTENCY_KEY_HEADER)])
+                .expose_headers([request_id, header::RETRY_AFTER]),
+        )
+    }
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
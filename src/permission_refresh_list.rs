@@ -5,7 +5,6 @@ use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::auth::REISSUE_AFTER_SECONDS;
 
 
 // As far as I can tell, there is no way to implement timely permission updates in users' JWTs without accessing server-side state on each user request.
@@ -74,8 +73,7 @@ fn current_timestamp() -> usize {
         .as_secs() as usize
 }
 
-pub async fn start_cleanup_task(refresh_list: Arc<PermissionRefreshList>) {
-    let reissue_time: usize = REISSUE_AFTER_SECONDS;
+pub async fn start_cleanup_task(refresh_list: Arc<PermissionRefreshList>, reissue_time: usize) {
     let prune_age = reissue_time * 2;
     let interval = Duration::from_secs(reissue_time as u64);
 
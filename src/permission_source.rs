@@ -0,0 +1,99 @@
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{
+    canvas_id::CanvasId, identifiable_web_socket::IdentifiableWebSocket, socket_claims_manager::SocketClaimsManager,
+};
+
+/// Where the canvas manager looks up the users it serves: what they may do on a canvas,
+/// their names and their open connections.
+pub trait PermissionSource: Send + Sync {
+    /// The permission level of a user on a canvas ("R", "W", "V", "M", "O" or "C"),
+    /// or an empty string if the user has none.
+    fn permission_level<'a>(&'a self, user_id: i64, canvas_id: &'a CanvasId) -> BoxFuture<'a, String>;
+
+    /// The display name of a connected user, `None` if the user has no open connection.
+    fn display_name(&self, user_id: i64) -> BoxFuture<'_, Option<String>>;
+
+    /// The open connections of a user.
+    fn connections(&self, user_id: i64) -> BoxFuture<'_, Vec<IdentifiableWebSocket>>;
+
+    /// Forgets a connection found dead, e.g. while broadcasting.
+    fn remove_connection<'a>(&'a self, user_id: i64, connection: &'a IdentifiableWebSocket) -> BoxFuture<'a, ()>;
+}
+
+/// Permissions from the claims of the users' open connections.
+/// They are refreshed whenever a user's permissions change (see `PermissionRefreshList`).
+impl PermissionSource for SocketClaimsManager {
+    fn permission_level<'a>(&'a self, user_id: i64, canvas_id: &'a CanvasId) -> BoxFuture<'a, String> {
+        self.get_permission_level(user_id, canvas_id).boxed()
+    }
+
+    fn display_name(&self, user_id: i64) -> BoxFuture<'_, Option<String>> {
+        self.get_display_name(user_id).boxed()
+    }
+
+    fn connections(&self, user_id: i64) -> BoxFuture<'_, Vec<IdentifiableWebSocket>> {
+        self.get_connections(user_id).boxed()
+    }
+
+    fn remove_connection<'a>(&'a self, user_id: i64, connection: &'a IdentifiableWebSocket) -> BoxFuture<'a, ()> {
+        SocketClaimsManager::remove_connection(self, user_id, connection).map(|_| ()).boxed()
+    }
+}
+
+/// Users and their permissions kept in memory, for tests of the canvas manager.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryPermissions {
+    users: std::sync::Mutex<std::collections::HashMap<i64, MemoryUser>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryUser {
+    display_name: String,
+    permissions: std::collections::HashMap<CanvasId, String>,
+    connections: Vec<IdentifiableWebSocket>,
+}
+
+#[cfg(test)]
+impl MemoryPermissions {
+    /// Gives a user a permission level on a canvas, replacing the previous one.
+    pub fn grant(&self, user_id: i64, canvas_id: CanvasId, permission: &str) {
+        let mut users = self.users.lock().unwrap();
+        let user = users.entry(user_id).or_default();
+        user.display_name = format!("user {}", user_id);
+        user.permissions.insert(canvas_id, permission.to_string());
+    }
+
+    /// Adds an open connection of a user.
+    pub fn connect(&self, user_id: i64, connection: IdentifiableWebSocket) {
+        self.users.lock().unwrap().entry(user_id).or_default().connections.push(connection);
+    }
+}
+
+#[cfg(test)]
+impl PermissionSource for MemoryPermissions {
+    fn permission_level<'a>(&'a self, user_id: i64, canvas_id: &'a CanvasId) -> BoxFuture<'a, String> {
+        let users = self.users.lock().unwrap();
+        let level = users.get(&user_id).and_then(|user| user.permissions.get(canvas_id)).cloned();
+        futures::future::ready(level.unwrap_or_default()).boxed()
+    }
+
+    fn display_name(&self, user_id: i64) -> BoxFuture<'_, Option<String>> {
+        let name = self.users.lock().unwrap().get(&user_id).map(|user| user.display_name.clone());
+        futures::future::ready(name).boxed()
+    }
+
+    fn connections(&self, user_id: i64) -> BoxFuture<'_, Vec<IdentifiableWebSocket>> {
+        let connections = self.users.lock().unwrap().get(&user_id).map(|user| user.connections.clone());
+        futures::future::ready(connections.unwrap_or_default()).boxed()
+    }
+
+    fn remove_connection<'a>(&'a self, user_id: i64, connection: &'a IdentifiableWebSocket) -> BoxFuture<'a, ()> {
+        if let Some(user) = self.users.lock().unwrap().get_mut(&user_id) {
+            user.connections.retain(|open| open.id != connection.id);
+        }
+        futures::future::ready(()).boxed()
+    }
+}
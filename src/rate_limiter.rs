@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+
+/// A simple token bucket used to limit how often a single connection may do something.
+/// Tokens refill continuously, so a bucket with capacity 30 over one second
+/// allows bursts of up to 30 and a sustained rate of 30 per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket that refills `capacity` tokens every `period`.
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        let capacity = capacity as f64;
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / period.as_secs_f64(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes `amount` tokens if available. Returns false (and takes nothing) otherwise.
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until `amount` tokens will be available.
+    /// Amounts larger than the capacity are reported as a full refill.
+    pub fn time_until_available(&mut self, amount: f64) -> Duration {
+        self.refill();
+        let missing = (amount.min(self.capacity) - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.refill_per_sec)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+// ============================= Per-connection limits =============================
+
+/// Maximum number of cursor messages a single connection may send per second.
+/// Excess messages are dropped silently.
+const CURSOR_MESSAGES_PER_SECOND: u32 = 30;
+
+/// Rate limits applied to every WebSocket connection.
+/// Each value can be overridden through an environment variable, see `AppConfig::from_env`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Length of the window the budgets below refer to.
+    pub window: Duration,
+    /// Event messages (`eventsForCanvas`) and chat messages allowed per window.
+    pub event_messages: u32,
+    /// Bytes of incoming text allowed per window. Also the largest message the budget admits at once.
+    pub payload_bytes: u32,
+    /// Commands (register, unregister, ...) allowed per window.
+    pub commands: u32,
+    /// Number of violations within one window after which the connection is closed.
+    pub max_violations: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            event_messages: 60,
+            // Not below `PayloadLimits::max_message_bytes`, or the largest messages could never pass
+            payload_bytes: 1024 * 1024,
+            commands: 20,
+            max_violations: 10,
+        }
+    }
+}
+
+/// The rate limiting state of a single WebSocket connection.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    event_messages: TokenBucket,
+    payload_bytes: TokenBucket,
+    commands: TokenBucket,
+    cursor: TokenBucket,
+    window: Duration,
+    max_violations: u32,
+    violations: u32,
+    last_violation: Option<Instant>,
+    closed: bool,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            event_messages: TokenBucket::new(config.event_messages, config.window),
+            payload_bytes: TokenBucket::new(config.payload_bytes, config.window),
+            commands: TokenBucket::new(config.commands, config.window),
+            cursor: TokenBucket::new(CURSOR_MESSAGES_PER_SECOND, Duration::from_secs(1)),
+            window: config.window,
+            max_violations: config.max_violations,
+            violations: 0,
+            last_violation: None,
+            closed: false,
+        }
+    }
+
+    /// Charges an incoming message of `bytes` length against the payload budget.
+    /// On failure, returns how long the client should wait before retrying.
+    pub fn check_payload(&mut self, bytes: usize) -> Result<(), Duration> {
+        Self::take(&mut self.payload_bytes, bytes as f64)
+    }
+
+    /// Charges one event message against the event budget.
+    pub fn check_event_message(&mut self) -> Result<(), Duration> {
+        Self::take(&mut self.event_messages, 1.0)
+    }
+
+    /// Charges one command against the (stricter) command budget.
+    pub fn check_command(&mut self) -> Result<(), Duration> {
+        Self::take(&mut self.commands, 1.0)
+    }
+
+    /// Returns true if another cursor update may be relayed.
+    pub fn check_cursor(&mut self) -> bool {
+        self.cursor.try_take(1.0)
+    }
+
+    /// Records a rate limit violation.
+    /// Returns true once the connection has violated its limits too often and should be closed.
+    pub fn record_violation(&mut self) -> bool {
+        let now = Instant::now();
+        if self.last_violation.is_some_and(|last| now.duration_since(last) > self.window) {
+            self.violations = 0;
+        }
+        self.last_violation = Some(now);
+        self.violations += 1;
+        self.closed = self.violations >= self.max_violations;
+        self.closed
+    }
+
+    /// Returns true once the connection exceeded its limits too often and has been closed.
+    pub fn should_close(&self) -> bool {
+        self.closed
+    }
+
+    /// REST users can't be disconnected. Once they exceeded their limits too often,
+    /// their requests are rejected until a window has passed since the last violation.
+    fn check_penalty(&mut self) -> Result<(), Duration> {
+        if !self.closed {
+            return Ok(());
+        }
+        let remaining = self
+            .last_violation
+            .and_then(|last| (last + self.window).checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero());
+        match remaining {
+            Some(remaining) => Err(remaining),
+            None => {
+                self.closed = false;
+                self.violations = 0;
+                Ok(())
+            }
+        }
+    }
+
+    fn take(bucket: &mut TokenBucket, amount: f64) -> Result<(), Duration> {
+        if bucket.try_take(amount) {
+            Ok(())
+        } else {
+            Err(bucket.time_until_available(amount))
+        }
+    }
+}
+
+// ============================= Per-user REST limits =============================
+
+/// Rate limits for the REST endpoints that append and poll events.
+/// Each user gets the same budgets as a single WebSocket connection (see `RateLimitConfig`),
+/// so a client gains nothing by switching transports.
+#[derive(Debug, Clone)]
+pub struct RestRateLimiter {
+    config: RateLimitConfig,
+    users: Arc<Mutex<HashMap<i64, (ConnectionLimits, Instant)>>>,
+}
+
+impl RestRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            users: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Charges one events request of `bytes` length against the user's event and payload budgets.
+    /// On failure, returns how long the client should wait before retrying.
+    pub fn check_events(&self, user_id: i64, bytes: usize) -> Result<(), Duration> {
+        self.with_limits(user_id, |limits| {
+            limits.check_penalty()?;
+            limits.check_payload(bytes)?;
+            limits.check_event_message()
+        })
+    }
+
+    /// Records a request that violated the payload limits. Too many of them within a window
+    /// block the user's event requests for a window.
+    pub fn record_violation(&self, user_id: i64) {
+        let _ = self.with_limits(user_id, |limits| {
+            limits.record_violation();
+            Ok(())
+        });
+    }
+
+    /// Charges one poll against the user's command budget.
+    pub fn check_poll(&self, user_id: i64) -> Result<(), Duration> {
+        self.with_limits(user_id, ConnectionLimits::check_command)
+    }
+
+    fn with_limits(
+        &self,
+        user_id: i64,
+        check: impl FnOnce(&mut ConnectionLimits) -> Result<(), Duration>,
+    ) -> Result<(), Duration> {
+        let mut users = self.users.lock().unwrap();
+        let now = Instant::now();
+
+        // Users idle for a whole window have full buckets again, so their state can go
+        let window = self.config.window;
+        users.retain(|_, (_, last_used)| now.duration_since(*last_used) <= window);
+
+        let (limits, last_used) = users
+            .entry(user_id)
+            .or_insert_with(|| (ConnectionLimits::new(&self.config), now));
+        *last_used = now;
+        check(limits)
+    }
+}
+
+// ============================= Public endpoints =============================
+
+/// Requests per minute to public endpoints like `/api/version`, across all clients.
+pub const PUBLIC_REQUESTS_PER_MINUTE: u32 = 60;
+/// Requests per minute to public endpoints from one client address (see `ClientIp`).
+pub const PUBLIC_REQUESTS_PER_MINUTE_PER_CLIENT: u32 = 20;
+
+/// Token buckets for an endpoint that needs no login, so there is no user to charge. Each client
+/// address gets a budget of its own, so one client can't use up the budget of everyone else, and a
+/// shared bucket keeps a flood of anonymous requests from costing more than a fixed budget.
+#[derive(Debug, Clone)]
+pub struct SharedRateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+    clients: Arc<Mutex<HashMap<IpAddr, (TokenBucket, Instant)>>>,
+    client_capacity: u32,
+    period: Duration,
+}
+
+impl SharedRateLimiter {
+    pub fn new(capacity: u32, client_capacity: u32, period: Duration) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(capacity, period))),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            client_capacity,
+            period,
+        }
+    }
+
+    /// Charges one request of `client`. On failure, returns how long the client should wait before retrying.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        {
+            let mut clients = self.clients.lock().unwrap();
+            let now = Instant::now();
+
+            // Clients idle for a whole period have full buckets again, so their state can go
+            let period = self.period;
+            clients.retain(|_, (_, last_used)| now.duration_since(*last_used) <= period);
+
+            let (bucket, last_used) = clients
+                .entry(client)
+                .or_insert_with(|| (TokenBucket::new(self.client_capacity, period), now));
+            *last_used = now;
+            if !bucket.try_take(1.0) {
+                return Err(bucket.time_until_available(1.0));
+            }
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.try_take(1.0) { Ok(()) } else { Err(bucket.time_until_available(1.0)) }
+    }
+}
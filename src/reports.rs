@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, SqlitePool};
+
+use crate::{canvas_id::CanvasId, event_log::timestamp_ms};
+
+/// Maximum length of the reason given with a report.
+pub const MAX_REPORT_REASON_CHARS: usize = 500;
+
+/// Maximum number of events a single report can name.
+pub const MAX_REPORTED_SEQS: usize = 100;
+
+/// Maximum number of reports returned by a listing.
+const REPORT_LIST_LIMIT: i64 = 200;
+
+/// How a moderator or admin closed a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportResolution {
+    /// Nothing to do about the reported content.
+    Dismissed,
+    /// The reported content was dealt with, e.g. deleted or its author banned.
+    Actioned,
+}
+
+impl ReportResolution {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReportResolution::Dismissed => "dismissed",
+            ReportResolution::Actioned => "actioned",
+        }
+    }
+}
+
+/// A report as listed for owners, co-owners and admins.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub report_id: i64,
+    pub canvas_id: CanvasId,
+    pub reporter_id: i64,
+    pub reporter_name: String,
+    /// The reported events, empty if the whole canvas is reported.
+    pub seqs: Vec<u64>,
+    pub reason: String,
+    /// `open`, `dismissed` or `actioned`.
+    pub status: String,
+    pub resolved_by: Option<i64>,
+    /// Milliseconds since the Unix epoch.
+    pub resolved_at: Option<i64>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// A row of `reports` joined with the reporter's display name.
+struct ReportRow {
+    report_id: i64,
+    canvas_id: CanvasId,
+    reporter_id: i64,
+    reporter_name: Option<String>,
+    seqs: String,
+    reason: String,
+    status: String,
+    resolved_by: Option<i64>,
+    resolved_at: Option<i64>,
+    created_at: i64,
+}
+
+impl From<ReportRow> for Report {
+    fn from(row: ReportRow) -> Self {
+        Report {
+            report_id: row.report_id,
+            canvas_id: row.canvas_id,
+            reporter_id: row.reporter_id,
+            reporter_name: row.reporter_name.unwrap_or_default(),
+            seqs: serde_json::from_str(&row.seqs).unwrap_or_default(),
+            reason: row.reason,
+            status: row.status,
+            resolved_by: row.resolved_by,
+            resolved_at: row.resolved_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// Files a report, or updates the reason of the reporter's open report of the same target.
+/// `seqs` must be sorted and free of duplicates, so the same target is always stored the same way.
+pub async fn file(
+    pool: &SqlitePool,
+    canvas_id: &CanvasId,
+    reporter_id: i64,
+    seqs: &[u64],
+    reason: &str,
+) -> Result<Report, sqlx::Error> {
+    let seqs_json = serde_json::to_string(seqs).unwrap_or_else(|_| "[]".to_string());
+    let created_at = timestamp_ms() as i64;
+    let report_id = query!(
+        r#"INSERT INTO reports (canvas_id, reporter_id, seqs, reason, created_at) VALUES (?, ?, ?, ?, ?)
+           ON CONFLICT (canvas_id, reporter_id, seqs) WHERE status = 'open' DO UPDATE SET reason = excluded.reason
+           RETURNING report_id AS "report_id!""#,
+        canvas_id,
+        reporter_id,
+        seqs_json,
+        reason,
+        created_at
+    )
+    .fetch_one(pool)
+    .await?
+    .report_id;
+
+    get(pool, report_id).await?.ok_or(sqlx::Error::RowNotFound)
+}
+
+/// A single report.
+async fn get(pool: &SqlitePool, report_id: i64) -> Result<Option<Report>, sqlx::Error> {
+    let row = query_as!(
+        ReportRow,
+        r#"SELECT R.report_id AS "report_id!", R.canvas_id AS "canvas_id: CanvasId", R.reporter_id, U.display_name AS "reporter_name?", R.seqs,
+                  R.reason, R.status, R.resolved_by, R.resolved_at, R.created_at
+           FROM reports AS R LEFT JOIN users AS U ON U.user_id = R.reporter_id
+           WHERE R.report_id = ?"#,
+        report_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(Report::from))
+}
+
+/// Lists reports, newest first: of one canvas, or of all canvases if `canvas_id` is `None`.
+/// `open_only` leaves out resolved reports.
+pub async fn list(pool: &SqlitePool, canvas_id: Option<&CanvasId>, open_only: bool) -> Result<Vec<Report>, sqlx::Error> {
+    let rows = query_as!(
+        ReportRow,
+        r#"SELECT R.report_id AS "report_id!", R.canvas_id AS "canvas_id: CanvasId", R.reporter_id, U.display_name AS "reporter_name?", R.seqs,
+                  R.reason, R.status, R.resolved_by, R.resolved_at, R.created_at
+           FROM reports AS R LEFT JOIN users AS U ON U.user_id = R.reporter_id
+           WHERE (?1 IS NULL OR R.canvas_id = ?1) AND (NOT ?2 OR R.status = 'open')
+           ORDER BY R.report_id DESC
+           LIMIT ?3"#,
+        canvas_id,
+        open_only,
+        REPORT_LIST_LIMIT
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(Report::from).collect())
+}
+
+/// Closes an open report, recording who resolved it and how. Restricted to reports of `canvas_id`
+/// if given. Returns `None` if there is no such open report.
+pub async fn resolve(
+    pool: &SqlitePool,
+    report_id: i64,
+    canvas_id: Option<&CanvasId>,
+    resolver_id: i64,
+    resolution: ReportResolution,
+) -> Result<Option<Report>, sqlx::Error> {
+    let status = resolution.as_str();
+    let resolved_at = timestamp_ms() as i64;
+    let updated = query!(
+        "UPDATE reports SET status = ?1, resolved_by = ?2, resolved_at = ?3
+         WHERE report_id = ?4 AND status = 'open' AND (?5 IS NULL OR canvas_id = ?5)",
+        status,
+        resolver_id,
+        resolved_at,
+        report_id,
+        canvas_id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if updated == 0 {
+        return Ok(None);
+    }
+    get(pool, report_id).await
+}
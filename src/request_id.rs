@@ -0,0 +1,34 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Header carrying the id that correlates the log lines of a request.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id taken over from a client; longer ones are replaced by a fresh one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Runs every request in a `request` span carrying its id, so all of its log lines can be correlated.
+/// The id is taken from the `X-Request-Id` header, e.g. set by a proxy, or generated, and echoed in the response.
+pub async fn request_id_middleware(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,109 @@
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::canvas_id::CanvasId;
+
+/// Events a writer drew while the canvas was moderated, held until a moderator approves or rejects them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeldBatch {
+    pub batch_id: String,
+    pub user_id: i64,
+    /// Server time the events were held at, in milliseconds since the Unix epoch.
+    pub held_at: u64,
+    /// The events as stamped by `append_events`, without sequence numbers yet.
+    pub events: Vec<Value>,
+    /// The connection that sent the events, left out when they are broadcast after approval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Uuid>,
+}
+
+/// Persists the held batches of each canvas to `{canvas_id}.pending.jsonl` in the canvases directory,
+/// one batch per line, oldest first. Callers serialize access per canvas (see `CanvasState::review`).
+/// Events deleted by moderators can be kept for audit in `{canvas_id}.deleted.jsonl` next to them.
+#[derive(Debug, Clone)]
+pub struct ReviewQueue {
+    dir: PathBuf,
+}
+
+impl ReviewQueue {
+    pub fn new(canvases_dir: PathBuf) -> Self {
+        Self { dir: canvases_dir }
+    }
+
+    fn path(&self, canvas_id: &CanvasId) -> PathBuf {
+        self.dir.join(format!("{}.pending.jsonl", canvas_id))
+    }
+
+    /// The held batches of a canvas. Lines that can't be parsed are skipped.
+    pub async fn read(&self, canvas_id: &CanvasId) -> io::Result<Vec<HeldBatch>> {
+        let content = match tokio::fs::read_to_string(self.path(canvas_id)).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(batch) => Some(batch),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable held batch of canvas {}: {}", canvas_id, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Appends a batch and syncs it to disk.
+    pub async fn append(&self, canvas_id: &CanvasId, batch: &HeldBatch) -> io::Result<()> {
+        let line = serde_json::to_string(batch).map_err(io::Error::other)?;
+        self.append_line(self.path(canvas_id), line).await
+    }
+
+    /// Appends a record of events deleted by a moderator to the canvas' `.deleted.jsonl` sidecar.
+    pub async fn quarantine(&self, canvas_id: &CanvasId, entry: &Value) -> io::Result<()> {
+        let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+        self.append_line(self.dir.join(format!("{}.deleted.jsonl", canvas_id)), line).await
+    }
+
+    async fn append_line(&self, path: PathBuf, mut line: String) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        line.push('\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await
+    }
+
+    /// Replaces the held batches of a canvas. The file is removed once no batch is left.
+    pub async fn replace(&self, canvas_id: &CanvasId, batches: &[HeldBatch]) -> io::Result<()> {
+        let path = self.path(canvas_id);
+        if batches.is_empty() {
+            return match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        let mut content = String::new();
+        for batch in batches {
+            content.push_str(&serde_json::to_string(batch).map_err(io::Error::other)?);
+            content.push('\n');
+        }
+        let tmp_path = self.dir.join(format!("{}.pending.jsonl.tmp", canvas_id));
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(content.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+}
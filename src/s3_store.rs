@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{
+    blocking::BlockingPool,
+    event_log::{path_with_suffix, timestamp_ms, VerifyReport, GZIP_SUFFIX},
+    event_store::{Durability, EventStore, EventWriter, FileEventStore, LogArchive, RemoteSyncStatus},
+};
+
+/// Default interval of the periodic upload of changed logs.
+const DEFAULT_S3_SYNC_INTERVAL_SECONDS: u64 = 60;
+
+/// Sync state of the logs, by canvas id. Shared with the writers, which mark their log as changed.
+type SyncStates = Arc<Mutex<HashMap<String, RemoteSyncStatus>>>;
+
+/// Keeps the event logs in an S3-compatible bucket, so they survive redeploys of ephemeral containers.
+///
+/// The local event files serve as a write-through cache: appends and reads go to the local file,
+/// which is downloaded on the first access if it is missing. Changed logs are uploaded as a whole
+/// periodically, when their canvas unloads and on shutdown (see `sync`).
+/// Archives stay local, and logs are not compressed, since cold logs live in the bucket anyway.
+pub struct S3EventStore {
+    local: FileEventStore,
+    bucket: Box<Bucket>,
+    /// Prepended to the object keys, e.g. `canvases/`.
+    prefix: String,
+    states: SyncStates,
+    /// Held while a log is downloaded, so concurrent cold loads fetch it once.
+    downloads: AsyncMutex<()>,
+}
+
+/// Settings of the bucket store, read by `AppConfig::from_env` for `CANVAS_STORE=s3`.
+#[derive(Clone)]
+pub struct S3Config {
+    /// `S3_BUCKET`, required.
+    pub bucket: String,
+    /// `S3_ENDPOINT`, for providers other than AWS.
+    pub endpoint: Option<String>,
+    /// `S3_REGION`, default `us-east-1`. Without an endpoint it has to be a known AWS region.
+    pub region: String,
+    /// `S3_ACCESS_KEY_ID` and `S3_SECRET_ACCESS_KEY`. Without them the usual AWS sources are tried.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// `S3_PREFIX`, prepended to the object keys, default `canvases/`.
+    pub prefix: String,
+    /// `S3_PATH_STYLE`, default on, as most self-hosted servers need it.
+    pub path_style: bool,
+    /// `S3_SYNC_INTERVAL_SECS`, default 60.
+    pub sync_interval: Duration,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            bucket: String::new(),
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            access_key_id: None,
+            secret_access_key: None,
+            prefix: "canvases/".to_string(),
+            path_style: true,
+            sync_interval: Duration::from_secs(DEFAULT_S3_SYNC_INTERVAL_SECONDS),
+        }
+    }
+}
+
+// Keeps the secret out of logs
+impl fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("bucket", &self.bucket)
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &self.secret_access_key.as_ref().map(|_| "<redacted>"))
+            .field("prefix", &self.prefix)
+            .field("path_style", &self.path_style)
+            .field("sync_interval", &self.sync_interval)
+            .finish()
+    }
+}
+
+impl S3Config {
+    /// The region of the bucket: a custom one with an endpoint, a known AWS region otherwise.
+    pub fn region(&self) -> Result<Region, String> {
+        match &self.endpoint {
+            Some(endpoint) => Ok(Region::Custom { region: self.region.clone(), endpoint: endpoint.clone() }),
+            None => self
+                .region
+                .parse()
+                .map_err(|_| format!("'{}' is not a known region, set S3_ENDPOINT for other providers", self.region)),
+        }
+    }
+}
+
+/// Creates the bucket store and starts its periodic upload.
+pub fn open(
+    pool: SqlitePool,
+    canvases_dir: PathBuf,
+    config: &S3Config,
+    durability: Durability,
+    blocking: BlockingPool,
+) -> Arc<dyn EventStore> {
+    let region = config.region().expect("AppConfig validates the S3 region");
+    let credentials = Credentials::new(
+        config.access_key_id.as_deref(),
+        config.secret_access_key.as_deref(),
+        None,
+        None,
+        None,
+    )
+    .expect("Invalid S3 credentials");
+    let mut bucket = Bucket::new(&config.bucket, region, credentials).expect("Invalid S3 bucket configuration");
+    if config.path_style {
+        bucket = bucket.with_path_style();
+    }
+
+    tracing::info!("Storing canvas events in bucket {} with local files. Durability: {:?}", config.bucket, durability);
+    let store = Arc::new(S3EventStore {
+        local: FileEventStore::new(pool, canvases_dir, durability, blocking),
+        bucket,
+        prefix: config.prefix.clone(),
+        states: Arc::new(Mutex::new(HashMap::new())),
+        downloads: AsyncMutex::new(()),
+    });
+    tokio::spawn(run_periodic_upload(store.clone(), config.sync_interval));
+    store
+}
+
+/// Uploads the changed logs every `interval`.
+async fn run_periodic_upload(store: Arc<S3EventStore>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = store.sync_all().await {
+            tracing::error!("Failed to upload event logs: {}", e);
+        }
+    }
+}
+
+/// Maps a failed request to an I/O error, keeping "not found" recognizable.
+fn s3_error(e: S3Error) -> io::Error {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => io::Error::new(io::ErrorKind::NotFound, "object not found"),
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+impl S3EventStore {
+    fn key(&self, canvas_id: &str) -> String {
+        format!("{}{}.jsonl", self.prefix, canvas_id)
+    }
+
+    fn mark_dirty(&self, canvas_id: &str) {
+        mark_dirty(&self.states, canvas_id);
+    }
+
+    /// Whether the local log of a canvas exists, plain or compressed.
+    async fn has_local(path: &Path) -> io::Result<bool> {
+        Ok(tokio::fs::try_exists(path).await? || tokio::fs::try_exists(path_with_suffix(path, GZIP_SUFFIX)).await?)
+    }
+
+    /// Downloads the log of a canvas if there is no local copy, e.g. after a redeploy.
+    /// A log that is in neither place stays missing, so reads fail as with local files.
+    async fn ensure_local(&self, canvas_id: &str) -> io::Result<()> {
+        let path = self.local.path(canvas_id).await?;
+        if Self::has_local(&path).await? {
+            return Ok(());
+        }
+
+        let _download = self.downloads.lock().await;
+        if Self::has_local(&path).await? {
+            return Ok(());
+        }
+        let bytes = match self.bucket.get_object(self.key(canvas_id)).await {
+            Ok(response) => response.bytes().to_vec(),
+            Err(e) => {
+                let e = s3_error(e);
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::error!("Failed to download the event log of canvas {}: {}", canvas_id, e);
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = path_with_suffix(&path, ".download.tmp");
+        let write = async {
+            tokio::fs::write(&tmp_path, &bytes).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+        if let Err(e) = write {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        sync_state(&mut self.states.lock().unwrap(), canvas_id);
+        tracing::info!("Downloaded the event log of canvas {} ({} bytes).", canvas_id, bytes.len());
+        Ok(())
+    }
+
+    /// Uploads the local log of a canvas as a whole. An append racing with the upload
+    /// marks the log as changed again, so it is uploaded with the next sync.
+    /// A line torn by such an append is repaired on download, like after a crash.
+    async fn upload(&self, canvas_id: &str) -> io::Result<()> {
+        sync_state(&mut self.states.lock().unwrap(), canvas_id).dirty = false;
+        let result = async {
+            let path = self.local.path(canvas_id).await?;
+            let bytes = tokio::fs::read(&path).await?;
+            self.bucket.put_object(self.key(canvas_id), &bytes).await.map_err(s3_error)?;
+            Ok::<_, io::Error>(bytes.len())
+        }
+        .await;
+
+        let mut states = self.states.lock().unwrap();
+        let state = sync_state(&mut states, canvas_id);
+        match result {
+            Ok(bytes) => {
+                state.last_upload_ms = Some(timestamp_ms());
+                state.last_error = None;
+                tracing::debug!("Uploaded the event log of canvas {} ({} bytes).", canvas_id, bytes);
+                Ok(())
+            }
+            Err(e) => {
+                state.dirty = true;
+                state.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The sync state of a canvas' log, created on first use.
+fn sync_state<'a>(states: &'a mut HashMap<String, RemoteSyncStatus>, canvas_id: &str) -> &'a mut RemoteSyncStatus {
+    states.entry(canvas_id.to_string()).or_insert_with(|| RemoteSyncStatus {
+        canvas_id: canvas_id.to_string(),
+        dirty: false,
+        last_upload_ms: None,
+        last_error: None,
+    })
+}
+
+/// Marks the log of a canvas as changed since its last upload.
+fn mark_dirty(states: &SyncStates, canvas_id: &str) {
+    sync_state(&mut states.lock().unwrap(), canvas_id).dirty = true;
+}
+
+impl EventStore for S3EventStore {
+    fn open_writer<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Box<dyn EventWriter>>> {
+        async move {
+            match self.ensure_local(canvas_id).await {
+                // A new log, created locally by the first append
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+            let writer = self.local.open_writer(canvas_id).await?;
+            Ok(Box::new(S3EventWriter {
+                writer,
+                canvas_id: canvas_id.to_string(),
+                states: self.states.clone(),
+            }) as Box<dyn EventWriter>)
+        }
+        .boxed()
+    }
+
+    fn read_since<'a>(&'a self, canvas_id: &'a str, since_seq: Option<u64>) -> BoxStream<'a, io::Result<Value>> {
+        stream::once(self.ensure_local(canvas_id))
+            .map_ok(move |()| self.local.read_since(canvas_id, since_seq))
+            .try_flatten()
+            .boxed()
+    }
+
+    fn replace_log<'a>(&'a self, canvas_id: &'a str, events: &'a [Value]) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.local.replace_log(canvas_id, events).await?;
+            self.mark_dirty(canvas_id);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list_archives<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<Vec<LogArchive>>> {
+        self.local.list_archives(canvas_id)
+    }
+
+    fn read_archive<'a>(&'a self, canvas_id: &'a str, archive_id: u64) -> BoxFuture<'a, io::Result<Vec<Value>>> {
+        self.local.read_archive(canvas_id, archive_id)
+    }
+
+    fn prune_archives(&self, retention: Duration) -> BoxFuture<'_, io::Result<usize>> {
+        self.local.prune_archives(retention)
+    }
+
+    fn create<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            // The bucket may still have the log
+            match self.ensure_local(canvas_id).await {
+                Ok(()) => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let created = self.local.create(canvas_id).await?;
+            if created {
+                self.mark_dirty(canvas_id);
+            }
+            Ok(created)
+        }
+        .boxed()
+    }
+
+    fn verify<'a>(&'a self, canvas_id: &'a str, quarantine: bool) -> BoxFuture<'a, io::Result<VerifyReport>> {
+        async move {
+            self.ensure_local(canvas_id).await?;
+            let report = self.local.verify(canvas_id, quarantine).await?;
+            if report.quarantined {
+                self.mark_dirty(canvas_id);
+            }
+            Ok(report)
+        }
+        .boxed()
+    }
+
+    fn compress<'a>(&'a self, _canvas_id: &'a str, _idle_for: Duration) -> BoxFuture<'a, io::Result<bool>> {
+        future::ready(Ok(false)).boxed()
+    }
+
+    fn decompress<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        self.local.decompress(canvas_id)
+    }
+
+    fn corrupt_entries(&self, canvas_id: &str) -> u64 {
+        self.local.corrupt_entries(canvas_id)
+    }
+
+    fn sync<'a>(&'a self, canvas_id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let dirty = self.states.lock().unwrap().get(canvas_id).is_some_and(|state| state.dirty);
+            if dirty { self.upload(canvas_id).await } else { Ok(()) }
+        }
+        .boxed()
+    }
+
+    /// Uploads every changed log. Failed uploads are retried with the next sync;
+    /// the first error is returned once all logs were tried.
+    fn sync_all(&self) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            let dirty: Vec<String> = self
+                .states
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|state| state.dirty)
+                .map(|state| state.canvas_id.clone())
+                .collect();
+
+            let mut result = Ok(());
+            for canvas_id in dirty {
+                if let Err(e) = self.upload(&canvas_id).await {
+                    tracing::error!("Failed to upload the event log of canvas {}: {}", canvas_id, e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+            result
+        }
+        .boxed()
+    }
+
+    fn sync_status(&self) -> Vec<RemoteSyncStatus> {
+        let mut states: Vec<RemoteSyncStatus> = self.states.lock().unwrap().values().cloned().collect();
+        states.sort_by(|a, b| a.canvas_id.cmp(&b.canvas_id));
+        states
+    }
+}
+
+/// Appends to the local log and marks it for the next upload.
+struct S3EventWriter {
+    writer: Box<dyn EventWriter>,
+    canvas_id: String,
+    states: SyncStates,
+}
+
+impl EventWriter for S3EventWriter {
+    fn append<'a>(&'a mut self, events: &'a [Value]) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let last_seq = self.writer.append(events).await?;
+            mark_dirty(&self.states, &self.canvas_id);
+            Ok(last_seq)
+        }
+        .boxed()
+    }
+}
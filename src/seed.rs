@@ -0,0 +1,244 @@
+use std::path::Path;
+
+use serde_json::{json, Value};
+use sqlx::{query, SqlitePool};
+use tokio::fs;
+
+use crate::{
+    auth::hash_password,
+    canvas_id::CanvasId,
+    canvas_manager::CanvasManager,
+    event_store::{canvases_dir, event_file_name},
+};
+
+/// Password of every demo user.
+pub const DEMO_PASSWORD: &str = "demo1234";
+
+/// Domain of the demo users' emails, which tells them apart from real users.
+const DEMO_DOMAIN: &str = "@demo.local";
+
+/// (name, display name, instance admin)
+const DEMO_USERS: [(&str, &str, bool); 5] = [
+    ("alice", "Alice (demo admin)", true),
+    ("bob", "Bob", false),
+    ("carol", "Carol", false),
+    ("dave", "Dave", false),
+    ("erin", "Erin", false),
+];
+
+struct DemoCanvas {
+    /// Fixed, so seeding again finds the canvas instead of creating another one.
+    id: &'static str,
+    name: &'static str,
+    moderated: bool,
+    /// (user, permission level); the first entry is the owner.
+    permissions: &'static [(&'static str, &'static str)],
+    strokes: usize,
+}
+
+const DEMO_CANVASES: [DemoCanvas; 4] = [
+    DemoCanvas {
+        id: "00000000-0000-4000-8000-000000000001",
+        name: "Demo: Shared sketch",
+        moderated: false,
+        permissions: &[("alice", "O"), ("bob", "W"), ("carol", "W"), ("dave", "R")],
+        strokes: 40,
+    },
+    DemoCanvas {
+        id: "00000000-0000-4000-8000-000000000002",
+        name: "Demo: Read-only gallery",
+        moderated: false,
+        permissions: &[("bob", "O"), ("alice", "R"), ("carol", "R"), ("dave", "R"), ("erin", "R")],
+        strokes: 25,
+    },
+    DemoCanvas {
+        id: "00000000-0000-4000-8000-000000000003",
+        name: "Demo: Team board",
+        moderated: false,
+        permissions: &[("carol", "O"), ("dave", "C"), ("erin", "W")],
+        strokes: 60,
+    },
+    DemoCanvas {
+        id: "00000000-0000-4000-8000-000000000004",
+        name: "Demo: Moderated classroom",
+        moderated: true,
+        permissions: &[("alice", "O"), ("bob", "M"), ("carol", "V"), ("dave", "W"), ("erin", "W")],
+        strokes: 30,
+    },
+];
+
+/// Events appended per call, well below the payload limits.
+const STROKES_PER_BATCH: usize = 20;
+
+/// Creates demo users (password `DEMO_PASSWORD`), canvases with different permissions and drawn
+/// shapes, and a moderated canvas. Seeding again refreshes the users and permissions and leaves
+/// existing demo canvases and their events alone.
+/// Refuses to touch a database with other users unless `force` is set.
+pub async fn seed(manager: &CanvasManager, pool: &SqlitePool, data_dir: &Path, force: bool) -> Result<(), String> {
+    let demo_pattern = format!("%{}", DEMO_DOMAIN);
+    let real_users = query!("SELECT COUNT(*) as count FROM users WHERE email NOT LIKE ?", demo_pattern)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .count;
+    if real_users > 0 && !force {
+        return Err(format!(
+            "The database has {} users besides the demo users. Pass --force to seed anyway.",
+            real_users
+        ));
+    }
+
+    let password_hash = hash_password(DEMO_PASSWORD).map_err(|e| e.to_string())?;
+    for (name, display_name, is_admin) in DEMO_USERS {
+        let email = demo_email(name);
+        query!(
+            "INSERT INTO users (email, password_hash, display_name, is_admin) VALUES (?, ?, ?, ?)
+             ON CONFLICT(email) DO UPDATE SET password_hash = excluded.password_hash,
+                display_name = excluded.display_name, is_admin = excluded.is_admin",
+            email,
+            password_hash,
+            display_name,
+            is_admin
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| format!("Failed to seed user {}: {}", email, e))?;
+    }
+
+    for canvas in &DEMO_CANVASES {
+        seed_canvas(manager, pool, data_dir, canvas).await?;
+    }
+
+    tracing::info!(
+        "Seeded {} demo users (password '{}') and {} canvases.",
+        DEMO_USERS.len(),
+        DEMO_PASSWORD,
+        DEMO_CANVASES.len()
+    );
+    Ok(())
+}
+
+/// Seeds the demo data into a test database, e.g. one from `app::test_app`.
+#[cfg(test)]
+pub async fn seed_demo(pool: &SqlitePool, data_dir: &Path) {
+    let config = crate::config::AppConfig { data_dir: data_dir.to_path_buf(), ..crate::config::AppConfig::test_default() };
+    let state = crate::app::build_state(std::sync::Arc::new(config), pool.clone()).await;
+    seed(&state.canvas_manager, pool, data_dir, true).await.expect("Failed to seed the demo data");
+    state.canvas_manager.flush_all().await;
+}
+
+async fn seed_canvas(manager: &CanvasManager, pool: &SqlitePool, data_dir: &Path, canvas: &DemoCanvas) -> Result<(), String> {
+    let mut user_ids = Vec::with_capacity(canvas.permissions.len());
+    for (name, permission) in canvas.permissions {
+        let email = demo_email(name);
+        let user_id = query!(r#"SELECT user_id as "user_id!" FROM users WHERE email = ?"#, email)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())?
+            .user_id;
+        user_ids.push((user_id, *permission));
+    }
+    let owner_id = user_ids[0].0;
+
+    let exists = query!("SELECT canvas_id FROM Canvas WHERE canvas_id = ?", canvas.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .is_some();
+    if !exists {
+        // Like `create_canvas`: an empty event file first, then the row
+        let dir = canvases_dir(data_dir);
+        let file_name = event_file_name(canvas.id);
+        fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+        fs::File::create(dir.join(&file_name)).await.map_err(|e| e.to_string())?;
+        query!(
+            "INSERT INTO Canvas (canvas_id, name, owner_user_id, moderated, event_file_path) VALUES (?, ?, ?, ?, ?)",
+            canvas.id,
+            canvas.name,
+            owner_id,
+            canvas.moderated,
+            file_name
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| format!("Failed to seed canvas {}: {}", canvas.name, e))?;
+    }
+
+    for (user_id, permission) in &user_ids {
+        query!(
+            "INSERT OR REPLACE INTO Canvas_Permissions (user_id, canvas_id, permission_level) VALUES (?, ?, ?)",
+            user_id,
+            canvas.id,
+            permission
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    if exists {
+        return Ok(());
+    }
+    let canvas_id: CanvasId = canvas.id.parse().map_err(|e| format!("Invalid demo canvas id {}: {}", canvas.id, e))?;
+    let strokes = demo_strokes(canvas.id, canvas.strokes);
+    for batch in strokes.chunks(STROKES_PER_BATCH) {
+        manager
+            .append_events(pool, "O", owner_id, &canvas_id, Value::Array(batch.to_vec()), None)
+            .await
+            .map_err(|e| format!("Failed to draw on canvas {}: {:?}", canvas.name, e))?;
+    }
+    Ok(())
+}
+
+fn demo_email(name: &str) -> String {
+    format!("{}{}", name, DEMO_DOMAIN)
+}
+
+const COLORS: [&str; 6] = ["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "black"];
+
+/// Lines, circles, rectangles and triangles at pseudo-random positions, the same for every run.
+fn demo_strokes(canvas_id: &str, count: usize) -> Vec<Value> {
+    // A linear congruential generator is random enough for a demo and needs no seed state
+    let mut state = canvas_id.bytes().fold(0x9E37_79B9_7F4A_7C15u64, |hash, b| hash.rotate_left(5) ^ b as u64);
+    let mut next = |max: f64| {
+        state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) as f64 / (1u64 << 31) as f64 * max
+    };
+
+    (0..count)
+        .map(|index| {
+            let id = format!("demo-{}-{}", &canvas_id[canvas_id.len() - 4..], index);
+            let border_color = COLORS[index % COLORS.len()];
+            let shape = match index % 4 {
+                0 => json!({ "id": id, "borderColor": border_color, "from": random_point(&mut next), "to": random_point(&mut next) }),
+                1 => json!({
+                    "id": id,
+                    "borderColor": border_color,
+                    "backgroundColor": null,
+                    "center": random_point(&mut next),
+                    "radius": (10.0 + next(80.0)).round(),
+                }),
+                2 => json!({
+                    "id": id,
+                    "borderColor": border_color,
+                    "backgroundColor": COLORS[(index + 2) % COLORS.len()],
+                    "start": random_point(&mut next),
+                    "end": random_point(&mut next),
+                }),
+                _ => json!({
+                    "id": id,
+                    "borderColor": border_color,
+                    "backgroundColor": null,
+                    "p1": random_point(&mut next),
+                    "p2": random_point(&mut next),
+                    "p3": random_point(&mut next),
+                }),
+            };
+            json!({ "type": "shapeAdded", "shape": shape })
+        })
+        .collect()
+}
+
+fn random_point(next: &mut impl FnMut(f64) -> f64) -> Value {
+    json!({ "x": next(800.0).round(), "y": next(600.0).round() })
+}
@@ -0,0 +1,382 @@
+use axum::extract::ws::Message;
+use serde::Serialize;
+
+use crate::{
+    canvas_manager::{Announcement, OnlineUser, PresenceEntry},
+    chat_store::ChatMessage,
+    reports::Report,
+};
+
+/// Every message the server sends over a WebSocket.
+/// Serialized with a `type` tag, e.g. `{"type": "moderated", "canvasId": "...", "moderated": true}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ServerMessage {
+    /// The event history of a canvas, sent on registration.
+    /// Large histories are split into several messages; `chunk` counts from 0
+    /// and clients start over on chunk 0.
+    History {
+        canvas_id: String,
+        chunk: u32,
+        events_for_canvas: Vec<serde_json::Value>,
+    },
+    /// Sent before the history of a canvas whose log exceeds the history cap:
+    /// the history starts with a compacted snapshot of the log up to `from_seq`
+    /// (deleted events left out), followed by the events after it.
+    HistorySnapshot {
+        canvas_id: String,
+        from_seq: u64,
+    },
+    /// Sent after the history and the rest of the registration state.
+    /// Live messages for the canvas follow from here on.
+    HistoryComplete {
+        canvas_id: String,
+    },
+    /// Sent once events are written: to the connection that sent them, or to the one that approved them
+    /// if they were held. Coalesced events are acknowledged when their batch is written.
+    Ack {
+        canvas_id: String,
+        count: usize,
+    },
+    /// Live drawing events from another client.
+    Events {
+        canvas_id: String,
+        events_for_canvas: Vec<serde_json::Value>,
+    },
+    /// The moderation state of a canvas. `changed_by` names who toggled it and is left out
+    /// when the state is sent on registration. `slow_mode_ms` is the canvas' slow mode interval, 0 if off.
+    Moderated {
+        canvas_id: String,
+        moderated: bool,
+        slow_mode_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        changed_by: Option<PresenceEntry>,
+    },
+    /// The announcement pinned to a canvas, sent to every subscriber when it changes and with the history
+    /// while one is active. `null` when a moderator cleared it.
+    Announcement {
+        canvas_id: String,
+        announcement: Option<Announcement>,
+    },
+    /// Sent to every subscriber when a moderator changes the slow mode interval, 0 if it was turned off.
+    SlowMode {
+        canvas_id: String,
+        slow_mode_ms: u64,
+    },
+    /// Sent to every subscriber when the owner starts or ends presentation mode, and with the history
+    /// while it runs. `presenters` are the users besides the owner who may draw.
+    PresentationMode {
+        canvas_id: String,
+        enabled: bool,
+        presenters: Vec<i64>,
+    },
+    /// Sent to the subscribed moderators, owners and co-owners when someone reports the canvas or its events.
+    ReportFiled {
+        canvas_id: String,
+        report: Report,
+    },
+    /// Confirms a `report` command to its sender.
+    ReportReceived {
+        canvas_id: String,
+        report_id: i64,
+    },
+    /// Sent to a writer whose events were held for review because the canvas is moderated.
+    EventsHeld {
+        canvas_id: String,
+        batch_id: String,
+        count: usize,
+    },
+    /// Sent to moderators: the number of batches held for review changed.
+    PendingEvents {
+        canvas_id: String,
+        count: usize,
+    },
+    /// Sent to the author of held events once a moderator approved or rejected them, or they expired.
+    /// Rejected and expired events come with a reason, if there is one.
+    HeldEventsReviewed {
+        canvas_id: String,
+        batch_id: String,
+        approved: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Sent to every connection of a user a moderator muted on a canvas.
+    /// `until` is in milliseconds since the Unix epoch, `null` until unmuted.
+    Muted {
+        canvas_id: String,
+        until: Option<u64>,
+    },
+    /// Sent to every connection of a user whose mute a moderator lifted.
+    Unmuted {
+        canvas_id: String,
+    },
+    /// Sent to the connections of a user a moderator banned from a canvas, which are removed from it.
+    /// `expires_at` is in milliseconds since the Unix epoch, `null` for a permanent ban.
+    Banned {
+        canvas_id: String,
+        expires_at: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Sent to every connection of a user whose ban a moderator lifted.
+    Unbanned {
+        canvas_id: String,
+    },
+    Permission {
+        canvas_id: String,
+        your_permission: String,
+    },
+    Presence {
+        canvas_id: String,
+        presence: PresenceUpdate,
+    },
+    OnlineUsers {
+        canvas_id: String,
+        online_users: Vec<OnlineUser>,
+    },
+    Cursor {
+        canvas_id: String,
+        cursor: CursorPosition,
+    },
+    Chat {
+        canvas_id: String,
+        chat: ChatMessage,
+    },
+    ChatHistory {
+        canvas_id: String,
+        chat_history: Vec<ChatMessage>,
+    },
+    /// Messages for this canvas were dropped; the client has to re-register.
+    Resync {
+        canvas_id: String,
+    },
+    /// The canvas was removed from the server, e.g. deleted by its owner (`reason` is `deleted`).
+    /// Nothing more is sent for it.
+    CanvasEvicted {
+        canvas_id: String,
+        reason: String,
+    },
+    Notify {
+        notify: String,
+    },
+    /// The session's hard expiry is close; the client should re-login or refresh.
+    AuthExpiring {
+        auth_expiring: AuthExpiring,
+    },
+    Error {
+        code: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<serde_json::Value>,
+    },
+}
+
+/// A change in the set of users present on a canvas.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PresenceUpdate {
+    Joined(PresenceEntry),
+    Left(PresenceEntry),
+    /// The full list of present users, sent on registration.
+    Online(Vec<PresenceEntry>),
+}
+
+/// A cursor position, stamped with the user it belongs to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CursorPosition {
+    pub user_id: i64,
+    pub display_name: String,
+    pub x: f64,
+    pub y: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+}
+
+/// When the claims of a connection hit their hard expiry.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthExpiring {
+    /// Absolute epoch seconds.
+    pub expires_at: usize,
+}
+
+impl ServerMessage {
+    /// Serializes the message into a WebSocket text frame.
+    pub fn to_ws_message(&self) -> Message {
+        let text = serde_json::to_string(self).expect("ServerMessage is always serializable");
+        Message::Text(text.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::canvas_id::CanvasId;
+
+    const CANVAS: &str = "6f9619ff-8b86-d011-b42d-00c04fc964ff";
+
+    fn alice() -> PresenceEntry {
+        PresenceEntry { user_id: 1, display_name: "Alice".to_string() }
+    }
+
+    fn chat_line() -> ChatMessage {
+        ChatMessage { user_id: 1, display_name: "Alice".to_string(), text: "hi".to_string(), timestamp: 1_700_000_000 }
+    }
+
+    /// One message of every variant. The exhaustive match makes a new variant fail to compile until it is added here.
+    fn every_message() -> Vec<ServerMessage> {
+        let canvas_id = CANVAS.to_string();
+        let messages = vec![
+            ServerMessage::History { canvas_id: canvas_id.clone(), chunk: 0, events_for_canvas: vec![json!({ "_seq": 1 })] },
+            ServerMessage::HistorySnapshot { canvas_id: canvas_id.clone(), from_seq: 7 },
+            ServerMessage::HistoryComplete { canvas_id: canvas_id.clone() },
+            ServerMessage::Ack { canvas_id: canvas_id.clone(), count: 2 },
+            ServerMessage::Events { canvas_id: canvas_id.clone(), events_for_canvas: vec![json!({ "_seq": 2 })] },
+            ServerMessage::Moderated { canvas_id: canvas_id.clone(), moderated: true, slow_mode_ms: 0, changed_by: Some(alice()) },
+            ServerMessage::Announcement {
+                canvas_id: canvas_id.clone(),
+                announcement: Some(Announcement {
+                    announcement_id: 3,
+                    text: "Welcome".to_string(),
+                    author_id: 1,
+                    author_name: "Alice".to_string(),
+                    created_at: 1_700_000_000_000,
+                }),
+            },
+            ServerMessage::SlowMode { canvas_id: canvas_id.clone(), slow_mode_ms: 5000 },
+            ServerMessage::PresentationMode { canvas_id: canvas_id.clone(), enabled: true, presenters: vec![2] },
+            ServerMessage::ReportFiled {
+                canvas_id: canvas_id.clone(),
+                report: Report {
+                    report_id: 4,
+                    canvas_id: CANVAS.parse::<CanvasId>().unwrap(),
+                    reporter_id: 2,
+                    reporter_name: "Bob".to_string(),
+                    seqs: vec![1],
+                    reason: "spam".to_string(),
+                    status: "open".to_string(),
+                    resolved_by: None,
+                    resolved_at: None,
+                    created_at: 1_700_000_000_000,
+                },
+            },
+            ServerMessage::ReportReceived { canvas_id: canvas_id.clone(), report_id: 4 },
+            ServerMessage::EventsHeld { canvas_id: canvas_id.clone(), batch_id: "b1".to_string(), count: 3 },
+            ServerMessage::PendingEvents { canvas_id: canvas_id.clone(), count: 1 },
+            ServerMessage::HeldEventsReviewed { canvas_id: canvas_id.clone(), batch_id: "b1".to_string(), approved: false, reason: Some("off topic".to_string()) },
+            ServerMessage::Muted { canvas_id: canvas_id.clone(), until: None },
+            ServerMessage::Unmuted { canvas_id: canvas_id.clone() },
+            ServerMessage::Banned { canvas_id: canvas_id.clone(), expires_at: Some(1_700_000_000_000), reason: None },
+            ServerMessage::Unbanned { canvas_id: canvas_id.clone() },
+            ServerMessage::Permission { canvas_id: canvas_id.clone(), your_permission: "W".to_string() },
+            ServerMessage::Presence { canvas_id: canvas_id.clone(), presence: PresenceUpdate::Joined(alice()) },
+            ServerMessage::OnlineUsers {
+                canvas_id: canvas_id.clone(),
+                online_users: vec![OnlineUser {
+                    user_id: 1,
+                    display_name: "Alice".to_string(),
+                    permission_level: "O".to_string(),
+                    connection_count: Some(2),
+                }],
+            },
+            ServerMessage::Cursor {
+                canvas_id: canvas_id.clone(),
+                cursor: CursorPosition { user_id: 1, display_name: "Alice".to_string(), x: 1.5, y: 2.0, tool: None },
+            },
+            ServerMessage::Chat { canvas_id: canvas_id.clone(), chat: chat_line() },
+            ServerMessage::ChatHistory { canvas_id: canvas_id.clone(), chat_history: vec![chat_line()] },
+            ServerMessage::Resync { canvas_id: canvas_id.clone() },
+            ServerMessage::CanvasEvicted { canvas_id: canvas_id.clone(), reason: "deleted".to_string() },
+            ServerMessage::Notify { notify: "Hello".to_string() },
+            ServerMessage::AuthExpiring { auth_expiring: AuthExpiring { expires_at: 1_700_000_000 } },
+            ServerMessage::Error { code: "muted".to_string(), message: "You are muted.".to_string(), details: None },
+        ];
+        for message in &messages {
+            match message {
+                ServerMessage::History { .. }
+                | ServerMessage::HistorySnapshot { .. }
+                | ServerMessage::HistoryComplete { .. }
+                | ServerMessage::Ack { .. }
+                | ServerMessage::Events { .. }
+                | ServerMessage::Moderated { .. }
+                | ServerMessage::Announcement { .. }
+                | ServerMessage::SlowMode { .. }
+                | ServerMessage::PresentationMode { .. }
+                | ServerMessage::ReportFiled { .. }
+                | ServerMessage::ReportReceived { .. }
+                | ServerMessage::EventsHeld { .. }
+                | ServerMessage::PendingEvents { .. }
+                | ServerMessage::HeldEventsReviewed { .. }
+                | ServerMessage::Muted { .. }
+                | ServerMessage::Unmuted { .. }
+                | ServerMessage::Banned { .. }
+                | ServerMessage::Unbanned { .. }
+                | ServerMessage::Permission { .. }
+                | ServerMessage::Presence { .. }
+                | ServerMessage::OnlineUsers { .. }
+                | ServerMessage::Cursor { .. }
+                | ServerMessage::Chat { .. }
+                | ServerMessage::ChatHistory { .. }
+                | ServerMessage::Resync { .. }
+                | ServerMessage::CanvasEvicted { .. }
+                | ServerMessage::Notify { .. }
+                | ServerMessage::AuthExpiring { .. }
+                | ServerMessage::Error { .. } => {}
+            }
+        }
+        messages
+    }
+
+    /// The wire format of `every_message`, `{c}` standing for the canvas id. Changing one breaks clients.
+    const SNAPSHOTS: [&str; 29] = [
+        r#"{"type":"history","canvasId":"{c}","chunk":0,"eventsForCanvas":[{"_seq":1}]}"#,
+        r#"{"type":"historySnapshot","canvasId":"{c}","fromSeq":7}"#,
+        r#"{"type":"historyComplete","canvasId":"{c}"}"#,
+        r#"{"type":"ack","canvasId":"{c}","count":2}"#,
+        r#"{"type":"events","canvasId":"{c}","eventsForCanvas":[{"_seq":2}]}"#,
+        r#"{"type":"moderated","canvasId":"{c}","moderated":true,"slowModeMs":0,"changedBy":{"userId":1,"displayName":"Alice"}}"#,
+        r#"{"type":"announcement","canvasId":"{c}","announcement":{"announcementId":3,"text":"Welcome","authorId":1,"authorName":"Alice","createdAt":1700000000000}}"#,
+        r#"{"type":"slowMode","canvasId":"{c}","slowModeMs":5000}"#,
+        r#"{"type":"presentationMode","canvasId":"{c}","enabled":true,"presenters":[2]}"#,
+        r#"{"type":"reportFiled","canvasId":"{c}","report":{"reportId":4,"canvasId":"{c}","reporterId":2,"reporterName":"Bob","seqs":[1],"reason":"spam","status":"open","resolvedBy":null,"resolvedAt":null,"createdAt":1700000000000}}"#,
+        r#"{"type":"reportReceived","canvasId":"{c}","reportId":4}"#,
+        r#"{"type":"eventsHeld","canvasId":"{c}","batchId":"b1","count":3}"#,
+        r#"{"type":"pendingEvents","canvasId":"{c}","count":1}"#,
+        r#"{"type":"heldEventsReviewed","canvasId":"{c}","batchId":"b1","approved":false,"reason":"off topic"}"#,
+        r#"{"type":"muted","canvasId":"{c}","until":null}"#,
+        r#"{"type":"unmuted","canvasId":"{c}"}"#,
+        r#"{"type":"banned","canvasId":"{c}","expiresAt":1700000000000,"reason":null}"#,
+        r#"{"type":"unbanned","canvasId":"{c}"}"#,
+        r#"{"type":"permission","canvasId":"{c}","yourPermission":"W"}"#,
+        r#"{"type":"presence","canvasId":"{c}","presence":{"joined":{"userId":1,"displayName":"Alice"}}}"#,
+        r#"{"type":"onlineUsers","canvasId":"{c}","onlineUsers":[{"user_id":1,"display_name":"Alice","permission_level":"O","connection_count":2}]}"#,
+        r#"{"type":"cursor","canvasId":"{c}","cursor":{"userId":1,"displayName":"Alice","x":1.5,"y":2.0}}"#,
+        r#"{"type":"chat","canvasId":"{c}","chat":{"userId":1,"displayName":"Alice","text":"hi","timestamp":1700000000}}"#,
+        r#"{"type":"chatHistory","canvasId":"{c}","chatHistory":[{"userId":1,"displayName":"Alice","text":"hi","timestamp":1700000000}]}"#,
+        r#"{"type":"resync","canvasId":"{c}"}"#,
+        r#"{"type":"canvasEvicted","canvasId":"{c}","reason":"deleted"}"#,
+        r#"{"type":"notify","notify":"Hello"}"#,
+        r#"{"type":"authExpiring","authExpiring":{"expiresAt":1700000000}}"#,
+        r#"{"type":"error","code":"muted","message":"You are muted."}"#,
+    ];
+
+    #[test]
+    fn every_variant_serializes_as_pinned() {
+        let messages = every_message();
+        assert_eq!(messages.len(), SNAPSHOTS.len());
+        for (message, snapshot) in messages.iter().zip(SNAPSHOTS) {
+            assert_eq!(serde_json::to_string(message).unwrap(), snapshot.replace("{c}", CANVAS));
+        }
+    }
+
+    #[test]
+    fn ws_messages_are_the_json_text() {
+        let message = ServerMessage::Ack { canvas_id: CANVAS.to_string(), count: 1 };
+        let Message::Text(text) = message.to_ws_message() else {
+            panic!("not a text frame");
+        };
+        assert_eq!(text.as_str(), serde_json::to_string(&message).unwrap());
+    }
+}
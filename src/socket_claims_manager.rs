@@ -1,11 +1,19 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+use sqlx::SqlitePool;
 use tokio::sync::RwLock;
-use crate::{auth::{get_claims, Claims, PartialClaims}, identifiable_web_socket::IdentifiableWebSocket, AppState};
-use serde_json::json;
-use axum::extract::ws::Message;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+use crate::{auth::{get_claims, Claims, PartialClaims}, canvas_id::CanvasId, identifiable_web_socket::{IdentifiableWebSocket, CLOSE_AUTH_EXPIRED}, server_message::{AuthExpiring, ServerMessage}, AppState};
 
-// A tuple holding the user's claims and a list of their active connections
-pub type ClaimsConnections = (Claims, Vec<IdentifiableWebSocket>);
+/// How often the claims of connected users are checked for hard expiry.
+const CLAIMS_SWEEP_INTERVAL_SECONDS: u64 = 60;
+
+/// Default window before the hard expiry in which connections are warned (10 minutes).
+pub const DEFAULT_AUTH_EXPIRY_WARNING_SECONDS: usize = 10 * 60;
+
+// A tuple holding the user's claims, a list of their active connections
+// and the ids of the connections already warned about the upcoming expiry
+pub type ClaimsConnections = (Claims, Vec<IdentifiableWebSocket>, HashSet<Uuid>);
 
 #[derive(Clone)]
 pub struct SocketClaimsManager {
@@ -26,14 +34,14 @@ impl SocketClaimsManager {
         let mut map = self.inner.write().await;
         
         // Check if the user ID is already in the map.
-        if let Some((_, connections)) = map.get_mut(&user_id) {
+        if let Some((_, connections, _)) = map.get_mut(&user_id) {
             // User exists, so we just add the new connection to their list.
             connections.push(ws);
             tracing::debug!("User {} connected again. Total connections: {}", user_id, connections.len());
         } else {
             // New user, so we insert the claims and the new connection.
             tracing::info!("First connection for user {}.", user_id);
-            map.insert(user_id, (claims, vec![ws]));
+            map.insert(user_id, (claims, vec![ws], HashSet::new()));
         }
     }
 
@@ -41,7 +49,11 @@ impl SocketClaimsManager {
     /// This function will not change the connection count.
     pub async fn update_claims(&self, user_id: i64, updated_claims: Claims) -> bool {
         let mut map = self.inner.write().await;
-        if let Some((existing_claims, _)) = map.get_mut(&user_id) {
+        if let Some((existing_claims, _, expiry_warned)) = map.get_mut(&user_id) {
+            // A new expiry deserves a new warning
+            if existing_claims.exp != updated_claims.exp {
+                expiry_warned.clear();
+            }
             *existing_claims = updated_claims;
             tracing::info!("Claims updated for user {}.", user_id);
             true
@@ -57,7 +69,7 @@ impl SocketClaimsManager {
 
         let mut write_map = self.inner.write().await;
 
-        if let Some((old_claims, connections)) = write_map.get_mut(&user_id) {
+        if let Some((old_claims, connections, expiry_warned)) = write_map.get_mut(&user_id) {
             // Build a partial claims object to force a refresh of permissions.
             let partial_claims = PartialClaims {
                 email: old_claims.email.clone(),
@@ -76,18 +88,21 @@ impl SocketClaimsManager {
             };
             
             // Update the claims in the in-memory map
+            if old_claims.exp != updated_claims.exp {
+                expiry_warned.clear();
+            }
             *old_claims = updated_claims.clone();
             tracing::info!("Claims successfully refreshed for user {}", user_id);
 
             // Send the new permission to all active connections
             for ws in connections.iter() {
                 for (canvas_id, new_permission) in &updated_claims.canvas_permissions {
-                    let message = json!({
-                        "canvasId": canvas_id,
-                        "yourPermission": new_permission,
-                    });
+                    let message = ServerMessage::Permission {
+                        canvas_id: canvas_id.to_string(),
+                        your_permission: new_permission.clone(),
+                    };
                     
-                    if let Err(e) = ws.send(Message::Text(message.to_string().into())).await {
+                    if let Err(e) = ws.send_msg(&message).await {
                         tracing::error!("Failed to send permission update to client {}: {}", ws.id, e);
                     }
                 }
@@ -101,9 +116,10 @@ impl SocketClaimsManager {
     pub async fn remove_connection(&self, user_id: i64, ws_to_remove: &IdentifiableWebSocket) -> bool {
         let mut map = self.inner.write().await;
         
-        if let Some((_, connections)) = map.get_mut(&user_id) {
+        if let Some((_, connections, expiry_warned)) = map.get_mut(&user_id) {
             // Remove the specific WebSocket connection from the vector.
             connections.retain(|ws| ws.id != ws_to_remove.id);
+            expiry_warned.remove(&ws_to_remove.id);
             
             if connections.is_empty() {
                 // Last connection closed, remove the entry
@@ -123,12 +139,12 @@ impl SocketClaimsManager {
 
     /// Retrieves the permission level for a user on a specific canvas.
     /// Returns the permission string or an empty string if not found.
-    pub async fn get_permission_level(&self, user_id: i64, canvas_id: &str) -> String {
+    pub async fn get_permission_level(&self, user_id: i64, canvas_id: &CanvasId) -> String {
         let map = self.inner.read().await;
         
         // Use a chain of option methods to safely get the permission
         map.get(&user_id)
-            .and_then(|(claims, _)| {
+            .and_then(|(claims, _, _)| {
                 claims.canvas_permissions.get(canvas_id)
             })
             .cloned() // Clone the string to return it
@@ -137,4 +153,148 @@ impl SocketClaimsManager {
                 "".to_string()
             })
     }
-}
\ No newline at end of file
+
+    /// Returns all active connections of a user.
+    pub async fn get_connections(&self, user_id: i64) -> Vec<IdentifiableWebSocket> {
+        let map = self.inner.read().await;
+        map.get(&user_id)
+            .map(|(_, connections, _)| connections.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the active connections of all users.
+    pub async fn all_connections(&self) -> Vec<IdentifiableWebSocket> {
+        let map = self.inner.read().await;
+        map.values().flat_map(|(_, connections, _)| connections.iter().cloned()).collect()
+    }
+
+    /// Retrieves the display name stored in a connected user's claims.
+    /// Returns None if the user has no active connection.
+    pub async fn get_display_name(&self, user_id: i64) -> Option<String> {
+        let map = self.inner.read().await;
+        map.get(&user_id).map(|(claims, _, _)| claims.display_name.clone())
+    }
+
+    /// Checks the claims of every connected user against their hard expiry.
+    /// Expired claims are refreshed from the DB, keeping their expiry: a refresh picks up
+    /// changed permissions but never extends the session. Connections whose claims are
+    /// still expired afterwards, or failed to refresh, are told and closed with the auth expired close code.
+    pub async fn sweep_expired_claims(&self, pool: &SqlitePool) {
+        let now = jsonwebtoken::get_current_timestamp() as usize;
+
+        let expired: Vec<Claims> = {
+            let map = self.inner.read().await;
+            map.values()
+                .filter(|(claims, _, _)| claims.exp <= now)
+                .map(|(claims, _, _)| claims.clone())
+                .collect()
+        };
+
+        for old_claims in expired {
+            let user_id = old_claims.user_id;
+            let partial_claims = PartialClaims {
+                email: old_claims.email.clone(),
+                user_id: Some(user_id),
+                display_name: Some(old_claims.display_name.clone()),
+                exp: old_claims.exp,
+                ..PartialClaims::default()
+            };
+
+            match get_claims(pool, partial_claims).await {
+                Ok(fresh_claims) if fresh_claims.exp > now => {
+                    tracing::info!("Refreshed expired WebSocket claims for user {}", user_id);
+                    self.update_claims(user_id, fresh_claims).await;
+                }
+                result => {
+                    match result {
+                        Ok(_) => tracing::info!("Claims of user {} are past their hard expiry. Closing connections.", user_id),
+                        Err(e) => tracing::warn!("Failed to refresh expired claims for user {}: {:?}. Closing connections.", user_id, e),
+                    }
+
+                    let connections = self.get_connections(user_id).await;
+
+                    for ws in connections {
+                        ws.send_error("auth_expired", "Your session has expired. Please log in again.", None)
+                            .await;
+                        ws.close(CLOSE_AUTH_EXPIRED, "auth expired").await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Warns every connection whose claims expire within `window_seconds`.
+    /// Each connection is warned at most once per expiry.
+    pub async fn warn_expiring_claims(&self, window_seconds: usize) {
+        let now = jsonwebtoken::get_current_timestamp() as usize;
+        let mut to_warn = Vec::new();
+
+        {
+            let mut map = self.inner.write().await;
+            for (claims, connections, expiry_warned) in map.values_mut() {
+                if claims.exp <= now || claims.exp > now + window_seconds {
+                    continue;
+                }
+
+                for ws in connections.iter() {
+                    if expiry_warned.insert(ws.id) {
+                        to_warn.push((ws.clone(), claims.exp));
+                    }
+                }
+            }
+        }
+
+        for (ws, expires_at) in to_warn {
+            let message = ServerMessage::AuthExpiring {
+                auth_expiring: AuthExpiring { expires_at },
+            };
+
+            if let Err(e) = ws.send_msg(&message).await {
+                tracing::error!("Failed to send expiry warning to client {}: {}", ws.id, e);
+            }
+        }
+    }
+}
+
+/// Periodically enforces the hard expiry of claims held by open WebSocket connections
+/// and warns connections shortly before their claims expire.
+pub async fn start_claims_sweep_task(manager: SocketClaimsManager, pool: SqlitePool, warning_window_seconds: usize) {
+    let interval = Duration::from_secs(CLAIMS_SWEEP_INTERVAL_SECONDS);
+
+    loop {
+        sleep(interval).await;
+        tracing::debug!("running WebSocket claims expiry sweep");
+        manager.sweep_expired_claims(&pool).await;
+        manager.warn_expiring_claims(warning_window_seconds).await;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+
+    use super::*;
+    use crate::{
+        app::test_app,
+        tests::{claims_of, connect, receive_until, register},
+    };
+
+    #[tokio::test]
+    async fn the_sweep_closes_connections_past_their_hard_expiry() {
+        let (app, state) = test_app().await;
+        register(&app, "expired@example.com", "Expired").await;
+        let mut claims = claims_of(&state, "expired@example.com").await;
+        claims.exp = jsonwebtoken::get_current_timestamp() as usize - 1;
+        let (_connection, mut rx) = connect(&state, &claims).await;
+
+        state.socket_claims_manager.sweep_expired_claims(&state.pool).await;
+
+        receive_until(&mut rx, "auth_expired").await;
+        match rx.recv().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CLOSE_AUTH_EXPIRED),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        // The refresh kept the expiry instead of issuing a fresh one
+        let (stored, _, _) = state.socket_claims_manager.inner.read().await[&claims.user_id].clone();
+        assert_eq!(stored.exp, claims.exp);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::VecDeque, convert::Infallible};
+
+use axum::{
+    extract::{ws::Message, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+};
+use futures::stream;
+use serde_json::Value;
+use tokio::{sync::{mpsc, watch}, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    auth::Claims,
+    canvas_id::CanvasId,
+    canvas_manager::CanvasManager,
+    error::AppError,
+    handlers::require_canvas_permission,
+    identifiable_web_socket::{CloseRequest, IdentifiableWebSocket},
+    openapi::ErrorResponse,
+    validation::AppPath,
+    AppState,
+};
+
+/// Queue size of a viewer's synthetic connection, same as for WebSocket connections.
+const SSE_QUEUE_SIZE: usize = 128;
+
+/// Unregisters the synthetic viewer connection once the SSE stream is dropped,
+/// i.e. when the client disconnects.
+struct ViewerGuard {
+    canvas_manager: CanvasManager,
+    canvas_id: CanvasId,
+    conn_id: Uuid,
+    registration: Option<JoinHandle<()>>,
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        let canvas_manager = self.canvas_manager.clone();
+        let canvas_id = self.canvas_id;
+        let conn_id = self.conn_id;
+        let registration = self.registration.take();
+
+        tokio::spawn(async move {
+            // Let a registration still sending the history finish first,
+            // so it can't subscribe the viewer after it was unregistered
+            if let Some(registration) = registration {
+                registration.abort();
+                let _ = registration.await;
+            }
+            canvas_manager.unregister_connection(&canvas_id, &conn_id).await;
+        });
+    }
+}
+
+struct ViewerStream {
+    receiver: mpsc::Receiver<Message>,
+    close_requests: watch::Receiver<CloseRequest>,
+    /// False once the connection is gone and can no longer be asked to close.
+    watch_close: bool,
+    pending: VecDeque<Event>,
+    guard: ViewerGuard,
+}
+
+/// Converts a queued server message into an SSE event named after the message type.
+fn to_sse_event(text: &str) -> Option<Event> {
+    let message = serde_json::from_str::<Value>(text).ok()?;
+    let message_type = message.get("type").and_then(Value::as_str).unwrap_or("message");
+    Some(Event::default().event(message_type).data(text))
+}
+
+/// Streams a canvas as Server-Sent Events for read-only viewers:
+/// first the history, then the live messages of the canvas.
+/// Any permission on the canvas allows viewing it, as for WebSocket registration.
+#[utoipa::path(
+    get,
+    path = "/api/canvas/{canvas_id}/stream",
+    tag = "events",
+    params(("canvas_id" = String, Path, description = "Id of the canvas")),
+    responses(
+        (status = 200, description = "History, then live messages as Server-Sent Events", content_type = "text/event-stream"),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 404, description = "No such canvas, or no permission on it", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
+pub async fn stream_canvas(
+    State(state): State<AppState>,
+    claims: Claims,
+    AppPath(canvas_id): AppPath<CanvasId>,
+) -> Result<impl IntoResponse, AppError> {
+    let permission = require_canvas_permission(&claims, &state.pool, &canvas_id, "R").await?;
+
+    let (tx, receiver) = mpsc::channel::<Message>(SSE_QUEUE_SIZE);
+    let connection = IdentifiableWebSocket::new(tx);
+    let conn_id = connection.id;
+    let close_requests = connection.close_requests();
+    tracing::info!("User {} opened an SSE stream for canvas {} (conn_id: {})", claims.user_id, canvas_id, conn_id);
+
+    // The history is sent through the queue, so registration runs alongside the stream draining it
+    let registration = {
+        let canvas_manager = state.canvas_manager.clone();
+        let pool = state.pool.clone();
+        tokio::spawn(async move {
+            canvas_manager
+                .register_viewer(&pool, canvas_id, claims.user_id, claims.display_name, &permission, connection)
+                .await;
+        })
+    };
+
+    let viewer = ViewerStream {
+        receiver,
+        close_requests,
+        watch_close: true,
+        pending: VecDeque::new(),
+        guard: ViewerGuard {
+            canvas_manager: state.canvas_manager.clone(),
+            canvas_id,
+            conn_id,
+            registration: Some(registration),
+        },
+    };
+
+    // Ends once every handle to the connection is gone (e.g. the canvas does not exist)
+    // or the connection is closed by the server
+    let events = stream::unfold(viewer, |mut viewer| async move {
+        loop {
+            if let Some(event) = viewer.pending.pop_front() {
+                return Some((Ok::<_, Infallible>(event), viewer));
+            }
+
+            let message = tokio::select! {
+                biased;
+                changed = viewer.close_requests.changed(), if viewer.watch_close => {
+                    if changed.is_ok() {
+                        tracing::info!("Closing SSE stream {} on request.", viewer.guard.conn_id);
+                        return None;
+                    }
+                    viewer.watch_close = false;
+                    continue;
+                }
+                message = viewer.receiver.recv() => message?,
+            };
+
+            match message {
+                Message::Text(text) => viewer.pending.extend(to_sse_event(text.as_str())),
+                Message::Close(_) => return None,
+                _ => {}
+            }
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
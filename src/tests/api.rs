@@ -0,0 +1,237 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+use super::{claims_of, create_canvas, json_body, register, send, session_cookie, PASSWORD};
+use crate::{
+    app::{self, test_app},
+    config::AppConfig,
+};
+
+#[tokio::test]
+async fn register_login_create_and_list_canvases() {
+    let (app, _state) = test_app().await;
+
+    register(&app, "alice@example.com", "Alice").await;
+
+    let response = send(
+        &app,
+        Method::POST,
+        "/api/login",
+        None,
+        Some(json!({ "email": "alice@example.com", "password": PASSWORD })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = session_cookie(&response);
+
+    let response = send(&app, Method::GET, "/api/canvases/list", Some(&cookie), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await, json!([]));
+
+    let (canvas_id, cookie) = create_canvas(&app, &cookie, "Sketches").await;
+
+    let response = send(&app, Method::GET, "/api/canvases/list", Some(&cookie), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let canvases = json_body(response).await;
+    let canvases = canvases.as_array().unwrap();
+    assert_eq!(canvases.len(), 1);
+    assert_eq!(canvases[0]["canvas_id"], canvas_id.as_str());
+    assert_eq!(canvases[0]["name"], "Sketches");
+    assert_eq!(canvases[0]["permission_level"], "O");
+}
+
+#[tokio::test]
+async fn login_with_a_wrong_password_is_rejected() {
+    let (app, _state) = test_app().await;
+    register(&app, "bob@example.com", "Bob").await;
+
+    let response = send(
+        &app,
+        Method::POST,
+        "/api/login",
+        None,
+        Some(json!({ "email": "bob@example.com", "password": "not the password" })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().get("set-cookie").is_none());
+}
+
+#[tokio::test]
+async fn canvas_routes_require_a_session() {
+    let (app, _state) = test_app().await;
+
+    let response = send(&app, Method::GET, "/api/canvases/list", None, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, Method::POST, "/api/canvases/create", None, Some(json!({ "name": "Nope" }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn registering_an_email_twice_is_a_conflict() {
+    // `build` on a pool of our own, as a test needing a non-default configuration would
+    let config = AppConfig::test_default();
+    crate::auth::init(&config);
+    let pool = app::setup_database(&config).await;
+    let app = app::build(config, pool).await;
+
+    register(&app, "carol@example.com", "Carol").await;
+    let response = send(
+        &app,
+        Method::POST,
+        "/api/register",
+        None,
+        Some(json!({ "email": "carol@example.com", "password": PASSWORD, "display_name": "Carol" })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn invalid_registrations_list_every_invalid_field() {
+    let (app, _state) = test_app().await;
+
+    let response = send(
+        &app,
+        Method::POST,
+        "/api/register",
+        None,
+        Some(json!({ "email": "not-an-email", "password": "short", "display_name": " " })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = json_body(response).await;
+    assert_eq!(body["error"]["code"], "validation_failed");
+    let mut fields: Vec<(&str, &str)> = body["error"]["details"]["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| (field["field"].as_str().unwrap(), field["code"].as_str().unwrap()))
+        .collect();
+    fields.sort();
+    assert_eq!(fields, [("display_name", "required"), ("email", "invalid_email"), ("password", "too_short")]);
+
+    let response = send(&app, Method::POST, "/api/register", None, Some(json!({ "email": 1 }))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json_body(response).await["error"]["code"], "invalid_body");
+}
+
+#[tokio::test]
+async fn created_canvas_is_returned_and_its_location_resolves() {
+    let (app, _state) = test_app().await;
+    let cookie = register(&app, "dave@example.com", "Dave").await;
+
+    let response = send(&app, Method::POST, "/api/canvases/create", Some(&cookie), Some(json!({ "name": "Plans" }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let location = response.headers()[header::LOCATION].to_str().unwrap().to_string();
+    let cookie = session_cookie(&response);
+    let created = json_body(response).await;
+    assert_eq!(location, format!("/api/canvas/{}", created["canvas_id"].as_str().unwrap()));
+    assert_eq!(created["name"], "Plans");
+    assert_eq!(created["owner_display_name"], "Dave");
+    assert_eq!(created["permission_level"], "O");
+    assert!(created["created_at"].as_i64().unwrap() > 0);
+
+    let response = send(&app, Method::GET, &location, Some(&cookie), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await, created);
+}
+
+#[tokio::test]
+async fn retrying_a_creation_with_the_same_idempotency_key_returns_the_first_canvas() {
+    let (app, _state) = test_app().await;
+    let mut cookie = register(&app, "erin@example.com", "Erin").await;
+
+    let mut created = Vec::new();
+    for _ in 0..2 {
+        let request = Request::post("/api/canvases/create")
+            .header(header::COOKIE, &cookie)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", "2f1c8a9e-retry")
+            .body(Body::from(json!({ "name": "Once" }).to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        cookie = session_cookie(&response);
+        created.push(json_body(response).await["canvas_id"].clone());
+    }
+    assert_eq!(created[0], created[1]);
+
+    let response = send(&app, Method::GET, "/api/canvases/list", Some(&cookie), None).await;
+    assert_eq!(json_body(response).await.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn updating_the_profile_returns_the_updated_user() {
+    let (app, _state) = test_app().await;
+    let cookie = register(&app, "frank@example.com", "Frank").await;
+
+    let response = send(&app, Method::POST, "/api/user/update", Some(&cookie), Some(json!({ "display_name": "Franky" }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = session_cookie(&response);
+    let user = json_body(response).await;
+    assert_eq!(user["email"], "frank@example.com");
+    assert_eq!(user["display_name"], "Franky");
+
+    let response = send(&app, Method::GET, "/api/me", Some(&cookie), None).await;
+    assert_eq!(json_body(response).await, user);
+
+    let response = send(&app, Method::POST, "/api/user/update", Some(&cookie), Some(json!({ "displayname": "Typo" }))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json_body(response).await["error"]["code"], "invalid_body");
+}
+
+#[tokio::test]
+async fn compressing_while_the_blocking_pool_is_full_answers_503() {
+    let mut config = AppConfig::test_default();
+    config.canvas_manager.idle_ttl = std::time::Duration::ZERO;
+    let (app, state) = app::test_app_with(config).await;
+    let cookie = register(&app, "admin@example.com", "Admin").await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE email = 'admin@example.com'").execute(&state.pool).await.unwrap();
+    let (canvas_id, cookie) = create_canvas(&app, &cookie, "Idle").await;
+    let user_id = claims_of(&state, "admin@example.com").await.user_id;
+    let stroke = json!({ "type": "shapeAdded", "shape": { "id": "stroke-1", "from": { "x": 1, "y": 2 }, "to": { "x": 3, "y": 4 } } });
+    let canvas_uuid = canvas_id.parse().unwrap();
+    let appended = state.canvas_manager.append_events(&state.pool, "O", user_id, &canvas_uuid, json!([stroke]), None);
+    appended.await.unwrap().persisted.unwrap().await.unwrap().unwrap();
+    state.canvas_manager.unload_idle().await;
+
+    // Occupy every slot of the pool until the gates open
+    let (started_tx, mut started) = tokio::sync::mpsc::unbounded_channel();
+    let mut gates = Vec::new();
+    let mut holders = Vec::new();
+    for _ in 0..state.config.event_store.blocking_concurrency {
+        let (gate, closed) = tokio::sync::oneshot::channel::<()>();
+        let (started_tx, blocking) = (started_tx.clone(), state.blocking.clone());
+        gates.push(gate);
+        holders.push(tokio::spawn(async move {
+            blocking
+                .run(move || {
+                    let _ = started_tx.send(());
+                    let _ = closed.blocking_recv();
+                    Ok(())
+                })
+                .await
+        }));
+        started.recv().await.unwrap();
+    }
+
+    let uri = format!("/api/admin/maintenance/compress_canvas/{}", canvas_id);
+    let response = send(&app, Method::POST, &uri, Some(&cookie), None).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    assert_eq!(json_body(response).await["error"]["code"], "busy");
+
+    drop(gates);
+    for holder in holders {
+        holder.await.unwrap().unwrap();
+    }
+    let response = send(&app, Method::POST, &uri, Some(&cookie), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await, json!({ "changed": true }));
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs,
+    io::{Read, Write},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Method, Request, Response, StatusCode},
+    Router,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tower::ServiceExt;
+
+use crate::app::{self, resolve_public_dir, test_app};
+
+async fn get_gzip(app: &Router, uri: &str) -> Response<Body> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn gunzip(response: Response<Body>) -> String {
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mut text = String::new();
+    GzDecoder::new(&bytes[..]).read_to_string(&mut text).unwrap();
+    text
+}
+
+fn assert_gzipped(response: &Response<Body>) {
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    let vary = response.headers()[header::VARY].to_str().unwrap().to_ascii_lowercase();
+    assert!(vary.contains("accept-encoding"), "Vary: {}", vary);
+}
+
+#[tokio::test]
+async fn api_responses_are_gzipped_when_accepted() {
+    let (app, _state) = test_app().await;
+
+    let response = get_gzip(&app, "/api/openapi.json").await;
+    assert_gzipped(&response);
+    let document: serde_json::Value = serde_json::from_str(&gunzip(response).await).unwrap();
+    assert!(document["paths"].is_object());
+
+    let response = app
+        .oneshot(Request::builder().uri("/api/openapi.json").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+}
+
+#[tokio::test]
+async fn static_files_are_gzipped_or_served_precompressed() {
+    let (_app, state) = test_app().await;
+    let dir = std::env::temp_dir().join(format!("drawing_app_public_{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.html"), "<html>drawing app</html>").unwrap();
+    let script = "console.log('drawing app');\n".repeat(100);
+    fs::write(dir.join("app.js"), &script).unwrap();
+    fs::write(dir.join("vendor.js"), "uncompressed vendor bundle").unwrap();
+    let mut precompressed = GzEncoder::new(Vec::new(), Compression::default());
+    precompressed.write_all(b"precompressed vendor bundle").unwrap();
+    fs::write(dir.join("vendor.js.gz"), precompressed.finish().unwrap()).unwrap();
+    let app = app::router(state, Some(&resolve_public_dir(&dir).unwrap()));
+
+    let response = get_gzip(&app, "/app.js").await;
+    assert_gzipped(&response);
+    assert_eq!(gunzip(response).await, script);
+
+    // The `.gz` next to the file is sent as is
+    let response = get_gzip(&app, "/vendor.js").await;
+    assert_gzipped(&response);
+    assert_eq!(gunzip(response).await, "precompressed vendor bundle");
+}
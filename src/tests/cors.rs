@@ -0,0 +1,77 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode},
+};
+use tower::ServiceExt;
+
+use crate::{
+    app::{self, test_app},
+    origin_policy::OriginPolicy,
+};
+
+const ALLOWED: &str = "https://draw.example.com";
+
+async fn app_allowing(origins: &[&str]) -> axum::Router {
+    let (_app, mut state) = test_app().await;
+    state.origin_policy = OriginPolicy {
+        allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+        allow_missing_origin: false,
+    };
+    app::router(state, None)
+}
+
+async fn preflight(app: &axum::Router, origin: &str) -> Response<Body> {
+    let request = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/canvases/create")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type, idempotency-key")
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn preflights_from_allowed_origins_may_send_credentials() {
+    let app = app_allowing(&[ALLOWED]).await;
+
+    let response = preflight(&app, ALLOWED).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], ALLOWED);
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("POST"));
+    let allowed_headers = headers[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap();
+    assert!(allowed_headers.contains("content-type"));
+    // Retried creates carry an idempotency key
+    assert!(allowed_headers.contains("idempotency-key"));
+}
+
+#[tokio::test]
+async fn preflights_from_other_origins_get_no_cors_headers() {
+    let app = app_allowing(&[ALLOWED]).await;
+    let response = preflight(&app, "https://evil.example.com").await;
+    assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+    // Without an allowlist the API is same-origin only
+    let (app, _state) = test_app().await;
+    let response = preflight(&app, ALLOWED).await;
+    assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+}
+
+#[test]
+fn websocket_upgrades_follow_the_same_allowlist() {
+    let policy = OriginPolicy { allowed_origins: vec![ALLOWED.to_string()], allow_missing_origin: false };
+    let upgrade_from = |origin: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers.insert(header::HOST, HeaderValue::from_static("api.example.com"));
+        policy.check(&headers)
+    };
+
+    assert!(upgrade_from(ALLOWED).is_ok());
+    assert!(upgrade_from("HTTPS://Draw.Example.com/").is_ok());
+    assert_eq!(upgrade_from("https://evil.example.com"), Err("https://evil.example.com".to_string()));
+    assert!(policy.check(&HeaderMap::new()).is_err());
+}
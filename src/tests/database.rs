@@ -0,0 +1,49 @@
+use crate::{
+    app::setup_database,
+    config::{AppConfig, DatabaseConfig},
+};
+
+const WRITERS: usize = 16;
+const WRITES_PER_WRITER: usize = 20;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writers_wait_for_the_lock_instead_of_failing() {
+    let mut config = AppConfig::test_default();
+    std::fs::create_dir_all(&config.data_dir).unwrap();
+    config.database_url = format!("sqlite://{}?mode=rwc", config.data_dir.join("stress.db").display());
+    config.database = DatabaseConfig { max_connections: 8, ..DatabaseConfig::default() };
+    let pool = setup_database(&config).await;
+
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(&pool).await.unwrap();
+    assert_eq!(journal_mode, "wal");
+
+    let writers = (0..WRITERS).map(|writer| {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            for write in 0..WRITES_PER_WRITER {
+                // Like a permission update: a transaction of several writes
+                let mut tx = pool.begin().await?;
+                let user_id = sqlx::query("INSERT INTO users (email, password_hash, display_name) VALUES (?, '', 'stress')")
+                    .bind(format!("writer{}-{}@example.com", writer, write))
+                    .execute(&mut *tx)
+                    .await?
+                    .last_insert_rowid();
+                sqlx::query("UPDATE users SET display_name = ? WHERE user_id = ?")
+                    .bind(format!("Writer {}", writer))
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                // And readers in between
+                sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users").fetch_one(&pool).await?;
+            }
+            Ok::<_, sqlx::Error>(())
+        })
+    });
+    for writer in futures::future::join_all(writers).await {
+        writer.unwrap().unwrap();
+    }
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+    assert_eq!(users as usize, WRITERS * WRITES_PER_WRITER);
+}
@@ -0,0 +1,59 @@
+use std::{fs, path::PathBuf};
+
+use axum::{
+    body::to_bytes,
+    http::{header, Method, StatusCode},
+};
+
+use super::{json_body, send};
+use crate::app::{self, resolve_public_dir, test_app};
+
+/// A public directory holding a built frontend of one page and one script.
+fn public_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("drawing_app_public_{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.html"), "<html>drawing app</html>").unwrap();
+    fs::write(dir.join("app.js"), "console.log('drawing app');").unwrap();
+    dir
+}
+
+#[test]
+fn public_dir_must_exist_and_hold_index_html() {
+    let missing = std::env::temp_dir().join(format!("drawing_app_missing_{}", uuid::Uuid::new_v4()));
+    assert!(resolve_public_dir(&missing).unwrap_err().contains("not accessible"));
+
+    let dir = public_dir();
+    fs::remove_file(dir.join("index.html")).unwrap();
+    assert!(resolve_public_dir(&dir).unwrap_err().contains("no index.html"));
+
+    let dir = public_dir();
+    assert_eq!(resolve_public_dir(&dir).unwrap(), dir.canonicalize().unwrap());
+}
+
+#[tokio::test]
+async fn frontend_mode_serves_files_and_falls_back_to_index_html() {
+    let (_app, state) = test_app().await;
+    let dir = resolve_public_dir(&public_dir()).unwrap();
+    let app = app::router(state, Some(&dir));
+
+    let response = send(&app, Method::GET, "/app.js", None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+
+    // Client-side routes get the SPA
+    let response = send(&app, Method::GET, "/canvas/some-page", None, None).await;
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"<html>drawing app</html>");
+}
+
+#[tokio::test]
+async fn api_only_mode_answers_other_paths_with_a_json_404() {
+    // `test_app` runs without a public directory, like `--no-frontend`
+    let (app, _state) = test_app().await;
+
+    let response = send(&app, Method::GET, "/canvas/some-page", None, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("application/json"));
+    assert_eq!(json_body(response).await["error"]["code"], "not_found");
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, Response, StatusCode},
+    Router,
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+use super::{create_canvas, json_body, register, send};
+use crate::{
+    app::{self, test_app},
+    config::AppConfig,
+    limits::PayloadLimits,
+    rate_limiter::{ConnectionLimits, RateLimitConfig},
+};
+
+const BODY_LIMIT: usize = 1024;
+const UPLOAD_LIMIT: usize = 4096;
+
+async fn app_with_small_limits() -> Router {
+    let (_app, mut state) = test_app().await;
+    state.config = Arc::new(AppConfig {
+        max_request_body_bytes: BODY_LIMIT,
+        max_upload_body_bytes: UPLOAD_LIMIT,
+        ..(*state.config).clone()
+    });
+    app::router(state, None)
+}
+
+/// Asserts the JSON 413 of a body over `limit`.
+async fn assert_payload_too_large(response: Response<Body>, limit: usize) {
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("application/json"));
+    let body = json_body(response).await;
+    assert_eq!(body["error"]["code"], "payload_too_large");
+    assert_eq!(body["error"]["details"]["limit"], limit);
+}
+
+#[tokio::test]
+async fn normal_routes_and_upload_routes_have_distinct_body_limits() {
+    let app = app_with_small_limits().await;
+    let cookie = register(&app, "alice@example.com", "Alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, &cookie, "Sketch").await;
+
+    let between_limits = "x".repeat(2 * BODY_LIMIT);
+    let response = send(
+        &app,
+        Method::POST,
+        "/api/canvases/create",
+        Some(&cookie),
+        Some(json!({ "name": between_limits })),
+    )
+    .await;
+    assert_payload_too_large(response, BODY_LIMIT).await;
+
+    // Past the body limit, the upload route only looks at the events
+    let events_uri = format!("/api/canvas/{}/events", canvas_id);
+    let response = send(&app, Method::POST, &events_uri, Some(&cookie), Some(json!({ "eventsForCanvas": between_limits }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let over_upload_limit = "x".repeat(2 * UPLOAD_LIMIT);
+    let response = send(&app, Method::POST, &events_uri, Some(&cookie), Some(json!({ "eventsForCanvas": over_upload_limit }))).await;
+    assert_payload_too_large(response, UPLOAD_LIMIT).await;
+}
+
+#[tokio::test]
+async fn a_declared_content_length_over_the_limit_is_rejected_before_authentication() {
+    let app = app_with_small_limits().await;
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/api/canvases/create")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, 2 * BODY_LIMIT)
+        .body(Body::from("x".repeat(2 * BODY_LIMIT)))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_payload_too_large(response, BODY_LIMIT).await;
+}
+
+#[test]
+fn the_largest_accepted_message_fits_the_payload_rate_limit() {
+    let limits = PayloadLimits::default();
+    let rate_limits = RateLimitConfig::default();
+    assert_eq!(limits.check_rate_limits(&rate_limits), Ok(()));
+    let mut connection = ConnectionLimits::new(&rate_limits);
+    assert!(connection.check_payload(limits.max_message_bytes).is_ok());
+
+    // A budget below the message limit is a configuration error
+    let small_budget = RateLimitConfig { payload_bytes: 200 * 1024, ..rate_limits };
+    let error = limits.check_rate_limits(&small_budget).unwrap_err();
+    assert!(error.starts_with("WS_RATE_PAYLOAD_BYTES"), "{}", error);
+}
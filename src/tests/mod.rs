@@ -0,0 +1,178 @@
+//! Tests driving the whole app through its router, on the in-memory database of `app::test_app`.
+
+mod api;
+mod compression;
+mod cors;
+mod database;
+mod frontend;
+mod limits;
+mod openapi;
+mod permissions;
+mod request_id;
+mod seed;
+mod version;
+mod websocket;
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ws::Message,
+    http::{header, Method, Request, Response},
+    Router,
+};
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest},
+    MaybeTlsStream, WebSocketStream,
+};
+use tower::ServiceExt;
+
+use crate::{
+    app,
+    auth::{get_claims, Claims, PartialClaims},
+    identifiable_web_socket::IdentifiableWebSocket,
+    AppState,
+};
+
+pub const PASSWORD: &str = "correct horse battery";
+
+/// Sends a request to the app, with a JSON body and the session cookie if given.
+pub async fn send(app: &Router, method: Method, uri: &str, cookie: Option<&str>, body: Option<Value>) -> Response<Body> {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+    let request = match body {
+        Some(body) => request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+pub async fn json_body(response: Response<Body>) -> Value {
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+/// The `auth_token=..` pair of the response's `Set-Cookie`, to send back as `Cookie`.
+pub fn session_cookie(response: &Response<Body>) -> String {
+    let set_cookie = response
+        .headers()
+        .get(header::SET_COOKIE)
+        .expect("response sets the auth cookie")
+        .to_str()
+        .unwrap();
+    set_cookie.split(';').next().unwrap().to_string()
+}
+
+/// Registers a user with `PASSWORD` and returns their session cookie.
+pub async fn register(app: &Router, email: &str, display_name: &str) -> String {
+    let response = send(
+        app,
+        Method::POST,
+        "/api/register",
+        None,
+        Some(json!({ "email": email, "password": PASSWORD, "display_name": display_name })),
+    )
+    .await;
+    assert_eq!(response.status(), 201);
+    session_cookie(&response)
+}
+
+/// Creates a canvas and returns its id and the owner's refreshed session cookie.
+pub async fn create_canvas(app: &Router, cookie: &str, name: &str) -> (String, String) {
+    let response = send(app, Method::POST, "/api/canvases/create", Some(cookie), Some(json!({ "name": name }))).await;
+    assert_eq!(response.status(), 201);
+    let cookie = session_cookie(&response);
+    let body = json_body(response).await;
+    (body["canvas_id"].as_str().unwrap().to_string(), cookie)
+}
+
+/// Gives a user a permission level on a canvas, as its owner.
+pub async fn set_permission(app: &Router, owner_cookie: &str, canvas_id: &str, user_id: i64, permission: &str) {
+    let response = send(
+        app,
+        Method::POST,
+        &format!("/api/canvas/{}/permissions", canvas_id),
+        Some(owner_cookie),
+        Some(json!({ "user_id": user_id, "permission": permission })),
+    )
+    .await;
+    assert!(response.status().is_success(), "{}", response.status());
+}
+
+/// The claims of a user's WebSocket connections, with their permissions as stored.
+pub async fn claims_of(state: &AppState, email: &str) -> Claims {
+    get_claims(&state.pool, PartialClaims { email: email.to_string(), ..PartialClaims::default() })
+        .await
+        .unwrap()
+}
+
+/// A mock WebSocket connection of the claims' user, added to the claims manager like a real one.
+/// Returns the receiving end of what the server sends to it.
+pub async fn connect(state: &AppState, claims: &Claims) -> (IdentifiableWebSocket, mpsc::Receiver<Message>) {
+    let (tx, rx) = mpsc::channel(128);
+    let connection = IdentifiableWebSocket::new(tx);
+    state
+        .socket_claims_manager
+        .add_connection_and_claims(claims.user_id, claims.clone(), connection.clone())
+        .await;
+    (connection, rx)
+}
+
+/// Waits for the first message sent to a mock connection that mentions `needle`.
+/// Returns the text messages received until then, that one included.
+pub async fn receive_until(rx: &mut mpsc::Receiver<Message>, needle: &str) -> Vec<String> {
+    let mut received = Vec::new();
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap_or_else(|_| panic!("no message mentioning {} after {:?}", needle, received))
+            .unwrap();
+        if let Message::Text(text) = message {
+            received.push(text.to_string());
+            if text.contains(needle) {
+                return received;
+            }
+        }
+    }
+}
+
+/// Serves the app on a free local port, for tests that need real connections (WebSocket upgrades).
+pub async fn serve(state: AppState) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app::router(state, None);
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+    addr
+}
+
+pub type WsClient = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Opens a WebSocket to a served app with the session cookie, from `origin` (the app's own origin if `None`).
+pub async fn ws_connect(addr: SocketAddr, cookie: &str, origin: Option<&str>) -> Result<WsClient, tungstenite::Error> {
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    let origin = origin.map(str::to_string).unwrap_or_else(|| format!("http://{}", addr));
+    request.headers_mut().insert(header::COOKIE, cookie.parse().unwrap());
+    request.headers_mut().insert(header::ORIGIN, origin.parse().unwrap());
+    tokio_tungstenite::connect_async(request).await.map(|(socket, _response)| socket)
+}
+
+/// Polls `condition` until it holds, failing the test after 5 seconds.
+pub async fn eventually<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !condition().await {
+        assert!(tokio::time::Instant::now() < deadline, "timed out waiting for {}", what);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
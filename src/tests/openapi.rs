@@ -0,0 +1,47 @@
+use axum::http::{Method, StatusCode};
+
+use super::{json_body, send};
+use crate::app::test_app;
+
+const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+
+/// (path, method) of every `.route(..)` in `app::router`. Routes before the `/api` nest are mounted below it.
+fn mounted_routes() -> Vec<(String, &'static str)> {
+    let source = include_str!("../app.rs");
+    let mut prefix = "/api";
+    let mut routes = Vec::new();
+    for line in source.lines() {
+        if line.contains(".nest(\"/api\"") {
+            prefix = "";
+        }
+        let Some((_, route)) = line.split_once(".route(\"") else {
+            continue;
+        };
+        let (path, handlers) = route.split_once('"').unwrap();
+        for method in METHODS {
+            if handlers.contains(&format!("{}(", method)) {
+                routes.push((format!("{}{}", prefix, path), method));
+            }
+        }
+    }
+    routes
+}
+
+#[tokio::test]
+async fn openapi_document_covers_every_mounted_route() {
+    let (app, _state) = test_app().await;
+
+    let response = send(&app, Method::GET, "/api/openapi.json", None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let document = json_body(response).await;
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+
+    let routes = mounted_routes();
+    assert!(routes.len() > 40, "failed to find the routes in app.rs");
+    let missing: Vec<String> = routes
+        .iter()
+        .filter(|(path, method)| document["paths"][path.as_str()][*method].is_null())
+        .map(|(path, method)| format!("{} {}", method.to_uppercase(), path))
+        .collect();
+    assert!(missing.is_empty(), "routes missing from the OpenAPI document: {:?}", missing);
+}
@@ -0,0 +1,93 @@
+use axum::http::{Method, StatusCode};
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+
+use super::{create_canvas, json_body, register, send, set_permission};
+use crate::app::test_app;
+
+async fn user_id(pool: &SqlitePool, email: &str) -> i64 {
+    sqlx::query_scalar::<_, i64>("SELECT user_id FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+async fn error_of(app: &axum::Router, method: Method, uri: &str, cookie: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let response = send(app, method, uri, Some(cookie), body).await;
+    (response.status(), json_body(response).await["error"].clone())
+}
+
+#[tokio::test]
+async fn non_members_get_the_same_404_as_for_a_missing_canvas() {
+    let (app, _state) = test_app().await;
+    let owner = register(&app, "owner@example.com", "Owner").await;
+    let (canvas_id, _owner) = create_canvas(&app, &owner, "Private").await;
+    let stranger = register(&app, "stranger@example.com", "Stranger").await;
+    let missing_id = uuid::Uuid::new_v4().to_string();
+
+    for (method, path, body) in [
+        (Method::GET, "", None),
+        (Method::DELETE, "", None),
+        (Method::GET, "/permissions", None),
+        (Method::POST, "/permissions", Some(json!({ "user_id": 1, "permission": "" }))),
+        (Method::GET, "/events", None),
+        (Method::GET, "/stream", None),
+        (Method::POST, "/settings", Some(json!({ "coalesce_events": true }))),
+    ] {
+        let existing = error_of(&app, method.clone(), &format!("/api/canvas/{}{}", canvas_id, path), &stranger, body.clone()).await;
+        let missing = error_of(&app, method.clone(), &format!("/api/canvas/{}{}", missing_id, path), &stranger, body).await;
+        assert_eq!(existing.0, StatusCode::NOT_FOUND, "{} {}", method, path);
+        assert_eq!(existing, missing, "{} {}", method, path);
+    }
+}
+
+#[tokio::test]
+async fn members_below_the_required_level_get_a_403_naming_both_levels() {
+    let (app, state) = test_app().await;
+    let owner = register(&app, "owner@example.com", "Owner").await;
+    let (canvas_id, owner) = create_canvas(&app, &owner, "Shared").await;
+    let reader = register(&app, "reader@example.com", "Reader").await;
+    set_permission(&app, &owner, &canvas_id, user_id(&state.pool, "reader@example.com").await, "R").await;
+
+    let response = send(&app, Method::GET, &format!("/api/canvas/{}", canvas_id), Some(&reader), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["permission_level"], "R");
+
+    for (method, path, body, required) in [
+        (Method::DELETE, "", None, "O"),
+        (Method::POST, "/settings", Some(json!({ "coalesce_events": true })), "C"),
+        (Method::POST, "/permissions", Some(json!({ "user_id": 1, "permission": "" })), "M"),
+    ] {
+        let (status, error) = error_of(&app, method.clone(), &format!("/api/canvas/{}{}", canvas_id, path), &reader, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, path);
+        assert_eq!(error["code"], "forbidden");
+        assert_eq!(error["details"], json!({ "required": required, "permission": "R" }));
+    }
+}
+
+#[tokio::test]
+async fn a_revoked_permission_counts_before_the_cookie_is_reissued() {
+    let (app, state) = test_app().await;
+    let owner = register(&app, "owner@example.com", "Owner").await;
+    let (canvas_id, owner) = create_canvas(&app, &owner, "Shared").await;
+    let writer = register(&app, "writer@example.com", "Writer").await;
+    let writer_id = user_id(&state.pool, "writer@example.com").await;
+    let uri = format!("/api/canvas/{}", canvas_id);
+
+    set_permission(&app, &owner, &canvas_id, writer_id, "W").await;
+    assert_eq!(send(&app, Method::GET, &uri, Some(&writer), None).await.status(), StatusCode::OK);
+
+    set_permission(&app, &owner, &canvas_id, writer_id, "").await;
+    assert_eq!(send(&app, Method::GET, &uri, Some(&writer), None).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn malformed_canvas_ids_are_rejected_before_the_permission_check() {
+    let (app, _state) = test_app().await;
+    let cookie = register(&app, "mallory@example.com", "Mallory").await;
+
+    let (status, error) = error_of(&app, Method::GET, "/api/canvas/not-a-uuid", &cookie, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_path");
+}
@@ -0,0 +1,81 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::Value;
+use tower::ServiceExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{app::test_app, json_log_layer, request_id::REQUEST_ID_HEADER};
+
+async fn request_id_of(app: &axum::Router, request_id: Option<&str>) -> String {
+    let mut request = Request::get("/healthz");
+    if let Some(request_id) = request_id {
+        request = request.header(REQUEST_ID_HEADER, request_id);
+    }
+    let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn request_ids_are_echoed_or_generated() {
+    let (app, _state) = test_app().await;
+
+    assert_eq!(request_id_of(&app, Some("proxy-4711")).await, "proxy-4711");
+
+    let generated = request_id_of(&app, None).await;
+    assert!(uuid::Uuid::parse_str(&generated).is_ok());
+    assert_ne!(request_id_of(&app, None).await, generated);
+
+    // Unusable ids are replaced rather than echoed
+    for unusable in ["has spaces", &"x".repeat(129)] {
+        let replaced = request_id_of(&app, Some(unusable)).await;
+        assert!(uuid::Uuid::parse_str(&replaced).is_ok(), "{:?}", unusable);
+    }
+}
+
+/// Collects what the log layer writes.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn json_logs_are_one_parseable_object_per_line_with_the_request_id() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::registry().with(json_log_layer(move || writer.clone()));
+    // The test runtime has a single thread, so everything the request logs goes through this subscriber
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (app, _state) = test_app().await;
+    request_id_of(&app, Some("json-log-test")).await;
+    tracing::info!(answer = 42, "after the request");
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {:?}", e, line)))
+        .collect();
+    assert!(lines.iter().any(|line| line["fields"]["answer"] == 42));
+    assert!(
+        lines.iter().any(|line| line["span"]["request_id"] == "json-log-test"),
+        "no line of the request: {}",
+        output
+    );
+}
@@ -0,0 +1,86 @@
+use axum::http::{Method, StatusCode};
+use serde_json::json;
+
+use super::{json_body, register, send, session_cookie};
+use crate::{
+    app::test_app,
+    event_store::{canvases_dir, event_file_name},
+    seed::{seed, seed_demo, DEMO_PASSWORD},
+};
+
+async fn login(app: &axum::Router, email: &str) -> String {
+    let response = send(
+        app,
+        Method::POST,
+        "/api/login",
+        None,
+        Some(json!({ "email": email, "password": DEMO_PASSWORD })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    session_cookie(&response)
+}
+
+#[tokio::test]
+async fn demo_users_see_their_demo_canvases() {
+    let (app, state) = test_app().await;
+    seed_demo(&state.pool, &state.data_dir).await;
+
+    let cookie = login(&app, "alice@demo.local").await;
+    let response = send(&app, Method::GET, "/api/canvases/list", Some(&cookie), None).await;
+    let mut canvases: Vec<(String, String)> = json_body(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|canvas| {
+            (
+                canvas["name"].as_str().unwrap().to_string(),
+                canvas["permission_level"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    canvases.sort();
+    assert_eq!(
+        canvases,
+        [
+            ("Demo: Moderated classroom".to_string(), "O".to_string()),
+            ("Demo: Read-only gallery".to_string(), "R".to_string()),
+            ("Demo: Shared sketch".to_string(), "O".to_string()),
+        ]
+    );
+
+    let events = std::fs::read_to_string(
+        canvases_dir(&state.data_dir).join(event_file_name("00000000-0000-4000-8000-000000000001")),
+    )
+    .unwrap();
+    assert!(events.lines().count() >= 40);
+}
+
+#[tokio::test]
+async fn seeding_again_keeps_a_single_copy_of_the_demo_data() {
+    let (_app, state) = test_app().await;
+    seed_demo(&state.pool, &state.data_dir).await;
+    seed_demo(&state.pool, &state.data_dir).await;
+
+    let users = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    let canvases = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM Canvas")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!((users, canvases), (5, 4));
+}
+
+#[tokio::test]
+async fn seeding_refuses_a_database_with_real_users_unless_forced() {
+    let (app, state) = test_app().await;
+    register(&app, "real@example.com", "Real").await;
+
+    let result = seed(&state.canvas_manager, &state.pool, &state.data_dir, false).await;
+    assert!(result.unwrap_err().contains("--force"));
+
+    seed(&state.canvas_manager, &state.pool, &state.data_dir, true).await.unwrap();
+}
@@ -0,0 +1,40 @@
+use axum::http::{Method, StatusCode};
+
+use super::{json_body, send};
+use crate::{app::test_app, rate_limiter::PUBLIC_REQUESTS_PER_MINUTE};
+
+#[tokio::test]
+async fn version_reports_the_build() {
+    let (app, _state) = test_app().await;
+
+    let response = send(&app, Method::GET, "/api/version", None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let build = json_body(response).await;
+    assert_eq!(build["version"], env!("CARGO_PKG_VERSION"));
+    for field in ["gitCommit", "eventStore", "database"] {
+        assert!(!build[field].as_str().unwrap().is_empty(), "{} is empty", field);
+    }
+    assert!(build["buildTimestamp"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn healthz_includes_the_build() {
+    let (app, _state) = test_app().await;
+
+    let response = send(&app, Method::GET, "/healthz", None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let health = json_body(response).await;
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["build"]["version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[tokio::test]
+async fn version_is_rate_limited() {
+    let (app, _state) = test_app().await;
+
+    for _ in 0..PUBLIC_REQUESTS_PER_MINUTE {
+        send(&app, Method::GET, "/api/version", None, None).await;
+    }
+    let response = send(&app, Method::GET, "/api/version", None, None).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
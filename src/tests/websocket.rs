@@ -0,0 +1,125 @@
+//! WebSocket connections to a served app, with a real client.
+
+use std::time::Duration;
+
+use axum::http::{header, StatusCode};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, Message};
+
+use super::{claims_of, create_canvas, eventually, register, serve, ws_connect, WsClient};
+use crate::{app::test_app, canvas_id::CanvasId, origin_policy::OriginPolicy};
+
+/// Asserts an upgrade was refused with a 403 before any socket was opened.
+fn assert_forbidden(result: Result<WsClient, tungstenite::Error>, origin: &str) {
+    match result {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "origin {}", origin);
+            let body = String::from_utf8(response.into_body().unwrap_or_default()).unwrap();
+            assert!(body.contains("origin_not_allowed"), "origin {}: {}", origin, body);
+        }
+        Err(e) => panic!("origin {}: unexpected error {}", origin, e),
+        Ok(_) => panic!("origin {}: the upgrade was accepted", origin),
+    }
+}
+
+/// Subscribes a client to a canvas and waits for the end of its history.
+async fn subscribe(socket: &mut WsClient, canvas_id: &CanvasId) {
+    let command = json!({ "type": "registerForCanvas", "canvasId": canvas_id });
+    socket.send(Message::Text(command.to_string().into())).await.unwrap();
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await;
+        let message = message.expect("timed out waiting for the history").unwrap().unwrap();
+        if message.to_text().unwrap_or_default().contains("historyComplete") {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn connections_dropped_without_a_close_frame_are_unregistered() {
+    let (app, state) = test_app().await;
+    let cookie = register(&app, "dropped@example.com", "Dropped").await;
+    let (first, cookie) = create_canvas(&app, &cookie, "First").await;
+    let (second, cookie) = create_canvas(&app, &cookie, "Second").await;
+    let canvases: [CanvasId; 2] = [first.parse().unwrap(), second.parse().unwrap()];
+    let user_id = claims_of(&state, "dropped@example.com").await.user_id;
+    let addr = serve(state.clone()).await;
+    let manager = &state.canvas_manager;
+
+    let mut socket = ws_connect(addr, &cookie, None).await.unwrap();
+    for canvas_id in &canvases {
+        subscribe(&mut socket, canvas_id).await;
+    }
+    let connections = state.socket_claims_manager.get_connections(user_id).await;
+    assert_eq!(connections.len(), 1);
+    assert_eq!(manager.subscriptions(connections[0].id).await.len(), 2);
+
+    // Dropping the client closes the TCP stream without a close frame or unsubscribing
+    drop(socket);
+    eventually("the connection to be unregistered", || async {
+        state.socket_claims_manager.get_connections(user_id).await.is_empty()
+    })
+    .await;
+    eventually("the connection to leave every canvas", || async {
+        for canvas_id in &canvases {
+            if !manager.online_users(canvas_id, false).await.is_empty() {
+                return false;
+            }
+        }
+        manager.subscriptions(connections[0].id).await.is_empty()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn upgrades_from_other_origins_are_forbidden() {
+    let (app, state) = test_app().await;
+    let cookie = register(&app, "forged@example.com", "Forged").await;
+    let user_id = claims_of(&state, "forged@example.com").await.user_id;
+    let addr = serve(state.clone()).await;
+
+    let forged = [
+        "https://evil.example.com".to_string(),
+        "null".to_string(),
+        // Same host, another port. The scheme isn't compared: behind a TLS proxy the app can't know its own
+        format!("http://{}", addr.ip()),
+        format!("http://{}:1", addr.ip()),
+        // Lookalikes of the app's own origin
+        format!("http://{}.evil.example.com", addr),
+        format!("http://evil.example.com@{}", addr),
+        format!("http://{}.nip.io:{}", addr.ip(), addr.port()),
+    ];
+    for origin in &forged {
+        assert_forbidden(ws_connect(addr, &cookie, Some(origin)).await, origin);
+    }
+
+    // Browsers always send an Origin, so native clients without one need `WS_ALLOW_MISSING_ORIGIN`
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    request.headers_mut().insert(header::COOKIE, cookie.parse().unwrap());
+    assert_forbidden(tokio_tungstenite::connect_async(request).await.map(|(socket, _)| socket), "<missing>");
+
+    assert!(state.socket_claims_manager.get_connections(user_id).await.is_empty());
+    // The app's own origin, in any case and with a trailing slash, is the control
+    ws_connect(addr, &cookie, Some(&format!("HTTP://{}/", addr))).await.unwrap();
+}
+
+#[tokio::test]
+async fn upgrades_are_only_allowed_from_the_allowlist_when_there_is_one() {
+    let (app, mut state) = test_app().await;
+    let cookie = register(&app, "listed@example.com", "Listed").await;
+    state.origin_policy = OriginPolicy::new(["https://draw.example.com"], false).unwrap();
+    let addr = serve(state).await;
+
+    let forged = [
+        "https://draw.example.com.evil.example.com".to_string(),
+        "http://draw.example.com".to_string(),
+        "https://draw.example.com:8443".to_string(),
+        // The allowlist replaces the same-origin check
+        format!("http://{}", addr),
+    ];
+    for origin in &forged {
+        assert_forbidden(ws_connect(addr, &cookie, Some(origin)).await, origin);
+    }
+    ws_connect(addr, &cookie, Some("https://draw.example.com")).await.unwrap();
+}
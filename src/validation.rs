@@ -0,0 +1,177 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        FromRequest, FromRequestParts, OptionalFromRequest, Path, Request,
+    },
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::{
+    error::{AppError, FieldError},
+    limits::BodyLimit,
+    AppState,
+};
+
+pub const MAX_EMAIL_CHARS: usize = 254;
+pub const MIN_PASSWORD_CHARS: usize = 8;
+pub const MAX_PASSWORD_CHARS: usize = 128;
+pub const MAX_DISPLAY_NAME_CHARS: usize = 50;
+pub const MAX_CANVAS_NAME_CHARS: usize = 100;
+
+/// A payload that checks its fields after it was deserialized, see `ValidJson`.
+pub trait Validate {
+    /// Adds every invalid field to `errors`, not only the first.
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+/// The invalid fields of a payload, answered with a 422 listing all of them.
+#[derive(Debug, Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, code: &'static str, message: impl Into<String>) {
+        self.0.push(FieldError { field: field.to_string(), code, message: message.into() });
+    }
+
+    /// Checks the length in characters, surrounding whitespace excluded. A blank value is `required`.
+    pub fn length(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        let chars = value.trim().chars().count();
+        if chars == 0 && min > 0 {
+            self.add(field, "required", format!("{} is required.", field));
+        } else if chars < min {
+            self.add(field, "too_short", format!("{} must be at least {} characters long.", field, min));
+        } else if chars > max {
+            self.add(field, "too_long", format!("{} must be at most {} characters long.", field, max));
+        }
+    }
+
+    pub fn email(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.add(field, "required", format!("{} is required.", field));
+        } else if value.chars().count() > MAX_EMAIL_CHARS {
+            self.add(field, "too_long", format!("{} must be at most {} characters long.", field, MAX_EMAIL_CHARS));
+        } else if !is_email(value) {
+            self.add(field, "invalid_email", format!("{} is not a valid email address.", field));
+        }
+    }
+
+    pub fn one_of(&mut self, field: &str, value: &str, allowed: &[&str]) {
+        if !allowed.contains(&value) {
+            self.add(field, "invalid_choice", format!("{} must be one of {:?}.", field, allowed));
+        }
+    }
+
+    fn into_result(self) -> Result<(), AppError> {
+        if self.0.is_empty() { Ok(()) } else { Err(AppError::Validation(self.0)) }
+    }
+}
+
+/// `local@domain.tld`: no whitespace, one `@`, and a domain of at least two non-empty labels.
+/// Deliberately loose, whether the address exists only a mail to it can tell.
+fn is_email(value: &str) -> bool {
+    let Some((local, domain)) = value.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+        && domain.split('.').count() >= 2
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-'))
+}
+
+/// `Json` whose rejections (malformed JSON, missing fields, wrong content type, body too large) use the
+/// error envelope instead of axum's plain-text responses.
+pub struct AppJson<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest<AppState> for AppJson<T> {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let limit = body_limit(&request, state);
+        <Json<T> as FromRequest<AppState>>::from_request(request, state)
+            .await
+            .map(|Json(value)| Self(value))
+            .map_err(|rejection| json_rejection(rejection, limit))
+    }
+}
+
+// For optional bodies: `None` without a `Content-Type`, still rejected if there is one and the body is invalid
+impl<T: DeserializeOwned> OptionalFromRequest<AppState> for AppJson<T> {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &AppState) -> Result<Option<Self>, Self::Rejection> {
+        let limit = body_limit(&request, state);
+        <Json<T> as OptionalFromRequest<AppState>>::from_request(request, state)
+            .await
+            .map(|json| json.map(|Json(value)| Self(value)))
+            .map_err(|rejection| json_rejection(rejection, limit))
+    }
+}
+
+/// `AppJson` that also runs the payload's `Validate` rules.
+pub struct ValidJson<T>(pub T);
+
+impl<T: DeserializeOwned + Validate> FromRequest<AppState> for ValidJson<T> {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let AppJson(value) = <AppJson<T> as FromRequest<AppState>>::from_request(request, state).await?;
+        let mut errors = FieldErrors::default();
+        value.validate(&mut errors);
+        errors.into_result()?;
+        Ok(Self(value))
+    }
+}
+
+/// `Path` whose rejections use the error envelope, e.g. a canvas id that is not a UUID is a 400
+/// instead of axum's plain-text response.
+pub struct AppPath<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequestParts<AppState> for AppPath<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        <Path<T> as FromRequestParts<AppState>>::from_request_parts(parts, state)
+            .await
+            .map(|Path(value)| Self(value))
+            .map_err(path_rejection)
+    }
+}
+
+fn path_rejection(rejection: PathRejection) -> AppError {
+    match rejection {
+        PathRejection::FailedToDeserializePathParams(e) => {
+            AppError::BadRequest { code: "invalid_path", message: e.body_text(), details: None }
+        }
+        // A route whose pattern doesn't match its extractor, a bug rather than a bad request
+        rejection => AppError::Internal(anyhow::anyhow!("Failed to extract the path: {}", rejection.body_text())),
+    }
+}
+
+/// The body limit of the request's route, as set by `payload_too_large_middleware`.
+fn body_limit(request: &Request, state: &AppState) -> usize {
+    request
+        .extensions()
+        .get::<BodyLimit>()
+        .map_or(state.config.max_request_body_bytes, |BodyLimit(limit)| *limit)
+}
+
+fn json_rejection(rejection: JsonRejection, limit: usize) -> AppError {
+    match rejection {
+        // Valid JSON of the wrong shape, e.g. a missing field or a string where a number belongs
+        JsonRejection::JsonDataError(e) => AppError::Unprocessable { code: "invalid_body", message: e.body_text() },
+        JsonRejection::JsonSyntaxError(e) => {
+            AppError::BadRequest { code: "malformed_json", message: e.body_text(), details: None }
+        }
+        JsonRejection::MissingJsonContentType(e) => AppError::UnsupportedMediaType(e.body_text()),
+        rejection if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge {
+            message: "Payload too large.".to_string(),
+            details: json!({ "limit": limit }),
+        },
+        rejection => AppError::BadRequest { code: "invalid_body", message: rejection.body_text(), details: None },
+    }
+}
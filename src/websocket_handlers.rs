@@ -1,12 +1,22 @@
-use axum::{extract::{ws::{Message, WebSocket}, State, WebSocketUpgrade}, response::IntoResponse};
-use futures::StreamExt;
-use std::collections::HashSet;
-use tokio::sync::mpsc;
+use axum::{extract::{ws::{CloseFrame, Message, WebSocket}, State, WebSocketUpgrade}, http::HeaderMap, response::IntoResponse};
+use futures::{stream::SplitSink, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{mpsc, watch};
+use crate::access_log::{AuthenticatedUser, WsAccessLog};
 use crate::auth::{get_claims, Claims, PartialClaims};
+use crate::canvas_id::CanvasId;
+use crate::canvas_manager::{BanChange, HeldReview, HistoryOptions, MuteChange};
+use crate::error::AppError;
+use crate::error_reporting;
+use crate::metrics::WsMetrics;
+use crate::openapi::ErrorResponse;
+use crate::rate_limiter::ConnectionLimits;
+use crate::server_message::{CursorPosition, ServerMessage};
 use crate::AppState;
 use serde::{Deserialize, Serialize};
-use crate::identifiable_web_socket::IdentifiableWebSocket;
+use crate::identifiable_web_socket::{CloseRequest, IdentifiableWebSocket, CLOSE_AUTH_EXPIRED, CLOSE_RATE_LIMITED};
 use futures::SinkExt; // needed for sender.send(...)
+use tracing::Instrument;
 
 
 // ============================= message Struct =============================
@@ -14,16 +24,220 @@ use futures::SinkExt; // needed for sender.send(...)
 #[derive(Serialize, Deserialize)]
 pub struct WebSocketEvents {
     #[serde(rename = "canvasId")]
-    pub canvas_id: String,
+    pub canvas_id: CanvasId,
     #[serde(rename = "eventsForCanvas")]
     pub events_for_canvas: serde_json::Value,
 }
 
+/// An ephemeral cursor position. Relayed to other subscribers but never persisted.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketCursor {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub x: f64,
+    pub y: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+}
+
+/// A chat message sent to everyone subscribed to a canvas.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketChat {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub text: String,
+}
+
+/// A command targeting a single canvas.
 #[derive(Serialize, Deserialize)]
 pub struct WebSocketCommand {
-    pub command: String,
     #[serde(rename = "canvasId")]
-    pub canvas_id: String,
+    pub canvas_id: CanvasId,
+    /// Only used by `registerForCanvas` on an already subscribed canvas.
+    #[serde(rename = "resendHistory", default)]
+    pub resend_history: bool,
+    /// Only used by `registerForCanvas`: leave deleted events out of the history.
+    #[serde(rename = "compactHistory", default)]
+    pub compact_history: bool,
+    /// Only used by `registerForCanvas`: send the full history even if it exceeds the history cap.
+    #[serde(rename = "fullHistory", default)]
+    pub full_history: bool,
+}
+
+/// Deletes events, identified by their sequence numbers, for everyone on a canvas.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketDeleteEvents {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub seqs: Vec<u64>,
+}
+
+/// Sets the slow mode interval of a canvas, 0 turning it off.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketSlowMode {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    #[serde(rename = "slowModeMs")]
+    pub slow_mode_ms: u64,
+}
+
+/// Starts or ends presentation mode on a canvas. Only used by its owner.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketPresentationMode {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub enabled: bool,
+    /// Users besides the owner who may keep drawing while presenting.
+    #[serde(default)]
+    pub presenters: Vec<i64>,
+}
+
+/// Deletes events of anyone on a canvas as a moderator, owner or co-owner.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketModeratorDelete {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub seqs: Vec<u64>,
+    /// Keep the deleted events in a sidecar file for audit.
+    #[serde(default)]
+    pub quarantine: bool,
+}
+
+/// Approves or rejects a batch of events held for review on a moderated canvas.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketHeldBatch {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    #[serde(rename = "batchId")]
+    pub batch_id: String,
+    /// Only used by `rejectHeldEvents`: told to the author.
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Reports a canvas, or some of its events, to its moderators.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketReport {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    /// The reported events; left out to report the whole canvas.
+    #[serde(default)]
+    pub seqs: Vec<u64>,
+    pub reason: String,
+}
+
+/// Pins an announcement to a canvas, replacing the active one.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketAnnouncement {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    pub text: String,
+}
+
+/// Mutes or unmutes a user on a canvas.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketMute {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    /// Only used by `muteUser`: without it the user stays muted until unmuted.
+    #[serde(rename = "durationSecs", default)]
+    pub duration_secs: Option<u64>,
+}
+
+/// Bans or unbans a user from a canvas.
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketBan {
+    #[serde(rename = "canvasId")]
+    pub canvas_id: CanvasId,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    /// Only used by `banUser`: without it the ban is permanent.
+    #[serde(rename = "durationSecs", default)]
+    pub duration_secs: Option<u64>,
+    /// Only used by `banUser`: told to the banned user.
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Every message a client can send, discriminated by its `type` field.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Events(WebSocketEvents),
+    Cursor(WebSocketCursor),
+    Chat(WebSocketChat),
+    DeleteEvents(WebSocketDeleteEvents),
+    ModeratorDelete(WebSocketModeratorDelete),
+    RegisterForCanvas(WebSocketCommand),
+    UnregisterForCanvas(WebSocketCommand),
+    UnregisterAll,
+    ListOnlineUsers(WebSocketCommand),
+    ToggleModerated(WebSocketCommand),
+    ApproveHeldEvents(WebSocketHeldBatch),
+    RejectHeldEvents(WebSocketHeldBatch),
+    Report(WebSocketReport),
+    Announce(WebSocketAnnouncement),
+    SetPresentationMode(WebSocketPresentationMode),
+    SetSlowMode(WebSocketSlowMode),
+    ClearAnnouncement(WebSocketCommand),
+    MuteUser(WebSocketMute),
+    UnmuteUser(WebSocketMute),
+    BanUser(WebSocketBan),
+    UnbanUser(WebSocketBan),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum ClientMessageError {
+    /// The message is not valid JSON or does not match its type.
+    Invalid(String),
+    /// The message has a `type` the server does not know.
+    UnknownType(String),
+}
+
+impl ClientMessage {
+    /// Parses an incoming message in a single pass.
+    ///
+    /// Messages without a `type` field are still accepted in the older untagged shapes:
+    /// `{canvasId, eventsForCanvas}`, `{command, canvasId}`, `{canvasId, x, y}` and `{canvasId, text}`.
+    pub fn parse(text: &str) -> Result<Self, ClientMessageError> {
+        let mut value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| ClientMessageError::Invalid(e.to_string()))?;
+
+        let Some(object) = value.as_object_mut() else {
+            return Err(ClientMessageError::Invalid("Message must be a JSON object.".to_string()));
+        };
+
+        if !object.contains_key("type") {
+            let legacy_type = if object.contains_key("eventsForCanvas") {
+                "events".to_string()
+            } else if let Some(command) = object.get("command").and_then(|c| c.as_str()) {
+                command.to_string()
+            } else if object.contains_key("x") && object.contains_key("y") {
+                "cursor".to_string()
+            } else if object.contains_key("text") {
+                "chat".to_string()
+            } else {
+                return Err(ClientMessageError::Invalid("Message has no type.".to_string()));
+            };
+            object.insert("type".to_string(), serde_json::Value::String(legacy_type));
+        }
+
+        let message_type = object
+            .get("type")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        match serde_json::from_value::<ClientMessage>(value) {
+            Ok(ClientMessage::Unknown) => Err(ClientMessageError::UnknownType(message_type)),
+            Ok(message) => Ok(message),
+            Err(e) => Err(ClientMessageError::Invalid(e.to_string())),
+        }
+    }
 }
 
 
@@ -32,12 +246,46 @@ pub struct WebSocketCommand {
 
 // ============================= handlers =============================
 
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "events",
+    responses(
+        (status = 101, description = "Switches to the WebSocket protocol"),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Origin not allowed", body = ErrorResponse),
+        (status = 503, description = "The server is shutting down", body = ErrorResponse),
+    ),
+    security(("auth_token" = [])),
+)]
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     mut claims: Claims,
     State(state): State<AppState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
 
+    // New connections should go to another instance; the load balancer learns it from /readyz
+    if state.drain.is_draining() {
+        return AppError::Unavailable {
+            code: "server_draining",
+            message: "Server is shutting down.".to_string(),
+            retry_after: Duration::from_secs(5),
+        }
+        .into_response();
+    }
+
+    // A page on another origin must not be able to open a socket riding the user's cookie
+    if let Err(origin) = state.origin_policy.check(&headers) {
+        tracing::warn!("Rejected WebSocket upgrade for user {} from origin {}", claims.user_id, origin);
+        return AppError::Forbidden {
+            code: "origin_not_allowed",
+            message: "Origin not allowed.".to_string(),
+            details: None,
+        }
+        .into_response();
+    }
+
     let now = jsonwebtoken::get_current_timestamp() as usize;
 
     let soft_expired = claims.reissue_time <= now;
@@ -63,10 +311,10 @@ pub async fn ws_handler(
             }
             Err(e) => {
                 tracing::warn!("Failed to refresh claims for WebSocket user {}: {:?}", claims.user_id, e);
-                return axum::response::Response::builder()
-                    .status(axum::http::StatusCode::UNAUTHORIZED)
-                    .body(axum::body::Body::empty())
-                    .unwrap()
+                // Browsers can't see the status of a failed upgrade, so upgrade and close
+                // with a close code the client can act on instead of answering 401.
+                return ws
+                    .on_upgrade(|socket| close_immediately(socket, CLOSE_AUTH_EXPIRED, "auth expired"))
                     .into_response();
             }
         }
@@ -75,66 +323,166 @@ pub async fn ws_handler(
     let user_id = claims.user_id;
     tracing::debug!("Upgrading WebSocket connection for user {}", user_id);
 
-    ws.on_upgrade(move |socket| handle_websocket(socket, claims, state))
+    let max_message_bytes = state.payload_limits.max_message_bytes;
+    let mut response = ws
+        .max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes)
+        .on_upgrade(move |socket| handle_websocket(socket, claims, state, tracing::Span::current()))
+        .into_response();
+    response.extensions_mut().insert(AuthenticatedUser(user_id));
+    response
 }
 
 
 
 
-async fn handle_websocket(socket: WebSocket, claims: Claims, state: AppState) {
+/// `upgrade_span` is the span of the upgrade request, so the connection's log lines carry its request id.
+async fn handle_websocket(socket: WebSocket, claims: Claims, state: AppState, upgrade_span: tracing::Span) {
     let user_id = claims.user_id;
     
     // Create the IdentifiableWebSocket before adding the connection
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::channel::<Message>(128);
+    let (sender, mut receiver) = socket.split();
+    let (tx, rx) = mpsc::channel::<Message>(128);
     let id_socket = IdentifiableWebSocket::new(tx);
 
-    // Add the IdentifiableWebSocket to the claims manager
-    state.socket_claims_manager.add_connection_and_claims(user_id, claims, id_socket.clone()).await;
+    // Every log line of the connection, e.g. of `handle_event`, names the connection and its user
+    let span = tracing::info_span!(parent: &upgrade_span, "ws", connection_id = %id_socket.id, user_id);
 
-    tracing::info!("User {} connected via WebSocket.", user_id);
+    async move {
+        // Add the IdentifiableWebSocket to the claims manager
+        state.socket_claims_manager.add_connection_and_claims(user_id, claims, id_socket.clone()).await;
 
-    // Spawn a task to forward messages from the channel to the WebSocket sink
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Err(e) = sender.send(msg).await {
-                tracing::error!("Failed to send message to client: {}", e);
-                break;
-            }
-        }
-    });
+        tracing::info!("User {} connected via WebSocket.", user_id);
+        WsMetrics::inc(&state.metrics.connections_opened);
+        let access_log = Arc::new(WsAccessLog::connected());
+
+        // Spawn a task to forward messages from the channel to the WebSocket sink
+        error_reporting::spawn(
+            "ws_sender",
+            Some(user_id),
+            None,
+            forward_messages(sender, rx, id_socket.close_requests(), access_log.clone()).in_current_span(),
+        );
 
-    // Track canvases this connection has subscribed to
-    let mut subscribed_canvases = HashSet::<String>::new();
+        // Runs the cleanup once this task ends, however it ends
+        let _cleanup = ConnectionCleanup {
+            state: state.clone(),
+            user_id,
+            id_socket: id_socket.clone(),
+            access_log: access_log.clone(),
+        };
+
+        // Rate limiting state for this connection
+        let mut limits = ConnectionLimits::new(&state.rate_limit_config);
 
-    // Handle incoming messages loop
-    handle_incoming_messages(
-        user_id,
-        &mut receiver,
-        &state,
-        id_socket.clone(),
-        &mut subscribed_canvases,
-    )
+        // Handle incoming messages loop
+        handle_incoming_messages(user_id, &mut receiver, &state, id_socket.clone(), &mut limits, &access_log).await;
+    }
+    .instrument(span)
     .await;
+}
 
-    // Cleanup
-    tracing::info!(
-        "User {}'s WebSocket connection closed. Unsubscribing from {} canvases.",
-        user_id,
-        subscribed_canvases.len()
-    );
+/// Cleans up a WebSocket connection when its socket task ends, also if the task panics:
+/// unsubscribes the connection from every canvas the claims manager has recorded for it
+/// and removes it from the claims manager. Also writes the disconnect line of the access log.
+struct ConnectionCleanup {
+    state: AppState,
+    user_id: i64,
+    id_socket: IdentifiableWebSocket,
+    access_log: Arc<WsAccessLog>,
+}
 
-    for canvas_id in subscribed_canvases.drain() {
-        state
-            .canvas_manager
-            .unregister_connection(&canvas_id, &id_socket.id)
-            .await;
+impl Drop for ConnectionCleanup {
+    fn drop(&mut self) {
+        self.access_log.disconnected();
+
+        let state = self.state.clone();
+        let user_id = self.user_id;
+        let id_socket = self.id_socket.clone();
+
+        // Dropped at the end of the connection's task, so the cleanup stays in the connection's span
+        let cleanup = async move {
+            let subscribed_canvases = state.canvas_manager.take_subscriptions(id_socket.id).await;
+            tracing::info!(
+                "User {}'s WebSocket connection closed. Unsubscribing from {} canvases.",
+                user_id,
+                subscribed_canvases.len()
+            );
+
+            for canvas_id in subscribed_canvases {
+                state.canvas_manager.unregister_connection(&canvas_id, &id_socket.id).await;
+            }
+
+            // Remove the IdentifiableWebSocket from the claims manager
+            state.socket_claims_manager.remove_connection(user_id, &id_socket).await;
+
+            WsMetrics::inc(&state.metrics.connections_closed);
+            tracing::info!("User {}'s WebSocket connection cleanup complete.", user_id);
+        };
+        error_reporting::spawn("ws_cleanup", Some(user_id), None, cleanup.in_current_span());
+    }
+}
+
+
+/// Closes a freshly upgraded socket with the given close code.
+async fn close_immediately(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = socket.send(Message::Close(Some(frame))).await {
+        tracing::debug!("Failed to send close frame: {}", e);
     }
+}
+
+/// Forwards queued messages to the WebSocket sink until the queue closes
+/// or a close is requested. A close request also interrupts a send that is
+/// stuck on a slow client.
+async fn forward_messages(
+    mut sender: SplitSink<WebSocket, Message>,
+    mut rx: mpsc::Receiver<Message>,
+    mut close_requests: watch::Receiver<CloseRequest>,
+    access_log: Arc<WsAccessLog>,
+) {
+    loop {
+        let msg = tokio::select! {
+            biased;
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = close_requests.changed() => break,
+        };
 
-    // Remove the IdentifiableWebSocket from the claims manager
-    state.socket_claims_manager.remove_connection(user_id, &id_socket).await;
+        let is_close = matches!(msg, Message::Close(_));
 
-    tracing::info!("User {}'s WebSocket connection cleanup complete.", user_id);
+        tokio::select! {
+            biased;
+            _ = close_requests.changed() => break,
+            result = sender.send(msg) => {
+                if let Err(e) = result {
+                    tracing::error!("Failed to send message to client: {}", e);
+                    return;
+                }
+                access_log.sent();
+            }
+        }
+
+        // Nothing may follow a close frame
+        if is_close {
+            return;
+        }
+    }
+
+    // Best effort: tell the client why it is being disconnected
+    let close_request = close_requests.borrow().clone();
+    if let Some((code, reason)) = close_request {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(1), sender.send(Message::Close(Some(frame)))).await;
+    }
 }
 
 
@@ -144,24 +492,50 @@ async fn handle_incoming_messages(
     receiver: &mut futures::stream::SplitStream<WebSocket>,
     state: &AppState,
     id_socket: IdentifiableWebSocket,
-    subscribed_canvases: &mut HashSet<String>,
+    limits: &mut ConnectionLimits,
+    access_log: &WsAccessLog,
 ) {
+    let mut close_requests = id_socket.close_requests();
+
     loop {
         tokio::select! {
-            Some(Ok(message)) = receiver.next() => {
+            _ = close_requests.changed() => {
+                tracing::info!("Closing WebSocket connection {} of user {} on request.", id_socket.id, user_id);
+                break;
+            }
+            message = receiver.next() => {
+                // The stream ends, or fails, when the client went away without a close frame
+                // (a dropped TCP connection, a frame over the size limit)
+                let Some(Ok(message)) = message else {
+                    tracing::info!("WebSocket connection {} of user {} ended.", id_socket.id, user_id);
+                    break;
+                };
+                access_log.received();
                 match message {
                     Message::Text(text) => {
                         tracing::info!("Received message from user {}: {}", user_id, text);
 
+                        // Charge the payload budget before doing any parsing work
+                        if let Err(retry_after) = limits.check_payload(text.len()) {
+                            if reject_rate_limited(user_id, &id_socket, limits, retry_after).await {
+                                break;
+                            }
+                            continue;
+                        }
+
                         if let Err(e) = process_command(
                             user_id,
                             text.to_string(),
                             state,
                             id_socket.clone(),
-                            subscribed_canvases
+                            limits,
                         ).await {
                             tracing::error!("Failed to process command for user {}: {}", user_id, e);
                         }
+
+                        if limits.should_close() {
+                            break;
+                        }
                     }
                     Message::Close(_) => {
                         tracing::info!("User {} sent a close frame. Exiting loop.", user_id);
@@ -170,58 +544,465 @@ async fn handle_incoming_messages(
                     _ => {}
                 }
             }
-            else => {
-                break;
-            }
         }
     }
 }
 
+/// Tells the client it is being rate limited and records the violation.
+/// Closes the connection and returns true if the client violated its limits too often.
+async fn reject_rate_limited(
+    user_id: i64,
+    id_socket: &IdentifiableWebSocket,
+    limits: &mut ConnectionLimits,
+    retry_after: Duration,
+) -> bool {
+    tracing::warn!("User {} exceeded a rate limit on connection {}", user_id, id_socket.id);
+
+    id_socket
+        .send_error(
+            "rate_limited",
+            "Too many messages. Slow down.",
+            Some(serde_json::json!({ "retryAfterMs": retry_after.as_millis() as u64 })),
+        )
+        .await;
+
+    if limits.record_violation() {
+        tracing::warn!("User {} repeatedly exceeded rate limits. Closing connection {}.", user_id, id_socket.id);
+        id_socket.close(CLOSE_RATE_LIMITED, "rate limit exceeded").await;
+        return true;
+    }
+    false
+}
+
 async fn process_command(
     user_id: i64,
     text: String,
     state: &AppState,
     id_socket: IdentifiableWebSocket,
-    subscribed_canvases: &mut HashSet<String>,
+    limits: &mut ConnectionLimits,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if let Ok(events) = serde_json::from_str::<WebSocketEvents>(&text) {
-        tracing::info!("Processing WebSocketEvents for canvas {}", events.canvas_id);
-
-        if !events.events_for_canvas.is_array() {
-            tracing::warn!("eventsForCanvas was not an array for user {} on canvas {}", user_id, events.canvas_id);
+    let message = match ClientMessage::parse(&text) {
+        Ok(message) => message,
+        Err(ClientMessageError::UnknownType(message_type)) => {
+            WsMetrics::inc(&state.metrics.messages_invalid);
+            tracing::warn!("Unknown message type '{}' from user {}", message_type, user_id);
+            id_socket
+                .send_error(
+                    "unknown_command",
+                    &format!("Unknown message type '{}'.", message_type),
+                    None,
+                )
+                .await;
+            return Ok(());
+        }
+        Err(ClientMessageError::Invalid(reason)) => {
+            WsMetrics::inc(&state.metrics.messages_invalid);
+            tracing::warn!("Failed to parse incoming message from user {}: {} ({})", user_id, text, reason);
+            id_socket.send_error("invalid_message", &reason, None).await;
             return Ok(());
         }
+    };
 
-        state.canvas_manager.handle_event(state, user_id, events, text).await;
+    state.metrics.record_message(&message);
+
+    // Charge the matching budget before handling the message
+    let budget = match &message {
+        ClientMessage::Events(_) | ClientMessage::Chat(_) => limits.check_event_message(),
+        ClientMessage::Cursor(_) => {
+            if !limits.check_cursor() {
+                return Ok(());
+            }
+            Ok(())
+        }
+        _ => limits.check_command(),
+    };
+    if let Err(retry_after) = budget {
+        reject_rate_limited(user_id, &id_socket, limits, retry_after).await;
         return Ok(());
     }
 
-    if let Ok(cmd) = serde_json::from_str::<WebSocketCommand>(&text) {
-        tracing::info!("Processing WebSocketCommand '{}' for canvas {}", cmd.command, cmd.canvas_id);
-
-        match cmd.command.as_str() {
-            "registerForCanvas" => {
-                state.canvas_manager.register(state, cmd.canvas_id.clone(), user_id, id_socket.clone()).await;
-                subscribed_canvases.insert(cmd.canvas_id.clone());
-                tracing::info!("User {} subscribed to canvas {}", user_id, cmd.canvas_id);
+    match message {
+        ClientMessage::Events(events) => {
+            tracing::info!("Processing WebSocketEvents for canvas {}", events.canvas_id);
+
+            let Some(event_list) = events.events_for_canvas.as_array() else {
+                tracing::warn!("eventsForCanvas was not an array for user {} on canvas {}", user_id, events.canvas_id);
+                id_socket
+                    .send_error("invalid_payload", "eventsForCanvas must be an array.", None)
+                    .await;
+                return Ok(());
+            };
+
+            if let Err(violation) = state.payload_limits.validate_events(event_list, text.len()) {
+                tracing::warn!("Rejected events from user {} on canvas {}: {}", user_id, events.canvas_id, violation);
+                id_socket
+                    .send_error(
+                        "invalid_payload",
+                        &violation.to_string(),
+                        Some(violation.to_json(&state.payload_limits)),
+                    )
+                    .await;
+                // Oversized payloads count towards the same penalty as rate limit violations
+                if limits.record_violation() {
+                    tracing::warn!("User {} repeatedly exceeded payload limits. Closing connection {}.", user_id, id_socket.id);
+                    id_socket.close(CLOSE_RATE_LIMITED, "rate limit exceeded").await;
+                }
+                return Ok(());
             }
-            "unregisterForCanvas" => {
-                state.canvas_manager.unregister_connection(&cmd.canvas_id, &id_socket.id).await;
-                subscribed_canvases.remove(&cmd.canvas_id);
-                tracing::info!("User {} unsubscribed from canvas {}", user_id, cmd.canvas_id);
+
+            state.canvas_manager.handle_event(state, user_id, &id_socket, events).await;
+        }
+        ClientMessage::Cursor(cursor) => {
+            let canvas_id = cursor.canvas_id.to_string();
+            state
+                .canvas_manager
+                .relay_ephemeral(user_id, &id_socket.id, &cursor.canvas_id, |user_id, display_name| {
+                    ServerMessage::Cursor {
+                        canvas_id,
+                        cursor: CursorPosition {
+                            user_id,
+                            display_name,
+                            x: cursor.x,
+                            y: cursor.y,
+                            tool: cursor.tool,
+                        },
+                    }
+                })
+                .await;
+        }
+        ClientMessage::Chat(chat) => {
+            state
+                .canvas_manager
+                .handle_chat(user_id, &id_socket, &chat.canvas_id, &chat.text)
+                .await;
+        }
+        ClientMessage::DeleteEvents(delete) => {
+            state
+                .canvas_manager
+                .delete_events(user_id, &id_socket, &delete.canvas_id, delete.seqs)
+                .await;
+        }
+        ClientMessage::ModeratorDelete(delete) => {
+            state
+                .canvas_manager
+                .moderator_delete_events(&state.pool, user_id, &id_socket, &delete.canvas_id, delete.seqs, delete.quarantine)
+                .await;
+        }
+        ClientMessage::RegisterForCanvas(cmd) => {
+            // Subscriptions on a draining server only delay its shutdown
+            if state.drain.is_draining() {
+                id_socket
+                    .send_error(
+                        "serverDraining",
+                        "The server is shutting down. Reconnect to register for the canvas.",
+                        Some(serde_json::json!({ "canvasId": cmd.canvas_id })),
+                    )
+                    .await;
+                return Ok(());
             }
-            "toggleModerated" => {
-                state.canvas_manager.toggle_moderated_state(state, user_id, cmd.canvas_id.clone()).await;
-                tracing::info!("User {} toggled moderation on canvas {}", user_id, cmd.canvas_id);
+
+            // Re-registering an already subscribed canvas does not take another slot
+            let limit = state.max_subscriptions_per_connection;
+            let subscribed_canvases = state.canvas_manager.subscriptions(id_socket.id).await;
+            if !subscribed_canvases.contains(&cmd.canvas_id) && subscribed_canvases.len() >= limit {
+                tracing::warn!(
+                    "User {} hit the subscription limit ({}) on connection {}",
+                    user_id,
+                    limit,
+                    id_socket.id
+                );
+                id_socket
+                    .send_error(
+                        "subscription_limit",
+                        "Too many canvas subscriptions on this connection. Unregister from a canvas first.",
+                        Some(serde_json::json!({ "canvasId": cmd.canvas_id, "maxSubscriptions": limit })),
+                    )
+                    .await;
+                return Ok(());
             }
-            _ => {
-                tracing::warn!("Unknown WebSocketCommand '{}' from user {}", cmd.command, user_id);
+
+            let history = HistoryOptions { compact: cmd.compact_history, full: cmd.full_history };
+            state
+                .canvas_manager
+                .register(&state.pool, cmd.canvas_id, user_id, id_socket.clone(), cmd.resend_history, history)
+                .await;
+            tracing::info!("User {} subscribed to canvas {}", user_id, cmd.canvas_id);
+        }
+        ClientMessage::UnregisterForCanvas(cmd) => {
+            state.canvas_manager.unregister_connection(&cmd.canvas_id, &id_socket.id).await;
+            tracing::info!("User {} unsubscribed from canvas {}", user_id, cmd.canvas_id);
+        }
+        ClientMessage::UnregisterAll => {
+            for canvas_id in state.canvas_manager.take_subscriptions(id_socket.id).await {
+                state.canvas_manager.unregister_connection(&canvas_id, &id_socket.id).await;
             }
+            tracing::info!("User {} unsubscribed connection {} from all canvases", user_id, id_socket.id);
         }
-
-        return Ok(());
+        ClientMessage::ListOnlineUsers(cmd) => {
+            state.canvas_manager.send_online_users(user_id, &cmd.canvas_id, &id_socket).await;
+        }
+        ClientMessage::ToggleModerated(cmd) => {
+            state.canvas_manager.toggle_moderated_state(state, user_id, &id_socket, &cmd.canvas_id).await;
+        }
+        ClientMessage::ApproveHeldEvents(review) => {
+            state
+                .canvas_manager
+                .handle_held_review(state, user_id, &id_socket, &review.canvas_id, &review.batch_id, HeldReview::Approve)
+                .await;
+        }
+        ClientMessage::RejectHeldEvents(review) => {
+            state
+                .canvas_manager
+                .handle_held_review(state, user_id, &id_socket, &review.canvas_id, &review.batch_id, HeldReview::Reject(review.reason))
+                .await;
+        }
+        ClientMessage::Report(report) => {
+            state
+                .canvas_manager
+                .handle_report(state, user_id, &id_socket, &report.canvas_id, report.seqs, &report.reason)
+                .await;
+        }
+        ClientMessage::Announce(announcement) => {
+            state
+                .canvas_manager
+                .handle_announcement(state, user_id, &id_socket, &announcement.canvas_id, Some(announcement.text))
+                .await;
+        }
+        ClientMessage::SetSlowMode(slow_mode) => {
+            state
+                .canvas_manager
+                .handle_slow_mode(state, user_id, &id_socket, &slow_mode.canvas_id, slow_mode.slow_mode_ms)
+                .await;
+        }
+        ClientMessage::SetPresentationMode(mode) => {
+            let presenters = mode.enabled.then_some(mode.presenters);
+            state
+                .canvas_manager
+                .handle_presentation_mode(user_id, &id_socket, &mode.canvas_id, presenters)
+                .await;
+        }
+        ClientMessage::ClearAnnouncement(cmd) => {
+            state.canvas_manager.handle_announcement(state, user_id, &id_socket, &cmd.canvas_id, None).await;
+        }
+        ClientMessage::MuteUser(mute) => {
+            let change = MuteChange::Mute(mute.duration_secs.map(Duration::from_secs));
+            state
+                .canvas_manager
+                .handle_mute(state, user_id, &id_socket, &mute.canvas_id, mute.user_id, change)
+                .await;
+        }
+        ClientMessage::UnmuteUser(mute) => {
+            state
+                .canvas_manager
+                .handle_mute(state, user_id, &id_socket, &mute.canvas_id, mute.user_id, MuteChange::Unmute)
+                .await;
+        }
+        ClientMessage::BanUser(ban) => {
+            let change = BanChange::Ban(ban.duration_secs.map(Duration::from_secs), ban.reason);
+            state
+                .canvas_manager
+                .handle_ban(state, user_id, &id_socket, &ban.canvas_id, ban.user_id, change)
+                .await;
+        }
+        ClientMessage::UnbanUser(ban) => {
+            state
+                .canvas_manager
+                .handle_ban(state, user_id, &id_socket, &ban.canvas_id, ban.user_id, BanChange::Unban)
+                .await;
+        }
+        ClientMessage::Unknown => {}
     }
 
-    tracing::warn!("Failed to parse incoming message from user {}: {}", user_id, text);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        app::test_app,
+        tests::{claims_of, connect, create_canvas, receive_until, register},
+    };
+
+    async fn send_text(state: &AppState, claims: &Claims, connection: &IdentifiableWebSocket, text: String) {
+        let mut limits = ConnectionLimits::new(&state.rate_limit_config);
+        process_command(claims.user_id, text, state, connection.clone(), &mut limits).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn registering_again_sends_the_history_only_when_asked_to() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (canvas_id, _cookie) = create_canvas(&app, &cookie, "Twice").await;
+        let claims = claims_of(&state, "owner@example.com").await;
+        let (connection, mut rx) = connect(&state, &claims).await;
+        let register_for = |resend: bool| {
+            serde_json::json!({ "type": "registerForCanvas", "canvasId": canvas_id, "resendHistory": resend }).to_string()
+        };
+
+        send_text(&state, &claims, &connection, register_for(false)).await;
+        let received = receive_until(&mut rx, "historyComplete").await;
+        assert!(received.iter().any(|text| text.contains(r#""type":"history""#)));
+
+        send_text(&state, &claims, &connection, register_for(false)).await;
+        let list = serde_json::json!({ "type": "listOnlineUsers", "canvasId": canvas_id }).to_string();
+        send_text(&state, &claims, &connection, list).await;
+        let received = receive_until(&mut rx, "onlineUsers").await;
+        // Neither the history nor its `historyComplete`
+        assert!(!received.iter().any(|text| text.contains(r#""type":"history"#)), "{:?}", received);
+
+        send_text(&state, &claims, &connection, register_for(true)).await;
+        let received = receive_until(&mut rx, "historyComplete").await;
+        assert!(received.iter().any(|text| text.contains(r#""type":"history""#)));
+    }
+
+    #[tokio::test]
+    async fn unregister_all_leaves_every_canvas_of_the_connection() {
+        let (app, state) = test_app().await;
+        let cookie = register(&app, "owner@example.com", "Owner").await;
+        let (first, cookie) = create_canvas(&app, &cookie, "First").await;
+        let (second, _cookie) = create_canvas(&app, &cookie, "Second").await;
+        let claims = claims_of(&state, "owner@example.com").await;
+        let (connection, mut rx) = connect(&state, &claims).await;
+
+        for canvas_id in [&first, &second] {
+            let text = serde_json::json!({ "type": "registerForCanvas", "canvasId": canvas_id }).to_string();
+            send_text(&state, &claims, &connection, text).await;
+            receive_until(&mut rx, "historyComplete").await;
+        }
+        assert_eq!(state.canvas_manager.subscriptions(connection.id).await.len(), 2);
+
+        send_text(&state, &claims, &connection, r#"{"type": "unregisterAll"}"#.to_string()).await;
+        assert!(state.canvas_manager.subscriptions(connection.id).await.is_empty());
+
+        // Events on either canvas don't reach the connection any more
+        for canvas_id in [&first, &second] {
+            let stroke = serde_json::json!([{ "type": "shapeAdded", "shape": { "id": canvas_id, "borderColor": "black", "from": { "x": 0, "y": 0 }, "to": { "x": 1, "y": 1 } } }]);
+            state
+                .canvas_manager
+                .append_events(&state.pool, "O", claims.user_id, &canvas_id.parse().unwrap(), stroke, None)
+                .await
+                .unwrap();
+        }
+        let text = serde_json::json!({ "type": "registerForCanvas", "canvasId": first }).to_string();
+        send_text(&state, &claims, &connection, text).await;
+        let received = receive_until(&mut rx, "historyComplete").await;
+        assert!(!received.iter().any(|text| text.contains(r#""type":"events""#)), "{:?}", received);
+    }
+
+    const CANVAS: &str = "6f9619ff-8b86-d011-b42d-00c04fc964ff";
+
+    /// One message of every type, with every field the type serializes.
+    fn every_message() -> Vec<serde_json::Value> {
+        let c = CANVAS;
+        vec![
+            serde_json::json!({ "type": "events", "canvasId": c, "eventsForCanvas": [{ "type": "shapeAdded" }] }),
+            serde_json::json!({ "type": "cursor", "canvasId": c, "x": 1.5, "y": 2.0, "tool": "pen" }),
+            serde_json::json!({ "type": "chat", "canvasId": c, "text": "hi" }),
+            serde_json::json!({ "type": "deleteEvents", "canvasId": c, "seqs": [1, 2] }),
+            serde_json::json!({ "type": "moderatorDelete", "canvasId": c, "seqs": [3], "quarantine": true }),
+            serde_json::json!({ "type": "registerForCanvas", "canvasId": c, "resendHistory": true, "compactHistory": false, "fullHistory": true }),
+            serde_json::json!({ "type": "unregisterForCanvas", "canvasId": c, "resendHistory": false, "compactHistory": false, "fullHistory": false }),
+            serde_json::json!({ "type": "unregisterAll" }),
+            serde_json::json!({ "type": "listOnlineUsers", "canvasId": c, "resendHistory": false, "compactHistory": false, "fullHistory": false }),
+            serde_json::json!({ "type": "toggleModerated", "canvasId": c, "resendHistory": false, "compactHistory": false, "fullHistory": false }),
+            serde_json::json!({ "type": "approveHeldEvents", "canvasId": c, "batchId": "b1", "reason": null }),
+            serde_json::json!({ "type": "rejectHeldEvents", "canvasId": c, "batchId": "b1", "reason": "off topic" }),
+            serde_json::json!({ "type": "report", "canvasId": c, "seqs": [4], "reason": "spam" }),
+            serde_json::json!({ "type": "announce", "canvasId": c, "text": "Welcome" }),
+            serde_json::json!({ "type": "setPresentationMode", "canvasId": c, "enabled": true, "presenters": [2] }),
+            serde_json::json!({ "type": "setSlowMode", "canvasId": c, "slowModeMs": 5000 }),
+            serde_json::json!({ "type": "clearAnnouncement", "canvasId": c, "resendHistory": false, "compactHistory": false, "fullHistory": false }),
+            serde_json::json!({ "type": "muteUser", "canvasId": c, "userId": 2, "durationSecs": 60 }),
+            serde_json::json!({ "type": "unmuteUser", "canvasId": c, "userId": 2, "durationSecs": null }),
+            serde_json::json!({ "type": "banUser", "canvasId": c, "userId": 2, "durationSecs": null, "reason": "spam" }),
+            serde_json::json!({ "type": "unbanUser", "canvasId": c, "userId": 2, "durationSecs": null, "reason": null }),
+        ]
+    }
+
+    fn parse(value: serde_json::Value) -> Result<ClientMessage, ClientMessageError> {
+        ClientMessage::parse(&value.to_string())
+    }
+
+    #[test]
+    fn every_message_type_round_trips() {
+        let mut types = HashSet::new();
+        for value in every_message() {
+            let message = parse(value.clone()).unwrap_or_else(|e| panic!("{} failed to parse: {:?}", value, e));
+            // Exhaustive, so a new message type fails to compile until it is added to `every_message`
+            let message_type = match &message {
+                ClientMessage::Events(_) => "events",
+                ClientMessage::Cursor(_) => "cursor",
+                ClientMessage::Chat(_) => "chat",
+                ClientMessage::DeleteEvents(_) => "deleteEvents",
+                ClientMessage::ModeratorDelete(_) => "moderatorDelete",
+                ClientMessage::RegisterForCanvas(_) => "registerForCanvas",
+                ClientMessage::UnregisterForCanvas(_) => "unregisterForCanvas",
+                ClientMessage::UnregisterAll => "unregisterAll",
+                ClientMessage::ListOnlineUsers(_) => "listOnlineUsers",
+                ClientMessage::ToggleModerated(_) => "toggleModerated",
+                ClientMessage::ApproveHeldEvents(_) => "approveHeldEvents",
+                ClientMessage::RejectHeldEvents(_) => "rejectHeldEvents",
+                ClientMessage::Report(_) => "report",
+                ClientMessage::Announce(_) => "announce",
+                ClientMessage::SetPresentationMode(_) => "setPresentationMode",
+                ClientMessage::SetSlowMode(_) => "setSlowMode",
+                ClientMessage::ClearAnnouncement(_) => "clearAnnouncement",
+                ClientMessage::MuteUser(_) => "muteUser",
+                ClientMessage::UnmuteUser(_) => "unmuteUser",
+                ClientMessage::BanUser(_) => "banUser",
+                ClientMessage::UnbanUser(_) => "unbanUser",
+                ClientMessage::Unknown => unreachable!("parse rejects unknown types"),
+            };
+            assert_eq!(value["type"], message_type);
+            assert_eq!(serde_json::to_value(&message).unwrap(), value);
+            types.insert(message_type);
+        }
+        assert_eq!(types.len(), every_message().len());
+    }
+
+    #[test]
+    fn untagged_messages_of_older_clients_still_parse() {
+        let c = CANVAS;
+        let events = parse(serde_json::json!({ "canvasId": c, "eventsForCanvas": [] }));
+        assert!(matches!(events, Ok(ClientMessage::Events(_))));
+        // Events win over a stray `command`, as they did before
+        let events = parse(serde_json::json!({ "command": "registerForCanvas", "canvasId": c, "eventsForCanvas": [] }));
+        assert!(matches!(events, Ok(ClientMessage::Events(_))));
+
+        let register = parse(serde_json::json!({ "command": "registerForCanvas", "canvasId": c, "resendHistory": true }));
+        assert!(matches!(register, Ok(ClientMessage::RegisterForCanvas(WebSocketCommand { resend_history: true, .. }))));
+        let unregister_all = parse(serde_json::json!({ "command": "unregisterAll" }));
+        assert!(matches!(unregister_all, Ok(ClientMessage::UnregisterAll)));
+        let mute = parse(serde_json::json!({ "command": "muteUser", "canvasId": c, "userId": 2 }));
+        assert!(matches!(mute, Ok(ClientMessage::MuteUser(WebSocketMute { user_id: 2, duration_secs: None, .. }))));
+
+        let cursor = parse(serde_json::json!({ "canvasId": c, "x": 1, "y": 2 }));
+        assert!(matches!(cursor, Ok(ClientMessage::Cursor(WebSocketCursor { tool: None, .. }))));
+        let chat = parse(serde_json::json!({ "canvasId": c, "text": "hi" }));
+        assert!(matches!(chat, Ok(ClientMessage::Chat(_))));
+    }
+
+    #[test]
+    fn unknown_and_malformed_messages_are_told_apart() {
+        let c = CANVAS;
+        assert!(matches!(parse(serde_json::json!({ "type": "dance", "canvasId": c })), Err(ClientMessageError::UnknownType(t)) if t == "dance"));
+        assert!(matches!(parse(serde_json::json!({ "command": "dance", "canvasId": c })), Err(ClientMessageError::UnknownType(t)) if t == "dance"));
+
+        // A malformed events message is not mistaken for the command it also names
+        let misrouted = parse(serde_json::json!({ "type": "events", "command": "registerForCanvas", "canvasId": c }));
+        assert!(matches!(misrouted, Err(ClientMessageError::Invalid(_))));
+
+        for invalid in [
+            "not json",
+            "[]",
+            "{}",
+            r#"{"type": "chat", "canvasId": "not-a-uuid", "text": "hi"}"#,
+            r#"{"type": "setSlowMode", "canvasId": "6f9619ff-8b86-d011-b42d-00c04fc964ff"}"#,
+        ] {
+            assert!(matches!(ClientMessage::parse(invalid), Err(ClientMessageError::Invalid(_))), "{}", invalid);
+        }
+    }
+}